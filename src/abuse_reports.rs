@@ -0,0 +1,69 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use tokio::sync::Mutex;
+use crate::ws_dto_models::AbuseReportDto;
+
+/// Reports kept for the admin API, the oldest are dropped first
+const ABUSE_REPORTS_SIZE: usize = 1000;
+
+/// Reports of members about other members, listed by `GET /api/reports` and posted as JSON to
+/// `report_webhook_url` for moderation tools
+#[derive(Debug)]
+pub struct AbuseReports {
+    reports: Mutex<VecDeque<AbuseReportDto>>,
+    client: Client<HttpConnector>,
+}
+
+impl AbuseReports {
+    pub fn new() -> Self {
+        AbuseReports {
+            reports: Mutex::new(VecDeque::new()),
+            client: Client::new(),
+        }
+    }
+
+    /// Keeps the report and forwards it in the background when there is a webhook
+    pub async fn record(self: &Arc<Self>, report: AbuseReportDto, webhook_url: Option<&str>) {
+        let webhook_url = webhook_url.and_then(|url| url.parse::<Uri>().map_err(|e| tracing::error!("Invalid report_webhook_url: {}", e)).ok());
+        {
+            let mut reports = self.reports.lock().await;
+            if reports.len() == ABUSE_REPORTS_SIZE {
+                reports.pop_front();
+            }
+            reports.push_back(report.clone());
+        }
+
+        if let Some(webhook_url) = webhook_url {
+            let abuse_reports = self.clone();
+            tokio::spawn(async move {
+                match abuse_reports.deliver(webhook_url, &report).await {
+                    Ok(status) if !status.is_success() => {
+                        tracing::warn!(report_id = %report.report_id, "Report webhook responded with {}", status);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!("Failed to deliver abuse report: {:?}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Newest first
+    pub async fn list(&self) -> Vec<AbuseReportDto> {
+        self.reports.lock().await.iter().rev().cloned().collect()
+    }
+
+    async fn deliver(&self, webhook_url: Uri, report: &AbuseReportDto) -> anyhow::Result<StatusCode> {
+        let body = serde_json::to_string(report)?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(webhook_url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))?;
+
+        Ok(self.client.request(request).await?.status())
+    }
+}
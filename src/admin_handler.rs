@@ -0,0 +1,220 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::{Request, State};
+use uuid::Uuid;
+use crate::auth::constant_time_eq;
+use crate::ws_app_state::{DisconnectReason, Room, WsAppState};
+use crate::config_reload::reload_config;
+use crate::ws_dto_models::{AbuseReportDto, AdminClientDto, ArchivedRoomDto, AdminRoomDetailsDto, AdminRoomDto, ConfigReloadDto, DrainStatusDto, RoomDataDto, RoomHistoryEntryDto, SessionSummaryDto};
+use crate::ws_handler::{close_room, handle_client_disconnect, response_with_json};
+use crate::protocol::{NoticeLevel, OutgoingMessage};
+use rocket::serde::Deserialize;
+use ts_rs::TS;
+
+/// Requests carrying `Authorization: Bearer <admin_token>`. Without a configured token every
+/// request is answered with 404, as if the routes didn't exist.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(admin_token) = request.rocket().state::<Arc<WsAppState>>().and_then(|state| state.config().admin_token.clone()) else {
+            return Outcome::Error((Status::NotFound, ()));
+        };
+        let token = request.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer "));
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Outcome::Success(Admin),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+async fn find_room(state: &WsAppState, namespace: Option<&str>, room_id: &str) -> Option<Arc<Room>> {
+    let room_id = state.resolve_room_id(namespace, room_id).await;
+    state.store.room(namespace, &room_id).await
+}
+
+#[get("/api/rooms")]
+pub async fn list_rooms(_admin: Admin, state: &State<Arc<WsAppState>>) -> Json<Vec<AdminRoomDto>> {
+    Json(state.admin_rooms().await)
+}
+
+#[get("/api/rooms/<room_id>?<namespace>")]
+pub async fn get_room(_admin: Admin, room_id: &str, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Option<Json<AdminRoomDetailsDto>> {
+    let room = find_room(state, namespace, room_id).await?;
+    let summary_room_id = room.room_id.clone();
+    let (data, summary) = room.run(move |room_data| (RoomDataDto::with_all_members(room_data), SessionSummaryDto::from(&summary_room_id, room_data))).await.ok()?;
+    Some(Json(AdminRoomDetailsDto {
+        room_id: room.room_id.clone(),
+        creator_uid: room.creator_uid,
+        creator_ip: room.creator_ip.map(|ip| ip.to_string()),
+        summary,
+        data,
+    }))
+}
+
+/// Audit log of the room, newest first
+#[get("/api/rooms/<room_id>/history?<namespace>")]
+pub async fn get_room_history(_admin: Admin, room_id: &str, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Option<Json<Vec<RoomHistoryEntryDto>>> {
+    let room = find_room(state, namespace, room_id).await?;
+    let entries = room.run(|room_data| room_data.history.iter().rev().cloned().collect()).await.ok()?;
+    Some(Json(entries))
+}
+
+/// Sends every member a `RoomClosed` and removes the room, the members stay connected
+#[delete("/api/rooms/<room_id>?<namespace>")]
+pub async fn delete_room(_admin: Admin, room_id: &str, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Status {
+    let Some(room) = find_room(state, namespace, room_id).await else {
+        return Status::NotFound;
+    };
+    match close_room(state, &room).await {
+        Ok(()) => Status::NoContent,
+        Err(e) => {
+            tracing::error!("Error while closing room: {:?}", e);
+            Status::InternalServerError
+        }
+    }
+}
+
+#[get("/api/clients")]
+pub async fn list_clients(_admin: Admin, state: &State<Arc<WsAppState>>) -> Json<Vec<AdminClientDto>> {
+    let mut client_dtos = Vec::new();
+    for client in state.clients.snapshot() {
+        let (name, room_id, user_id) = {
+            let client_data = client.data.lock().await;
+            (client_data.name.clone(), client_data.room.as_ref().map(|room| room.room_id.clone()), client_data.user_id.clone())
+        };
+        let client_info = client.client_info();
+        let traffic = client.traffic();
+        client_dtos.push(AdminClientDto {
+            uid: client.uid,
+            name,
+            ip: client.ip.map(|ip| ip.to_string()),
+            room_id,
+            user_id,
+            detached: client.detached.load(Ordering::SeqCst),
+            idle_secs: client.idle_for().as_secs(),
+            protocol_version: client_info.as_ref().map(|client_info| client_info.protocol_version),
+            client_name: client_info.as_ref().map(|client_info| client_info.client_name.clone()),
+            client_version: client_info.map(|client_info| client_info.client_version),
+            bytes_received: traffic.connection.received(),
+            bytes_sent: traffic.connection.sent(),
+        });
+    }
+    client_dtos.sort_by_key(|client| client.uid);
+    Json(client_dtos)
+}
+
+/// Closes the connection of the client, a client waiting to be resumed is removed right away
+#[delete("/api/clients/<uid>")]
+pub async fn delete_client(_admin: Admin, uid: &str, state: &State<Arc<WsAppState>>) -> Status {
+    let Some(client) = Uuid::parse_str(uid).ok().and_then(|uid| state.clients.get(uid)) else {
+        return Status::NotFound;
+    };
+    tracing::info!(client_uid = %client.uid, "Removing client on behalf of an administrator");
+    client.disconnect(DisconnectReason::RemovedByAdmin, "Removed by an administrator", None);
+    if client.detached.load(Ordering::SeqCst) {
+        handle_client_disconnect(state, &client).await;
+    }
+    Status::NoContent
+}
+
+#[derive(Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ServerNoticeRequest {
+    text: String,
+    #[serde(default)]
+    level: NoticeLevel,
+    /// Only the members of this room get the notice, every client does without one
+    room_id: Option<String>,
+    namespace: Option<String>,
+}
+
+/// Sends a `ServerNotice` to every client or the members of a room, answered with 422 when the
+/// text is empty
+#[post("/api/notices", data = "<request>")]
+pub async fn send_server_notice(_admin: Admin, request: Json<ServerNoticeRequest>, state: &State<Arc<WsAppState>>) -> Status {
+    let ServerNoticeRequest { text, level, room_id, namespace } = request.into_inner();
+    if text.trim().is_empty() {
+        return Status::UnprocessableEntity;
+    }
+    let Some(room_id) = room_id else {
+        tracing::info!(?level, "Sending a notice to every client on behalf of an administrator");
+        for client in state.clients.snapshot() {
+            response_with_json(&client, OutgoingMessage::ServerNotice { text: text.clone(), level });
+        }
+        return Status::NoContent;
+    };
+    let Some(room) = find_room(state, namespace.as_deref(), &room_id).await else {
+        return Status::NotFound;
+    };
+    tracing::info!(?level, room_id = %room.room_id, "Sending a notice to a room on behalf of an administrator");
+    let _ = room.run(move |room_data| {
+        for room_client in room_data.clients.iter() {
+            response_with_json(&room_client.client, OutgoingMessage::ServerNotice { text: text.clone(), level });
+        }
+    }).await;
+    Status::NoContent
+}
+
+async fn drain_status(state: &WsAppState) -> DrainStatusDto {
+    DrainStatusDto {
+        draining: state.is_draining(),
+        rooms_count: state.store.rooms().await.len(),
+        clients_count: state.clients.len(),
+    }
+}
+
+/// Stops new rooms from being joined or opened and reports the server as not ready, the rooms
+/// already open keep working until their members leave
+#[post("/api/drain")]
+pub async fn start_draining(_admin: Admin, state: &State<Arc<WsAppState>>) -> Json<DrainStatusDto> {
+    tracing::info!("Draining on behalf of an administrator");
+    state.set_draining(true);
+    Json(drain_status(state).await)
+}
+
+/// Leaves drain mode
+#[delete("/api/drain")]
+pub async fn stop_draining(_admin: Admin, state: &State<Arc<WsAppState>>) -> Json<DrainStatusDto> {
+    tracing::info!("Draining stopped by an administrator");
+    state.set_draining(false);
+    Json(drain_status(state).await)
+}
+
+/// Reports of members about other members, newest first
+#[get("/api/reports")]
+pub async fn list_abuse_reports(_admin: Admin, state: &State<Arc<WsAppState>>) -> Json<Vec<AbuseReportDto>> {
+    Json(state.abuse_reports.list().await)
+}
+
+/// Closed rooms of the namespace, newest first, `room_id` picks the closings of one room. Answered
+/// with 501 when `archive_path` is not configured.
+#[get("/api/archive?<room_id>&<namespace>")]
+pub async fn list_archived_rooms(_admin: Admin, room_id: Option<&str>, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Result<Json<Vec<ArchivedRoomDto>>, Status> {
+    let room_archive = state.room_archive.as_ref().ok_or(Status::NotImplemented)?;
+    Ok(Json(room_archive.list(namespace, room_id).await))
+}
+
+/// Reads the config file again and applies the changed settings, see `config_reload`. Answered
+/// with 501 when the server was started without a config file to read, and with 422 when the file
+/// is invalid, the running settings stay in place then.
+#[post("/api/config/reload")]
+pub async fn reload_server_config(_admin: Admin, state: &State<Arc<WsAppState>>) -> Result<Json<ConfigReloadDto>, Status> {
+    if state.config_loader.is_none() {
+        return Err(Status::NotImplemented);
+    }
+    match reload_config(state) {
+        Ok(changes) => Ok(Json(changes)),
+        Err(e) => {
+            tracing::error!("Failed to reload configuration: {:?}", e);
+            Err(Status::UnprocessableEntity)
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Optional authentication of connections, for private instances. Once any of `auth_api_keys`,
+//! `auth_jwt_secret` or `auth_jwt_public_key` is configured, `/ws` refuses connections without a
+//! valid token in the `token` query parameter or an `Authorization: Bearer` header. The
+//! `admin_token` is always accepted and makes the connection a moderator, see `Client::moderator`.
+
+use jwt_simple::prelude::{HS256Key, MACLike, NoCustomClaims, RS256PublicKey, RSAPublicKeyLike};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use crate::config::ServerConfig;
+
+/// Token of the `Authorization: Bearer` header, if the request has one
+pub struct BearerToken(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer "));
+        Outcome::Success(BearerToken(token.map(str::to_string)))
+    }
+}
+
+#[derive(Debug)]
+pub struct Authenticator {
+    required: bool,
+    api_keys: Vec<String>,
+    jwt_secret: Option<HS256Key>,
+    jwt_public_key: Option<RS256PublicKey>,
+}
+
+impl Authenticator {
+    pub fn new(config: &ServerConfig) -> Self {
+        let jwt_public_key = config.auth_jwt_public_key.as_deref().and_then(|pem| {
+            // Every token is refused then, the instance stays closed rather than open to anybody
+            RS256PublicKey::from_pem(pem).map_err(|e| tracing::error!("Invalid auth_jwt_public_key: {}", e)).ok()
+        });
+        Authenticator {
+            required: !config.auth_api_keys.is_empty() || config.auth_jwt_secret.is_some() || config.auth_jwt_public_key.is_some(),
+            api_keys: config.auth_api_keys.clone(),
+            jwt_secret: config.auth_jwt_secret.as_deref().map(|secret| HS256Key::from_bytes(secret.as_bytes())),
+            jwt_public_key,
+        }
+    }
+
+    /// Verified identity of the connection, the `sub` of a JWT. `Err` when the token is missing or
+    /// invalid while authentication is required.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Option<String>, ()> {
+        if !self.required {
+            return Ok(None);
+        }
+        let token = token.ok_or(())?;
+        if self.api_keys.iter().any(|api_key| constant_time_eq(api_key.as_bytes(), token.as_bytes())) {
+            return Ok(None);
+        }
+
+        let claims = self.jwt_secret.as_ref().and_then(|key| key.verify_token::<NoCustomClaims>(token, None).ok())
+            .or_else(|| self.jwt_public_key.as_ref().and_then(|key| key.verify_token::<NoCustomClaims>(token, None).ok()))
+            .ok_or(())?;
+        Ok(claims.subject)
+    }
+}
+
+/// Whether `token` is the configured `admin_token`, which makes a connection a moderator of every room
+pub fn is_admin_token(config: &ServerConfig, token: &str) -> bool {
+    config.admin_token.as_deref().is_some_and(|admin_token| constant_time_eq(admin_token.as_bytes(), token.as_bytes()))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,14 @@
+//! Writes the TypeScript definitions of the protocol, `generate-types [out_dir]`. Clients check the
+//! output in, so changes of the messages show up in their diffs.
+
+use std::path::PathBuf;
+use sent_sync_server::protocol::export_typescript;
+
+fn main() {
+    let out_dir = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| "bindings".to_string()));
+    if let Err(e) = export_typescript(&out_dir) {
+        eprintln!("Failed to export types: {}", e);
+        std::process::exit(1);
+    }
+    println!("Types written to {}", out_dir.display());
+}
@@ -0,0 +1,281 @@
+//! Load test of a running server, `loadtest [OPTIONS]`. Simulated clients join rooms, change their
+//! names and send playback commands, the latencies of the answers are reported per operation once
+//! the time is up. Built with the `client` feature.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use sent_sync_server::client::SyncClient;
+use sent_sync_server::protocol::{IncomingMessage, OutgoingMessage};
+
+const USAGE: &str = "\
+Usage: loadtest [OPTIONS]
+
+Options:
+  --url <URL>               Websocket endpoint, ws://127.0.0.1:8000/ws by default
+  --clients <N>             Simulated clients, 100 by default
+  --rooms <N>               Rooms the clients are spread over, 10 by default
+  --duration <SECS>         How long the clients keep sending, 30 by default
+  --interval <MS>           Pause of each client between two operations, 500 by default
+  --mix <WEIGHTS>           Weights of the operations, join=1,name=1,playback=8 by default
+  --namespace <NAMESPACE>   Namespace announced in Hello
+  -h, --help                Print this help and exit
+
+Playback commands of members other than the owner are answered with Forbidden unless the permission
+preset of the rooms lets members control playback, they are reported as errors.";
+
+/// Answers taking longer than this are counted as errors
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Operation {
+    /// `QuitRoom` followed by `JoinRoom`, the first join right after connecting included
+    Join,
+    ChangeName,
+    /// `Play`, `Pause` or `Seek`
+    Playback,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Join => "join",
+            Operation::ChangeName => "name",
+            Operation::Playback => "playback",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Args {
+    url: String,
+    clients: usize,
+    rooms: usize,
+    duration: Duration,
+    interval: Duration,
+    /// Operations with their weights
+    mix: Vec<(Operation, u32)>,
+    namespace: Option<String>,
+    help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            url: "ws://127.0.0.1:8000/ws".to_string(),
+            clients: 100,
+            rooms: 10,
+            duration: Duration::from_secs(30),
+            interval: Duration::from_millis(500),
+            mix: vec![(Operation::Join, 1), (Operation::ChangeName, 1), (Operation::Playback, 8)],
+            namespace: None,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    /// Same syntax as the options of the server, `--clients 100` or `--clients=100`
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if matches!(name.as_str(), "-h" | "--help") {
+                parsed.help = true;
+                continue;
+            }
+            let value = inline_value.or_else(|| args.next()).ok_or_else(|| anyhow!("{} needs a value", name))?;
+            match name.as_str() {
+                "--url" => parsed.url = value,
+                "--clients" => parsed.clients = value.parse().with_context(|| format!("Invalid number of clients {}", value))?,
+                "--rooms" => parsed.rooms = value.parse().with_context(|| format!("Invalid number of rooms {}", value))?,
+                "--duration" => parsed.duration = Duration::from_secs(value.parse().with_context(|| format!("Invalid duration {}", value))?),
+                "--interval" => parsed.interval = Duration::from_millis(value.parse().with_context(|| format!("Invalid interval {}", value))?),
+                "--mix" => parsed.mix = parse_mix(&value)?,
+                "--namespace" => parsed.namespace = Some(value),
+                _ => bail!("Unknown option {}", name),
+            }
+        }
+        if parsed.clients == 0 || parsed.rooms == 0 {
+            bail!("--clients and --rooms have to be at least 1");
+        }
+        Ok(parsed)
+    }
+
+    /// Random operation according to the weights of `mix`
+    fn pick_operation(&self) -> Operation {
+        let total: u32 = self.mix.iter().map(|(_, weight)| weight).sum();
+        let mut pick = rand::random::<u32>() % total;
+        for (operation, weight) in self.mix.iter() {
+            if pick < *weight {
+                return *operation;
+            }
+            pick -= weight;
+        }
+        unreachable!("The weights add up to the total")
+    }
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<(Operation, u32)>> {
+    let mut parsed = Vec::new();
+    for entry in mix.split(',').filter(|entry| !entry.is_empty()) {
+        let (name, weight) = entry.split_once('=').ok_or_else(|| anyhow!("Invalid mix entry {}, expected like playback=8", entry))?;
+        let operation = match name {
+            "join" => Operation::Join,
+            "name" => Operation::ChangeName,
+            "playback" => Operation::Playback,
+            _ => bail!("Unknown operation {}, expected join, name or playback", name),
+        };
+        let weight = weight.parse().with_context(|| format!("Invalid weight {}", weight))?;
+        parsed.push((operation, weight));
+    }
+    if parsed.iter().all(|(_, weight)| *weight == 0) {
+        bail!("The mix needs an operation with a weight above 0");
+    }
+    Ok(parsed)
+}
+
+#[derive(Debug)]
+struct Sample {
+    operation: Operation,
+    latency: Duration,
+    /// Kind of the error answered, `None` for `Success`
+    error: Option<String>,
+}
+
+/// Samples of one simulated client, `failure` is why it stopped early
+#[derive(Debug, Default)]
+struct ClientReport {
+    samples: Vec<Sample>,
+    failure: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    println!("{} clients in {} rooms against {} for {} seconds", args.clients, args.rooms, args.url, args.duration.as_secs());
+    let args = Arc::new(args);
+    let started_at = Instant::now();
+    let deadline = started_at + args.duration;
+    let tasks: Vec<_> = (0..args.clients).map(|index| tokio::spawn(run_client(args.clone(), index, deadline))).collect();
+    let mut reports = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        reports.push(task.await.unwrap_or_else(|e| ClientReport { samples: Vec::new(), failure: Some(e.to_string()) }));
+    }
+    print_summary(&reports, started_at.elapsed());
+}
+
+async fn run_client(args: Arc<Args>, index: usize, deadline: Instant) -> ClientReport {
+    let mut report = ClientReport::default();
+    if let Err(e) = simulate_client(&args, index, deadline, &mut report.samples).await {
+        report.failure = Some(e.to_string());
+    }
+    report
+}
+
+async fn simulate_client(args: &Args, index: usize, deadline: Instant, samples: &mut Vec<Sample>) -> Result<()> {
+    let mut client = SyncClient::connect(&args.url, "loadtest", args.namespace.as_deref()).await?;
+    let room_id = format!("loadtest-{}", index % args.rooms);
+    let join_room = || IncomingMessage::JoinRoom { room_id: room_id.clone(), invite: None, spectator: false, hidden: false };
+
+    samples.push(request(&mut client, Operation::ChangeName, &[IncomingMessage::ChangeName { new_name: format!("client-{}", index) }]).await?);
+    samples.push(request(&mut client, Operation::Join, &[join_room()]).await?);
+    while Instant::now() + args.interval < deadline {
+        tokio::time::sleep(args.interval).await;
+        let sample = match args.pick_operation() {
+            Operation::Join => request(&mut client, Operation::Join, &[IncomingMessage::QuitRoom, join_room()]).await?,
+            Operation::ChangeName => {
+                let new_name = format!("client-{}-{}", index, rand::random::<u16>());
+                request(&mut client, Operation::ChangeName, &[IncomingMessage::ChangeName { new_name }]).await?
+            }
+            Operation::Playback => {
+                let position = rand::random::<f64>() * 600.0;
+                let message = match rand::random::<u8>() % 3 {
+                    0 => IncomingMessage::Play,
+                    1 => IncomingMessage::Pause { position },
+                    _ => IncomingMessage::Seek { position },
+                };
+                request(&mut client, Operation::Playback, &[message]).await?
+            }
+        };
+        samples.push(sample);
+    }
+    client.close().await
+}
+
+/// Sends the messages one after the other, each waiting for its answer. The latency is the sum,
+/// the error the first one answered.
+async fn request(client: &mut SyncClient, operation: Operation, messages: &[IncomingMessage]) -> Result<Sample> {
+    let started_at = Instant::now();
+    let mut error = None;
+    for message in messages {
+        client.send(message).await?;
+        let answer = match tokio::time::timeout(REPLY_TIMEOUT, next_answer(client)).await {
+            Ok(answer) => answer?,
+            Err(_) => Some("Timeout".to_string()),
+        };
+        error = error.or(answer);
+    }
+    Ok(Sample { operation, latency: started_at.elapsed(), error })
+}
+
+/// Skips the events of the room until `Success` or `Error`, returns the kind of the error
+async fn next_answer(client: &mut SyncClient) -> Result<Option<String>> {
+    loop {
+        match client.recv().await? {
+            OutgoingMessage::Success => return Ok(None),
+            OutgoingMessage::Error { kind, .. } => return Ok(Some(format!("{:?}", kind))),
+            _ => continue,
+        }
+    }
+}
+
+fn print_summary(reports: &[ClientReport], elapsed: Duration) {
+    let mut latencies: BTreeMap<Operation, Vec<Duration>> = BTreeMap::new();
+    let mut errors: BTreeMap<(Operation, String), usize> = BTreeMap::new();
+    for sample in reports.iter().flat_map(|report| report.samples.iter()) {
+        latencies.entry(sample.operation).or_default().push(sample.latency);
+        if let Some(error) = &sample.error {
+            *errors.entry((sample.operation, error.clone())).or_default() += 1;
+        }
+    }
+    let total: usize = latencies.values().map(Vec::len).sum();
+
+    println!();
+    println!("{:<10} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9}", "operation", "count", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms");
+    for (operation, latencies) in latencies.iter_mut() {
+        latencies.sort();
+        let error_count: usize = errors.iter().filter(|((errored, _), _)| errored == operation).map(|(_, count)| count).sum();
+        let percentile = |quantile: f64| latencies[((latencies.len() - 1) as f64 * quantile).round() as usize].as_secs_f64() * 1000.0;
+        println!(
+            "{:<10} {:>8} {:>8} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+            operation.name(), latencies.len(), error_count, percentile(0.5), percentile(0.9), percentile(0.99), percentile(1.0),
+        );
+    }
+    println!();
+    println!("{} operations in {:.1} seconds, {:.1} per second", total, elapsed.as_secs_f64(), total as f64 / elapsed.as_secs_f64());
+    for ((operation, error), count) in errors.iter() {
+        println!("{} answered {} {} times", operation.name(), error, count);
+    }
+
+    let failures: Vec<&str> = reports.iter().filter_map(|report| report.failure.as_deref()).collect();
+    if !failures.is_empty() {
+        println!("{} of {} clients stopped early, first because of: {}", failures.len(), reports.len(), failures[0]);
+    }
+}
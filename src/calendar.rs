@@ -0,0 +1,81 @@
+use std::time::Duration;
+use time::OffsetDateTime;
+use crate::scheduler::unix_millis_now;
+use crate::ws_dto_models::ScheduledSessionDto;
+
+/// Sessions have no explicit end, so calendar entries get a typical movie length
+const DEFAULT_SESSION_DURATION: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Renders scheduled sessions as an iCalendar (RFC 5545) feed
+pub fn sessions_to_ics(calendar_name: &str, sessions: &[ScheduledSessionDto]) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//sent-sync-server//Scheduled sessions//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape_text(calendar_name)),
+    ];
+
+    let dtstamp = format_utc(unix_millis_now());
+    for session in sessions {
+        let mut description = format!("Join room {}", session.room_id);
+        if let Some(page_url) = &session.page_url {
+            description.push_str(&format!("\nVideo: {}", page_url));
+        }
+
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}@sent-sync-server", session.session_id));
+        lines.push(format!("DTSTAMP:{}", dtstamp));
+        lines.push(format!("DTSTART:{}", format_utc(session.starts_at)));
+        lines.push(format!("DTEND:{}", format_utc(session.starts_at + DEFAULT_SESSION_DURATION.as_millis() as u64)));
+        lines.push(format!("SUMMARY:{}", escape_text(&session.title)));
+        lines.push(format!("DESCRIPTION:{}", escape_text(&description)));
+        if let Some(page_url) = session.page_url.as_ref().filter(|page_url| !page_url.chars().any(char::is_control)) {
+            lines.push(format!("URL:{}", page_url));
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold_line(line)).collect::<Vec<_>>().join("")
+}
+
+fn format_utc(unix_millis: u64) -> String {
+    let datetime = OffsetDateTime::from_unix_timestamp((unix_millis / 1000) as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        datetime.year(),
+        datetime.month() as u8,
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second()
+    )
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace(['\r', '\n'], "\\n")
+        // Other control characters aren't allowed in text values
+        .replace(|c: char| c.is_control() && c != '\t', "")
+}
+
+/// Content lines must not be longer than 75 octets, continuation lines start with a space
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut line_length = 0;
+    for c in line.chars() {
+        if line_length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            line_length = 1;
+        }
+        folded.push(c);
+        line_length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
@@ -0,0 +1,112 @@
+//! Command line of the binary. The options override `Rocket.toml` and the environment, so the
+//! server can be configured in a container without mounting a config file.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Context, Result};
+use rocket::config::LogLevel;
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::{Figment, Profile};
+use rocket::serde::Serialize;
+use crate::config::ServerConfig;
+
+pub const USAGE: &str = "\
+Usage: sent-sync-server [OPTIONS]
+
+Options:
+  --address <IP>            Address to listen on
+  --port <PORT>             Port to listen on
+  --config <FILE>           Config file to read instead of Rocket.toml
+  --log-level <LEVEL>       off, critical, normal or debug
+  --print-default-config    Print the default configuration as TOML and exit
+  -h, --help                Print this help and exit";
+
+#[derive(Debug, Clone, Default)]
+pub struct CliArgs {
+    pub address: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub config: Option<PathBuf>,
+    pub log_level: Option<LogLevel>,
+    pub print_default_config: bool,
+    pub help: bool,
+}
+
+impl CliArgs {
+    /// Parses the arguments without the program name, values are given as `--port 8000` or
+    /// `--port=8000`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliArgs> {
+        let mut cli_args = CliArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            match name.as_str() {
+                "--print-default-config" | "-h" | "--help" if inline_value.is_some() => {
+                    bail!("{} takes no value", name);
+                }
+                "--print-default-config" => cli_args.print_default_config = true,
+                "-h" | "--help" => cli_args.help = true,
+                "--address" | "--port" | "--config" | "--log-level" => {
+                    let value = inline_value.or_else(|| args.next()).ok_or_else(|| anyhow!("{} needs a value", name))?;
+                    match name.as_str() {
+                        "--address" => cli_args.address = Some(value.parse().with_context(|| format!("Invalid address {}", value))?),
+                        "--port" => cli_args.port = Some(value.parse().with_context(|| format!("Invalid port {}", value))?),
+                        "--config" => cli_args.config = Some(PathBuf::from(value)),
+                        _ => cli_args.log_level = Some(value.parse().map_err(|_| anyhow!("Invalid log level {}", value))?),
+                    }
+                }
+                _ => bail!("Unknown option {}", name),
+            }
+        }
+        Ok(cli_args)
+    }
+
+    /// Rocket's figment with `--config` read in place of `Rocket.toml` and the options on top.
+    /// Unlike a missing `Rocket.toml`, a missing `--config` file is an error.
+    pub fn figment(&self) -> Result<Figment> {
+        let mut figment = match &self.config {
+            Some(config) if !config.is_file() => bail!("Config file {} not found", config.display()),
+            Some(config) => Figment::from(rocket::Config::default())
+                .merge(Toml::file(config).nested())
+                .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).global())
+                .select(Profile::from_env_or("ROCKET_PROFILE", rocket::Config::DEFAULT_PROFILE)),
+            None => rocket::Config::figment(),
+        };
+        if let Some(address) = self.address {
+            figment = figment.merge(("address", address));
+        }
+        if let Some(port) = self.port {
+            figment = figment.merge(("port", port));
+        }
+        if let Some(log_level) = self.log_level {
+            figment = figment.merge(("log_level", log_level));
+        }
+        Ok(figment)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct DefaultProfile {
+    address: IpAddr,
+    port: u16,
+    log_level: LogLevel,
+    #[serde(flatten)]
+    server: ServerConfig,
+}
+
+/// `Rocket.toml` with the listening settings of Rocket and every setting of the server at its
+/// default, settings without a default are left out
+pub fn default_config_toml() -> Result<String> {
+    let rocket_config = rocket::Config::default();
+    let profile = DefaultProfile {
+        address: rocket_config.address,
+        port: rocket_config.port,
+        log_level: rocket_config.log_level,
+        server: ServerConfig::default(),
+    };
+    Ok(toml::to_string_pretty(&HashMap::from([("default", profile)]))?)
+}
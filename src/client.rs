@@ -0,0 +1,82 @@
+//! Minimal client of the JSON protocol for Rust applications and bots, built with the `client`
+//! feature. It does the hello handshake and leaves everything after it to the caller.
+
+use anyhow::{anyhow, bail, Result};
+use rocket::futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+use crate::protocol::{IncomingMessage, OutgoingMessage, ProtocolFeature, SUPPORTED_PROTOCOL_VERSIONS};
+
+pub struct SyncClient {
+    pub uid: Uuid,
+    /// For `Resume` after a reconnect
+    pub resume_token: String,
+    /// Version agreed on in `Welcome`
+    pub protocol_version: u32,
+    pub features: Vec<ProtocolFeature>,
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl SyncClient {
+    /// Connects to `url` like `ws://localhost:8000/ws?token=...` and says hello, the client can join
+    /// rooms once this returns
+    pub async fn connect(url: &str, client_name: &str, namespace: Option<&str>) -> Result<SyncClient> {
+        let (stream, _) = tokio_tungstenite::connect_async(url).await?;
+        let mut client = SyncClient {
+            uid: Uuid::nil(),
+            resume_token: String::new(),
+            protocol_version: *SUPPORTED_PROTOCOL_VERSIONS.end(),
+            features: Vec::new(),
+            stream,
+        };
+
+        match client.recv().await? {
+            OutgoingMessage::ClientUid { client_uid, resume_token } => {
+                client.uid = client_uid;
+                client.resume_token = resume_token;
+            }
+            msg => bail!("Expected ClientUid, got {:?}", msg),
+        }
+
+        client.send(&IncomingMessage::Hello {
+            protocol_version: client.protocol_version,
+            client_name: client_name.to_string(),
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            namespace: namespace.map(str::to_string),
+            capabilities: None,
+            locale: None,
+        }).await?;
+        match client.recv().await? {
+            OutgoingMessage::Welcome { protocol_version, features, .. } => {
+                client.protocol_version = protocol_version;
+                client.features = features;
+            }
+            OutgoingMessage::Error { kind, msg, .. } => bail!("Hello refused with {:?}: {:?}", kind, msg),
+            msg => bail!("Expected Welcome, got {:?}", msg),
+        }
+        Ok(client)
+    }
+
+    pub async fn send(&mut self, msg: &IncomingMessage) -> Result<()> {
+        self.stream.send(Message::Text(serde_json::to_string(msg)?)).await?;
+        Ok(())
+    }
+
+    /// Next message of the server, control frames are skipped. Fails once the connection is closed.
+    pub async fn recv(&mut self) -> Result<OutgoingMessage> {
+        loop {
+            match self.stream.next().await.ok_or_else(|| anyhow!("Connection closed"))?? {
+                Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+                Message::Close(frame) => bail!("Connection closed: {:?}", frame),
+                _ => continue,
+            }
+        }
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.stream.close(None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use anyhow::{anyhow, bail, Result};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+use crate::listeners::ForwardedPeer;
+use crate::ws_app_state::WsAppState;
+
+/// Network in the CIDR notation, a plain address is a network of one
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(network.to_bits() as u128, ip.to_bits() as u128, 32, self.prefix_len),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(network.to_bits(), ip.to_bits(), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u32, prefix_len: u32) -> bool {
+    let shift = bits - prefix_len;
+    shift >= bits || network >> shift == ip >> shift
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(range: &str) -> Result<Self> {
+        let (network, prefix_len) = match range.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (range, None),
+        };
+        let network = network.trim().parse::<IpAddr>()?.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse::<u32>().map_err(|_| anyhow!("Invalid prefix length in {}", range))?,
+            None => bits,
+        };
+        if prefix_len > bits {
+            bail!("Prefix length of {} is longer than the address", range);
+        }
+        Ok(IpRange { network, prefix_len })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> String {
+        range.to_string()
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = anyhow::Error;
+
+    fn try_from(range: String) -> Result<Self> {
+        range.parse()
+    }
+}
+
+fn is_trusted_proxy(trusted_proxies: &[IpRange], ip: IpAddr) -> bool {
+    trusted_proxies.iter().any(|range| range.contains(ip))
+}
+
+/// Address of the client. Behind one of `ServerConfig::trusted_proxies` it's the last address in
+/// `X-Forwarded-For` which was not added by a trusted proxy, or `X-Real-IP` when there is no
+/// `X-Forwarded-For`. Headers sent by anyone else are ignored since they can be forged.
+pub struct ClientAddress(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientAddress {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(remote) = request.remote() else {
+            return Outcome::Success(ClientAddress(None));
+        };
+        let state = request.rocket().state::<Arc<WsAppState>>();
+        let config = state.map(|state| state.config());
+        let trusted_proxies = config.as_ref().map(|config| config.trusted_proxies.as_slice()).unwrap_or_default();
+        let (peer, trusted_peer) = match state.and_then(|state| state.forwarded_peers.get(remote)) {
+            Some(ForwardedPeer::Tcp(peer)) => (peer.ip().to_canonical(), is_trusted_proxy(trusted_proxies, peer.ip().to_canonical())),
+            Some(ForwardedPeer::Unix) => (remote.ip().to_canonical(), true),
+            None => (remote.ip().to_canonical(), is_trusted_proxy(trusted_proxies, remote.ip().to_canonical())),
+        };
+        if !trusted_peer {
+            return Outcome::Success(ClientAddress(Some(peer)));
+        }
+
+        let forwarded_for = request
+            .headers()
+            .get("X-Forwarded-For")
+            .flat_map(|header| header.split(','))
+            .map(|ip| ip.trim().parse::<IpAddr>().map(|ip| ip.to_canonical()))
+            .collect::<Vec<_>>();
+        if !forwarded_for.is_empty() {
+            let mut client_ip = peer;
+            let mut trusted = trusted_peer;
+            // Walks back from the nearest hop, a garbled entry can't be attributed to anyone
+            for ip in forwarded_for.into_iter().rev() {
+                if !trusted {
+                    break;
+                }
+                match ip {
+                    Ok(ip) => client_ip = ip,
+                    Err(_) => break,
+                }
+                trusted = is_trusted_proxy(trusted_proxies, client_ip);
+            }
+            return Outcome::Success(ClientAddress(Some(client_ip)));
+        }
+
+        let real_ip = request.headers().get_one("X-Real-IP").and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        Outcome::Success(ClientAddress(Some(real_ip.map(|ip| ip.to_canonical()).unwrap_or(peer))))
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use uuid::Uuid;
+use crate::ws_app_state::Client;
+
+const SHARDS_COUNT: usize = 16;
+
+/// Connected clients keyed by uid. The map is split into shards with their own locks, so connects
+/// and disconnects of different clients rarely wait for each other. Locks are never held across
+/// an `.await`.
+#[derive(Debug)]
+pub struct ClientRegistry {
+    shards: Vec<RwLock<HashMap<Uuid, Arc<Client>>>>,
+    len: AtomicUsize,
+    /// Registered clients per address, clients without one are not counted
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Why `ClientRegistry::try_insert` refused a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationRefused {
+    ServerFull,
+    TooManyFromAddress,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        ClientRegistry {
+            shards: (0..SHARDS_COUNT).map(|_| RwLock::new(HashMap::new())).collect(),
+            len: AtomicUsize::new(0),
+            per_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn shard(&self, uid: Uuid) -> &RwLock<HashMap<Uuid, Arc<Client>>> {
+        &self.shards[(uid.as_u128() % SHARDS_COUNT as u128) as usize]
+    }
+
+    /// Registers the client unless `max_clients` are registered already, or `max_clients_per_ip`
+    /// from its address
+    pub fn try_insert(&self, client: Arc<Client>, max_clients: Option<usize>, max_clients_per_ip: Option<usize>) -> Result<(), RegistrationRefused> {
+        let previous_len = self.len.fetch_add(1, Ordering::SeqCst);
+        if max_clients.is_some_and(|max_clients| previous_len >= max_clients) {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            return Err(RegistrationRefused::ServerFull);
+        }
+
+        if let Some(ip) = client.ip {
+            let mut per_ip = self.per_ip.lock().unwrap_or_else(PoisonError::into_inner);
+            let count = per_ip.entry(ip).or_default();
+            if max_clients_per_ip.is_some_and(|max_clients_per_ip| *count >= max_clients_per_ip) {
+                drop(per_ip);
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                return Err(RegistrationRefused::TooManyFromAddress);
+            }
+            *count += 1;
+        }
+
+        let replaced = self.shard(client.uid).write().unwrap_or_else(PoisonError::into_inner).insert(client.uid, client);
+        if let Some(replaced) = replaced {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            self.release_ip(replaced.ip);
+        }
+        Ok(())
+    }
+
+    /// Removes exactly this client, a newer client with the same uid is kept
+    pub fn remove(&self, client: &Arc<Client>) -> bool {
+        let mut shard = self.shard(client.uid).write().unwrap_or_else(PoisonError::into_inner);
+        if !shard.get(&client.uid).is_some_and(|existing_client| Arc::ptr_eq(existing_client, client)) {
+            return false;
+        }
+        shard.remove(&client.uid);
+        drop(shard);
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        self.release_ip(client.ip);
+        true
+    }
+
+    fn release_ip(&self, ip: Option<IpAddr>) {
+        let Some(ip) = ip else {
+            return;
+        };
+        let mut per_ip = self.per_ip.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+
+    pub fn get(&self, uid: Uuid) -> Option<Arc<Client>> {
+        self.shard(uid).read().unwrap_or_else(PoisonError::into_inner).get(&uid).cloned()
+    }
+
+    pub fn contains(&self, client: &Arc<Client>) -> bool {
+        self.get(client.uid).is_some_and(|existing_client| Arc::ptr_eq(&existing_client, client))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// Number of addresses with registered clients
+    pub fn ips_count(&self) -> usize {
+        self.per_ip.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Copy of all clients, shards are locked one at a time
+    pub fn snapshot(&self) -> Vec<Arc<Client>> {
+        let mut clients = Vec::with_capacity(self.len());
+        for shard in self.shards.iter() {
+            clients.extend(shard.read().unwrap_or_else(PoisonError::into_inner).values().cloned());
+        }
+        clients
+    }
+}
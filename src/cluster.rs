@@ -0,0 +1,385 @@
+//! Optional Redis backend which lets several instances behind a load balancer serve the same rooms.
+//! Every instance keeps running its own room for its own connections, membership and playback
+//! state of the room are mirrored to Redis and changes are relayed to the other instances through
+//! pub/sub. Ownership, roles, bans, polls and chat stay local to each instance.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use rocket::serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+use crate::redis_client::{RedisConnection, RespValue};
+use crate::ws_app_state::{PlaybackState, Room, RoomData, RoomKey, WsAppState};
+use crate::ws_dto_models::{PlaybackStateDto, RoomClientDto};
+use crate::protocol::OutgoingMessage;
+use crate::ws_handler::{broadcast_room_change, broadcast_room_event, response_with_text};
+
+const CHANNEL: &str = "sent-sync:rooms";
+/// Rooms nobody touched for this long are forgotten by Redis
+const ROOM_TTL_SECS: u64 = 24 * 60 * 60;
+/// Members of instances which stopped refreshing their key for this long are dropped
+const INSTANCE_TTL_SECS: u64 = 30;
+const INSTANCE_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Rooms of the default namespace keep the keys they had before namespaces existed
+fn room_key(namespace: Option<&str>, room_id: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("sent-sync:ns:{}:room:{}", namespace, room_id),
+        None => format!("sent-sync:room:{}", room_id),
+    }
+}
+
+fn members_key(namespace: Option<&str>, room_id: &str) -> String {
+    format!("{}:members", room_key(namespace, room_id))
+}
+
+fn state_key(namespace: Option<&str>, room_id: &str) -> String {
+    format!("{}:state", room_key(namespace, room_id))
+}
+
+fn instance_key(instance_id: Uuid) -> String {
+    format!("sent-sync:instance:{}", instance_id)
+}
+
+/// Change made by one instance, applied by the others to their copy of the room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", rename_all_fields = "camelCase", tag = "type")]
+pub enum ClusterEvent {
+    MemberJoined { client: RoomClientDto },
+    MemberLeft { client_uid: Uuid },
+    /// State after the change, `message` is the payload the local members got and is relayed as is
+    PlaybackChanged { page_url: Option<String>, playback: PlaybackStateDto, message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct ClusterEnvelope {
+    instance_id: Uuid,
+    #[serde(default)]
+    namespace: Option<String>,
+    room_id: String,
+    event: ClusterEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct StoredMember {
+    instance_id: Uuid,
+    client: RoomClientDto,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct StoredRoomState {
+    page_url: Option<String>,
+    playback: PlaybackStateDto,
+}
+
+/// Member connected to another instance
+#[derive(Debug, Clone)]
+pub struct RemoteClient {
+    pub instance_id: Uuid,
+    pub client: RoomClientDto,
+}
+
+/// Handle of an attached room, events published through it are relayed to the other instances
+#[derive(Debug, Clone)]
+pub struct ClusterLink {
+    room_key: RoomKey,
+    outbox: mpsc::UnboundedSender<(RoomKey, ClusterEvent)>,
+}
+
+impl ClusterLink {
+    pub fn publish(&self, event: ClusterEvent) {
+        let _ = self.outbox.send((self.room_key.clone(), event));
+    }
+}
+
+/// Relays the event when the room is attached to the cluster
+pub fn publish(room_data: &RoomData, event: impl FnOnce() -> ClusterEvent) {
+    if let Some(cluster) = &room_data.cluster {
+        cluster.publish(event());
+    }
+}
+
+/// Relays a change of the play state together with the `payload` the local members were sent
+pub fn publish_playback(room_data: &RoomData, payload: &str) {
+    publish(room_data, || ClusterEvent::PlaybackChanged {
+        page_url: room_data.page_url.clone(),
+        playback: PlaybackStateDto::from(&room_data.playback),
+        message: payload.to_string(),
+    });
+}
+
+#[derive(Debug)]
+pub struct ClusterBridge {
+    instance_id: Uuid,
+    redis_url: String,
+    outbox: mpsc::UnboundedSender<(RoomKey, ClusterEvent)>,
+    /// Taken by the publishing task once it starts
+    outbox_rx: Mutex<Option<mpsc::UnboundedReceiver<(RoomKey, ClusterEvent)>>>,
+    /// Connection for the reads done while attaching rooms and checking instances
+    queries: Mutex<Option<RedisConnection>>,
+}
+
+impl ClusterBridge {
+    pub fn new(redis_url: String) -> Self {
+        let (outbox, outbox_rx) = mpsc::unbounded_channel();
+        ClusterBridge {
+            instance_id: Uuid::new_v4(),
+            redis_url,
+            outbox,
+            outbox_rx: Mutex::new(Some(outbox_rx)),
+            queries: Mutex::new(None),
+        }
+    }
+
+    /// Starts relaying the room. Members already known to Redis are added to it and, if it's
+    /// being served elsewhere, the room takes over the video and play state from there.
+    pub fn attach_room(self: &Arc<Self>, room: Arc<Room>) {
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let (members, room_state) = match bridge.load_room(room.namespace.as_deref(), &room.room_id).await {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    tracing::warn!(room_id = %room.room_id, "Failed to load the room from Redis: {:?}", e);
+                    (Vec::new(), None)
+                }
+            };
+            let link = ClusterLink { room_key: room.key(), outbox: bridge.outbox.clone() };
+            let _ = room.run(move |room_data| {
+                room_data.cluster = Some(link);
+                let local_uids: HashSet<Uuid> = room_data.clients.iter().map(|room_client| room_client.client.uid).collect();
+                room_data.remote_clients = members.into_iter().filter(|member| !local_uids.contains(&member.client.uid)).collect();
+
+                match room_state {
+                    Some(room_state) if !room_data.remote_clients.is_empty() => apply_room_state(room_data, room_state.page_url, &room_state.playback),
+                    _ => publish_playback(room_data, ""),
+                }
+                for room_client in room_data.clients.iter() {
+                    let client = RoomClientDto::from(room_client, room_data.total_play_time());
+                    publish(room_data, || ClusterEvent::MemberJoined { client });
+                }
+                broadcast_room_change(room_data);
+            }).await;
+        });
+    }
+
+    async fn load_room(&self, namespace: Option<&str>, room_id: &str) -> Result<(Vec<RemoteClient>, Option<StoredRoomState>)> {
+        let mut queries = self.queries.lock().await;
+        let result = async {
+            let connection = match queries.as_mut() {
+                Some(connection) => connection,
+                None => queries.insert(RedisConnection::connect(&self.redis_url).await?),
+            };
+
+            let entries = connection.command(&[b"HGETALL", members_key(namespace, room_id).as_bytes()]).await?.into_array().unwrap_or_default();
+            let mut members = Vec::new();
+            for value in entries.into_iter().skip(1).step_by(2) {
+                let Some(member) = value.into_bytes().and_then(|value| serde_json::from_slice::<StoredMember>(&value).ok()) else {
+                    continue;
+                };
+                members.push(RemoteClient { instance_id: member.instance_id, client: member.client });
+            }
+            let alive = alive_instances(connection, members.iter().map(|member| member.instance_id)).await?;
+            members.retain(|member| alive.contains(&member.instance_id));
+
+            let room_state = connection
+                .command(&[b"GET", state_key(namespace, room_id).as_bytes()])
+                .await?
+                .into_bytes()
+                .and_then(|value| serde_json::from_slice::<StoredRoomState>(&value).ok());
+            Ok((members, room_state))
+        }.await;
+        // A broken connection is replaced on the next use
+        if result.is_err() {
+            *queries = None;
+        }
+        result
+    }
+}
+
+/// Runs the tasks exchanging events with Redis, does nothing when no Redis backend is configured
+pub async fn run_cluster_bridge(state: Arc<WsAppState>) {
+    let Some(bridge) = state.cluster.clone() else {
+        return;
+    };
+    let Some(outbox_rx) = bridge.outbox_rx.lock().await.take() else {
+        return;
+    };
+    tracing::info!(instance_id = %bridge.instance_id, "Relaying rooms through Redis");
+
+    tokio::spawn(run_publisher(bridge.clone(), outbox_rx));
+    tokio::spawn(run_instance_heartbeat(state.clone(), bridge.clone()));
+    loop {
+        if let Err(e) = run_subscriber(&state, &bridge).await {
+            tracing::warn!("Redis subscription failed: {:?}", e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn run_publisher(bridge: Arc<ClusterBridge>, mut outbox_rx: mpsc::UnboundedReceiver<(RoomKey, ClusterEvent)>) {
+    let mut connection: Option<RedisConnection> = None;
+    while let Some(((namespace, room_id), event)) = outbox_rx.recv().await {
+        let envelope = ClusterEnvelope { instance_id: bridge.instance_id, namespace, room_id, event };
+        let result = async {
+            let connection = match connection.as_mut() {
+                Some(connection) => connection,
+                None => connection.insert(RedisConnection::connect(&bridge.redis_url).await?),
+            };
+            store_event(connection, &envelope).await?;
+            connection.command(&[b"PUBLISH", CHANNEL.as_bytes(), serde_json::to_string(&envelope)?.as_bytes()]).await?;
+            Ok::<_, anyhow::Error>(())
+        }.await;
+        if let Err(e) = result {
+            tracing::warn!(room_id = %envelope.room_id, "Failed to publish a room event: {:?}", e);
+            connection = None;
+        }
+    }
+}
+
+/// Mirrors the event to the keys read when another instance attaches the room
+async fn store_event(connection: &mut RedisConnection, envelope: &ClusterEnvelope) -> Result<()> {
+    let ttl = ROOM_TTL_SECS.to_string();
+    match &envelope.event {
+        ClusterEvent::MemberJoined { client } => {
+            let key = members_key(envelope.namespace.as_deref(), &envelope.room_id);
+            let member = serde_json::to_string(&StoredMember { instance_id: envelope.instance_id, client: client.clone() })?;
+            connection.command(&[b"HSET", key.as_bytes(), client.uid.to_string().as_bytes(), member.as_bytes()]).await?;
+            connection.command(&[b"EXPIRE", key.as_bytes(), ttl.as_bytes()]).await?;
+        }
+        ClusterEvent::MemberLeft { client_uid } => {
+            connection.command(&[b"HDEL", members_key(envelope.namespace.as_deref(), &envelope.room_id).as_bytes(), client_uid.to_string().as_bytes()]).await?;
+        }
+        ClusterEvent::PlaybackChanged { page_url, playback, .. } => {
+            let room_state = serde_json::to_string(&StoredRoomState { page_url: page_url.clone(), playback: playback.clone() })?;
+            connection.command(&[b"SET", state_key(envelope.namespace.as_deref(), &envelope.room_id).as_bytes(), room_state.as_bytes(), b"EX", ttl.as_bytes()]).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_subscriber(state: &Arc<WsAppState>, bridge: &ClusterBridge) -> Result<()> {
+    let mut connection = RedisConnection::connect(&bridge.redis_url).await?;
+    connection.send(&[b"SUBSCRIBE", CHANNEL.as_bytes()]).await?;
+    loop {
+        let reply = connection.read().await?.into_array().ok_or(anyhow!("Unexpected reply to the subscription"))?;
+        let [kind, _channel, payload] = <[RespValue; 3]>::try_from(reply).map_err(|_| anyhow!("Unexpected reply to the subscription"))?;
+        if kind.into_bytes().as_deref() != Some(b"message") {
+            continue;
+        }
+        let Some(envelope) = payload.into_bytes().and_then(|payload| serde_json::from_slice::<ClusterEnvelope>(&payload).ok()) else {
+            tracing::warn!("Ignoring a malformed room event from Redis");
+            continue;
+        };
+        if envelope.instance_id == bridge.instance_id {
+            continue;
+        }
+
+        let Some(room) = state.store.room(envelope.namespace.as_deref(), &envelope.room_id).await else {
+            continue;
+        };
+        let _ = room.run(move |room_data| apply_event(room_data, envelope.instance_id, envelope.event)).await;
+    }
+}
+
+fn apply_event(room_data: &mut RoomData, instance_id: Uuid, event: ClusterEvent) {
+    // Events racing with the attachment of the room are covered by the state it loads
+    if room_data.cluster.is_none() {
+        return;
+    }
+
+    match event {
+        ClusterEvent::MemberJoined { client } => {
+            room_data.remote_clients.retain(|remote_client| remote_client.client.uid != client.uid);
+            room_data.remote_clients.push(RemoteClient { instance_id, client: client.clone() });
+            broadcast_room_event(room_data, |seq| OutgoingMessage::ClientJoined { seq, client });
+        }
+        ClusterEvent::MemberLeft { client_uid } => {
+            remove_remote_clients(room_data, |remote_client| remote_client.client.uid == client_uid);
+        }
+        ClusterEvent::PlaybackChanged { page_url, playback, message } => {
+            apply_room_state(room_data, page_url, &playback);
+            if !message.is_empty() {
+                for room_client in room_data.clients.iter() {
+                    let _ = response_with_text(&room_client.client, message.clone());
+                }
+            }
+        }
+    }
+}
+
+fn apply_room_state(room_data: &mut RoomData, page_url: Option<String>, playback: &PlaybackStateDto) {
+    room_data.page_url = page_url;
+    room_data.set_playing(playback.playing);
+    room_data.playback = PlaybackState {
+        position: playback.position,
+        playing: playback.playing,
+        rate: playback.rate,
+        last_update: Instant::now(),
+        audio_track: playback.audio_track.clone(),
+        subtitle_track: playback.subtitle_track.clone(),
+    };
+}
+
+fn remove_remote_clients(room_data: &mut RoomData, filter: impl Fn(&RemoteClient) -> bool) {
+    let (removed, kept) = std::mem::take(&mut room_data.remote_clients).into_iter().partition::<Vec<_>, _>(|remote_client| filter(remote_client));
+    room_data.remote_clients = kept;
+    for remote_client in removed {
+        let client_uid = remote_client.client.uid;
+        broadcast_room_event(room_data, |seq| OutgoingMessage::ClientLeft { seq, client_uid });
+    }
+}
+
+/// Keeps the key telling the others this instance is alive and drops the members of instances
+/// which went away without saying goodbye
+async fn run_instance_heartbeat(state: Arc<WsAppState>, bridge: Arc<ClusterBridge>) {
+    let mut interval = tokio::time::interval(INSTANCE_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = refresh_instance(&state, &bridge).await {
+            tracing::warn!("Failed to refresh the instance in Redis: {:?}", e);
+            *bridge.queries.lock().await = None;
+        }
+    }
+}
+
+async fn refresh_instance(state: &WsAppState, bridge: &ClusterBridge) -> Result<()> {
+    let rooms: Vec<Arc<Room>> = state.store.rooms().await;
+    let mut remote_instances = Vec::new();
+    for room in rooms.iter() {
+        let instance_ids = room.run(|room_data| room_data.remote_clients.iter().map(|remote_client| remote_client.instance_id).collect::<Vec<_>>()).await?;
+        remote_instances.extend(instance_ids);
+    }
+
+    let alive = {
+        let mut queries = bridge.queries.lock().await;
+        let connection = match queries.as_mut() {
+            Some(connection) => connection,
+            None => queries.insert(RedisConnection::connect(&bridge.redis_url).await?),
+        };
+        let ttl = INSTANCE_TTL_SECS.to_string();
+        connection.command(&[b"SET", instance_key(bridge.instance_id).as_bytes(), b"1", b"EX", ttl.as_bytes()]).await?;
+        alive_instances(connection, remote_instances.into_iter()).await?
+    };
+
+    for room in rooms {
+        let alive = alive.clone();
+        let _ = room.run(move |room_data| remove_remote_clients(room_data, |remote_client| !alive.contains(&remote_client.instance_id))).await;
+    }
+    Ok(())
+}
+
+async fn alive_instances(connection: &mut RedisConnection, instance_ids: impl Iterator<Item = Uuid>) -> Result<HashSet<Uuid>> {
+    let mut alive = HashSet::new();
+    for instance_id in instance_ids.collect::<HashSet<_>>() {
+        if connection.command(&[b"EXISTS", instance_key(instance_id).as_bytes()]).await? == RespValue::Integer(1) {
+            alive.insert(instance_id);
+        }
+    }
+    Ok(alive)
+}
@@ -0,0 +1,60 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+pub const SIGNING_SECRET_SIZE: usize = 32;
+
+pub fn generate_signing_secret() -> [u8; SIGNING_SECRET_SIZE] {
+    let mut secret = [0u8; SIGNING_SECRET_SIZE];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    Hmac::<Sha256>::new_from_slice(key).unwrap_or_else(|_| unreachable!())
+}
+
+/// HMAC-SHA256 (RFC 2104), available in WebCrypto so extensions can sign without extra libraries
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = hmac(key);
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Message covered by the signature of a page URL change. The nonce has to grow with every
+/// signed command so captured commands can't be replayed.
+pub fn page_url_change_message(room_id: &str, nonce: u64, page_url: &str) -> String {
+    format!("changeRoomPreferences:{}:{}:{}", room_id, nonce, page_url)
+}
+
+/// Whether `signature` signs a page URL change with a nonce above the last accepted one
+pub fn verify_page_url_change_signature(secret: &[u8], last_nonce: u64, room_id: &str, nonce: u64, page_url: &str, signature_hex: &str) -> bool {
+    nonce > last_nonce && verify_signature(secret, &page_url_change_message(room_id, nonce, page_url), signature_hex)
+}
+
+pub fn verify_signature(secret: &[u8], message: &str, signature_hex: &str) -> bool {
+    verify_signature_bytes(secret, message.as_bytes(), signature_hex)
+}
+
+pub fn verify_signature_bytes(secret: &[u8], message: &[u8], signature_hex: &str) -> bool {
+    let Some(signature) = from_hex(signature_hex) else {
+        return false;
+    };
+    let mut mac = hmac(secret);
+    mac.update(message);
+    // Constant time comparison
+    mac.verify_slice(&signature).is_ok()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use anyhow::Result;
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+use crate::client_address::IpRange;
+use crate::listeners::ListenAddress;
+use crate::content_filter::ContentFilterAction;
+use crate::logging::LogFormat;
+use crate::validation::CharacterPolicy;
+use crate::ws_dto_models::{ControlMode, DuplicateNames, OwnerSuccession, PermissionPreset};
+
+/// Settings of the server, read from `Rocket.toml` and the environment. Keys are accepted with the
+/// `ROCKET_` prefix like Rocket's own settings, or with `SENT_SYNC_` which takes precedence.
+/// Limits and timeouts documented with "0 disables" are turned off by 0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ServerConfig {
+    /// Base64url encoded P-256 private key (the 32 byte scalar) signing Web Push requests (VAPID,
+    /// RFC 8292). Push notifications are off without one. Browsers subscribe with its public key
+    /// from `GET /push/public-key`.
+    pub vapid_private_key: Option<String>,
+    /// Contact of the operator for push services, a `mailto:` or `https:` URL
+    pub vapid_subject: Option<String>,
+    /// Push subscriptions of one room
+    pub max_push_subscriptions_per_room: usize,
+    /// Push subscriptions on the whole server, 0 disables
+    pub max_push_subscriptions: usize,
+    /// Abuse reports of members are posted here as JSON, they are only listed by `GET /api/reports`
+    /// without one
+    pub report_webhook_url: Option<String>,
+    /// Externally reachable base URL used to build invite links, the listening address by default
+    pub public_url: Option<String>,
+    /// Sent to every client as a `ServerNotice` right after connecting
+    pub motd: Option<String>,
+    /// Addresses or CIDR ranges of the reverse proxies in front of the server
+    pub trusted_proxies: Vec<IpRange>,
+    /// Listened on besides `address` and `port`, like `[::]:8000` or `unix:/run/sent-sync.sock`.
+    /// Connections over a Unix domain socket are trusted like `trusted_proxies`.
+    pub listeners: Vec<ListenAddress>,
+    /// Bearer token of the `/api` routes, the admin API is disabled without one
+    pub admin_token: Option<String>,
+    /// Tokens clients may connect with as they are, see `auth`. Connecting without a token is only
+    /// possible while none of the `auth_` settings is set.
+    pub auth_api_keys: Vec<String>,
+    /// HS256 secret of JWTs clients may connect with, at least 12 bytes. Their `sub` is the identity
+    /// of the client.
+    pub auth_jwt_secret: Option<String>,
+    /// PEM encoded RSA public key verifying RS256 JWTs clients may connect with
+    pub auth_jwt_public_key: Option<String>,
+    /// Origins of the pages and extensions allowed to connect to `/ws`, like
+    /// `chrome-extension://<id>` or `https://*.example.com`. Any origin may connect when empty.
+    pub allowed_origins: Vec<String>,
+    /// `redis://[[user]:password@]host[:port][/db]` shared by the instances serving the same rooms,
+    /// only used when the server is built with the `redis` feature
+    pub redis_url: Option<String>,
+    /// File the rooms are saved to and restored from after a restart, rooms are not saved without one
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval_secs: u64,
+    /// File closed rooms are appended to, see `GET /api/archive` and `RestoreRoom`. Rooms are not
+    /// archived without one.
+    pub archive_path: Option<String>,
+
+    pub lobby_enabled: bool,
+    pub repair_inconsistencies: bool,
+    /// Rejects messages with fields the server doesn't know, meant for developing clients
+    pub strict_messages: bool,
+    /// Word list names, room titles and chat messages are checked against, one word per line.
+    /// Nothing is filtered without one.
+    pub content_filter_words_path: Option<String>,
+    pub content_filter_action: ContentFilterAction,
+
+    pub log_format: LogFormat,
+    /// Directives in the `RUST_LOG` syntax, derived from Rocket's `log_level` when not set
+    pub log_filter: Option<String>,
+
+    /// Lengths of names and room ids are counted in user-perceived characters
+    pub min_name_length: usize,
+    pub max_name_length: usize,
+    pub name_characters: CharacterPolicy,
+    pub min_room_id_length: usize,
+    pub max_room_id_length: usize,
+    pub room_id_characters: CharacterPolicy,
+    /// Room ids nobody may open or use as an alias, compared case-insensitively
+    pub reserved_room_ids: Vec<String>,
+    /// Room ids picked by the server for `CreateRoom`
+    pub room_code_length: usize,
+    pub room_code_alphabet: String,
+    /// Whether `JoinRoom` opens the room when there is none with the id, otherwise rooms are only
+    /// opened with `CreateRoom` and a mistyped id is answered with `NoSuchRoom`
+    pub join_creates_rooms: bool,
+    pub max_page_url_length: usize,
+    /// Domains page URLs may point to, subdomains included, like `youtube.com`. Any http(s) URL is
+    /// accepted when empty.
+    pub allowed_page_domains: Vec<String>,
+    pub max_chat_message_length: usize,
+    pub max_room_title_length: usize,
+    pub max_room_description_length: usize,
+    pub max_lobby_message_length: usize,
+    pub max_shared_file_size: usize,
+    /// Text frames above this size are refused with `PayloadTooLarge` before they are parsed, the
+    /// sizes of the fields are limited by the settings above
+    pub max_message_size: usize,
+    /// Limits of `Custom` messages on channels without an entry in `custom_channels`
+    pub custom_message_limits: CustomChannelLimits,
+    /// Channel name -> limits replacing `custom_message_limits` for it
+    pub custom_channels: HashMap<String, CustomChannelLimits>,
+    /// Bytes the members of one room may relay to each other per minute with `Custom`, `FileChunk`
+    /// and shared files, counted once per recipient, 0 disables
+    pub room_relay_quota_bytes_per_minute: u64,
+    pub room_relay_quota_action: RelayQuotaAction,
+
+    /// Message budget of one client, see `IncomingMessage::rate_limit_cost`
+    pub client_messages_per_second: f64,
+    pub client_messages_burst: f64,
+    /// Rejected messages within 10 seconds after which the client is disconnected
+    pub client_rate_limit_max_violations: u32,
+
+    /// Clients silent for this long are disconnected and leave their room, 0 disables
+    pub client_inactivity_timeout_secs: u64,
+    /// How long before the inactivity disconnect clients get `InactivityWarning`, at most half the
+    /// timeout. 0 disables the warning.
+    pub client_inactivity_warning_secs: u64,
+    pub consistency_check_interval_secs: u64,
+    /// Clients whose outgoing queue stays full, or whose connection doesn't accept a message, for
+    /// this long are disconnected
+    pub slow_client_timeout_secs: u64,
+    /// Connections which don't send a message this long after opening are closed without taking up
+    /// a slot of `max_connections`, 0 disables
+    pub handshake_timeout_secs: u64,
+    /// Reconnections per second the retry hints of disconnected clients spread them out to. The
+    /// more clients are connected the longer the hints get, 0 keeps them between 5 and 30 seconds.
+    pub reconnects_per_second: u32,
+    /// How long members whose connection was lost stay in their room waiting to be resumed, 0
+    /// removes them right away
+    pub disconnect_grace_period_secs: u64,
+    /// 0 disables
+    pub heartbeat_interval_secs: u64,
+    /// Rooms no member sent anything to for this long are closed, 0 disables
+    pub room_idle_ttl_secs: u64,
+    /// How often members are sent a `RoomDigest` to check their copy of the room against, 0 disables
+    pub room_digest_interval_secs: u64,
+    pub max_missed_heartbeats: u32,
+    /// Milliseconds a reported position may be off before the member is corrected, members who
+    /// sent a `NetworkReport` get more room for their connection on top
+    pub drift_tolerance_ms: u64,
+    /// Share of the members, spectators aside, who have to report `VideoEnded` before the room
+    /// moves on to the next queued video
+    pub video_ended_quorum_percent: u8,
+
+    /// Cap on live rooms opened by one client or IP address
+    pub max_rooms_per_creator: usize,
+    /// Rooms on the whole server, breakout rooms included, 0 disables
+    pub max_rooms: usize,
+    /// Cap on upcoming sessions scheduled by one client
+    pub max_sessions_per_creator: usize,
+    /// Upcoming sessions on the whole server, 0 disables
+    pub max_scheduled_sessions: usize,
+    /// Clients invited to one scheduled session
+    pub max_session_invitees: usize,
+    /// Members of one room, including the ones waiting to be resumed, 0 disables
+    pub max_clients_per_room: usize,
+    /// Members listed in the room state sent to everybody, larger rooms send the first ones and
+    /// the rest is fetched with `GetMembers`. 0 lists everybody.
+    pub max_listed_members: usize,
+    /// New connections beyond this are turned away with a retry hint, 0 disables
+    pub max_connections: usize,
+    /// Connections from one address, proxies are looked through only when trusted, 0 disables
+    pub max_connections_per_ip: usize,
+    /// Messages waiting to be written to one connection
+    pub outgoing_queue_capacity: usize,
+    /// Namespace chosen in `Hello` -> limits of its rooms, other namespaces only have the
+    /// server-wide limits
+    pub namespaces: HashMap<String, NamespaceLimits>,
+    /// Template name -> settings rooms opened with `CreateRoom { template }` start with
+    pub room_templates: HashMap<String, RoomTemplate>,
+}
+
+/// Reads the settings again from the sources they were first read from, for a reload
+pub struct ConfigLoader(Box<dyn Fn() -> Result<ServerConfig> + Send + Sync>);
+
+impl ConfigLoader {
+    pub fn new(load: impl Fn() -> Result<ServerConfig> + Send + Sync + 'static) -> Self {
+        ConfigLoader(Box::new(load))
+    }
+
+    pub fn load(&self) -> Result<ServerConfig> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for ConfigLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfigLoader")
+    }
+}
+
+/// Settings whose values are left out of logs and reload reports
+const SECRET_SETTINGS: [&str; 3] = ["admin_token", "auth_api_keys", "auth_jwt_secret"];
+
+/// Limits of the rooms of one namespace, the server-wide `max_rooms` applies on top
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct NamespaceLimits {
+    /// Rooms of the namespace, breakout rooms included, 0 disables
+    pub max_rooms: usize,
+    /// Replaces `max_clients_per_room` in the namespace when set, 0 disables
+    pub max_clients_per_room: Option<usize>,
+}
+
+/// Settings bundle of `room_templates`, the ones left out keep the defaults of new rooms. The owner
+/// may change the settings afterwards like in any other room.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct RoomTemplate {
+    /// Applied before `control_mode`, which overrides the mode of the preset
+    pub permission_preset: Option<PermissionPreset>,
+    pub control_mode: Option<ControlMode>,
+    pub public: Option<bool>,
+    pub voice_enabled: Option<bool>,
+    pub pause_on_leave: Option<bool>,
+    pub duplicate_names: Option<DuplicateNames>,
+    pub owner_succession: Option<OwnerSuccession>,
+    pub end_to_end_encrypted: Option<bool>,
+    /// Members of the room, the lower `max_clients_per_room` still applies
+    pub max_clients: Option<usize>,
+}
+
+/// Size and rate limits of the messages of one `Custom` channel, the rate is counted per member
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CustomChannelLimits {
+    /// Bytes of the payload serialized as JSON
+    pub max_payload_size: usize,
+    pub messages_per_second: f64,
+    pub messages_burst: f64,
+}
+
+impl Default for CustomChannelLimits {
+    fn default() -> Self {
+        CustomChannelLimits {
+            max_payload_size: 4 * 1024,
+            messages_per_second: 5.0,
+            messages_burst: 10.0,
+        }
+    }
+}
+
+/// What happens to the relays of a room over `room_relay_quota_bytes_per_minute`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum RelayQuotaAction {
+    /// Relayed anyway, operators are told in the log and the metrics
+    #[default]
+    Warn,
+    /// Refused with `RelayQuotaExceeded` until the minute is over
+    Throttle,
+}
+
+/// Lowercase letters and digits without the easily confused `0`, `o`, `1`, `l`
+const DEFAULT_ROOM_CODE_ALPHABET: &str = "abcdefghijkmnpqrstuvwxyz23456789";
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            vapid_private_key: None,
+            vapid_subject: None,
+            max_push_subscriptions_per_room: 200,
+            max_push_subscriptions: 20_000,
+            report_webhook_url: None,
+            public_url: None,
+            motd: None,
+            trusted_proxies: Vec::new(),
+            listeners: Vec::new(),
+            admin_token: None,
+            auth_api_keys: Vec::new(),
+            auth_jwt_secret: None,
+            auth_jwt_public_key: None,
+            allowed_origins: Vec::new(),
+            redis_url: None,
+            snapshot_path: None,
+            snapshot_interval_secs: 30,
+            archive_path: None,
+            lobby_enabled: false,
+            repair_inconsistencies: false,
+            strict_messages: false,
+            content_filter_words_path: None,
+            content_filter_action: ContentFilterAction::Reject,
+            log_format: LogFormat::Text,
+            log_filter: None,
+            min_name_length: 3,
+            max_name_length: 32,
+            name_characters: CharacterPolicy::Any,
+            min_room_id_length: 3,
+            max_room_id_length: 64,
+            room_id_characters: CharacterPolicy::Any,
+            reserved_room_ids: Vec::new(),
+            room_code_length: 6,
+            room_code_alphabet: DEFAULT_ROOM_CODE_ALPHABET.to_string(),
+            join_creates_rooms: true,
+            max_page_url_length: 2048,
+            allowed_page_domains: Vec::new(),
+            max_chat_message_length: 1000,
+            max_room_title_length: 100,
+            max_room_description_length: 500,
+            max_lobby_message_length: 500,
+            max_shared_file_size: 256 * 1024,
+            max_message_size: 64 * 1024,
+            custom_message_limits: CustomChannelLimits::default(),
+            custom_channels: HashMap::new(),
+            room_relay_quota_bytes_per_minute: 0,
+            room_relay_quota_action: RelayQuotaAction::Warn,
+            client_messages_per_second: 10.0,
+            client_messages_burst: 30.0,
+            client_rate_limit_max_violations: 20,
+            client_inactivity_timeout_secs: 600,
+            client_inactivity_warning_secs: 60,
+            consistency_check_interval_secs: 300,
+            slow_client_timeout_secs: 10,
+            handshake_timeout_secs: 10,
+            reconnects_per_second: 200,
+            disconnect_grace_period_secs: 30,
+            heartbeat_interval_secs: 15,
+            room_idle_ttl_secs: 6 * 60 * 60,
+            room_digest_interval_secs: 30,
+            max_missed_heartbeats: 3,
+            drift_tolerance_ms: 250,
+            video_ended_quorum_percent: 50,
+            max_rooms_per_creator: 10,
+            max_rooms: 5_000,
+            max_sessions_per_creator: 10,
+            max_scheduled_sessions: 1_000,
+            max_session_invitees: 50,
+            max_clients_per_room: 50,
+            max_listed_members: 100,
+            max_connections: 10_000,
+            max_connections_per_ip: 20,
+            outgoing_queue_capacity: 256,
+            namespaces: HashMap::new(),
+            room_templates: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(figment: &Figment) -> Result<Self> {
+        let mut config: ServerConfig = figment.clone().merge(Env::prefixed("SENT_SYNC_").global()).extract()?;
+        // Values which would make the server unusable are raised to the smallest sensible one
+        config.consistency_check_interval_secs = config.consistency_check_interval_secs.max(1);
+        config.slow_client_timeout_secs = config.slow_client_timeout_secs.max(1);
+        config.snapshot_interval_secs = config.snapshot_interval_secs.max(1);
+        config.max_missed_heartbeats = config.max_missed_heartbeats.max(1);
+        config.outgoing_queue_capacity = config.outgoing_queue_capacity.max(1);
+        config.client_messages_per_second = config.client_messages_per_second.max(0.1);
+        config.client_messages_burst = config.client_messages_burst.max(1.0);
+        config.client_rate_limit_max_violations = config.client_rate_limit_max_violations.max(1);
+        config.max_name_length = config.max_name_length.max(config.min_name_length);
+        // `Hello` and the like have to fit
+        config.max_message_size = config.max_message_size.max(4 * 1024);
+        config.max_room_id_length = config.max_room_id_length.max(config.min_room_id_length.max(1));
+        config.admin_token = config.admin_token.filter(|admin_token| !admin_token.is_empty());
+        config.auth_api_keys.retain(|api_key| !api_key.is_empty());
+        config.auth_jwt_secret = config.auth_jwt_secret.filter(|secret| !secret.is_empty());
+        config.auth_jwt_public_key = config.auth_jwt_public_key.filter(|public_key| !public_key.is_empty());
+        config.allowed_origins.retain(|origin| !origin.is_empty());
+        config.snapshot_path = config.snapshot_path.filter(|snapshot_path| !snapshot_path.is_empty());
+        config.archive_path = config.archive_path.filter(|archive_path| !archive_path.is_empty());
+        config.report_webhook_url = config.report_webhook_url.filter(|report_webhook_url| !report_webhook_url.is_empty());
+        config.motd = config.motd.filter(|motd| !motd.trim().is_empty());
+        config.content_filter_words_path = config.content_filter_words_path.filter(|words_path| !words_path.is_empty());
+        config.allowed_page_domains = config.allowed_page_domains.iter()
+            .map(|domain| domain.trim().trim_start_matches("*.").trim_start_matches('.').to_ascii_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        config.room_code_length = config.room_code_length.max(1);
+        if config.room_code_alphabet.is_empty() {
+            config.room_code_alphabet = DEFAULT_ROOM_CODE_ALPHABET.to_string();
+        }
+        Ok(config)
+    }
+
+    /// Copies the settings only read at startup from the running configuration, a reload leaves
+    /// them as they are until the next restart
+    pub fn keep_startup_settings(&mut self, running: &ServerConfig) {
+        self.vapid_private_key = running.vapid_private_key.clone();
+        self.vapid_subject = running.vapid_subject.clone();
+        self.public_url = running.public_url.clone();
+        self.listeners = running.listeners.clone();
+        self.auth_api_keys = running.auth_api_keys.clone();
+        self.auth_jwt_secret = running.auth_jwt_secret.clone();
+        self.auth_jwt_public_key = running.auth_jwt_public_key.clone();
+        self.redis_url = running.redis_url.clone();
+        self.snapshot_path = running.snapshot_path.clone();
+        self.snapshot_interval_secs = running.snapshot_interval_secs;
+        self.archive_path = running.archive_path.clone();
+        self.lobby_enabled = running.lobby_enabled;
+        self.repair_inconsistencies = running.repair_inconsistencies;
+        self.content_filter_words_path = running.content_filter_words_path.clone();
+        self.log_format = running.log_format;
+        self.log_filter = running.log_filter.clone();
+        self.consistency_check_interval_secs = running.consistency_check_interval_secs;
+        self.heartbeat_interval_secs = running.heartbeat_interval_secs;
+        self.max_missed_heartbeats = running.max_missed_heartbeats;
+        self.room_digest_interval_secs = running.room_digest_interval_secs;
+    }
+
+    /// Settings differing from `old` as `name: old -> new`, secrets only by their name
+    pub fn changes_from(&self, old: &ServerConfig) -> Vec<String> {
+        let (Ok(serde_json::Value::Object(new)), Ok(serde_json::Value::Object(old))) = (serde_json::to_value(self), serde_json::to_value(old)) else {
+            return Vec::new();
+        };
+        let mut changes: Vec<String> = new.iter()
+            .filter(|(name, value)| old.get(name.as_str()) != Some(value))
+            .map(|(name, value)| match old.get(name.as_str()) {
+                _ if SECRET_SETTINGS.contains(&name.as_str()) => name.clone(),
+                Some(old_value) => format!("{}: {} -> {}", name, old_value, value),
+                None => format!("{}: {}", name, value),
+            })
+            .collect();
+        changes.sort();
+        changes
+    }
+
+    pub fn client_inactivity_timeout(&self) -> Option<Duration> {
+        Some(self.client_inactivity_timeout_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn client_inactivity_warning(&self) -> Option<Duration> {
+        Some(self.client_inactivity_warning_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn consistency_check_interval(&self) -> Duration {
+        Duration::from_secs(self.consistency_check_interval_secs)
+    }
+
+    /// Largest frame a client may send, binary frames may be shared files
+    pub fn max_frame_size(&self) -> usize {
+        self.max_message_size.max(self.max_shared_file_size)
+    }
+
+    pub fn slow_client_timeout(&self) -> Duration {
+        Duration::from_secs(self.slow_client_timeout_secs)
+    }
+
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        Some(self.handshake_timeout_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn disconnect_grace_period(&self) -> Duration {
+        Duration::from_secs(self.disconnect_grace_period_secs)
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_interval_secs)
+    }
+
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        Some(self.heartbeat_interval_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn room_digest_interval(&self) -> Option<Duration> {
+        Some(self.room_digest_interval_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn room_idle_ttl(&self) -> Option<Duration> {
+        Some(self.room_idle_ttl_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn max_rooms(&self) -> Option<usize> {
+        limit(self.max_rooms)
+    }
+
+    pub fn max_clients_per_room(&self, namespace: Option<&str>) -> Option<usize> {
+        let namespace_limit = namespace.and_then(|namespace| self.namespaces.get(namespace)?.max_clients_per_room);
+        limit(namespace_limit.unwrap_or(self.max_clients_per_room))
+    }
+
+    pub fn namespace_max_rooms(&self, namespace: Option<&str>) -> Option<usize> {
+        limit(self.namespaces.get(namespace?)?.max_rooms)
+    }
+
+    pub fn max_scheduled_sessions(&self) -> Option<usize> {
+        limit(self.max_scheduled_sessions)
+    }
+
+    pub fn max_push_subscriptions(&self) -> Option<usize> {
+        limit(self.max_push_subscriptions)
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        limit(self.max_connections)
+    }
+
+    pub fn max_connections_per_ip(&self) -> Option<usize> {
+        limit(self.max_connections_per_ip)
+    }
+
+    pub fn custom_channel_limits(&self, channel: &str) -> CustomChannelLimits {
+        self.custom_channels.get(channel).copied().unwrap_or(self.custom_message_limits)
+    }
+}
+
+fn limit(value: usize) -> Option<usize> {
+    Some(value).filter(|value| *value > 0)
+}
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use crate::ws_app_state::WsAppState;
+use crate::ws_dto_models::ConfigReloadDto;
+
+/// Reads the settings again and puts them in place of the running ones. Connections stay open,
+/// limits are checked against the new values from now on. Settings captured when a connection
+/// opens, like its message budget, apply to connections opened after the reload.
+pub fn reload_config(state: &WsAppState) -> Result<ConfigReloadDto> {
+    let config_loader = state.config_loader.as_ref().ok_or_else(|| anyhow!("The server was started without a config loader"))?;
+    let running = state.config();
+    let mut config = config_loader.load()?;
+    let changed = config.changes_from(&running);
+    config.keep_startup_settings(&running);
+    let applied = config.changes_from(&running);
+    let needs_restart = changed.into_iter().filter(|change| !applied.contains(change)).collect::<Vec<_>>();
+    state.replace_config(Arc::new(config));
+
+    if applied.is_empty() {
+        tracing::info!("Configuration reloaded, nothing changed");
+    } else {
+        tracing::info!("Configuration reloaded: {}", applied.join(", "));
+    }
+    if !needs_restart.is_empty() {
+        tracing::warn!("Changed settings which only take effect after a restart: {}", needs_restart.join(", "));
+    }
+    Ok(ConfigReloadDto { applied, needs_restart })
+}
+
+/// Reloads the settings on every SIGHUP, a config that fails to load leaves the running one in place
+#[cfg(unix)]
+pub async fn run_reload_on_sighup(state: Arc<WsAppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(e) = reload_config(&state) {
+            tracing::error!("Failed to reload configuration: {:?}", e);
+        }
+    }
+}
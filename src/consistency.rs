@@ -0,0 +1,180 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::ws_app_state::{Client, Room, WsAppState};
+use crate::ws_dto_models::Role;
+
+/// Divergence between the client list, the rooms map and their back-references
+pub enum Inconsistency {
+    /// The client points to a room which is not in the rooms map
+    ClientInMissingRoom { client: Arc<Client>, room: Arc<Room> },
+    /// The client points to a room which doesn't list it as a member
+    ClientNotRoomMember { client: Arc<Client>, room: Arc<Room> },
+    /// The room lists a member which is not connected anymore
+    DisconnectedRoomMember { room: Arc<Room>, client: Arc<Client> },
+    /// The room lists a member which points to another room or none
+    RoomMemberElsewhere { room: Arc<Room>, client: Arc<Client> },
+    DuplicateRoomMember { room: Arc<Room>, client: Arc<Client> },
+    OwnerlessRoom { room: Arc<Room> },
+    MultipleOwners { room: Arc<Room>, owners: usize },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::ClientInMissingRoom { client, room } => write!(f, "client {} is in room {} which does not exist", client.uid, room.room_id),
+            Inconsistency::ClientNotRoomMember { client, room } => write!(f, "client {} is in room {} which does not list it", client.uid, room.room_id),
+            Inconsistency::DisconnectedRoomMember { room, client } => write!(f, "room {} lists disconnected client {}", room.room_id, client.uid),
+            Inconsistency::RoomMemberElsewhere { room, client } => write!(f, "room {} lists client {} which is not in it", room.room_id, client.uid),
+            Inconsistency::DuplicateRoomMember { room, client } => write!(f, "room {} lists client {} more than once", room.room_id, client.uid),
+            Inconsistency::OwnerlessRoom { room } => write!(f, "room {} has members but no owner", room.room_id),
+            Inconsistency::MultipleOwners { room, owners } => write!(f, "room {} has {} owners", room.room_id, owners),
+        }
+    }
+}
+
+pub async fn run_consistency_checker(state: Arc<WsAppState>, check_interval: Duration, repair: bool) {
+    let mut interval = tokio::time::interval(check_interval);
+    loop {
+        interval.tick().await;
+        let inconsistencies = check_state(&state).await;
+        for inconsistency in inconsistencies.iter() {
+            tracing::warn!("State inconsistency: {}", inconsistency);
+        }
+        if repair {
+            for inconsistency in inconsistencies {
+                repair_inconsistency(&state, inconsistency).await;
+            }
+        }
+    }
+}
+
+/// Compares snapshots taken one lock at a time, so a check never holds two locks at once. Changes
+/// made between the snapshots may show up as false positives, repairs re-check under the lock.
+pub async fn check_state(state: &WsAppState) -> Vec<Inconsistency> {
+    let clients: Vec<Arc<Client>> = state.clients.snapshot();
+    let rooms: Vec<Arc<Room>> = state.store.rooms().await;
+
+    let mut client_rooms = Vec::with_capacity(clients.len());
+    for client in clients.iter() {
+        client_rooms.push((client.clone(), client.data.lock().await.room.clone()));
+    }
+
+    let mut room_members = Vec::with_capacity(rooms.len());
+    for room in rooms.iter() {
+        let members = room.run(|room_data| {
+            room_data
+                .clients
+                .iter()
+                .map(|room_client| (room_client.client.clone(), room_client.is_owner(), room_client.spectator))
+                .collect::<Vec<(Arc<Client>, bool, bool)>>()
+        }).await;
+        // A room whose task is gone can't be inspected, it gets dropped by the maintenance
+        if let Ok(members) = members {
+            room_members.push((room.clone(), members));
+        }
+    }
+
+    let mut inconsistencies = Vec::new();
+
+    for (client, client_room) in client_rooms.iter() {
+        let Some(client_room) = client_room else {
+            continue;
+        };
+        match room_members.iter().find(|(room, _)| Arc::ptr_eq(room, client_room)) {
+            None => inconsistencies.push(Inconsistency::ClientInMissingRoom { client: client.clone(), room: client_room.clone() }),
+            Some((_, members)) => {
+                if !members.iter().any(|(member, ..)| Arc::ptr_eq(member, client)) {
+                    inconsistencies.push(Inconsistency::ClientNotRoomMember { client: client.clone(), room: client_room.clone() });
+                }
+            }
+        }
+    }
+
+    for (room, members) in room_members.iter() {
+        for (index, (member, ..)) in members.iter().enumerate() {
+            if members[..index].iter().any(|(previous, ..)| Arc::ptr_eq(previous, member)) {
+                inconsistencies.push(Inconsistency::DuplicateRoomMember { room: room.clone(), client: member.clone() });
+                continue;
+            }
+            match client_rooms.iter().find(|(client, _)| Arc::ptr_eq(client, member)) {
+                None => inconsistencies.push(Inconsistency::DisconnectedRoomMember { room: room.clone(), client: member.clone() }),
+                Some((_, client_room)) => {
+                    if !client_room.as_ref().is_some_and(|client_room| Arc::ptr_eq(client_room, room)) {
+                        inconsistencies.push(Inconsistency::RoomMemberElsewhere { room: room.clone(), client: member.clone() });
+                    }
+                }
+            }
+        }
+
+        let owners = members.iter().filter(|(_, owner, _)| *owner).count();
+        // Spectators never become owners, a room left to them has none
+        if owners == 0 && members.iter().any(|(_, _, spectator)| !spectator) {
+            inconsistencies.push(Inconsistency::OwnerlessRoom { room: room.clone() });
+        } else if owners > 1 {
+            inconsistencies.push(Inconsistency::MultipleOwners { room: room.clone(), owners });
+        }
+    }
+
+    inconsistencies
+}
+
+async fn repair_inconsistency(state: &WsAppState, inconsistency: Inconsistency) {
+    match inconsistency {
+        Inconsistency::ClientInMissingRoom { client, room } => {
+            let room_exists = state.store.room(room.namespace.as_deref(), &room.room_id).await.is_some_and(|existing_room| Arc::ptr_eq(&existing_room, &room));
+            let mut client_data = client.data.lock().await;
+            if !room_exists && client_data.room.as_ref().is_some_and(|client_room| Arc::ptr_eq(client_room, &room)) {
+                client.set_room(&mut client_data, None);
+            }
+        }
+        Inconsistency::ClientNotRoomMember { client, room } => {
+            let mut client_data = client.data.lock().await;
+            let member_client = client.clone();
+            let is_member = room.run(move |room_data| room_data.find_room_client(&member_client).is_some()).await.unwrap_or(true);
+            if !is_member && client_data.room.as_ref().is_some_and(|client_room| Arc::ptr_eq(client_room, &room)) {
+                client.set_room(&mut client_data, None);
+            }
+        }
+        Inconsistency::DisconnectedRoomMember { room, client } => {
+            let connected = state.clients.contains(&client);
+            if !connected {
+                let _ = room.run(move |room_data| room_data.remove_client(&client)).await;
+            }
+        }
+        Inconsistency::RoomMemberElsewhere { room, client } => {
+            let in_room = client.data.lock().await.room.as_ref().is_some_and(|client_room| Arc::ptr_eq(client_room, &room));
+            if !in_room {
+                let _ = room.run(move |room_data| room_data.remove_client(&client)).await;
+            }
+        }
+        Inconsistency::DuplicateRoomMember { room, client } => {
+            let _ = room.run(move |room_data| {
+                let mut seen = false;
+                room_data.clients.retain(|room_client| {
+                    let duplicate = seen && Arc::ptr_eq(&room_client.client, &client);
+                    seen = seen || Arc::ptr_eq(&room_client.client, &client);
+                    !duplicate
+                });
+            }).await;
+        }
+        Inconsistency::OwnerlessRoom { room } => {
+            let _ = room.run(|room_data| {
+                if !room_data.clients.iter().any(|room_client| room_client.is_owner()) {
+                    room_data.hand_over_ownership();
+                }
+            }).await;
+        }
+        Inconsistency::MultipleOwners { room, .. } => {
+            let _ = room.run(|room_data| {
+                let mut owner_seen = false;
+                for room_client in room_data.clients.iter_mut().filter(|room_client| room_client.is_owner()) {
+                    if owner_seen {
+                        room_client.role = Role::Admin;
+                    }
+                    owner_seen = true;
+                }
+            }).await;
+        }
+    }
+}
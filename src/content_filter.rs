@@ -0,0 +1,95 @@
+//! Checks of the text members show to each other. A filter only finds the unacceptable parts, whether
+//! the text is then rejected or masked is up to `ServerConfig::content_filter_action`.
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::ops::Range;
+use anyhow::{Context, Result};
+use rocket::serde::{Deserialize, Serialize};
+use crate::protocol::ErrorKind;
+use crate::ws_app_state::WsAppState;
+
+/// What the checked text is used as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Name,
+    RoomTitle,
+    RoomDescription,
+    ChatMessage,
+    LobbyMessage,
+}
+
+/// Embedders plug their own in with `build_rocket_with_content_filter`, otherwise the server uses a
+/// `WordListFilter` when `content_filter_words_path` is set
+pub trait ContentFilter: Send + Sync + Debug {
+    /// Byte ranges of the unacceptable parts of `text`, empty when all of it is fine
+    fn find_violations(&self, kind: ContentKind, text: &str) -> Vec<Range<usize>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ContentFilterAction {
+    /// The message is answered with `ContentRejected`
+    #[default]
+    Reject,
+    /// Every character of the violations is replaced with `*`
+    Mask,
+}
+
+/// Words matched case-insensitively as a whole, `ass` doesn't match `class`
+#[derive(Debug, Default)]
+pub struct WordListFilter {
+    words: HashSet<String>,
+}
+
+impl WordListFilter {
+    pub fn new(words: impl IntoIterator<Item = String>) -> Self {
+        WordListFilter { words: words.into_iter().map(|word| word.to_lowercase()).collect() }
+    }
+
+    /// One word per line, empty lines and lines starting with `#` are skipped
+    pub fn load(path: &str) -> Result<Self> {
+        let words = std::fs::read_to_string(path).with_context(|| format!("Failed to read the word list {}", path))?;
+        Ok(WordListFilter::new(
+            words.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(str::to_string),
+        ))
+    }
+}
+
+impl ContentFilter for WordListFilter {
+    fn find_violations(&self, _kind: ContentKind, text: &str) -> Vec<Range<usize>> {
+        let mut violations = Vec::new();
+        let mut word_start = None;
+        for (index, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+            match (word_start, c.is_alphanumeric()) {
+                (None, true) => word_start = Some(index),
+                (Some(start), false) => {
+                    if self.words.contains(&text[start..index].to_lowercase()) {
+                        violations.push(start..index);
+                    }
+                    word_start = None;
+                }
+                _ => {}
+            }
+        }
+        violations
+    }
+}
+
+/// `text` as it may be shown, masked when the config says so, or `ContentRejected`
+pub fn filter_content(state: &WsAppState, kind: ContentKind, text: String) -> Result<String, ErrorKind> {
+    let Some(content_filter) = state.content_filter.as_ref() else {
+        return Ok(text);
+    };
+    let violations = content_filter.find_violations(kind, &text);
+    if violations.is_empty() {
+        return Ok(text);
+    }
+    match state.config().content_filter_action {
+        ContentFilterAction::Reject => Err(ErrorKind::ContentRejected),
+        ContentFilterAction::Mask => Ok(text
+            .char_indices()
+            .map(|(index, c)| if violations.iter().any(|violation| violation.contains(&index)) { '*' } else { c })
+            .collect()),
+    }
+}
@@ -0,0 +1,81 @@
+/// Consecutive combining marks allowed on one character, more is only used for "zalgo" text
+const MAX_COMBINING_MARKS: usize = 2;
+
+/// Cyrillic and Greek letters indistinguishable from Latin ones
+const LATIN_HOMOGLYPHS: &[(char, char)] = &[
+    ('а', 'a'), ('в', 'B'), ('е', 'e'), ('к', 'k'), ('м', 'M'), ('н', 'H'), ('о', 'o'), ('р', 'p'),
+    ('с', 'c'), ('т', 'T'), ('у', 'y'), ('х', 'x'), ('і', 'i'), ('ј', 'j'), ('ѕ', 's'), ('ԁ', 'd'),
+    ('А', 'A'), ('В', 'B'), ('Е', 'E'), ('К', 'K'), ('М', 'M'), ('Н', 'H'), ('О', 'O'), ('Р', 'P'),
+    ('С', 'C'), ('Т', 'T'), ('У', 'Y'), ('Х', 'X'), ('І', 'I'), ('Ј', 'J'), ('Ѕ', 'S'),
+    ('α', 'a'), ('ο', 'o'), ('ρ', 'p'), ('ν', 'v'), ('ι', 'i'), ('κ', 'k'),
+    ('Α', 'A'), ('Β', 'B'), ('Ε', 'E'), ('Ζ', 'Z'), ('Η', 'H'), ('Ι', 'I'), ('Κ', 'K'), ('Μ', 'M'),
+    ('Ν', 'N'), ('Ο', 'O'), ('Ρ', 'P'), ('Τ', 'T'), ('Υ', 'Y'), ('Χ', 'X'),
+];
+
+/// Normalizes a display name before it is stored: invisible and control characters are dropped,
+/// whitespace is collapsed, fullwidth letters become ASCII and lookalike letters mixed into Latin
+/// names are replaced, so names can't be invisible or imitate somebody else's
+pub fn sanitize_display_name(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    let mut combining_marks = 0;
+    for c in name.chars() {
+        if is_invisible(c) {
+            continue;
+        }
+
+        if is_combining_mark(c) {
+            combining_marks += 1;
+            if combining_marks > MAX_COMBINING_MARKS {
+                continue;
+            }
+        } else {
+            combining_marks = 0;
+        }
+
+        if c.is_whitespace() {
+            if !sanitized.is_empty() && !sanitized.ends_with(' ') {
+                sanitized.push(' ');
+            }
+            continue;
+        }
+
+        sanitized.push(fullwidth_to_ascii(c));
+    }
+
+    let sanitized = sanitized.trim_end().to_string();
+
+    // Names written entirely in Cyrillic or Greek are left alone, only mixed ones are suspicious
+    if sanitized.chars().any(|c| c.is_ascii_alphabetic()) {
+        sanitized.chars().map(latin_homoglyph).collect()
+    } else {
+        sanitized
+    }
+}
+
+fn is_invisible(c: char) -> bool {
+    c.is_control()
+        || matches!(c,
+            '\u{00AD}' | '\u{034F}' | '\u{061C}' | '\u{115F}' | '\u{1160}' | '\u{180E}'
+            | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{206F}'
+            | '\u{3164}' | '\u{FE00}'..='\u{FE0F}' | '\u{FEFF}' | '\u{FFA0}' | '\u{E0000}'..='\u{E007F}'
+        )
+}
+
+pub fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}')
+}
+
+fn fullwidth_to_ascii(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFF01 + 0x21).unwrap_or(c),
+        _ => c,
+    }
+}
+
+fn latin_homoglyph(c: char) -> char {
+    LATIN_HOMOGLYPHS
+        .iter()
+        .find(|(homoglyph, _)| *homoglyph == c)
+        .map(|(_, latin)| *latin)
+        .unwrap_or(c)
+}
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use rocket_ws as ws;
+use crate::ws_app_state::{DisconnectReason, WsAppState};
+
+/// Pings every connection, connections which haven't sent anything for `max_missed_heartbeats`
+/// intervals are considered dead and go through the regular disconnect cleanup. A silently dropped
+/// TCP connection would otherwise keep its client in the room until the inactivity timeout.
+pub async fn run_heartbeat(state: Arc<WsAppState>, heartbeat_interval: Duration, max_missed_heartbeats: u32) {
+    let timeout = heartbeat_interval * max_missed_heartbeats;
+    let mut interval = tokio::time::interval(heartbeat_interval);
+    loop {
+        interval.tick().await;
+        for client in state.clients.snapshot() {
+            // Detached clients have no connection to ping, the grace period takes care of them
+            if client.detached.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            if client.unseen_for() >= timeout {
+                tracing::warn!(client_uid = %client.uid, "Disconnecting client which missed {} heartbeats", max_missed_heartbeats);
+                client.disconnect(DisconnectReason::HeartbeatTimeout, "Heartbeat timeout", Some(state.reconnect_retry_after()));
+            } else {
+                let _ = client.send(ws::Message::Ping(Vec::new()));
+            }
+        }
+    }
+}
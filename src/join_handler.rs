@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use rocket::http::{ContentType, RawStr};
+use rocket::response::content::RawHtml;
+use rocket::response::Redirect;
+use rocket::State;
+use crate::qr_code::QrCode;
+use crate::ws_app_state::WsAppState;
+
+/// Landing page for invite links. The browser extension picks the room code, namespace and invite
+/// token up from the `sent-sync-*` meta tags (or the `sent-sync-join` window message) and joins the
+/// room itself.
+#[get("/join/<room_id>?<invite>&<namespace>")]
+pub async fn join_page(room_id: &str, invite: Option<&str>, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> RawHtml<String> {
+    let canonical_room_id = state.resolve_room_id(namespace, room_id).await;
+    let (members_count, page_url, playing) = match state.store.room_summary(namespace, &canonical_room_id).await {
+        Some(summary) => (summary.members_count, summary.page_url, summary.playback.playing),
+        None => (0, None, false),
+    };
+
+    let room_id_html = escape_html(room_id);
+    let invite_html = escape_html(invite.unwrap_or_default());
+    let namespace_html = escape_html(namespace.unwrap_or_default());
+    let status_html = if members_count > 0 && playing {
+        format!("{} watching right now", members_count)
+    } else if members_count > 0 {
+        format!("{} in the room, paused", members_count)
+    } else {
+        "Nobody is here yet".to_string()
+    };
+    let page_url_html = page_url
+        .map(|url| format!(r#"<p>Video: <a href="{0}">{0}</a></p>"#, escape_html(&url)))
+        .unwrap_or_default();
+    let join_message = serde_json::json!({
+        "type": "sent-sync-join",
+        "roomId": room_id,
+        "invite": invite,
+        "namespace": namespace,
+    }).to_string().replace('<', "\\u003c");
+
+    RawHtml(format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Join room {room_id_html}</title>
+<meta property="og:title" content="Join watch party {room_id_html}">
+<meta property="og:description" content="{status_html}">
+<meta name="sent-sync-room-id" content="{room_id_html}">
+<meta name="sent-sync-invite" content="{invite_html}">
+<meta name="sent-sync-namespace" content="{namespace_html}">
+</head>
+<body>
+<h1>Watch party {room_id_html}</h1>
+<p>{status_html}</p>
+{page_url_html}
+<p>If the extension is installed the room will be joined automatically. Otherwise enter the room code <b>{room_id_html}</b> in the extension.</p>
+<script>window.postMessage({join_message}, "*");</script>
+</body>
+</html>
+"#))
+}
+
+/// QR code of the join link. Only reachable with the token of the `image_url` handed to members
+/// who may invite.
+#[get("/join/<room_id>/qr.svg?<namespace>&<token>")]
+pub async fn join_qr_code(room_id: &str, namespace: Option<&str>, token: Option<&str>, state: &State<Arc<WsAppState>>) -> Option<(ContentType, String)> {
+    if !state.verify_qr_code_token(namespace, room_id, token?) {
+        return None;
+    }
+    let qr_code = QrCode::encode(state.join_url(namespace, room_id).as_bytes())?;
+    Some((ContentType::SVG, qr_code.to_svg()))
+}
+
+/// Short invite links resolve to the regular join page with the invite token attached
+#[get("/i/<slug>")]
+pub async fn invite_link(slug: &str, state: &State<Arc<WsAppState>>) -> Option<Redirect> {
+    let link = state.resolve_invite_link(slug).await?;
+    let mut url = format!(
+        "/join/{}?invite={}",
+        RawStr::new(&link.room_id).percent_encode(),
+        RawStr::new(&link.invite_token).percent_encode()
+    );
+    if let Some(namespace) = &link.namespace {
+        url.push_str(&format!("&namespace={}", RawStr::new(namespace).percent_encode()));
+    }
+    Some(Redirect::to(url))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,360 @@
+//! JSON Schemas of the protocol types for the API description. They are converted from the
+//! TypeScript declarations ts-rs derives, which already follow the serde attributes of the types,
+//! so the schemas can't drift from what the server sends and accepts. Only the subset of
+//! TypeScript ts-rs emits is understood.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Map, Value};
+use ts_rs::{TypeVisitor, TS};
+
+/// Schemas of named types, keyed by their name as in `#/components/schemas/<name>`
+#[derive(Default)]
+pub struct Schemas {
+    schemas: Map<String, Value>,
+    error: Option<anyhow::Error>,
+}
+
+impl Schemas {
+    /// `$ref` to the schema of `T`, which is added together with the types it refers to
+    pub fn reference<T: TS + 'static + ?Sized>(&mut self) -> Value {
+        self.add::<T>();
+        schema_ref(&T::name())
+    }
+
+    pub fn array_of<T: TS + 'static + ?Sized>(&mut self) -> Value {
+        json!({ "type": "array", "items": self.reference::<T>() })
+    }
+
+    /// The schemas, or the first type which couldn't be converted
+    pub fn into_map(self) -> Result<Map<String, Value>> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.schemas),
+        }
+    }
+
+    fn add<T: TS + 'static + ?Sized>(&mut self) {
+        let name = T::name();
+        if self.schemas.contains_key(&name) {
+            return;
+        }
+        // Inserted before the dependencies, types referring to each other are only added once
+        self.schemas.insert(name.clone(), Value::Null);
+
+        match ts_to_json_schema(&T::inline()) {
+            Ok(mut schema) => {
+                if let (Some(docs), Some(schema)) = (T::docs(), schema.as_object_mut()) {
+                    schema.insert("description".to_string(), Value::String(doc_comment_text(&docs)));
+                }
+                self.schemas.insert(name, schema);
+            }
+            Err(e) => {
+                self.error.get_or_insert(e.context(format!("Unsupported TypeScript type {}", name)));
+            }
+        }
+        T::visit_dependencies(&mut DependencyVisitor(self));
+    }
+}
+
+struct DependencyVisitor<'a>(&'a mut Schemas);
+
+impl TypeVisitor for DependencyVisitor<'_> {
+    fn visit<T: TS + 'static + ?Sized>(&mut self) {
+        // Primitives and wrappers like `Option` have no declaration of their own
+        if T::output_path().is_some() {
+            self.0.add::<T>();
+        }
+    }
+}
+
+pub fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Schema of a TypeScript type expression like `{ "type": "ping", clientTime: bigint | null, }`
+pub fn ts_to_json_schema(ts: &str) -> Result<Value> {
+    let mut parser = Parser { tokens: tokenize(ts)?, position: 0 };
+    let schema = parser.parse_type()?;
+    match parser.next() {
+        None => Ok(schema),
+        Some(token) => Err(anyhow!("Unexpected {:?} after the type", token)),
+    }
+}
+
+/// Text of a `/** ... */` comment without the comment markers
+fn doc_comment_text(comment: &str) -> String {
+    let comment = comment.trim().trim_start_matches("/**").trim_end_matches("*/");
+    comment
+        .lines()
+        .map(|line| line.trim().trim_start_matches('*').trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punctuation(char),
+    Identifier(String),
+    String(String),
+    Number(f64),
+    Comment(String),
+}
+
+fn tokenize(ts: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = ts.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '/' if ts[start..].starts_with("/*") => {
+                let length = ts[start..].find("*/").ok_or(anyhow!("Unterminated comment"))? + 2;
+                tokens.push(Token::Comment(doc_comment_text(&ts[start..start + length])));
+                while chars.next_if(|(i, _)| *i < start + length).is_some() {}
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => text.extend(chars.next().map(|(_, c)| c)),
+                        Some((_, c)) => text.push(c),
+                        None => bail!("Unterminated string"),
+                    }
+                }
+                tokens.push(Token::String(text));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    end = i + c.len_utf8();
+                }
+                tokens.push(Token::Number(ts[start..end].parse()?));
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_' || *c == '$') {
+                    end = i + c.len_utf8();
+                }
+                tokens.push(Token::Identifier(ts[start..end].to_string()));
+            }
+            '{' | '}' | '[' | ']' | '<' | '>' | '(' | ')' | ':' | ';' | ',' | '|' | '&' | '?' => tokens.push(Token::Punctuation(c)),
+            c => bail!("Unexpected character {:?}", c),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    /// Text of the comments right before the next token
+    fn take_comment(&mut self) -> Option<String> {
+        let mut comment = None;
+        while let Some(Token::Comment(text)) = self.tokens.get(self.position) {
+            comment = Some(text.clone());
+            self.position += 1;
+        }
+        comment
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.take_comment();
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.take_comment();
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_if_punctuation(&mut self, punctuation: char) -> bool {
+        let found = self.peek() == Some(&Token::Punctuation(punctuation));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punctuation: char) -> Result<()> {
+        match self.next() {
+            Some(Token::Punctuation(c)) if c == punctuation => Ok(()),
+            token => Err(anyhow!("Expected {:?}, found {:?}", punctuation, token)),
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<Value> {
+        let mut members = vec![self.parse_intersection()?];
+        while self.next_if_punctuation('|') {
+            members.push(self.parse_intersection()?);
+        }
+        if members.len() == 1 {
+            return Ok(members.remove(0));
+        }
+
+        // Unit variants of enums are string literals, they are listed together
+        let (strings, mut others): (Vec<Value>, Vec<Value>) = members.into_iter().partition(|member| {
+            member.as_object().is_some_and(|member| member.len() == 1 && member.get("const").is_some_and(Value::is_string))
+        });
+        let strings: Vec<Value> = strings.into_iter().filter_map(|member| member.get("const").cloned()).collect();
+        if !strings.is_empty() {
+            let enumeration = json!({ "type": "string", "enum": strings });
+            if others.is_empty() {
+                return Ok(enumeration);
+            }
+            others.insert(0, enumeration);
+        }
+        Ok(json!({ "oneOf": others }))
+    }
+
+    fn parse_intersection(&mut self) -> Result<Value> {
+        let mut members = vec![self.parse_array()?];
+        while self.next_if_punctuation('&') {
+            members.push(self.parse_array()?);
+        }
+        if members.len() == 1 {
+            return Ok(members.remove(0));
+        }
+        Ok(json!({ "allOf": members }))
+    }
+
+    fn parse_array(&mut self) -> Result<Value> {
+        let mut schema = self.parse_primary()?;
+        while self.tokens.get(self.position) == Some(&Token::Punctuation('['))
+            && self.tokens.get(self.position + 1) == Some(&Token::Punctuation(']'))
+        {
+            self.position += 2;
+            schema = json!({ "type": "array", "items": schema });
+        }
+        Ok(schema)
+    }
+
+    fn parse_primary(&mut self) -> Result<Value> {
+        match self.next() {
+            Some(Token::Punctuation('{')) => self.parse_object(),
+            Some(Token::Punctuation('(')) => {
+                let schema = self.parse_type()?;
+                self.expect(')')?;
+                Ok(schema)
+            }
+            Some(Token::Punctuation('[')) => {
+                let mut items = Vec::new();
+                while !self.next_if_punctuation(']') {
+                    items.push(self.parse_type()?);
+                    if !self.next_if_punctuation(',') {
+                        self.expect(']')?;
+                        break;
+                    }
+                }
+                Ok(json!({ "type": "array", "prefixItems": items, "minItems": items.len(), "maxItems": items.len() }))
+            }
+            Some(Token::String(text)) => Ok(json!({ "const": text })),
+            Some(Token::Number(number)) => Ok(json!({ "const": number })),
+            Some(Token::Identifier(name)) => self.parse_named(name),
+            token => Err(anyhow!("Expected a type, found {:?}", token)),
+        }
+    }
+
+    fn parse_named(&mut self, name: String) -> Result<Value> {
+        let schema = match name.as_str() {
+            "string" => json!({ "type": "string" }),
+            "number" => json!({ "type": "number" }),
+            // 64 bit integers
+            "bigint" => json!({ "type": "integer" }),
+            "boolean" => json!({ "type": "boolean" }),
+            "null" => json!({ "type": "null" }),
+            "true" | "false" => json!({ "const": name == "true" }),
+            "unknown" | "any" => json!({}),
+            "never" => json!({ "not": {} }),
+            "Array" => {
+                self.expect('<')?;
+                let items = self.parse_type()?;
+                self.expect('>')?;
+                json!({ "type": "array", "items": items })
+            }
+            "Record" => {
+                self.expect('<')?;
+                self.parse_type()?;
+                self.expect(',')?;
+                let values = self.parse_type()?;
+                self.expect('>')?;
+                json!({ "type": "object", "additionalProperties": values })
+            }
+            _ if self.peek() == Some(&Token::Punctuation('<')) => bail!("Generic type {} is not supported", name),
+            _ => schema_ref(&name),
+        };
+        Ok(schema)
+    }
+
+    /// Members up to the closing brace, `{` was read already
+    fn parse_object(&mut self) -> Result<Value> {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        let mut additional_properties = None;
+        loop {
+            let description = self.take_comment();
+            if self.next_if_punctuation('}') {
+                break;
+            }
+
+            if self.next_if_punctuation('[') {
+                // `[key in string]?: T`
+                match (self.next(), self.next()) {
+                    (Some(Token::Identifier(_)), Some(Token::Identifier(keyword))) if keyword == "in" => {}
+                    tokens => bail!("Unsupported index signature {:?}", tokens),
+                }
+                self.parse_type()?;
+                self.expect(']')?;
+                self.next_if_punctuation('?');
+                self.expect(':')?;
+                additional_properties = Some(self.parse_type()?);
+            } else {
+                let key = match self.next() {
+                    Some(Token::Identifier(key) | Token::String(key)) => key,
+                    token => bail!("Expected a property name, found {:?}", token),
+                };
+                let optional = self.next_if_punctuation('?');
+                self.expect(':')?;
+                let mut schema = self.parse_type()?;
+                // Missing `Option` fields are read as `None` by serde
+                if !optional && !admits_null(&schema) {
+                    required.push(Value::String(key.clone()));
+                }
+                if let Some(description) = description {
+                    if schema.get("$ref").is_some() {
+                        schema = json!({ "allOf": [schema] });
+                    }
+                    if let Some(schema) = schema.as_object_mut() {
+                        schema.insert("description".to_string(), Value::String(description));
+                    }
+                }
+                properties.insert(key, schema);
+            }
+
+            if !self.next_if_punctuation(',') && !self.next_if_punctuation(';') {
+                self.take_comment();
+                self.expect('}')?;
+                break;
+            }
+        }
+
+        let mut schema = json!({ "type": "object", "properties": properties });
+        if !required.is_empty() {
+            schema["required"] = Value::Array(required);
+        }
+        if let Some(additional_properties) = additional_properties {
+            schema["additionalProperties"] = additional_properties;
+        }
+        Ok(schema)
+    }
+}
+
+fn admits_null(schema: &Value) -> bool {
+    schema.get("type").is_some_and(|kind| kind == "null")
+        || schema.get("oneOf").and_then(Value::as_array).is_some_and(|members| members.iter().any(admits_null))
+}
@@ -0,0 +1,44 @@
+//! Checks of incoming JSON beyond what the message types accept. Serde's `deny_unknown_fields`
+//! doesn't work together with the `flatten` of `IncomingEnvelope` and the tagged messages, so in
+//! strict mode the parsed message is serialized again and compared with what the client sent.
+
+use rocket::serde::Serialize;
+use serde_json::Value;
+
+/// Path of the first field of `text` the parsed `message` doesn't have, like `settings.pageUrl`.
+/// `allowed` top level fields are not part of the message itself.
+pub fn unknown_field<T: Serialize>(text: &str, message: &T, allowed: &[&str]) -> Option<String> {
+    let sent = serde_json::from_str::<Value>(text).ok()?;
+    let parsed = serde_json::to_value(message).ok()?;
+    find_unknown_field(&sent, &parsed, allowed)
+}
+
+fn find_unknown_field(sent: &Value, parsed: &Value, allowed: &[&str]) -> Option<String> {
+    let (Value::Object(sent), Value::Object(parsed)) = (sent, parsed) else {
+        return None;
+    };
+    for (key, sent_value) in sent.iter() {
+        if allowed.contains(&key.as_str()) {
+            continue;
+        }
+        let Some(parsed_value) = parsed.get(key) else {
+            return Some(key.clone());
+        };
+        let nested = match (sent_value, parsed_value) {
+            (Value::Array(sent_items), Value::Array(parsed_items)) => sent_items.iter().zip(parsed_items).enumerate()
+                .find_map(|(index, (sent_item, parsed_item))| Some(format!("{}.{}", index, find_unknown_field(sent_item, parsed_item, &[])?))),
+            _ => find_unknown_field(sent_value, parsed_value, &[]),
+        };
+        if let Some(nested) = nested {
+            return Some(format!("{}.{}", key, nested));
+        }
+    }
+    None
+}
+
+/// Field named in a serde error like "missing field `newName`"
+pub fn failed_field(error: &serde_json::Error) -> Option<String> {
+    let message = error.to_string();
+    let rest = message.strip_prefix("missing field `").or_else(|| message.strip_prefix("unknown field `"))?;
+    Some(rest.split('`').next()?.to_string())
+}
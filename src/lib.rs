@@ -0,0 +1,246 @@
+//! Watch party sync server. The binary serves it on its own, `build_rocket` lets it be embedded
+//! into another Rocket application or started in-process by tests.
+
+// A panic takes down the connection or room task it happens in, errors are returned instead
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+#[macro_use]
+extern crate rocket;
+pub mod ws_handler;
+pub mod ws_app_state;
+pub mod ws_dto_models;
+pub mod push_notifications;
+mod push_handler;
+mod abuse_reports;
+mod room_archive;
+mod scheduler;
+mod sessions_handler;
+mod calendar;
+mod join_handler;
+pub mod qr_code;
+pub mod command_signing;
+mod rate_limit;
+mod room_maintenance;
+mod display_name;
+pub mod validation;
+pub mod content_filter;
+mod consistency;
+pub mod localization;
+mod client_registry;
+pub mod protocol;
+mod heartbeat;
+mod room_digest;
+pub mod room_logic;
+pub mod listeners;
+mod msgpack;
+mod json_validation;
+mod metrics;
+mod metrics_handler;
+mod client_address;
+pub mod config;
+mod config_reload;
+pub mod cli;
+pub mod logging;
+mod admin_handler;
+mod json_schema;
+mod spec_handler;
+mod auth;
+mod origin;
+mod public_rooms_handler;
+pub mod state_store;
+mod room_snapshots;
+#[cfg(feature = "redis")]
+mod redis_client;
+#[cfg(feature = "redis")]
+mod cluster;
+#[cfg(feature = "client")]
+pub mod client;
+
+pub use crate::config::{ConfigLoader, ServerConfig};
+use crate::content_filter::{ContentFilter, WordListFilter};
+use crate::push_notifications::{PushNotifier, VapidKey};
+use crate::ws_app_state::WsAppState;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::{Build, Rocket};
+use std::sync::Arc;
+
+/// Rocket with the routes, state and background tasks of the server. `figment` holds Rocket's own
+/// settings, `config` is usually loaded from the same figment with `ServerConfig::load`. Logging
+/// is left to the caller, see `logging::init`.
+pub fn build_rocket(figment: Figment, config: ServerConfig) -> Rocket<Build> {
+    build(figment, config, None, None)
+}
+
+/// `build_rocket` for a server whose settings can be reloaded with SIGHUP or
+/// `POST /api/config/reload` without dropping connections. `config_loader` reads them again from
+/// where `config` came from.
+pub fn build_reloadable_rocket(figment: Figment, config: ServerConfig, config_loader: ConfigLoader) -> Rocket<Build> {
+    build(figment, config, Some(config_loader), None)
+}
+
+/// `build_rocket` checking names, room titles and chat messages with `content_filter` instead of
+/// the word list of `content_filter_words_path`
+pub fn build_rocket_with_content_filter(figment: Figment, config: ServerConfig, content_filter: Arc<dyn ContentFilter>) -> Rocket<Build> {
+    build(figment, config, None, Some(content_filter))
+}
+
+fn build(figment: Figment, config: ServerConfig, config_loader: Option<ConfigLoader>, content_filter: Option<Arc<dyn ContentFilter>>) -> Rocket<Build> {
+    let config = Arc::new(config);
+    #[cfg(not(feature = "redis"))]
+    if config.redis_url.is_some() {
+        tracing::warn!("redis_url is ignored, the server was built without the redis feature");
+    }
+    let rocket = rocket::custom(figment);
+
+    let vapid_key = config.vapid_private_key.as_ref().and_then(|private_key| {
+        VapidKey::new(private_key, config.vapid_subject.clone()).map_err(|e| tracing::error!("Invalid vapid_private_key: {}", e)).ok()
+    });
+    let public_url = config.public_url.clone().unwrap_or_else(|| {
+        let rocket_config = rocket::Config::from(rocket.figment());
+        format!("http://{}:{}", rocket_config.address, rocket_config.port)
+    });
+    let consistency_check_interval = config.consistency_check_interval();
+    let repair_inconsistencies = config.repair_inconsistencies;
+    let heartbeat_interval = config.heartbeat_interval();
+    let max_missed_heartbeats = config.max_missed_heartbeats;
+    let room_digest_interval = config.room_digest_interval();
+    let mut state = WsAppState::new(config.clone(), PushNotifier::new(vapid_key), public_url);
+    if let Some(config_loader) = config_loader {
+        state = state.with_config_loader(config_loader);
+    }
+    let content_filter = content_filter.or_else(|| {
+        let words_path = config.content_filter_words_path.as_deref()?;
+        WordListFilter::load(words_path)
+            .map(|filter| Arc::new(filter) as Arc<dyn ContentFilter>)
+            .map_err(|e| tracing::error!("Content filter disabled: {:?}", e))
+            .ok()
+    });
+    if let Some(content_filter) = content_filter {
+        state = state.with_content_filter(content_filter);
+    }
+    if let Some(archive_path) = config.archive_path.as_deref() {
+        state = state.with_room_archive(room_archive::RoomArchive::load(archive_path));
+    }
+    let snapshot = config.snapshot_path.as_deref().and_then(room_snapshots::load);
+    if let Some(resume_secret) = snapshot.as_ref().and_then(|snapshot| snapshot.resume_secret()) {
+        state = state.with_resume_secret(resume_secret);
+    }
+    let state = Arc::new(state);
+
+    let scheduler_state = state.clone();
+    let maintenance_state = state.clone();
+    let consistency_state = state.clone();
+    let heartbeat_state = state.clone();
+    let room_digest_state = state.clone();
+    let listeners_state = state.clone();
+    let restore_state = state.clone();
+    let snapshot_state = state.clone();
+    #[cfg(unix)]
+    let reload_state = state.clone();
+    #[cfg(feature = "redis")]
+    let cluster_state = state.clone();
+
+    rocket
+        .manage(state)
+        .attach(AdHoc::on_ignite("Restore rooms", |rocket| async move {
+            if let Some(snapshot) = snapshot {
+                room_snapshots::restore(&restore_state, snapshot).await;
+            }
+            rocket
+        }))
+        .attach(AdHoc::on_liftoff("Additional listeners", |rocket| Box::pin(async move {
+            let target = listeners::forward_target(rocket.config().address, rocket.config().port);
+            for address in listeners_state.config().listeners.iter() {
+                tokio::spawn(listeners::run_listener(listeners_state.clone(), address.clone(), target));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Room snapshots", |_| Box::pin(async move {
+            if let Some(snapshot_path) = snapshot_state.config().snapshot_path.clone() {
+                let snapshot_interval = snapshot_state.config().snapshot_interval();
+                tokio::spawn(room_snapshots::run_room_snapshots(snapshot_state, snapshot_path, snapshot_interval));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Session scheduler", |_| Box::pin(async move {
+            tokio::spawn(scheduler::run_session_scheduler(scheduler_state));
+        })))
+        .attach(AdHoc::on_liftoff("Room maintenance", |_| Box::pin(async move {
+            tokio::spawn(room_maintenance::run_room_maintenance(maintenance_state));
+        })))
+        .attach(AdHoc::on_liftoff("State consistency checker", move |_| Box::pin(async move {
+            tokio::spawn(consistency::run_consistency_checker(consistency_state, consistency_check_interval, repair_inconsistencies));
+        })))
+        .attach(AdHoc::on_liftoff("Heartbeat", move |_| Box::pin(async move {
+            if let Some(heartbeat_interval) = heartbeat_interval {
+                tokio::spawn(heartbeat::run_heartbeat(heartbeat_state, heartbeat_interval, max_missed_heartbeats));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Room digests", move |_| Box::pin(async move {
+            if let Some(room_digest_interval) = room_digest_interval {
+                tokio::spawn(room_digest::run_room_digests(room_digest_state, room_digest_interval));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Config reload on SIGHUP", |_| Box::pin(async move {
+            #[cfg(unix)]
+            if reload_state.config_loader.is_some() {
+                tokio::spawn(config_reload::run_reload_on_sighup(reload_state));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Redis cluster bridge", |_| Box::pin(async move {
+            #[cfg(feature = "redis")]
+            tokio::spawn(cluster::run_cluster_bridge(cluster_state));
+        })))
+        .attach(AdHoc::on_ignite("API description", |rocket| async move {
+            // Built from the routes mounted by then, including those of an embedding application
+            match spec_handler::ApiSpec::new(rocket.routes()) {
+                Ok(spec) => rocket.manage(spec),
+                Err(e) => {
+                    tracing::error!("Failed to describe the API: {:?}", e);
+                    rocket
+                }
+            }
+        }))
+        .attach(AdHoc::on_shutdown("Save rooms and disconnect clients", |rocket| Box::pin(async move {
+            if let Some(state) = rocket.state::<Arc<WsAppState>>() {
+                // Saved first, the rooms empty out as the clients go
+                if let Some(snapshot_path) = state.config().snapshot_path.as_deref()
+                    && let Err(e) = room_snapshots::save(state, snapshot_path).await
+                {
+                    tracing::error!("Failed to save room snapshot: {:?}", e);
+                }
+                ws_handler::disconnect_all_clients(state).await;
+            }
+        })))
+        .mount("/", routes![
+            ws_handler::ws_handler,
+            push_handler::push_public_key,
+            push_handler::push_subscribe,
+            push_handler::push_unsubscribe,
+            sessions_handler::list_upcoming_sessions,
+            sessions_handler::room_calendar,
+            sessions_handler::user_calendar,
+            join_handler::join_page,
+            join_handler::join_qr_code,
+            join_handler::invite_link,
+            public_rooms_handler::list_public_rooms,
+            public_rooms_handler::get_room_info,
+            public_rooms_handler::get_stats,
+            metrics_handler::metrics,
+            metrics_handler::ready,
+            admin_handler::list_rooms,
+            admin_handler::get_room,
+            admin_handler::get_room_history,
+            admin_handler::delete_room,
+            admin_handler::list_clients,
+            admin_handler::delete_client,
+            admin_handler::reload_server_config,
+            admin_handler::list_abuse_reports,
+            admin_handler::send_server_notice,
+            admin_handler::start_draining,
+            admin_handler::stop_draining,
+            admin_handler::list_archived_rooms,
+            spec_handler::spec_index,
+            spec_handler::asyncapi_spec,
+            spec_handler::openapi_spec,
+        ])
+}
@@ -0,0 +1,176 @@
+//! Listeners besides the one of Rocket, which only binds `address` and `port`. Their connections
+//! are forwarded to Rocket's listener over loopback, `ForwardedPeers` tells `ClientAddress` who
+//! is actually on the other end.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use anyhow::{anyhow, Result};
+use rocket::serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use crate::ws_app_state::WsAppState;
+
+/// `[::]:8000` or `0.0.0.0:8000` like, or `unix:` followed by the path of a Unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        match address.trim().strip_prefix("unix:") {
+            Some("") => Err(anyhow!("Missing path of the Unix domain socket in {}", address)),
+            Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            None => address.trim().parse().map(ListenAddress::Tcp).map_err(|_| anyhow!("Invalid listen address {}", address)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<ListenAddress> for String {
+    fn from(address: ListenAddress) -> String {
+        address.to_string()
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(address: String) -> Result<Self> {
+        address.parse()
+    }
+}
+
+/// Where a forwarded connection came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedPeer {
+    Tcp(SocketAddr),
+    /// Only local processes can connect to the socket, they are trusted like a proxy
+    Unix,
+}
+
+/// Peers of the forwarded connections by the address Rocket sees them connecting from
+#[derive(Debug, Default)]
+pub struct ForwardedPeers(Mutex<HashMap<SocketAddr, ForwardedPeer>>);
+
+impl ForwardedPeers {
+    pub fn get(&self, remote: SocketAddr) -> Option<ForwardedPeer> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).get(&remote).copied()
+    }
+
+    fn insert(&self, remote: SocketAddr, peer: ForwardedPeer) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).insert(remote, peer);
+    }
+
+    fn remove(&self, remote: SocketAddr) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).remove(&remote);
+    }
+}
+
+/// Address the forwarded connections are made to, a wildcard address is reached over loopback
+pub fn forward_target(address: IpAddr, port: u16) -> SocketAddr {
+    let address = match address {
+        IpAddr::V4(address) if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(address) if address.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        address => address,
+    };
+    SocketAddr::new(address, port)
+}
+
+/// Accepts connections on `address` until the server stops and forwards them to `target`
+pub async fn run_listener(state: Arc<WsAppState>, address: ListenAddress, target: SocketAddr) {
+    let result = match &address {
+        ListenAddress::Tcp(socket_address) => run_tcp_listener(state, *socket_address, target).await,
+        ListenAddress::Unix(path) => run_unix_listener(state, path.clone(), target).await,
+    };
+    if let Err(e) = result {
+        tracing::error!("Failed to listen on {}: {:?}", address, e);
+    }
+}
+
+async fn run_tcp_listener(state: Arc<WsAppState>, address: SocketAddr, target: SocketAddr) -> Result<()> {
+    let listener = bind_tcp(address)?;
+    tracing::info!("Listening on {}", address);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Failed to accept a connection on {}: {:?}", address, e);
+                continue;
+            }
+        };
+        tokio::spawn(forward(state.clone(), stream, ForwardedPeer::Tcp(peer), target));
+    }
+}
+
+/// IPv6 addresses only take IPv6 connections, so `0.0.0.0` and `[::]` can both be listened on with
+/// the same port
+fn bind_tcp(address: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(unix)]
+async fn run_unix_listener(state: Arc<WsAppState>, path: PathBuf, target: SocketAddr) -> Result<()> {
+    // Left behind when the server didn't stop cleanly, binding fails while it exists
+    if std::fs::metadata(&path).is_ok_and(|metadata| std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type())) {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    tracing::info!("Listening on unix:{}", path.display());
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Failed to accept a connection on unix:{}: {:?}", path.display(), e);
+                continue;
+            }
+        };
+        tokio::spawn(forward(state.clone(), stream, ForwardedPeer::Unix, target));
+    }
+}
+
+#[cfg(not(unix))]
+async fn run_unix_listener(_state: Arc<WsAppState>, path: PathBuf, _target: SocketAddr) -> Result<()> {
+    Err(anyhow!("Unix domain sockets are not supported on this platform, unix:{} is not listened on", path.display()))
+}
+
+async fn forward(state: Arc<WsAppState>, mut stream: impl AsyncRead + AsyncWrite + Unpin, peer: ForwardedPeer, target: SocketAddr) {
+    let mut upstream = match TcpStream::connect(target).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            tracing::warn!("Failed to forward a connection to {}: {:?}", target, e);
+            return;
+        }
+    };
+    let Ok(local_address) = upstream.local_addr() else {
+        return;
+    };
+    state.forwarded_peers.insert(local_address, peer);
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+    state.forwarded_peers.remove(local_address);
+}
@@ -0,0 +1,246 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use crate::protocol::ErrorKind;
+
+/// Languages of human readable texts, machine readable `kind`s never change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    Ru,
+}
+
+impl Locale {
+    /// Picks the first supported language from a BCP 47 tag or an `Accept-Language` list
+    pub fn negotiate(accepted: &str) -> Option<Locale> {
+        accepted
+            .split(',')
+            .map(|tag| tag.split(';').next().unwrap_or_default().trim())
+            .find_map(|tag| {
+                let language = tag.split(['-', '_']).next().unwrap_or_default();
+                match language.to_ascii_lowercase().as_str() {
+                    "en" => Some(Locale::En),
+                    "ru" => Some(Locale::Ru),
+                    _ => None,
+                }
+            })
+    }
+}
+
+/// Value of the `Accept-Language` header, used when the client doesn't pass `locale` explicitly
+pub struct AcceptLanguage(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptLanguage {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(AcceptLanguage(request.headers().get_one("Accept-Language").map(str::to_string)))
+    }
+}
+
+pub fn error_message(locale: Locale, error_kind: &ErrorKind) -> &'static str {
+    match locale {
+        Locale::En => error_message_en(error_kind),
+        Locale::Ru => error_message_ru(error_kind),
+    }
+}
+
+fn error_message_en(error_kind: &ErrorKind) -> &'static str {
+    match error_kind {
+        ErrorKind::InternalServerError => "Something went wrong on the server",
+        ErrorKind::JsonError => "The message could not be understood",
+        ErrorKind::ClientNotInAnyRoom => "You are not in a room",
+        ErrorKind::ClientNameNotSet => "Choose a name first",
+        ErrorKind::ClientNameTooShort => "The name is too short",
+        ErrorKind::ClientNameTooLong => "The name is too long",
+        ErrorKind::RoomIdTooShort => "The room code is too short",
+        ErrorKind::RoomIdTooLong => "The room code is too long",
+        ErrorKind::ClientNameInvalidCharacters => "The name contains characters which are not allowed",
+        ErrorKind::RoomIdInvalidCharacters => "The room code contains characters which are not allowed",
+        ErrorKind::RoomIdReserved => "This room code is reserved",
+        ErrorKind::RoomMetadataTooLong => "The room title or description is too long",
+        ErrorKind::NoSuchClient => "This member is not in the room",
+        ErrorKind::Forbidden => "You are not allowed to do this",
+        ErrorKind::FileTooLarge => "The file is too large",
+        ErrorKind::UnsupportedFileType => "Only images can be shared",
+        ErrorKind::SharedFilesQuotaExceeded => "Too many files were shared recently, try again later",
+        ErrorKind::RelayQuotaExceeded => "The room sent too much data recently, try again later",
+        ErrorKind::SessionStartInPast => "The session has to start in the future",
+        ErrorKind::NoSuchSession => "The session does not exist",
+        ErrorKind::TooManySessions => "Too many sessions are scheduled already",
+        ErrorKind::TooManyInvitees => "Too many people are invited to the session",
+        ErrorKind::InviteLinkTooLong => "The invite link is too long to be encoded",
+        ErrorKind::NoSuchInviteLink => "The invite link does not exist",
+        ErrorKind::InvalidInvite => "The invite is invalid, expired or used up",
+        ErrorKind::RoomNotEncrypted => "The room is not end-to-end encrypted",
+        ErrorKind::DisabledInEncryptedRoom => "Not available in end-to-end encrypted rooms",
+        ErrorKind::PayloadTooLarge => "The message is too large",
+        ErrorKind::InvalidSignature => "The command signature is invalid",
+        ErrorKind::AliasTaken => "This room code is already taken",
+        ErrorKind::NoSuchAlias => "The room has no such alias",
+        ErrorKind::NoSuchRoom => "The room does not exist",
+        ErrorKind::NoPendingMergeRequest => "There is no pending merge request",
+        ErrorKind::InvalidBreakoutRoomCount => "Invalid number of breakout rooms",
+        ErrorKind::BreakoutRoomsAlreadyOpen => "Breakout rooms are already open",
+        ErrorKind::NoBreakoutRooms => "There are no breakout rooms",
+        ErrorKind::LobbyDisabled => "The lobby is disabled on this server",
+        ErrorKind::NotInLobby => "You are not in the lobby",
+        ErrorKind::MessageEmpty => "The message is empty",
+        ErrorKind::MessageTooLong => "The message is too long",
+        ErrorKind::RateLimited => "Slow down, too many messages",
+        ErrorKind::Muted => "You are muted",
+        ErrorKind::InvalidRoleName => "Invalid role name",
+        ErrorKind::TooManyRoles => "The room has too many roles",
+        ErrorKind::NoSuchRole => "The role does not exist",
+        ErrorKind::InvalidAutoAdminDelay => "Invalid automatic promotion delay",
+        ErrorKind::OwnerActive => "The owner is still active",
+        ErrorKind::AlreadyAdmin => "This member is already an admin",
+        ErrorKind::TooManyRooms => "You have opened too many rooms",
+        ErrorKind::InvalidNetworkReport => "Invalid network report",
+        ErrorKind::InvalidPosition => "Invalid playback position",
+        ErrorKind::ServerOverloaded => "The server is overloaded, try again later",
+        ErrorKind::ServerDraining => "The server is being restarted, reconnect to join a room",
+        ErrorKind::InvalidPageUrl => "Invalid page address",
+        ErrorKind::Banned => "You are banned from this room",
+        ErrorKind::UnsupportedReaction => "This reaction is not supported",
+        ErrorKind::QueueFull => "The queue is full",
+        ErrorKind::QueueEmpty => "The queue is empty",
+        ErrorKind::InvalidQueueIndex => "There is no such position in the queue",
+        ErrorKind::InvalidPoll => "Invalid poll",
+        ErrorKind::InvalidCountdown => "Invalid countdown",
+        ErrorKind::PollAlreadyRunning => "Another poll is running",
+        ErrorKind::NoSuchPoll => "The poll has ended or does not exist",
+        ErrorKind::InvalidReadyCheck => "The quorum has to be between 1 and 100 percent",
+        ErrorKind::NoReadyCheck => "No ready check is running",
+        ErrorKind::InvalidTrack => "The track id is too long or contains invalid characters",
+        ErrorKind::InvalidMarker => "The marker needs a label and a valid position",
+        ErrorKind::NoSuchMarker => "The marker does not exist",
+        ErrorKind::NoSuchChatMessage => "The chat message does not exist",
+        ErrorKind::TooManyMarkers => "The room has too many markers",
+        ErrorKind::InvalidCustomChannel => "The channel name is invalid or too many channels are in use",
+        ErrorKind::InvalidClientMeta => "The key or the value is invalid, or there are too many keys",
+        ErrorKind::VoiceDisabled => "Voice chat is disabled in this room",
+        ErrorKind::NameTaken => "Somebody in the room already has this name",
+        ErrorKind::AuthenticationFailed => "Invalid token",
+        ErrorKind::ResumeFailed => "The session can no longer be resumed",
+        ErrorKind::HelloRequired => "The connection has to start with a hello",
+        ErrorKind::UnsupportedProtocolVersion => "This version of the extension is too old, please update it",
+        ErrorKind::RoomFull => "The room is full",
+        ErrorKind::ServerRoomLimitReached => "No more rooms can be opened on the server, try again later",
+        ErrorKind::TooManyConnections => "Too many connections from your network, close other tabs first",
+        ErrorKind::TooManySubscriptions => "You follow too many rooms, unsubscribe from some first",
+        ErrorKind::InvalidNamespace => "The namespace may only contain latin letters, digits, - and _",
+        ErrorKind::UnsupportedByClient => "The member's app can't receive this",
+        ErrorKind::InvalidReport => "Describe what happened in at most 500 characters",
+        ErrorKind::ContentRejected => "The text contains words which are not allowed",
+        ErrorKind::InvalidUrl => "Only http(s) links to allowed sites can be opened",
+        ErrorKind::RoomAlreadyOpen => "The room is open, join it instead",
+        ErrorKind::RoomNotStarted { .. } => "The watch party hasn't started yet",
+        ErrorKind::RoomLocked => "The room is locked",
+        ErrorKind::InvalidUtf8 => "The message is not valid UTF-8",
+        ErrorKind::NoSuchFile => "The file is not offered",
+        ErrorKind::InvalidFileOffer => "The file name or hash is invalid",
+        ErrorKind::InvalidFileChunk => "The file chunk is invalid",
+        ErrorKind::NoSuchTemplate => "The room template does not exist",
+    }
+}
+
+fn error_message_ru(error_kind: &ErrorKind) -> &'static str {
+    match error_kind {
+        ErrorKind::InternalServerError => "На сервере что-то пошло не так",
+        ErrorKind::JsonError => "Не удалось разобрать сообщение",
+        ErrorKind::ClientNotInAnyRoom => "Вы не в комнате",
+        ErrorKind::ClientNameNotSet => "Сначала выберите имя",
+        ErrorKind::ClientNameTooShort => "Имя слишком короткое",
+        ErrorKind::ClientNameTooLong => "Имя слишком длинное",
+        ErrorKind::RoomIdTooShort => "Код комнаты слишком короткий",
+        ErrorKind::RoomIdTooLong => "Код комнаты слишком длинный",
+        ErrorKind::ClientNameInvalidCharacters => "Имя содержит недопустимые символы",
+        ErrorKind::RoomIdInvalidCharacters => "Код комнаты содержит недопустимые символы",
+        ErrorKind::RoomIdReserved => "Этот код комнаты зарезервирован",
+        ErrorKind::RoomMetadataTooLong => "Название или описание комнаты слишком длинное",
+        ErrorKind::NoSuchClient => "Этого участника нет в комнате",
+        ErrorKind::Forbidden => "У вас нет на это прав",
+        ErrorKind::FileTooLarge => "Файл слишком большой",
+        ErrorKind::UnsupportedFileType => "Можно делиться только изображениями",
+        ErrorKind::SharedFilesQuotaExceeded => "Слишком много файлов за последнее время, попробуйте позже",
+        ErrorKind::RelayQuotaExceeded => "Комната передала слишком много данных за последнее время, попробуйте позже",
+        ErrorKind::SessionStartInPast => "Сеанс должен начинаться в будущем",
+        ErrorKind::NoSuchSession => "Сеанс не найден",
+        ErrorKind::TooManySessions => "Запланировано слишком много сеансов",
+        ErrorKind::TooManyInvitees => "На сеанс приглашено слишком много людей",
+        ErrorKind::InviteLinkTooLong => "Ссылка-приглашение слишком длинная для кодирования",
+        ErrorKind::NoSuchInviteLink => "Ссылка-приглашение не найдена",
+        ErrorKind::InvalidInvite => "Приглашение недействительно, истекло или уже использовано",
+        ErrorKind::RoomNotEncrypted => "В комнате не включено сквозное шифрование",
+        ErrorKind::DisabledInEncryptedRoom => "Недоступно в комнатах со сквозным шифрованием",
+        ErrorKind::PayloadTooLarge => "Сообщение слишком большое",
+        ErrorKind::InvalidSignature => "Неверная подпись команды",
+        ErrorKind::AliasTaken => "Этот код комнаты уже занят",
+        ErrorKind::NoSuchAlias => "У комнаты нет такого псевдонима",
+        ErrorKind::NoSuchRoom => "Комната не найдена",
+        ErrorKind::NoPendingMergeRequest => "Нет ожидающего запроса на объединение",
+        ErrorKind::InvalidBreakoutRoomCount => "Неверное количество комнат для групп",
+        ErrorKind::BreakoutRoomsAlreadyOpen => "Комнаты для групп уже открыты",
+        ErrorKind::NoBreakoutRooms => "Комнат для групп нет",
+        ErrorKind::LobbyDisabled => "Лобби на этом сервере отключено",
+        ErrorKind::NotInLobby => "Вы не в лобби",
+        ErrorKind::MessageEmpty => "Сообщение пустое",
+        ErrorKind::MessageTooLong => "Сообщение слишком длинное",
+        ErrorKind::RateLimited => "Слишком много сообщений, помедленнее",
+        ErrorKind::Muted => "Вам запрещено писать",
+        ErrorKind::InvalidRoleName => "Недопустимое название роли",
+        ErrorKind::TooManyRoles => "В комнате слишком много ролей",
+        ErrorKind::NoSuchRole => "Роль не найдена",
+        ErrorKind::InvalidAutoAdminDelay => "Недопустимая задержка автоматического повышения",
+        ErrorKind::OwnerActive => "Владелец комнаты ещё активен",
+        ErrorKind::AlreadyAdmin => "Этот участник уже администратор",
+        ErrorKind::TooManyRooms => "Вы открыли слишком много комнат",
+        ErrorKind::InvalidNetworkReport => "Неверный отчёт о сети",
+        ErrorKind::InvalidPosition => "Неверная позиция воспроизведения",
+        ErrorKind::ServerOverloaded => "Сервер перегружен, попробуйте позже",
+        ErrorKind::ServerDraining => "Сервер перезапускается, переподключитесь, чтобы войти в комнату",
+        ErrorKind::InvalidPageUrl => "Недопустимый адрес страницы",
+        ErrorKind::Banned => "Вам закрыт доступ в эту комнату",
+        ErrorKind::UnsupportedReaction => "Такая реакция не поддерживается",
+        ErrorKind::QueueFull => "Очередь заполнена",
+        ErrorKind::QueueEmpty => "Очередь пуста",
+        ErrorKind::InvalidQueueIndex => "В очереди нет такой позиции",
+        ErrorKind::InvalidPoll => "Недопустимый опрос",
+        ErrorKind::InvalidCountdown => "Недопустимый обратный отсчёт",
+        ErrorKind::PollAlreadyRunning => "Уже идёт другой опрос",
+        ErrorKind::NoSuchPoll => "Опрос завершён или не существует",
+        ErrorKind::InvalidReadyCheck => "Кворум должен быть от 1 до 100 процентов",
+        ErrorKind::NoReadyCheck => "Проверка готовности не идёт",
+        ErrorKind::InvalidTrack => "Идентификатор дорожки слишком длинный или содержит недопустимые символы",
+        ErrorKind::InvalidMarker => "У метки должны быть название и допустимая позиция",
+        ErrorKind::NoSuchMarker => "Метка не существует",
+        ErrorKind::NoSuchChatMessage => "Сообщение чата не существует",
+        ErrorKind::TooManyMarkers => "В комнате слишком много меток",
+        ErrorKind::InvalidCustomChannel => "Недопустимое имя канала или используется слишком много каналов",
+        ErrorKind::InvalidClientMeta => "Недопустимый ключ или значение, или слишком много ключей",
+        ErrorKind::VoiceDisabled => "Голосовой чат в этой комнате отключён",
+        ErrorKind::NameTaken => "Это имя уже занято в комнате",
+        ErrorKind::AuthenticationFailed => "Неверный токен",
+        ErrorKind::ResumeFailed => "Сеанс больше нельзя восстановить",
+        ErrorKind::HelloRequired => "Соединение должно начинаться с приветствия",
+        ErrorKind::UnsupportedProtocolVersion => "Эта версия расширения устарела, пожалуйста, обновите её",
+        ErrorKind::RoomFull => "Комната заполнена",
+        ErrorKind::ServerRoomLimitReached => "На сервере больше нельзя открыть комнаты, попробуйте позже",
+        ErrorKind::TooManyConnections => "Слишком много подключений из вашей сети, сначала закройте другие вкладки",
+        ErrorKind::TooManySubscriptions => "Вы следите за слишком многими комнатами, сначала отпишитесь от некоторых",
+        ErrorKind::InvalidNamespace => "Пространство имён может содержать только латинские буквы, цифры, - и _",
+        ErrorKind::UnsupportedByClient => "Приложение участника не может это принять",
+        ErrorKind::InvalidReport => "Опишите, что произошло, не более чем в 500 символах",
+        ErrorKind::ContentRejected => "Текст содержит недопустимые слова",
+        ErrorKind::InvalidUrl => "Открывать можно только http(s)-ссылки на разрешённые сайты",
+        ErrorKind::RoomAlreadyOpen => "Комната открыта, присоединитесь к ней",
+        ErrorKind::RoomNotStarted { .. } => "Совместный просмотр ещё не начался",
+        ErrorKind::RoomLocked => "Комната закрыта для входа",
+        ErrorKind::InvalidUtf8 => "Сообщение не в кодировке UTF-8",
+        ErrorKind::NoSuchFile => "Этот файл не предлагался",
+        ErrorKind::InvalidFileOffer => "Недопустимое имя или хеш файла",
+        ErrorKind::InvalidFileChunk => "Недопустимая часть файла",
+        ErrorKind::NoSuchTemplate => "Такого шаблона комнаты нет",
+    }
+}
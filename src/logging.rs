@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io::IsTerminal;
+use rocket::config::LogLevel;
+use rocket::serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+use crate::config::ServerConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+/// Installs the global subscriber, Rocket's own messages go through it too. Has to run before the
+/// Rocket instance is built, otherwise Rocket installs its own logger. `log_filter` uses the
+/// `RUST_LOG` syntax and defaults to what Rocket's `log_level` would show.
+pub fn init(config: &ServerConfig, rocket_log_level: LogLevel) {
+    let filter = config.log_filter.clone().unwrap_or_else(|| match rocket_log_level {
+        LogLevel::Off => "off".to_string(),
+        LogLevel::Critical => "warn".to_string(),
+        LogLevel::Normal => "info".to_string(),
+        LogLevel::Debug => "debug".to_string(),
+    });
+    let filter = EnvFilter::try_new(&filter).unwrap_or_else(|e| {
+        eprintln!("Invalid log_filter {}: {}", filter, e);
+        EnvFilter::new("info")
+    });
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_ansi(std::io::stdout().is_terminal());
+    let result = match config.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.fmt_fields(JsonFields).event_format(JsonFormat).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to set up logging: {}", e);
+    }
+}
+
+/// Formats the fields of spans as JSON objects, so `JsonFormat` can nest them
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: tracing_subscriber::field::RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut values = Map::new();
+        fields.record(&mut JsonVisitor(&mut values));
+        write!(writer, "{}", Value::Object(values))
+    }
+
+    fn add_fields(&self, current: &'writer mut FormattedFields<Self>, fields: &tracing::span::Record<'_>) -> fmt::Result {
+        let mut values = match serde_json::from_str::<Value>(&current.fields) {
+            Ok(Value::Object(values)) => values,
+            _ => Map::new(),
+        };
+        fields.record(&mut JsonVisitor(&mut values));
+        current.fields = Value::Object(values).to_string();
+        Ok(())
+    }
+}
+
+/// `{"timestamp", "level", "target", "message", "fields", "spans"}` per event, spans are listed
+/// from the outermost with their fields
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, JsonFields>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let message = fields.remove("message").unwrap_or(Value::Null);
+
+        let mut spans = Vec::new();
+        for span in ctx.event_scope().into_iter().flat_map(|scope| scope.from_root()) {
+            let mut span_fields = span
+                .extensions()
+                .get::<FormattedFields<JsonFields>>()
+                .and_then(|formatted| serde_json::from_str::<Map<String, Value>>(&formatted.fields).ok())
+                .unwrap_or_default();
+            span_fields.insert("name".to_string(), Value::from(span.name()));
+            spans.push(Value::Object(span_fields));
+        }
+
+        let metadata = event.metadata();
+        let line = serde_json::json!({
+            "timestamp": timestamp,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "message": message,
+            "fields": fields,
+            "spans": spans,
+        });
+        writeln!(writer, "{}", line)
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+}
@@ -1,17 +1,45 @@
-#[macro_use]
-extern crate rocket;
-mod ws_handler;
-mod ws_app_state;
-mod ws_dto_models;
+use sent_sync_server::cli::{default_config_toml, CliArgs, USAGE};
+use sent_sync_server::{build_reloadable_rocket, logging, ConfigLoader, ServerConfig};
 
-use crate::ws_app_state::WsAppState;
-use std::sync::Arc;
-
-#[launch]
+#[rocket::launch]
 fn rocket() -> _ {
-    let state = Arc::new(WsAppState::new());
-    
-    rocket::build()
-        .manage(state)
-        .mount("/", routes![ws_handler::ws_handler])
+    let args = match CliArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        std::process::exit(0);
+    }
+    if args.print_default_config {
+        match default_config_toml() {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => {
+                eprintln!("Failed to print the default configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    let figment = match args.figment() {
+        Ok(figment) => figment,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let config = match ServerConfig::load(&figment) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init(&config, rocket::Config::from(&figment).log_level);
+    let config_loader = ConfigLoader::new(move || ServerConfig::load(&args.figment()?));
+    build_reloadable_rocket(figment, config, config_loader)
 }
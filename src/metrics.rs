@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use crate::scheduler::unix_millis_now;
+
+/// Seconds `RateMeter` averages over
+const RATE_WINDOW_SECS: usize = 10;
+
+/// Upper bounds of the buckets of `Histogram` in seconds, the `+Inf` bucket is the count
+pub const HISTOGRAM_BUCKETS: [f64; 11] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Counters of events which leave no trace in the state, gauges are computed from the state when
+/// the metrics are requested
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Connections turned away because of `ServerConfig::max_connections`
+    pub connections_rejected: Counter,
+    /// Connections turned away because of `ServerConfig::max_connections_per_ip`
+    pub connections_per_ip_rejected: Counter,
+    /// Connections closed because of `ServerConfig::handshake_timeout_secs`
+    pub handshake_timeouts: Counter,
+    /// New rooms refused because of `ServerConfig::max_rooms`
+    pub rooms_rejected: Counter,
+    /// Joins refused because of `ServerConfig::max_clients_per_room`
+    pub room_joins_rejected: Counter,
+    /// Connections closed because a message couldn't be written to them in time or at all
+    pub send_failures: Counter,
+    /// Frames refused because of `ServerConfig::max_message_size`, including the ones which got
+    /// their connection closed
+    pub oversized_messages: Counter,
+    /// Messages of clients, without pings and pongs of the websocket itself
+    pub messages_received: RateMeter,
+    /// Time spent handling messages of clients, by the `type` of the message
+    pub message_handling: Histograms,
+    /// Websocket messages of all connections
+    pub traffic: Traffic,
+    /// Relays counted over `ServerConfig::room_relay_quota_bytes_per_minute`, refused or not
+    pub relay_quota_exceeded: Counter,
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes of websocket messages received from and sent to clients
+#[derive(Debug, Default)]
+pub struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Traffic {
+    pub fn record_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Durations counted into `HISTOGRAM_BUCKETS`, the buckets are cumulative like in Prometheus
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, upper_bound) in self.buckets.iter().zip(HISTOGRAM_BUCKETS) {
+            if seconds <= upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Upper bounds with the durations at or below them
+    pub fn buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        HISTOGRAM_BUCKETS.into_iter().zip(self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)))
+    }
+
+    pub fn sum_seconds(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// One `Histogram` per label. Labels have to come from a fixed set, every one of them stays
+/// exported until the server stops.
+#[derive(Debug, Default)]
+pub struct Histograms(RwLock<BTreeMap<String, Histogram>>);
+
+impl Histograms {
+    pub fn record(&self, label: &str, duration: Duration) {
+        if let Some(histogram) = self.0.read().unwrap_or_else(PoisonError::into_inner).get(label) {
+            histogram.record(duration);
+            return;
+        }
+        self.0.write().unwrap_or_else(PoisonError::into_inner).entry(label.to_string()).or_default().record(duration);
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&str, &Histogram)) {
+        for (label, histogram) in self.0.read().unwrap_or_else(PoisonError::into_inner).iter() {
+            f(label, histogram);
+        }
+    }
+}
+
+/// Events per second over the last `RATE_WINDOW_SECS` complete seconds
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    /// Unix second and the events counted in it, indexed by the second modulo the window
+    buckets: Mutex<[(u64, u64); RATE_WINDOW_SECS]>,
+}
+
+impl RateMeter {
+    pub fn record(&self) {
+        let now = unix_millis_now() / 1000;
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = &mut buckets[now as usize % RATE_WINDOW_SECS];
+        if bucket.0 != now {
+            *bucket = (now, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    pub fn per_second(&self) -> f64 {
+        let now = unix_millis_now() / 1000;
+        let buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let events: u64 = buckets.iter()
+            .filter(|(second, _)| *second < now && now - *second <= RATE_WINDOW_SECS as u64)
+            .map(|(_, events)| events)
+            .sum();
+        events as f64 / RATE_WINDOW_SECS as f64
+    }
+}
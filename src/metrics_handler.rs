@@ -0,0 +1,70 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use rocket::http::{ContentType, Status};
+use rocket::State;
+use crate::ws_app_state::{Room, WsAppState};
+
+/// Readiness for load balancers, 503 while draining so no new clients are sent to the server
+#[get("/ready")]
+pub fn ready(state: &State<Arc<WsAppState>>) -> (Status, &'static str) {
+    if state.is_draining() {
+        (Status::ServiceUnavailable, "draining")
+    } else {
+        (Status::Ok, "ready")
+    }
+}
+
+/// Metrics in the Prometheus text format. Limits set to unlimited are reported as 0.
+#[get("/metrics")]
+pub async fn metrics(state: &State<Arc<WsAppState>>) -> (ContentType, String) {
+    let rooms: Vec<Arc<Room>> = state.store.rooms().await;
+    let mut room_members = 0;
+    let mut full_rooms = 0;
+    for room in rooms.iter() {
+        // A room whose task is gone has no members to count
+        let members = state.store.room_summary(room.namespace.as_deref(), &room.room_id).await.map(|summary| summary.members_count).unwrap_or(0);
+        room_members += members;
+        if state.config().max_clients_per_room(room.namespace.as_deref()).is_some_and(|max_clients| members >= max_clients) {
+            full_rooms += 1;
+        }
+    }
+
+    let mut output = String::new();
+    let mut write_metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = write!(output, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+    };
+    write_metric("sent_sync_connections", "gauge", "Registered clients, including the ones waiting to be resumed", state.clients.len() as u64);
+    write_metric("sent_sync_connections_max", "gauge", "Limit of registered clients", state.config().max_connections as u64);
+    write_metric("sent_sync_connections_rejected_total", "counter", "Connections turned away because the server was full", state.metrics.connections_rejected.get());
+    write_metric("sent_sync_connection_ips", "gauge", "Distinct addresses of registered clients", state.clients.ips_count() as u64);
+    write_metric("sent_sync_connections_per_ip_max", "gauge", "Limit of registered clients from one address", state.config().max_connections_per_ip as u64);
+    write_metric("sent_sync_connections_per_ip_rejected_total", "counter", "Connections turned away because their address had too many", state.metrics.connections_per_ip_rejected.get());
+    write_metric("sent_sync_received_bytes_total", "counter", "Bytes of the messages received from clients", state.metrics.traffic.received());
+    write_metric("sent_sync_sent_bytes_total", "counter", "Bytes of the messages sent to clients", state.metrics.traffic.sent());
+    write_metric("sent_sync_relay_quota_exceeded_total", "counter", "Custom messages and files relayed or refused over the relay quota of their room", state.metrics.relay_quota_exceeded.get());
+    write_metric("sent_sync_draining", "gauge", "1 while draining, see POST /api/drain", state.is_draining() as u64);
+    write_metric("sent_sync_handshake_timeouts_total", "counter", "Connections closed because they didn't send anything in time", state.metrics.handshake_timeouts.get());
+    write_metric("sent_sync_rooms", "gauge", "Open rooms", rooms.len() as u64);
+    write_metric("sent_sync_rooms_max", "gauge", "Limit of open rooms", state.config().max_rooms as u64);
+    write_metric("sent_sync_rooms_rejected_total", "counter", "Rooms not opened because the room limit was reached", state.metrics.rooms_rejected.get());
+    write_metric("sent_sync_room_members", "gauge", "Members of all rooms", room_members as u64);
+    write_metric("sent_sync_room_members_max", "gauge", "Limit of members of one room", state.config().max_clients_per_room as u64);
+    write_metric("sent_sync_full_rooms", "gauge", "Rooms which reached the member limit", full_rooms);
+    write_metric("sent_sync_uptime_seconds", "gauge", "Seconds since the server started", state.started_at.elapsed().as_secs());
+    write_metric("sent_sync_room_joins_rejected_total", "counter", "Joins refused because the room was full", state.metrics.room_joins_rejected.get());
+    write_metric("sent_sync_send_failures_total", "counter", "Connections closed because writing to them failed or timed out", state.metrics.send_failures.get());
+    write_metric("sent_sync_oversized_messages_total", "counter", "Messages refused because they were too large", state.metrics.oversized_messages.get());
+
+    let name = "sent_sync_message_handling_seconds";
+    let _ = write!(output, "# HELP {name} Time spent handling messages of clients, by type\n# TYPE {name} histogram\n");
+    state.metrics.message_handling.for_each(|message_type, histogram| {
+        for (upper_bound, count) in histogram.buckets() {
+            let _ = writeln!(output, "{name}_bucket{{type=\"{message_type}\",le=\"{upper_bound}\"}} {count}");
+        }
+        let _ = writeln!(output, "{name}_bucket{{type=\"{message_type}\",le=\"+Inf\"}} {}", histogram.count());
+        let _ = writeln!(output, "{name}_sum{{type=\"{message_type}\"}} {}", histogram.sum_seconds());
+        let _ = writeln!(output, "{name}_count{{type=\"{message_type}\"}} {}", histogram.count());
+    });
+
+    (ContentType::Plain, output)
+}
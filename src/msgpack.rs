@@ -0,0 +1,266 @@
+//! Minimal MessagePack codec for clients which connect with `?format=msgpack`. Messages are converted
+//! from and to the same structure as their JSON form, so map keys are always strings. Extension
+//! types are not supported.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Number, Value};
+
+/// Nesting deeper than this is rejected instead of risking the stack
+const MAX_DEPTH: usize = 32;
+
+/// Top-level value of a frame, messages are maps while shared files are sent as plain binary
+pub enum Frame {
+    Value(Value),
+    Binary(Vec<u8>),
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_value(&mut buffer, value);
+    buffer
+}
+
+pub fn encode_binary(data: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(data.len() + 5);
+    match data.len() {
+        len if len <= u8::MAX as usize => buffer.extend([0xc4, len as u8]),
+        len if len <= u16::MAX as usize => {
+            buffer.push(0xc5);
+            buffer.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            buffer.push(0xc6);
+            buffer.extend((len as u32).to_be_bytes());
+        }
+    }
+    buffer.extend_from_slice(data);
+    buffer
+}
+
+pub fn decode(data: &[u8]) -> Result<Frame> {
+    let mut reader = Reader { data, position: 0 };
+    let frame = match data.first() {
+        Some(0xc4..=0xc6) => Frame::Binary(reader.read_binary()?),
+        _ => Frame::Value(reader.read_value(0)?),
+    };
+    if reader.position != data.len() {
+        bail!("Trailing bytes after the value");
+    }
+    Ok(frame)
+}
+
+fn write_value(buffer: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buffer.push(0xc0),
+        Value::Bool(false) => buffer.push(0xc2),
+        Value::Bool(true) => buffer.push(0xc3),
+        Value::Number(number) => write_number(buffer, number),
+        Value::String(string) => {
+            write_length(buffer, string.len(), (0xa0, 31), Some(0xd9), 0xda, 0xdb);
+            buffer.extend_from_slice(string.as_bytes());
+        }
+        Value::Array(items) => {
+            write_length(buffer, items.len(), (0x90, 15), None, 0xdc, 0xdd);
+            for item in items {
+                write_value(buffer, item);
+            }
+        }
+        Value::Object(entries) => {
+            write_length(buffer, entries.len(), (0x80, 15), None, 0xde, 0xdf);
+            for (key, item) in entries {
+                write_value(buffer, &Value::String(key.clone()));
+                write_value(buffer, item);
+            }
+        }
+    }
+}
+
+/// `fix` is the marker with the length in its lower bits and the longest length it fits, arrays
+/// and maps have no 8 bit variant
+fn write_length(buffer: &mut Vec<u8>, len: usize, fix: (u8, usize), marker8: Option<u8>, marker16: u8, marker32: u8) {
+    let (fix_marker, fix_max) = fix;
+    match marker8 {
+        _ if len <= fix_max => buffer.push(fix_marker | len as u8),
+        Some(marker8) if len <= u8::MAX as usize => buffer.extend([marker8, len as u8]),
+        _ if len <= u16::MAX as usize => {
+            buffer.push(marker16);
+            buffer.extend((len as u16).to_be_bytes());
+        }
+        _ => {
+            buffer.push(marker32);
+            buffer.extend((len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn write_number(buffer: &mut Vec<u8>, number: &Number) {
+    if let Some(value) = number.as_u64() {
+        match value {
+            0..=0x7f => buffer.push(value as u8),
+            0x80..=0xff => buffer.extend([0xcc, value as u8]),
+            0x100..=0xffff => {
+                buffer.push(0xcd);
+                buffer.extend((value as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                buffer.push(0xce);
+                buffer.extend((value as u32).to_be_bytes());
+            }
+            _ => {
+                buffer.push(0xcf);
+                buffer.extend(value.to_be_bytes());
+            }
+        }
+    } else if let Some(value) = number.as_i64() {
+        // Only negative values are left here
+        match value {
+            -32..=-1 => buffer.push(value as i8 as u8),
+            -128..=-33 => buffer.extend([0xd0, value as i8 as u8]),
+            -32768..=-129 => {
+                buffer.push(0xd1);
+                buffer.extend((value as i16).to_be_bytes());
+            }
+            -2_147_483_648..=-32769 => {
+                buffer.push(0xd2);
+                buffer.extend((value as i32).to_be_bytes());
+            }
+            _ => {
+                buffer.push(0xd3);
+                buffer.extend(value.to_be_bytes());
+            }
+        }
+    } else {
+        buffer.push(0xcb);
+        buffer.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn read_bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self.position.checked_add(len).filter(|end| *end <= self.data.len()).ok_or(anyhow!("Unexpected end of data"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_uint(&mut self, size: usize) -> Result<u64> {
+        Ok(self.read_bytes(size)?.iter().fold(0, |value, byte| value << 8 | *byte as u64))
+    }
+
+    fn read_int(&mut self, size: usize) -> Result<i64> {
+        let value = self.read_uint(size)?;
+        // Sign extension from the highest bit of the value
+        let shift = 64 - size as u32 * 8;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    fn read_binary(&mut self) -> Result<Vec<u8>> {
+        let len = match self.read_u8()? {
+            0xc4 => self.read_uint(1)?,
+            0xc5 => self.read_uint(2)?,
+            0xc6 => self.read_uint(4)?,
+            marker => bail!("Expected binary, found marker {:#04x}", marker),
+        };
+        Ok(self.read_bytes(len as usize)?.to_vec())
+    }
+
+    fn read_string(&mut self, len: u64) -> Result<Value> {
+        let bytes = self.read_bytes(len as usize)?;
+        Ok(Value::String(std::str::from_utf8(bytes)?.to_string()))
+    }
+
+    fn read_array(&mut self, len: u64, depth: usize) -> Result<Value> {
+        // Every item takes at least a byte, which bounds allocations by the size of the frame
+        let mut items = Vec::with_capacity((len as usize).min(self.data.len() - self.position));
+        for _ in 0..len {
+            items.push(self.read_value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn read_map(&mut self, len: u64, depth: usize) -> Result<Value> {
+        let mut entries = Map::new();
+        for _ in 0..len {
+            let Value::String(key) = self.read_value(depth + 1)? else {
+                bail!("Map keys have to be strings");
+            };
+            entries.insert(key, self.read_value(depth + 1)?);
+        }
+        Ok(Value::Object(entries))
+    }
+
+    fn read_value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("Values are nested too deep");
+        }
+
+        let marker = self.read_u8()?;
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.read_map((marker & 0x0f) as u64, depth)?,
+            0x90..=0x9f => self.read_array((marker & 0x0f) as u64, depth)?,
+            0xa0..=0xbf => self.read_string((marker & 0x1f) as u64)?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4..=0xc6 => {
+                self.position -= 1;
+                Value::from(self.read_binary()?)
+            }
+            0xca => float_value(f32::from_bits(self.read_uint(4)? as u32) as f64),
+            0xcb => float_value(f64::from_bits(self.read_uint(8)?)),
+            0xcc => Value::from(self.read_uint(1)?),
+            0xcd => Value::from(self.read_uint(2)?),
+            0xce => Value::from(self.read_uint(4)?),
+            0xcf => Value::from(self.read_uint(8)?),
+            0xd0 => Value::from(self.read_int(1)?),
+            0xd1 => Value::from(self.read_int(2)?),
+            0xd2 => Value::from(self.read_int(4)?),
+            0xd3 => Value::from(self.read_int(8)?),
+            0xd9 => {
+                let len = self.read_uint(1)?;
+                self.read_string(len)?
+            }
+            0xda => {
+                let len = self.read_uint(2)?;
+                self.read_string(len)?
+            }
+            0xdb => {
+                let len = self.read_uint(4)?;
+                self.read_string(len)?
+            }
+            0xdc => {
+                let len = self.read_uint(2)?;
+                self.read_array(len, depth)?
+            }
+            0xdd => {
+                let len = self.read_uint(4)?;
+                self.read_array(len, depth)?
+            }
+            0xde => {
+                let len = self.read_uint(2)?;
+                self.read_map(len, depth)?
+            }
+            0xdf => {
+                let len = self.read_uint(4)?;
+                self.read_map(len, depth)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            _ => bail!("Unsupported marker {:#04x}", marker),
+        })
+    }
+}
+
+/// JSON has no representation for NaN and infinities
+fn float_value(value: f64) -> Value {
+    Number::from_f64(value).map(Value::Number).unwrap_or(Value::Null)
+}
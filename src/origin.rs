@@ -0,0 +1,46 @@
+//! Keeps web pages the server doesn't know from connecting on behalf of their visitors. Browsers
+//! always send `Origin` with WebSocket handshakes, `chrome-extension://<id>` or
+//! `moz-extension://<uuid>` for extensions, so only allow-listed pages and extensions get through.
+//! Native clients send no `Origin` and are not affected.
+
+use std::sync::Arc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use crate::ws_app_state::WsAppState;
+
+/// Requests whose `Origin` matches `allowed_origins`, or that have none. Every origin is allowed
+/// while the list is empty.
+pub struct AllowedOrigin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AllowedOrigin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return Outcome::Success(AllowedOrigin);
+        };
+        let config = request.rocket().state::<Arc<WsAppState>>().map(|state| state.config());
+        let allowed_origins = config.as_ref().map(|config| config.allowed_origins.as_slice()).unwrap_or_default();
+        if allowed_origins.is_empty() || allowed_origins.iter().any(|pattern| origin_matches(pattern, origin)) {
+            Outcome::Success(AllowedOrigin)
+        } else {
+            tracing::info!(origin, "Refused connection from a foreign origin");
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+/// Origins are compared case-insensitively. A `*` in the pattern stands for any part of the host,
+/// like `https://*.example.com` or `moz-extension://*`.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let (pattern, origin) = (pattern.to_ascii_lowercase(), origin.to_ascii_lowercase());
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => origin.len() > prefix.len() + suffix.len()
+            && origin.starts_with(prefix)
+            && origin.ends_with(suffix)
+            && !origin[prefix.len()..origin.len() - suffix.len()].contains('/'),
+        None => pattern == origin,
+    }
+}
@@ -0,0 +1,843 @@
+//! Wire protocol of `/ws`: the messages clients and the server exchange and the version and
+//! feature negotiation around them. Clients written in Rust can depend on these types directly,
+//! see `client` for a small connection helper.
+
+use std::ops::RangeInclusive;
+use std::path::Path;
+use rocket::serde::{Deserialize, Serialize};
+use ts_rs::{ExportError, TS};
+use uuid::Uuid;
+use crate::push_handler::{PushSubscribeRequest, PushUnsubscribeRequest};
+use crate::push_notifications::PushNotification;
+use crate::ws_app_state::{DisconnectReason, WsAppState};
+use crate::ws_dto_models::{AbuseReportDto, AdminClientDto, ArchivedRoomDto, AdminRoomDetailsDto, AdminRoomDto, ChatMessageDto, ConfigReloadDto, DepartedClientDto, LobbyChatMessageDto, NetworkReportDto, PermissionPreset, PollDto, PollKind, PublicRoomDto, ReadyCheckDto, Role, RoomClientDto, RoomDataDto, RoomHistoryEntryDto, RoomInfoDto, RoomPermission, RoomSettingsDto, RoomSettingsUpdateDto, RoomStatsDto, ScheduledSessionDto, ServerStatsDto, SessionSummaryDto, TrackKind, WatchProgressDto};
+
+/// Protocol versions this server speaks, bumped on every incompatible change of the messages.
+/// Clients announce theirs in `Hello`.
+///
+/// 2: members get their room's state in `RoomSnapshot` instead of `RoomChanged`
+pub const SUPPORTED_PROTOCOL_VERSIONS: RangeInclusive<u32> = 1..=2;
+
+/// Optional parts of the protocol, announced in `Welcome` so clients can hide what is unavailable
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum ProtocolFeature {
+    /// Numbered `ClientJoined`, `ClientLeft`, `ClientUpdated` and `RoomSettingsUpdated` events
+    RoomEvents,
+    Resume,
+    EndToEndEncryption,
+    CommandSigning,
+    BreakoutRooms,
+    Polls,
+    ScheduledSessions,
+    FileSharing,
+    Lobby,
+    /// `?format=msgpack` on `/ws`
+    MessagePack,
+}
+
+/// Optional messages a client may announce it handles in `Hello`, the ones of capabilities it
+/// leaves out are not sent to it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum ClientCapability {
+    /// `ReactionReceived`
+    Reactions,
+    /// `RtcOffer`, `RtcAnswer` and `RtcIceCandidate`
+    Voice,
+    /// `PollUpdated` and `PollEnded`
+    Polls,
+    /// `ReadyCheckUpdated`
+    ReadyChecks,
+    /// `CountdownStarted` and `CountdownCancelled`
+    Countdowns,
+    /// `RoomDigest`
+    RoomDigests,
+    /// `TypingChanged`
+    TypingIndicators,
+    /// `FileOffered`, `FileRequested` and `FileChunk`
+    FileOffers,
+    /// `FileShared` and the file following it
+    FileSharing,
+    /// `Custom`
+    CustomMessages,
+    /// Announced by newer clients, ignored
+    #[serde(other)]
+    #[ts(skip)]
+    Unknown,
+}
+
+/// Encoding of the messages on a connection, picked with the `format` query parameter of `/ws`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Text frames
+    Json,
+    /// Binary frames, each holding one MessagePack value. Messages are maps shaped like their JSON
+    /// form, shared files are plain binary values.
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Unknown formats fall back to JSON, which every client understands
+    pub fn from_query(format: Option<&str>) -> WireFormat {
+        match format {
+            Some("msgpack") => WireFormat::MessagePack,
+            _ => WireFormat::Json,
+        }
+    }
+}
+
+/// Newer clients are downgraded to the latest version the server speaks, older ones than the
+/// server still understands are rejected
+pub fn negotiate_protocol_version(client_version: u32) -> Option<u32> {
+    if client_version < *SUPPORTED_PROTOCOL_VERSIONS.start() {
+        return None;
+    }
+    Some(client_version.min(*SUPPORTED_PROTOCOL_VERSIONS.end()))
+}
+
+/// Writes TypeScript definitions of the messages, the HTTP API bodies and the push notification
+/// payload into `out_dir`, one file per type. Run by the `generate-types` binary.
+pub fn export_typescript(out_dir: &Path) -> Result<(), ExportError> {
+    IncomingMessage::export_all_to(out_dir)?;
+    OutgoingMessage::export_all_to(out_dir)?;
+    PushNotification::export_all_to(out_dir)?;
+    PushSubscribeRequest::export_all_to(out_dir)?;
+    PushUnsubscribeRequest::export_all_to(out_dir)?;
+    AbuseReportDto::export_all_to(out_dir)?;
+    ArchivedRoomDto::export_all_to(out_dir)?;
+    PublicRoomDto::export_all_to(out_dir)?;
+    RoomInfoDto::export_all_to(out_dir)?;
+    ServerStatsDto::export_all_to(out_dir)?;
+    ScheduledSessionDto::export_all_to(out_dir)?;
+    RoomHistoryEntryDto::export_all_to(out_dir)?;
+    AdminRoomDto::export_all_to(out_dir)?;
+    AdminRoomDetailsDto::export_all_to(out_dir)?;
+    AdminClientDto::export_all_to(out_dir)?;
+    ConfigReloadDto::export_all_to(out_dir)?;
+    Ok(())
+}
+
+pub fn supported_features(state: &WsAppState) -> Vec<ProtocolFeature> {
+    let mut features = vec![
+        ProtocolFeature::RoomEvents,
+        ProtocolFeature::Resume,
+        ProtocolFeature::EndToEndEncryption,
+        ProtocolFeature::CommandSigning,
+        ProtocolFeature::BreakoutRooms,
+        ProtocolFeature::Polls,
+        ProtocolFeature::ScheduledSessions,
+        ProtocolFeature::FileSharing,
+        ProtocolFeature::MessagePack,
+    ];
+    if state.lobby.enabled {
+        features.push(ProtocolFeature::Lobby);
+    }
+    features
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
+#[ts(export)]
+pub enum IncomingMessage {
+    /// Answered with `Pong`. `client_time` is the client's clock in Unix milliseconds, echoed back
+    /// to measure the round trip, which is reported in the next ping as `rtt_ms`.
+    Ping { client_time: Option<u64>, rtt_ms: Option<u64> },
+    /// Has to be the first message on a connection, only `Ping` is accepted before it. Answered
+    /// with `Welcome`. Rooms are only found within the `namespace`, the default one when missing.
+    /// `capabilities` lists the optional messages the client handles, all of them when left out.
+    /// `locale` is a BCP 47 tag like `ru-RU` for the texts of errors, it overrides the `locale`
+    /// query parameter and `Accept-Language`. Unsupported languages are ignored.
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        client_version: String,
+        #[serde(default)]
+        namespace: Option<String>,
+        #[serde(default)]
+        capabilities: Option<Vec<ClientCapability>>,
+        #[serde(default)]
+        locale: Option<String>,
+    },
+    /// Takes over a client whose connection was lost within the disconnect grace period, keeping
+    /// its uid, name and room. Answered with `ClientUid` of the resumed client.
+    Resume { token: String },
+    /// Presents the `admin_token`, the connection becomes a moderator which may join any room
+    /// hidden, kick and ban in it and close rooms. Answered with `Success`.
+    Authenticate { token: String },
+    ChangeName { new_name: String },
+    /// WebRTC signaling for voice chat, relayed only to the addressed member while the room has
+    /// voice enabled
+    RtcOffer { #[ts(type = "string")] to_uid: Uuid, sdp: String },
+    RtcAnswer { #[ts(type = "string")] to_uid: Uuid, sdp: String },
+    RtcIceCandidate {
+        #[ts(type = "string")]
+        to_uid: Uuid,
+        candidate: String,
+        sdp_mid: Option<String>,
+        sdp_m_line_index: Option<u32>,
+    },
+    /// Publishes a value like an avatar color or the platform in `RoomClientDto::meta`, `None`
+    /// removes the key
+    SetClientMeta { key: String, value: Option<String> },
+    /// Opens the room when there is none with the id, unless `join_creates_rooms` is off. An
+    /// `invite` token of `CreateInvite` is checked and one of its uses is taken. Spectators only
+    /// join existing rooms and can't control playback or chat. Moderators may join existing rooms
+    /// `hidden`, as spectators the other members are not told about.
+    JoinRoom { room_id: String, invite: Option<String>, #[serde(default)] spectator: bool, #[serde(default)] hidden: bool },
+    /// Closes any room like the admin API, only for moderators
+    CloseRoom { room_id: String },
+    /// Opens a room under an id picked by the server, answered with `RoomCreated`. The room starts
+    /// with the settings of `template`, one of `ServerConfig::room_templates`.
+    CreateRoom { #[serde(default)] template: Option<String> },
+    /// Opens a room closed in the last days again with its settings and playlist, the client
+    /// becomes its owner. Only available when the server archives rooms.
+    RestoreRoom { room_id: String },
+    /// Answered with `PublicRooms`, also available as `GET /api/public-rooms`
+    ListPublicRooms,
+    /// Answered with `RoomInfo` without joining the room, also available as `GET /api/room-info/<room_id>`
+    GetRoomInfo { room_id: String },
+    PlayerEvent { event: PlayerEvent },
+    /// Owner only
+    UpdateRoomSettings { settings: RoomSettingsUpdateDto },
+    /// Owner only, see `RoomSettingsDto::locked`
+    LockRoom { locked: bool },
+    /// Owner only, replaces both, `null` or an empty text removes them
+    UpdateRoomMetadata { title: Option<String>, description: Option<String> },
+    /// Owner and admins, `nonce` and `signature` as in `ChangeRoomPreferences`
+    SetPageUrl {
+        url: String,
+        nonce: Option<u64>,
+        signature: Option<String>,
+    },
+    /// Queue messages require the `ChangePageUrl` permission. Queued URLs are signed when added,
+    /// `nonce` and `signature` as in `SetPageUrl`.
+    QueueAdd {
+        url: String,
+        nonce: Option<u64>,
+        signature: Option<String>,
+    },
+    QueueRemove { index: usize },
+    QueueMove { from: usize, to: usize },
+    QueueNext,
+    /// Reported when the video of `url` finished playing. Once enough members did, the video is
+    /// marked watched and the room moves on to the next queued one or stops.
+    VideoEnded { url: String },
+    Play,
+    /// Positions are in seconds
+    Pause { position: f64 },
+    Seek { position: f64 },
+    /// Owners and admins, markers are dropped when the video changes
+    AddMarker { position: f64, label: String },
+    RemoveMarker { #[ts(type = "string")] marker_id: Uuid },
+    /// Seeks to the marker, allowed to whoever may seek
+    JumpToMarker { #[ts(type = "string")] marker_id: Uuid },
+    /// Picks the dub or the subtitles for everybody, an empty `track_id` turns the subtitles off
+    /// or leaves the audio to the player
+    SetTrack { kind: TrackKind, track_id: String },
+    ReportPlayerStatus { player_status: PlayerStatus },
+    /// When the room allows stopping due to video loading, it is paused while anybody is buffering
+    ReportBufferState { buffering: bool },
+    /// Owner only, ownership is handed over with `TransferOwnership` instead
+    SetRole { #[ts(type = "string")] client_uid: Uuid, role: Role },
+    /// Deprecated, `SetRole` with `Admin` or `Member`
+    ChangeClientAdminStatus { #[ts(type = "string")] client_uid: Uuid, admin: bool },
+    /// Owners and admins, keeps the member from chatting and reacting. Capped at a day, a zero
+    /// duration lifts the mute.
+    MuteClient { #[ts(type = "string")] client_uid: Uuid, duration_secs: u64 },
+    /// Owner only, removes the member from the room
+    KickClient { #[ts(type = "string")] client_uid: Uuid },
+    /// Owner only, kicks the member and keeps them out, `ban_ip` also blocks their address
+    BanClient { #[ts(type = "string")] client_uid: Uuid, #[serde(default)] ban_ip: bool },
+    UnbanClient { #[ts(type = "string")] client_uid: Uuid },
+    /// Owner only, the previous owner stays an admin
+    TransferOwnership { #[ts(type = "string")] client_uid: Uuid },
+    ChangeRoomPreferences {
+        page_url: String,
+        allow_stop_due_to_video_loading: bool,
+        /// Required together with `signature` when the room requires signed commands. The nonce
+        /// has to be larger than the one of the previous signed command, the signature is the hex
+        /// HMAC-SHA256 of `changeRoomPreferences:<room id>:<nonce>:<page url>` with `SigningSecret`.
+        nonce: Option<u64>,
+        signature: Option<String>,
+    },
+    QuitRoom,
+    ScheduleSession {
+        room_id: String,
+        title: String,
+        page_url: Option<String>,
+        /// Unix time in milliseconds
+        starts_at: u64,
+        #[serde(default)]
+        #[ts(type = "string[]")]
+        invited_uids: Vec<Uuid>,
+        /// Listed to everybody, otherwise only the creator and the invited clients see the session
+        #[serde(default)]
+        public: bool,
+    },
+    /// Public sessions of the namespace and the ones the client scheduled or is invited to
+    ListUpcomingSessions,
+    /// Creates the room now and opens it for joining at `starts_at`, Unix milliseconds. Members
+    /// joining earlier get `RoomNotStarted`, only the client scheduling it may enter before.
+    ScheduleRoom { room_id: String, starts_at: u64, page_url: Option<String> },
+    CancelScheduledSession { #[ts(type = "string")] session_id: Uuid },
+    RequestInviteQrCode,
+    /// Answered with `CalendarUrl`, the feed of the sessions the client scheduled or is invited to
+    RequestCalendarUrl,
+    /// Defaults to a day, capped at a week
+    CreateInviteLink { expires_in_secs: Option<u64> },
+    RevokeInviteLink { slug: String },
+    /// Owner only, answered with `InviteCreated`. Defaults to a single use and a lifetime of a
+    /// day, capped at a week.
+    CreateInvite { max_uses: Option<u32>, expires_in_secs: Option<u64> },
+    /// Owner only. Chat and `Custom` messages of an encrypted room only go through
+    /// `EncryptedPayload`, membership and playback stay readable by the server.
+    SetEndToEndEncryption { enabled: bool },
+    /// Public key material relayed as is, to a single member or the whole room
+    KeyExchange { #[ts(type = "string | null")] to_uid: Option<Uuid>, public_key: String },
+    /// Relayed without being parsed, only its size and rate are checked. A chat message without
+    /// `channel`, otherwise a `Custom` message whose channel limits apply to the ciphertext.
+    EncryptedPayload { #[ts(type = "string | null")] to_uid: Option<Uuid>, ciphertext: String, channel: Option<String> },
+    RequestSigningSecret,
+    /// Owner only. Requiring signed commands drops the queue, its URLs weren't signed.
+    SetCommandSigning { required: bool },
+    AddRoomAlias { alias: String },
+    RemoveRoomAlias { alias: String },
+    /// Owner only, moves the room to another id, `AliasTaken` when a room or an alias has it
+    /// already. The old id leads to the room for a few minutes. Not available for breakout rooms.
+    RenameRoom { new_room_id: String },
+    /// Asks the owner of `room_id` to move everyone from their room into the current one
+    RequestRoomMerge { room_id: String },
+    /// Answer of the owner of the room which would be merged away
+    RespondRoomMerge { accept: bool },
+    /// Distributes everyone except the owner over `count` new rooms linked to the current one
+    CreateBreakoutRooms { count: usize },
+    RecallBreakoutRooms,
+    JoinLobby,
+    LeaveLobby,
+    SendLobbyMessage { text: String },
+    /// Text chat with the members of the current room
+    ChatMessage { text: String },
+    /// Messages can be changed and deleted by their sender and by admins of the room, as long as
+    /// they are in the chat history of the room
+    EditChatMessage { #[ts(type = "string")] message_id: Uuid, text: String },
+    DeleteChatMessage { #[ts(type = "string")] message_id: Uuid },
+    /// Relayed as is to the other members, for client extensions. Size and rate limits depend on
+    /// the channel, see `ServerConfig::custom_channels`.
+    Custom { channel: String, #[ts(type = "unknown")] payload: serde_json::Value },
+    /// `question` and `options` are ignored for polls of well-known kinds
+    StartPoll {
+        kind: PollKind,
+        #[serde(default)]
+        question: String,
+        #[serde(default)]
+        options: Vec<String>,
+        /// Defaults to a minute
+        duration_secs: Option<u64>,
+    },
+    /// Asks the members to confirm they are ready, the room starts playing once `quorum_percent` of
+    /// them did. Defaults to everybody.
+    RequestReadyCheck { quorum_percent: Option<u8> },
+    SetReady { ready: bool },
+    /// Counts down from `seconds` before the room starts playing, at most `MAX_COUNTDOWN_SECONDS`.
+    /// Starting another countdown replaces the running one.
+    StartCountdown { seconds: u32 },
+    /// Voting again replaces the previous vote
+    Vote { #[ts(type = "string")] poll_id: Uuid, option: usize },
+    /// Reports a member of the room to the operators of the server with the recent chat and audit
+    /// log of the room, see `GET /api/reports`. Answered with `Success`.
+    ReportClient { #[ts(type = "string")] client_uid: Uuid, reason: String },
+    /// `emoji` has to be one of `ALLOWED_REACTIONS`
+    SendReaction { emoji: String },
+    /// Shows the other members the client is typing a chat message, until `TypingStop`, the next
+    /// `ChatMessage` or `TYPING_TIMEOUT` without another `TypingStart`
+    TypingStart,
+    TypingStop,
+    /// Announces a file the client can send to the other members, like subtitles. `size` is in
+    /// bytes, `hash` tells the file apart in `RequestFile` and `FileChunk`. Offering the same hash
+    /// again replaces the offer. Answered with `Success`.
+    OfferFile { name: String, size: u64, hash: String },
+    /// Asks the member who offered the file to send it with `FileChunk`. Answered with `Success`.
+    RequestFile { #[ts(type = "string")] from_uid: Uuid, hash: String },
+    /// Part of an offered file for a member who requested it, `data` is the base64 of the bytes
+    /// starting at `offset`. The server relays it without keeping it. Answered with `Success`.
+    FileChunk { #[ts(type = "string")] to_uid: Uuid, hash: String, offset: u64, data: String },
+    GetRoomStats,
+    /// Totals of the server like the number of people watching, answered with `Stats`. Clients of
+    /// a namespace only get the totals of their namespace. Moderators also get the rooms.
+    GetStats,
+    /// Follows a public room without joining it, answered with `SubscribedRoomChanged` which is sent
+    /// again after each change of the room. Moderators may follow any room.
+    Subscribe { room_id: String },
+    Unsubscribe { room_id: String },
+    /// Answered with `RoomSnapshot`, used to resync after missing room events
+    RequestRoomSnapshot,
+    /// Asks for the room events after `seq`, the latest one the client got. Answered with the
+    /// missed events followed by `Success`, or with `RoomSnapshot` when they are not kept anymore.
+    ResyncFrom { seq: u64 },
+    /// Members of the room from `offset` on, in the order of `RoomDataDto::clients`. Answered with
+    /// `Members`, `limit` is capped at `MAX_MEMBERS_PAGE_SIZE`.
+    GetMembers { offset: usize, limit: usize },
+    GetDepartedClients,
+    /// Audit log of the room, newest first, answered with `RoomHistory`
+    GetRoomHistory,
+    /// Creates the role or replaces the permissions of an existing one with the same name
+    DefineRoomRole { name: String, permissions: Vec<RoomPermission> },
+    DeleteRoomRole { name: String },
+    ChangeClientRole { #[ts(type = "string")] client_uid: Uuid, role: String, assigned: bool },
+    SetPermissionPreset { preset: PermissionPreset },
+    /// Members present for this many minutes become admins, `None` turns it off
+    SetAutoAdminPromotion { after_minutes: Option<u32> },
+    /// Votes for promoting a member to admin, only possible while no owner is active
+    NominateAdmin { #[ts(type = "string")] client_uid: Uuid },
+    /// Sent periodically by clients, answered with `SyncTolerance`
+    NetworkReport { report: NetworkReportDto },
+    /// Sent periodically by clients while in a room, answered with `CorrectPosition` only when the
+    /// player drifted from the room further than it may
+    ReportPosition { position: f64, playing: bool },
+}
+
+impl IncomingMessage {
+    /// `type` of the message on the wire, e.g. the label of its handling time metric
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IncomingMessage::Ping { .. } => "ping",
+            IncomingMessage::Hello { .. } => "hello",
+            IncomingMessage::Resume { .. } => "resume",
+            IncomingMessage::Authenticate { .. } => "authenticate",
+            IncomingMessage::ChangeName { .. } => "changeName",
+            IncomingMessage::RtcOffer { .. } => "rtcOffer",
+            IncomingMessage::RtcAnswer { .. } => "rtcAnswer",
+            IncomingMessage::RtcIceCandidate { .. } => "rtcIceCandidate",
+            IncomingMessage::SetClientMeta { .. } => "setClientMeta",
+            IncomingMessage::JoinRoom { .. } => "joinRoom",
+            IncomingMessage::CloseRoom { .. } => "closeRoom",
+            IncomingMessage::CreateRoom { .. } => "createRoom",
+            IncomingMessage::RestoreRoom { .. } => "restoreRoom",
+            IncomingMessage::ListPublicRooms => "listPublicRooms",
+            IncomingMessage::GetRoomInfo { .. } => "getRoomInfo",
+            IncomingMessage::PlayerEvent { .. } => "playerEvent",
+            IncomingMessage::UpdateRoomSettings { .. } => "updateRoomSettings",
+            IncomingMessage::LockRoom { .. } => "lockRoom",
+            IncomingMessage::UpdateRoomMetadata { .. } => "updateRoomMetadata",
+            IncomingMessage::SetPageUrl { .. } => "setPageUrl",
+            IncomingMessage::QueueAdd { .. } => "queueAdd",
+            IncomingMessage::QueueRemove { .. } => "queueRemove",
+            IncomingMessage::QueueMove { .. } => "queueMove",
+            IncomingMessage::QueueNext => "queueNext",
+            IncomingMessage::VideoEnded { .. } => "videoEnded",
+            IncomingMessage::Play => "play",
+            IncomingMessage::Pause { .. } => "pause",
+            IncomingMessage::Seek { .. } => "seek",
+            IncomingMessage::AddMarker { .. } => "addMarker",
+            IncomingMessage::RemoveMarker { .. } => "removeMarker",
+            IncomingMessage::JumpToMarker { .. } => "jumpToMarker",
+            IncomingMessage::SetTrack { .. } => "setTrack",
+            IncomingMessage::ReportPlayerStatus { .. } => "reportPlayerStatus",
+            IncomingMessage::ReportBufferState { .. } => "reportBufferState",
+            IncomingMessage::SetRole { .. } => "setRole",
+            IncomingMessage::ChangeClientAdminStatus { .. } => "changeClientAdminStatus",
+            IncomingMessage::MuteClient { .. } => "muteClient",
+            IncomingMessage::KickClient { .. } => "kickClient",
+            IncomingMessage::BanClient { .. } => "banClient",
+            IncomingMessage::UnbanClient { .. } => "unbanClient",
+            IncomingMessage::TransferOwnership { .. } => "transferOwnership",
+            IncomingMessage::ChangeRoomPreferences { .. } => "changeRoomPreferences",
+            IncomingMessage::QuitRoom => "quitRoom",
+            IncomingMessage::ScheduleSession { .. } => "scheduleSession",
+            IncomingMessage::ListUpcomingSessions => "listUpcomingSessions",
+            IncomingMessage::ScheduleRoom { .. } => "scheduleRoom",
+            IncomingMessage::CancelScheduledSession { .. } => "cancelScheduledSession",
+            IncomingMessage::RequestInviteQrCode => "requestInviteQrCode",
+            IncomingMessage::RequestCalendarUrl => "requestCalendarUrl",
+            IncomingMessage::CreateInviteLink { .. } => "createInviteLink",
+            IncomingMessage::RevokeInviteLink { .. } => "revokeInviteLink",
+            IncomingMessage::CreateInvite { .. } => "createInvite",
+            IncomingMessage::SetEndToEndEncryption { .. } => "setEndToEndEncryption",
+            IncomingMessage::KeyExchange { .. } => "keyExchange",
+            IncomingMessage::EncryptedPayload { .. } => "encryptedPayload",
+            IncomingMessage::RequestSigningSecret => "requestSigningSecret",
+            IncomingMessage::SetCommandSigning { .. } => "setCommandSigning",
+            IncomingMessage::AddRoomAlias { .. } => "addRoomAlias",
+            IncomingMessage::RemoveRoomAlias { .. } => "removeRoomAlias",
+            IncomingMessage::RenameRoom { .. } => "renameRoom",
+            IncomingMessage::RequestRoomMerge { .. } => "requestRoomMerge",
+            IncomingMessage::RespondRoomMerge { .. } => "respondRoomMerge",
+            IncomingMessage::CreateBreakoutRooms { .. } => "createBreakoutRooms",
+            IncomingMessage::RecallBreakoutRooms => "recallBreakoutRooms",
+            IncomingMessage::JoinLobby => "joinLobby",
+            IncomingMessage::LeaveLobby => "leaveLobby",
+            IncomingMessage::SendLobbyMessage { .. } => "sendLobbyMessage",
+            IncomingMessage::ChatMessage { .. } => "chatMessage",
+            IncomingMessage::EditChatMessage { .. } => "editChatMessage",
+            IncomingMessage::DeleteChatMessage { .. } => "deleteChatMessage",
+            IncomingMessage::Custom { .. } => "custom",
+            IncomingMessage::StartPoll { .. } => "startPoll",
+            IncomingMessage::RequestReadyCheck { .. } => "requestReadyCheck",
+            IncomingMessage::SetReady { .. } => "setReady",
+            IncomingMessage::StartCountdown { .. } => "startCountdown",
+            IncomingMessage::Vote { .. } => "vote",
+            IncomingMessage::ReportClient { .. } => "reportClient",
+            IncomingMessage::SendReaction { .. } => "sendReaction",
+            IncomingMessage::TypingStart => "typingStart",
+            IncomingMessage::TypingStop => "typingStop",
+            IncomingMessage::OfferFile { .. } => "offerFile",
+            IncomingMessage::RequestFile { .. } => "requestFile",
+            IncomingMessage::FileChunk { .. } => "fileChunk",
+            IncomingMessage::GetRoomStats => "getRoomStats",
+            IncomingMessage::GetStats => "getStats",
+            IncomingMessage::Subscribe { .. } => "subscribe",
+            IncomingMessage::Unsubscribe { .. } => "unsubscribe",
+            IncomingMessage::RequestRoomSnapshot => "requestRoomSnapshot",
+            IncomingMessage::ResyncFrom { .. } => "resyncFrom",
+            IncomingMessage::GetMembers { .. } => "getMembers",
+            IncomingMessage::GetDepartedClients => "getDepartedClients",
+            IncomingMessage::GetRoomHistory => "getRoomHistory",
+            IncomingMessage::DefineRoomRole { .. } => "defineRoomRole",
+            IncomingMessage::DeleteRoomRole { .. } => "deleteRoomRole",
+            IncomingMessage::ChangeClientRole { .. } => "changeClientRole",
+            IncomingMessage::SetPermissionPreset { .. } => "setPermissionPreset",
+            IncomingMessage::SetAutoAdminPromotion { .. } => "setAutoAdminPromotion",
+            IncomingMessage::NominateAdmin { .. } => "nominateAdmin",
+            IncomingMessage::NetworkReport { .. } => "networkReport",
+            IncomingMessage::ReportPosition { .. } => "reportPosition",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
+#[ts(export)]
+pub enum OutgoingMessage {
+    /// `server_time` in Unix milliseconds, to estimate the offset of the clocks
+    Pong { client_time: Option<u64>, server_time: u64 },
+    /// `protocol_version` is the version the connection speaks from now on, it may be older than
+    /// the one the client asked for
+    Welcome { protocol_version: u32, supported_versions: Vec<u32>, features: Vec<ProtocolFeature> },
+    /// `resume_token` is used in `Resume` after a reconnect
+    ClientUid { #[ts(type = "string")] client_uid: Uuid, resume_token: String },
+    Success,
+    /// Answer to `CreateRoom`, the client is the owner of the new room
+    RoomCreated { room_id: String },
+    /// `retry_after` is a hint in milliseconds when repeating the request later may succeed, `field`
+    /// is the path of the field a `JsonError` is about when it is known. `kind` is what clients
+    /// should show their own text for, `msg` is only a fallback in the language of the client.
+    Error { kind: ErrorKind, msg: Option<String>, retry_after: Option<u64>, field: Option<String>, params: Option<ErrorParams> },
+    /// Final message before the server closes all connections, reconnect after `retry_after` milliseconds
+    ServerShuttingDown { retry_after: u64 },
+    /// Text of the operators, like announcing maintenance. The `motd` setting is sent right after
+    /// `ClientUid`, others come from `POST /api/notices`.
+    ServerNotice { text: String, level: NoticeLevel },
+    /// Last message before the server closes the connection, the close code tells the reason too.
+    /// Kicks and bans keep the connection open, they are told with `Kicked`. `retry_after` is the
+    /// delay in milliseconds to reconnect after, it is missing when reconnecting won't help.
+    Disconnecting { reason: DisconnectReason, retry_after: Option<u64> },
+    /// Full state of the room, sent after changes touching many members. `seq` is the number of the
+    /// latest room event it includes. Before protocol version 2 it is sent instead of `RoomSnapshot`.
+    RoomChanged { seq: u64, data: Box<RoomDataDto> },
+    /// Full state of the room for the receiving member alone, sent after joining, resuming and on
+    /// `RequestRoomSnapshot` before any event numbered after `seq`. The playback position is the
+    /// one at `server_time`, Unix milliseconds.
+    RoomSnapshot { seq: u64, server_time: u64, data: Box<RoomDataDto> },
+    /// Sent to every member periodically to check their copy of the room against, once the client
+    /// applied the room events up to `seq`. `members_digest` is the hex SHA-1 of the JSON array of
+    /// `[uid, role, name]` of the members sorted by `uid`, without whitespace. On any mismatch the
+    /// client should send `RequestRoomSnapshot`.
+    RoomDigest { seq: u64, server_time: u64, position: f64, playing: bool, member_count: usize, members_digest: String },
+    /// Room events are numbered consecutively, on a gap the client should send `RequestRoomSnapshot`
+    ClientJoined { seq: u64, client: RoomClientDto },
+    ClientLeft { seq: u64, #[ts(type = "string")] client_uid: Uuid },
+    /// Sent besides the `ClientUpdated` of the members, `automatic` when the owner left and the
+    /// successor was picked by `OwnerSuccession`
+    OwnershipTransferred { seq: u64, #[ts(type = "string")] from_uid: Uuid, #[ts(type = "string")] to_uid: Uuid, automatic: bool },
+    /// Sent instead of `ClientJoined` and `ClientLeft` for spectators
+    SpectatorCountChanged { seq: u64, spectator_count: usize },
+    ClientUpdated { seq: u64, client: RoomClientDto },
+    RoomSettingsUpdated { seq: u64, settings: Box<RoomSettingsDto> },
+    /// Sent to a member removed from the room by its owner
+    Kicked { room_id: String, #[ts(type = "string")] by_uid: Uuid },
+    /// Sent to every member of a room closed by an administrator
+    RoomClosed { room_id: String },
+    PlayerEvent { event: PlayerEvent, #[ts(type = "string")] client_uid: Uuid },
+    PageUrlChanged { url: String, #[ts(type = "string")] client_uid: Uuid },
+    /// `position` is where to start, already ahead by how long the message takes to reach this
+    /// member. `None` resumes where the player is.
+    Play { #[ts(type = "string")] client_uid: Uuid, position: Option<f64> },
+    /// `left_name` is the name of the member whose departure paused the room, see
+    /// `RoomSettingsDto::pause_on_leave`
+    Pause { position: f64, #[ts(type = "string")] client_uid: Uuid, #[serde(default)] left_name: Option<String> },
+    Seek { position: f64, #[ts(type = "string")] client_uid: Uuid },
+    TrackChanged { kind: TrackKind, track_id: Option<String>, #[ts(type = "string")] client_uid: Uuid },
+    ReportPlayerStatus {  player_status: PlayerStatus, #[ts(type = "string")] client_uid: Uuid },
+    /// Announces the binary frame with the file contents that immediately follows this message
+    FileShared { #[ts(type = "string")] client_uid: Uuid, mime_type: String, size: usize },
+    SessionScheduled { session: ScheduledSessionDto },
+    UpcomingSessions { sessions: Vec<ScheduledSessionDto> },
+    SessionInvitation { session: ScheduledSessionDto },
+    ScheduledSessionStarting { session: ScheduledSessionDto },
+    /// `image_url` serves the same SVG over HTTP, e.g. for an `<img>` on a shared screen
+    InviteQrCode { join_url: String, svg: String, image_url: String },
+    /// Private address, anybody who has it can read the feed
+    CalendarUrl { url: String },
+    InviteLinkCreated { slug: String, url: String, expires_at: u64 },
+    InviteCreated { token: String, max_uses: u32, expires_at: u64 },
+    KeyExchange { #[ts(type = "string")] from_uid: Uuid, public_key: String },
+    EncryptedPayload { #[ts(type = "string")] from_uid: Uuid, ciphertext: String, channel: Option<String> },
+    /// Hex encoded HMAC-SHA256 key for signing sensitive commands, sent only to controllers
+    SigningSecret { secret: String },
+    RoomMergeRequested { into_room_id: String, requested_by_name: Option<String> },
+    RoomMergeDeclined { room_id: String },
+    /// Sent to members of the merged room, followed by `RoomChanged` of their new room
+    RoomMerged { from_room_id: String, into_room_id: String },
+    /// Sent to the members when the owner renames the room, followed by `RoomChanged`
+    RoomRenamed { old_room_id: String, new_room_id: String },
+    MovedToBreakoutRoom { room_id: String, parent_room_id: String },
+    RecalledFromBreakoutRoom { room_id: String, parent_room_id: String },
+    LobbyJoined { members_count: usize, recent_messages: Vec<LobbyChatMessageDto> },
+    LobbyMessage { message: LobbyChatMessageDto },
+    ChatMessage {
+        #[serde(default)]
+        #[ts(type = "string")]
+        message_id: Uuid,
+        #[ts(type = "string")]
+        from_uid: Uuid,
+        from_name: Option<String>,
+        text: String,
+        timestamp: u64,
+    },
+    /// `client_uid` is who changed it, the sender or an admin
+    ChatMessageEdited { #[ts(type = "string")] message_id: Uuid, text: String, #[ts(type = "string")] client_uid: Uuid },
+    ChatMessageDeleted { #[ts(type = "string")] message_id: Uuid, #[ts(type = "string")] client_uid: Uuid },
+    /// Sent when a poll starts and after every vote
+    PollUpdated { poll: PollDto },
+    /// `winning_option` is `None` without votes or on a tie
+    PollEnded { poll: PollDto, winning_option: Option<usize> },
+    /// Sent when a ready check starts and after every change, `None` once it is over
+    ReadyCheckUpdated { ready_check: Option<ReadyCheckDto> },
+    /// The room starts playing at `starts_at`, Unix milliseconds on the clock of the server, see
+    /// `Pong` for its offset. The `Play` follows at that moment.
+    CountdownStarted { starts_at: u64, seconds: u32, #[ts(type = "string")] client_uid: Uuid },
+    /// Called off by another playback command or a change of the video
+    CountdownCancelled,
+    ReactionReceived { #[ts(type = "string")] from_uid: Uuid, emoji: String },
+    TypingChanged { #[ts(type = "string")] client_uid: Uuid, typing: bool },
+    FileOffered { #[ts(type = "string")] from_uid: Uuid, name: String, size: u64, hash: String },
+    /// Sent to the member who offered the file, `from_uid` is the member asking for it
+    FileRequested { #[ts(type = "string")] from_uid: Uuid, hash: String },
+    FileChunk { #[ts(type = "string")] from_uid: Uuid, hash: String, offset: u64, data: String },
+    Custom { channel: String, #[ts(type = "unknown")] payload: serde_json::Value, #[ts(type = "string")] from_uid: Uuid },
+    RtcOffer { #[ts(type = "string")] from_uid: Uuid, sdp: String },
+    RtcAnswer { #[ts(type = "string")] from_uid: Uuid, sdp: String },
+    RtcIceCandidate {
+        #[ts(type = "string")]
+        from_uid: Uuid,
+        candidate: String,
+        sdp_mid: Option<String>,
+        sdp_m_line_index: Option<u32>,
+    },
+    /// Recent messages of the room, sent after joining it
+    ChatHistory { messages: Vec<ChatMessageDto> },
+    /// Unix time in milliseconds
+    LobbyMuted { until: u64 },
+    RoomStats { stats: RoomStatsDto },
+    /// Answer to `GetMembers`, `total` is the number of members except spectators
+    Members { offset: usize, total: usize, members: Vec<RoomClientDto> },
+    /// Sent to the members before `RoomClosed`
+    SessionSummary { summary: SessionSummaryDto },
+    Stats { stats: ServerStatsDto },
+    /// State of a room followed with `Subscribe`. It carries no `seq`, the events in between are not
+    /// sent to subscribers.
+    SubscribedRoomChanged { room_id: String, data: Box<RoomDataDto> },
+    /// How far in milliseconds the player may drift from the room before it should resync
+    SyncTolerance { drift_tolerance_ms: u64 },
+    /// Where the player of this member should be, sent to it alone
+    CorrectPosition { position: f64, playing: bool },
+    /// Any message, including `Ping`, keeps the connection open
+    InactivityWarning { disconnect_in_secs: u64 },
+    AdminNominated { #[ts(type = "string")] client_uid: Uuid, votes: usize, required_votes: usize },
+    DepartedClients { clients: Vec<DepartedClientDto> },
+    RoomHistory { entries: Vec<RoomHistoryEntryDto> },
+    PublicRooms { rooms: Vec<PublicRoomDto> },
+    RoomInfo { info: RoomInfoDto },
+    /// Sent on connect to authenticated users who watched something before
+    ContinueWatching { progress: WatchProgressDto },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, TS)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
+pub enum PlayerEvent {
+    StartPlaying { at_second: f64 },
+    StopPlaying { at_second: f64 },
+    StopDueToVideoLoading { at_second: f64 },
+    Seek { to_second: f64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStatus {
+    pub playing: bool,
+    pub loading: bool,
+    pub at_second: f64,
+}
+
+/// Limits behind an error, so clients can word it like "between 3 and 32 characters" in their own
+/// language. Counts of characters for lengths, bytes for sizes and seconds for durations.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorParams {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl ErrorParams {
+    pub fn range(min: usize, max: usize) -> Self {
+        ErrorParams { min: Some(min as u64), max: Some(max as u64) }
+    }
+
+    pub fn max(max: usize) -> Self {
+        ErrorParams { min: None, max: Some(max as u64) }
+    }
+}
+
+/// How prominently clients should show a `ServerNotice`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+pub enum NoticeLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ErrorKind {
+    InternalServerError,
+    JsonError,
+    ClientNotInAnyRoom,
+    ClientNameNotSet,
+    ClientNameTooShort,
+    ClientNameTooLong,
+    RoomIdTooShort,
+    RoomIdTooLong,
+    ClientNameInvalidCharacters,
+    RoomIdInvalidCharacters,
+    RoomIdReserved,
+    RoomMetadataTooLong,
+    NoSuchClient,
+    Forbidden,
+    FileTooLarge,
+    UnsupportedFileType,
+    SharedFilesQuotaExceeded,
+    /// The members of the room relayed too much with `Custom` and files within the last minute
+    RelayQuotaExceeded,
+    SessionStartInPast,
+    NoSuchSession,
+    /// The client or the whole server has as many upcoming sessions as allowed
+    TooManySessions,
+    TooManyInvitees,
+    InviteLinkTooLong,
+    NoSuchInviteLink,
+    InvalidInvite,
+    RoomNotEncrypted,
+    DisabledInEncryptedRoom,
+    PayloadTooLarge,
+    InvalidSignature,
+    AliasTaken,
+    NoSuchAlias,
+    NoSuchRoom,
+    NoPendingMergeRequest,
+    InvalidBreakoutRoomCount,
+    BreakoutRoomsAlreadyOpen,
+    NoBreakoutRooms,
+    LobbyDisabled,
+    NotInLobby,
+    MessageEmpty,
+    MessageTooLong,
+    RateLimited,
+    Muted,
+    InvalidRoleName,
+    TooManyRoles,
+    NoSuchRole,
+    InvalidAutoAdminDelay,
+    OwnerActive,
+    AlreadyAdmin,
+    TooManyRooms,
+    InvalidNetworkReport,
+    InvalidPosition,
+    ServerOverloaded,
+    /// The server is about to be replaced, new rooms are joined and opened on another instance
+    ServerDraining,
+    InvalidPageUrl,
+    Banned,
+    UnsupportedReaction,
+    QueueFull,
+    QueueEmpty,
+    InvalidQueueIndex,
+    InvalidPoll,
+    PollAlreadyRunning,
+    NoSuchPoll,
+    InvalidReadyCheck,
+    NoReadyCheck,
+    InvalidCountdown,
+    InvalidTrack,
+    InvalidMarker,
+    NoSuchMarker,
+    NoSuchChatMessage,
+    TooManyMarkers,
+    InvalidCustomChannel,
+    InvalidClientMeta,
+    VoiceDisabled,
+    NameTaken,
+    AuthenticationFailed,
+    ResumeFailed,
+    HelloRequired,
+    UnsupportedProtocolVersion,
+    RoomFull,
+    ServerRoomLimitReached,
+    TooManyConnections,
+    TooManySubscriptions,
+    InvalidNamespace,
+    /// The addressed member's client doesn't handle the message, see `ClientCapability`
+    UnsupportedByClient,
+    InvalidReport,
+    /// The name, title or message contains words the server doesn't allow
+    ContentRejected,
+    /// Not an http(s) URL or not of a domain in `allowed_page_domains`
+    InvalidUrl,
+    /// The room to restore or to schedule a session for is open, it can be joined instead
+    RoomAlreadyOpen,
+    /// The room was scheduled for later. `starts_at` is the scheduled start in Unix milliseconds,
+    /// `retry_after` the time until then.
+    RoomNotStarted { starts_at: u64 },
+    RoomLocked,
+    /// A text frame was not valid UTF-8, the connection is closed right after
+    InvalidUtf8,
+    /// The member offered no file with the hash
+    NoSuchFile,
+    InvalidFileOffer,
+    /// Not base64, or reaching past the end of the offered file
+    InvalidFileChunk,
+    NoSuchTemplate,
+}
+
+impl OutgoingMessage {
+    /// Capability a client needs to be sent the message, `None` for messages every client handles
+    pub fn required_capability(&self) -> Option<ClientCapability> {
+        match self {
+            OutgoingMessage::ReactionReceived { .. } => Some(ClientCapability::Reactions),
+            OutgoingMessage::RtcOffer { .. } | OutgoingMessage::RtcAnswer { .. } | OutgoingMessage::RtcIceCandidate { .. } => Some(ClientCapability::Voice),
+            OutgoingMessage::PollUpdated { .. } | OutgoingMessage::PollEnded { .. } => Some(ClientCapability::Polls),
+            OutgoingMessage::ReadyCheckUpdated { .. } => Some(ClientCapability::ReadyChecks),
+            OutgoingMessage::CountdownStarted { .. } | OutgoingMessage::CountdownCancelled => Some(ClientCapability::Countdowns),
+            OutgoingMessage::RoomDigest { .. } => Some(ClientCapability::RoomDigests),
+            OutgoingMessage::TypingChanged { .. } => Some(ClientCapability::TypingIndicators),
+            OutgoingMessage::FileOffered { .. } | OutgoingMessage::FileRequested { .. } | OutgoingMessage::FileChunk { .. } => Some(ClientCapability::FileOffers),
+            OutgoingMessage::FileShared { .. } => Some(ClientCapability::FileSharing),
+            OutgoingMessage::Custom { .. } => Some(ClientCapability::CustomMessages),
+            _ => None,
+        }
+    }
+}
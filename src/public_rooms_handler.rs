@@ -0,0 +1,28 @@
+use std::sync::Arc;
+use rocket::serde::json::Json;
+use rocket::State;
+use crate::admin_handler::Admin;
+use crate::ws_app_state::WsAppState;
+use crate::ws_dto_models::{PublicRoomDto, RoomInfoDto, ServerStatsDto};
+
+/// Same list as `IncomingMessage::ListPublicRooms`, for pages showing open watch parties
+#[get("/api/public-rooms?<namespace>")]
+pub async fn list_public_rooms(namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Json<Vec<PublicRoomDto>> {
+    Json(state.public_rooms(namespace).await)
+}
+
+/// Same as `IncomingMessage::GetRoomInfo`, for confirmation pages shown before joining
+#[get("/api/room-info/<room_id>?<namespace>")]
+pub async fn get_room_info(room_id: &str, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Option<Json<RoomInfoDto>> {
+    state.room_info(namespace, room_id).await.map(Json)
+}
+
+/// Same as `IncomingMessage::GetStats`, the rooms are listed for requests with the admin token.
+/// Totals of the whole server unless a `namespace` is given.
+#[get("/api/stats?<namespace>")]
+pub async fn get_stats(admin: Option<Admin>, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Json<ServerStatsDto> {
+    match namespace {
+        Some(namespace) => Json(state.namespace_stats(Some(namespace), admin.is_some()).await),
+        None => Json(state.server_stats(admin.is_some()).await),
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::State;
+use crate::push_notifications::PushSubscription;
+use crate::ws_app_state::WsAppState;
+use ts_rs::TS;
+
+#[derive(Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PushSubscribeRequest {
+    #[serde(default)]
+    namespace: Option<String>,
+    room_id: String,
+    subscription: PushSubscription,
+}
+
+#[derive(Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PushUnsubscribeRequest {
+    #[serde(default)]
+    namespace: Option<String>,
+    room_id: String,
+    endpoint: String,
+}
+
+/// Base64url encoded `applicationServerKey` for `pushManager.subscribe()`
+#[get("/push/public-key")]
+pub fn push_public_key(state: &State<Arc<WsAppState>>) -> Option<String> {
+    state.push_notifier.vapid_public_key()
+}
+
+/// Rooms which are open or have a public session coming up can be subscribed to
+#[post("/push/subscribe", data = "<request>")]
+pub async fn push_subscribe(request: Json<PushSubscribeRequest>, state: &State<Arc<WsAppState>>) -> Status {
+    if !state.push_notifier.is_enabled() {
+        return Status::ServiceUnavailable;
+    }
+
+    let request = request.into_inner();
+    if !request.subscription.is_valid() {
+        return Status::UnprocessableEntity;
+    }
+    let room_key = (request.namespace, request.room_id);
+    let room_open = state.store.room(room_key.0.as_deref(), &room_key.1).await.is_some();
+    let session_upcoming = state.scheduled_sessions.lock().await.values().any(|session| {
+        session.public && !session.activated && session.namespace == room_key.0 && session.room_id == room_key.1
+    });
+    if !room_open && !session_upcoming {
+        return Status::NotFound;
+    }
+
+    let config = state.config();
+    if !state.push_notifier.subscribe(room_key, request.subscription, config.max_push_subscriptions_per_room, config.max_push_subscriptions()).await {
+        return Status::TooManyRequests;
+    }
+    Status::NoContent
+}
+
+#[post("/push/unsubscribe", data = "<request>")]
+pub async fn push_unsubscribe(request: Json<PushUnsubscribeRequest>, state: &State<Arc<WsAppState>>) -> Status {
+    let request = request.into_inner();
+    state.push_notifier.unsubscribe(&(request.namespace, request.room_id), &request.endpoint).await;
+    Status::NoContent
+}
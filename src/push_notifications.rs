@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use jwt_simple::prelude::{Claims, Duration, ECDSAP256KeyPairLike, ES256KeyPair, NoCustomClaims};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use ts_rs::TS;
+use crate::ws_app_state::RoomKey;
+
+/// Records are never split, so the record size only has to be larger than the payload
+const RECORD_SIZE: u32 = 4096;
+/// How long push services keep a notification for an offline browser
+const PUSH_TTL_SECS: u32 = 60 * 60;
+/// Validity of the VAPID token of one request, push services accept at most a day
+const VAPID_TOKEN_LIFETIME_HOURS: u64 = 12;
+
+/// Browser `PushSubscription` as returned by `pushManager.subscribe()`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PushSubscription {
+    pub endpoint: String,
+    pub keys: PushSubscriptionKeys,
+}
+
+/// Base64url encoded, as in `PushSubscription.toJSON()`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+impl PushSubscription {
+    /// Only https endpoints are pushed to, with keys the payload can be encrypted for
+    pub fn is_valid(&self) -> bool {
+        let https_endpoint = self.endpoint.parse::<Uri>().is_ok_and(|uri| uri.scheme_str() == Some("https") && uri.host().is_some());
+        https_endpoint
+            && decode_base64url(&self.keys.p256dh).is_some_and(|key| PublicKey::from_sec1_bytes(&key).is_ok())
+            && decode_base64url(&self.keys.auth).is_some_and(|auth| auth.len() == 16)
+    }
+}
+
+/// Payload handed to the service worker of the subscription
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PushNotification {
+    pub namespace: Option<String>,
+    pub room_id: String,
+    pub title: String,
+    pub body: String,
+}
+
+impl PushNotification {
+    pub fn room_key(&self) -> RoomKey {
+        (self.namespace.clone(), self.room_id.clone())
+    }
+}
+
+/// Key pair of the server identifying it to push services (VAPID, RFC 8292)
+pub struct VapidKey {
+    key_pair: ES256KeyPair,
+    /// Uncompressed point, the `applicationServerKey` browsers subscribe with
+    public_key: Vec<u8>,
+    subject: Option<String>,
+}
+
+impl VapidKey {
+    /// `private_key` is the base64url encoded 32 byte scalar
+    pub fn new(private_key: &str, subject: Option<String>) -> Result<Self> {
+        let private_key = decode_base64url(private_key).ok_or(anyhow!("VAPID private key is not base64url"))?;
+        let secret_key = SecretKey::from_slice(&private_key)?;
+        Ok(VapidKey {
+            key_pair: ES256KeyPair::from_bytes(&private_key).map_err(|e| anyhow!("{}", e))?,
+            public_key: secret_key.public_key().to_encoded_point(false).as_bytes().to_vec(),
+            subject,
+        })
+    }
+
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.public_key)
+    }
+
+    /// `Authorization` header of a push to `endpoint`, the token is bound to the push service origin
+    pub fn authorization(&self, endpoint: &Uri) -> Result<String> {
+        let (Some(scheme), Some(authority)) = (endpoint.scheme_str(), endpoint.authority()) else {
+            return Err(anyhow!("Push endpoint {} has no origin", endpoint));
+        };
+        let mut claims = Claims::create(Duration::from_hours(VAPID_TOKEN_LIFETIME_HOURS))
+            .with_audience(format!("{}://{}", scheme, authority));
+        if let Some(subject) = &self.subject {
+            claims = claims.with_subject(subject);
+        }
+        let token = self.key_pair.sign::<NoCustomClaims>(claims).map_err(|e| anyhow!("{}", e))?;
+        Ok(format!("vapid t={}, k={}", token, self.public_key()))
+    }
+}
+
+// The private key stays out of logs
+impl std::fmt::Debug for VapidKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VapidKey").field("public_key", &self.public_key()).field("subject", &self.subject).finish()
+    }
+}
+
+/// Encrypts `plaintext` for the subscription as a single `aes128gcm` record (RFC 8291, RFC 8188).
+/// `sender_key` and `salt` have to be fresh for every message.
+pub fn encrypt_payload(subscription_key: &[u8], auth_secret: &[u8], plaintext: &[u8], sender_key: &SecretKey, salt: [u8; 16]) -> Result<Vec<u8>> {
+    let receiver_key = PublicKey::from_sec1_bytes(subscription_key)?;
+    let receiver_key_bytes = receiver_key.to_encoded_point(false);
+    let sender_key_bytes = sender_key.public_key().to_encoded_point(false);
+    let shared_secret = p256::ecdh::diffie_hellman(sender_key.to_nonzero_scalar(), receiver_key.as_affine());
+
+    let key_info = [b"WebPush: info\0", receiver_key_bytes.as_bytes(), sender_key_bytes.as_bytes()].concat();
+    let mut input_key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), shared_secret.raw_secret_bytes())
+        .expand(&key_info, &mut input_key)
+        .map_err(|_| anyhow!("HKDF output too long"))?;
+
+    let content_hkdf = Hkdf::<Sha256>::new(Some(&salt), &input_key);
+    let mut content_key = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    content_hkdf.expand(b"Content-Encoding: aes128gcm\0", &mut content_key).map_err(|_| anyhow!("HKDF output too long"))?;
+    content_hkdf.expand(b"Content-Encoding: nonce\0", &mut nonce).map_err(|_| anyhow!("HKDF output too long"))?;
+
+    // Padding delimiter of the last record
+    let record = [plaintext, &[2]].concat();
+    let ciphertext = Aes128Gcm::new_from_slice(&content_key)?
+        .encrypt(&Nonce::from(nonce), record.as_slice())
+        .map_err(|_| anyhow!("Failed to encrypt the push payload"))?;
+
+    let sender_key_bytes = sender_key_bytes.as_bytes();
+    let mut body = Vec::with_capacity(16 + 4 + 1 + sender_key_bytes.len() + ciphertext.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(sender_key_bytes.len() as u8);
+    body.extend_from_slice(sender_key_bytes);
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// Keeps Web Push subscriptions per room and delivers notifications to them (RFC 8030), signed
+/// with the VAPID key and encrypted for each subscription.
+#[derive(Debug)]
+pub struct PushNotifier {
+    vapid_key: Option<VapidKey>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    subscriptions: Mutex<HashMap<RoomKey, Vec<PushSubscription>>>,
+}
+
+impl PushNotifier {
+    pub fn new(vapid_key: Option<VapidKey>) -> Self {
+        PushNotifier {
+            vapid_key,
+            client: Client::builder().build(HttpsConnector::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.vapid_key.is_some()
+    }
+
+    pub fn vapid_public_key(&self) -> Option<String> {
+        self.vapid_key.as_ref().map(VapidKey::public_key)
+    }
+
+    /// `false` when the room or the server has as many subscriptions as allowed. Subscribing the
+    /// same endpoint again replaces its keys.
+    pub async fn subscribe(&self, room_key: RoomKey, subscription: PushSubscription, max_per_room: usize, max_total: Option<usize>) -> bool {
+        let mut subscriptions = self.subscriptions.lock().await;
+        let total: usize = subscriptions.values().map(Vec::len).sum();
+        let room_subscriptions = subscriptions.entry(room_key.clone()).or_default();
+        room_subscriptions.retain(|s| s.endpoint != subscription.endpoint);
+        if room_subscriptions.len() >= max_per_room || max_total.is_some_and(|max_total| total >= max_total) {
+            if room_subscriptions.is_empty() {
+                subscriptions.remove(&room_key);
+            }
+            return false;
+        }
+        room_subscriptions.push(subscription);
+        true
+    }
+
+    pub async fn unsubscribe(&self, room_key: &RoomKey, endpoint: &str) {
+        let mut subscriptions = self.subscriptions.lock().await;
+        if let Some(room_subscriptions) = subscriptions.get_mut(room_key) {
+            room_subscriptions.retain(|s| s.endpoint != endpoint);
+            if room_subscriptions.is_empty() {
+                subscriptions.remove(room_key);
+            }
+        }
+    }
+
+    /// Forgets the subscriptions of a room which is gone
+    pub async fn remove_room(&self, room_key: &RoomKey) {
+        self.subscriptions.lock().await.remove(room_key);
+    }
+
+    /// Sends the notification to every subscriber of the room in the background
+    pub async fn notify_room(self: &Arc<Self>, notification: PushNotification) {
+        if self.vapid_key.is_none() {
+            return;
+        }
+
+        let room_key = notification.room_key();
+        let room_subscriptions = self.subscriptions.lock().await.get(&room_key).cloned().unwrap_or_default();
+        for subscription in room_subscriptions {
+            let notifier = self.clone();
+            let notification = notification.clone();
+            let room_key = room_key.clone();
+            tokio::spawn(async move {
+                match notifier.deliver(&subscription, &notification).await {
+                    Ok(StatusCode::NOT_FOUND | StatusCode::GONE) => {
+                        // Subscription expired on the push service side
+                        notifier.unsubscribe(&room_key, &subscription.endpoint).await;
+                    }
+                    Ok(status) if !status.is_success() => {
+                        tracing::warn!(room_id = %notification.room_id, "Push service responded with {}", status);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!("Failed to deliver push notification: {:?}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    async fn deliver(&self, subscription: &PushSubscription, notification: &PushNotification) -> Result<StatusCode> {
+        let vapid_key = self.vapid_key.as_ref().ok_or(anyhow!("Push notifications are disabled"))?;
+        let endpoint: Uri = subscription.endpoint.parse()?;
+        let subscription_key = decode_base64url(&subscription.keys.p256dh).ok_or(anyhow!("Invalid p256dh key"))?;
+        let auth_secret = decode_base64url(&subscription.keys.auth).ok_or(anyhow!("Invalid auth secret"))?;
+
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let body = encrypt_payload(&subscription_key, &auth_secret, &serde_json::to_vec(notification)?, &SecretKey::random(&mut OsRng), salt)?;
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&endpoint)
+            .header(hyper::header::AUTHORIZATION, vapid_key.authorization(&endpoint)?)
+            .header(hyper::header::CONTENT_ENCODING, "aes128gcm")
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header("TTL", PUSH_TTL_SECS)
+            .body(Body::from(body))?;
+
+        Ok(self.client.request(request).await?.status())
+    }
+}
+
+/// Browsers encode without padding, some libraries add it
+pub fn decode_base64url(text: &str) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(text.trim_end_matches('=')).ok()
+}
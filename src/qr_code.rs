@@ -0,0 +1,436 @@
+//! Minimal QR code encoder (byte mode, error correction level M, versions 1-10) used for invite codes.
+//! Follows ISO/IEC 18004; long inputs are rejected instead of switching to bigger symbol versions.
+
+const MAX_VERSION: usize = 10;
+/// Error correction codewords per block and number of blocks for level M, indexed by version
+const ECC_CODEWORDS_PER_BLOCK: [usize; MAX_VERSION + 1] = [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26];
+const NUM_ERROR_CORRECTION_BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5];
+/// Format information bits for error correction level M
+const ECC_LEVEL_M_FORMAT_BITS: u32 = 0;
+
+pub struct QrCode {
+    size: usize,
+    mask: u32,
+    modules: Vec<Vec<bool>>,
+    is_function: Vec<Vec<bool>>,
+}
+
+impl QrCode {
+    /// Returns `None` when the data does not fit into a version 10 symbol
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let version = (1..=MAX_VERSION).find(|&version| {
+            let count_bits = if version < 10 { 8 } else { 16 };
+            4 + count_bits + data.len() * 8 <= num_data_codewords(version) * 8
+        })?;
+
+        let mut qr = QrCode {
+            size: version * 4 + 17,
+            mask: 0,
+            modules: vec![vec![false; version * 4 + 17]; version * 4 + 17],
+            is_function: vec![vec![false; version * 4 + 17]; version * 4 + 17],
+        };
+
+        qr.draw_function_patterns(version);
+        let codewords = add_ecc_and_interleave(version, &encode_data_codewords(version, data));
+        qr.draw_codewords(&codewords);
+
+        let mut best_mask = 0;
+        let mut min_penalty = u32::MAX;
+        for mask in 0..8 {
+            qr.apply_mask(mask);
+            qr.draw_format_bits(mask);
+            let penalty = qr.penalty_score();
+            if penalty < min_penalty {
+                best_mask = mask;
+                min_penalty = penalty;
+            }
+            // Masking is a XOR, so applying it again undoes it
+            qr.apply_mask(mask);
+        }
+        qr.apply_mask(best_mask);
+        qr.draw_format_bits(best_mask);
+        qr.mask = best_mask;
+
+        Some(qr)
+    }
+
+    /// Modules per side, without the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Mask pattern (0-7) chosen for the symbol
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// `x` is the column and `y` the row, counted from the top left corner
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y][x]
+    }
+
+    pub fn to_svg(&self) -> String {
+        const QUIET_ZONE: usize = 4;
+        let mut path = String::new();
+        for (y, row) in self.modules.iter().enumerate() {
+            for (x, dark) in row.iter().enumerate() {
+                if *dark {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+                }
+            }
+        }
+
+        let dimension = self.size + QUIET_ZONE * 2;
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {0} {0}" shape-rendering="crispEdges"><rect width="100%" height="100%" fill="#ffffff"/><path d="{1}" fill="#000000"/></svg>"##,
+            dimension, path
+        )
+    }
+
+    fn set_function_module(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y][x] = dark;
+        self.is_function[y][x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function_module(6, i, i % 2 == 0);
+            self.set_function_module(i, 6, i % 2 == 0);
+        }
+
+        self.draw_finder_pattern(3, 3);
+        self.draw_finder_pattern(size - 4, 3);
+        self.draw_finder_pattern(3, size - 4);
+
+        let positions = alignment_pattern_positions(version, size);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Alignment patterns never overlap the three finder patterns
+                if (i == 0 && (j == 0 || j == last)) || (i == last && j == 0) {
+                    continue;
+                }
+                self.draw_alignment_pattern(x, y);
+            }
+        }
+
+        // Reserve the format area, real bits are drawn after choosing the mask
+        self.draw_format_bits(0);
+        self.draw_version_bits(version);
+    }
+
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if xx >= 0 && yy >= 0 && (xx as usize) < self.size && (yy as usize) < self.size {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function_module(xx as usize, yy as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment_pattern(&mut self, x: usize, y: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let distance = dx.abs().max(dy.abs());
+                self.set_function_module((x as i32 + dx) as usize, (y as i32 + dy) as usize, distance != 1);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        for i in 0..=5 {
+            self.set_function_module(8, i, bit(i));
+        }
+        self.set_function_module(8, 7, bit(6));
+        self.set_function_module(8, 8, bit(7));
+        self.set_function_module(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function_module(14 - i, 8, bit(i));
+        }
+
+        let size = self.size;
+        for i in 0..8 {
+            self.set_function_module(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function_module(8, size - 15 + i, bit(i));
+        }
+        self.set_function_module(8, size - 8, true);
+    }
+
+    fn draw_version_bits(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+
+        let bits = version_bits(version);
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+            self.set_function_module(a, b, dark);
+            self.set_function_module(b, a, dark);
+        }
+    }
+
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vertical } else { vertical };
+                    if !self.is_function[y][x] && i < codewords.len() * 8 {
+                        self.modules[y][x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.is_function[y][x] {
+                    self.modules[y][x] = !self.modules[y][x];
+                }
+            }
+        }
+    }
+
+    fn penalty_score(&self) -> u32 {
+        let size = self.size;
+        let module = |x: usize, y: usize, transposed: bool| if transposed { self.modules[x][y] } else { self.modules[y][x] };
+        let mut penalty = 0;
+
+        for transposed in [false, true] {
+            for y in 0..size {
+                // Runs of five or more modules of the same color
+                let mut run_length = 1;
+                for x in 1..size {
+                    if module(x, y, transposed) == module(x - 1, y, transposed) {
+                        run_length += 1;
+                        if run_length == 5 {
+                            penalty += 3;
+                        } else if run_length > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run_length = 1;
+                    }
+                }
+
+                // Patterns looking like finder patterns
+                for x in 0..size.saturating_sub(10) {
+                    let window: Vec<bool> = (x..x + 11).map(|xx| module(xx, y, transposed)).collect();
+                    const FINDER_LIKE: [bool; 7] = [true, false, true, true, true, false, true];
+                    if (window[..7] == FINDER_LIKE && window[7..].iter().all(|dark| !dark))
+                        || (window[..4].iter().all(|dark| !dark) && window[4..] == FINDER_LIKE)
+                    {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.modules[y][x];
+                if color == self.modules[y][x + 1] && color == self.modules[y + 1][x] && color == self.modules[y + 1][x + 1] {
+                    penalty += 3;
+                }
+            }
+        }
+
+        let dark: usize = self.modules.iter().map(|row| row.iter().filter(|dark| **dark).count()).sum();
+        let total = size * size;
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty += (deviation / total) as u32 * 10;
+
+        penalty
+    }
+}
+
+/// 15 bit format information of level M with the mask, BCH(15, 5) coded and masked with 0x5412
+pub fn format_bits(mask: u32) -> u32 {
+    let data = (ECC_LEVEL_M_FORMAT_BITS << 3) | mask;
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    ((data << 10) | remainder) ^ 0x5412
+}
+
+/// 18 bit version information, BCH(18, 6) coded. Only drawn from version 7 on.
+pub fn version_bits(version: usize) -> u32 {
+    let mut remainder = version as u32;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+    }
+    ((version as u32) << 12) | remainder
+}
+
+fn alignment_pattern_positions(version: usize, size: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+
+    let count = version / 7 + 2;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+fn num_raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let count = version / 7 + 2;
+        result -= (25 * count - 10) * count - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn num_data_codewords(version: usize) -> usize {
+    num_raw_data_modules(version) / 8 - ECC_CODEWORDS_PER_BLOCK[version] * NUM_ERROR_CORRECTION_BLOCKS[version]
+}
+
+fn encode_data_codewords(version: usize, data: &[u8]) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::new();
+    let append = |value: u32, length: usize, bits: &mut Vec<bool>| {
+        for i in (0..length).rev() {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+
+    // Byte mode indicator and character count
+    append(0b0100, 4, &mut bits);
+    append(data.len() as u32, if version < 10 { 8 } else { 16 }, &mut bits);
+    for byte in data {
+        append(*byte as u32, 8, &mut bits);
+    }
+
+    let capacity_bits = num_data_codewords(version) * 8;
+    let terminator = (capacity_bits - bits.len()).min(4);
+    append(0, terminator, &mut bits);
+    let byte_padding = (8 - bits.len() % 8) % 8;
+    append(0, byte_padding, &mut bits);
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|chunk| chunk.iter().fold(0u8, |byte, bit| (byte << 1) | *bit as u8))
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= num_data_codewords(version) {
+            break;
+        }
+        codewords.push(pad);
+    }
+
+    codewords
+}
+
+fn add_ecc_and_interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let num_blocks = NUM_ERROR_CORRECTION_BLOCKS[version];
+    let block_ecc_length = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = num_raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_length = raw_codewords / num_blocks;
+
+    let divisor = reed_solomon_divisor(block_ecc_length);
+    let mut blocks: Vec<Vec<u8>> = Vec::new();
+    let mut offset = 0;
+    for i in 0..num_blocks {
+        let data_length = short_block_length - block_ecc_length + if i < num_short_blocks { 0 } else { 1 };
+        let mut block = data[offset..offset + data_length].to_vec();
+        offset += data_length;
+        let ecc = reed_solomon_remainder(&block, &divisor);
+        if i < num_short_blocks {
+            // Placeholder keeping columns aligned with the long blocks, skipped while interleaving
+            block.push(0);
+        }
+        block.extend(ecc);
+        blocks.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_length - block_ecc_length || j >= num_short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+
+    result
+}
+
+/// Generator polynomial of degree `degree` without its leading term, highest power first
+pub fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+/// Error correction codewords of one block
+pub fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (value, coefficient) in result.iter_mut().zip(divisor) {
+            *value ^= gf_multiply(*coefficient, factor);
+        }
+    }
+    result
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
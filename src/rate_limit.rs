@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket holding up to `capacity` tokens, refilled continuously at `refill_per_second`
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, refill_per_second: f64) -> Self {
+        TokenBucket {
+            capacity,
+            refill_per_second,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self, cost: f64) -> bool {
+        self.try_take_keeping(cost, 0.0)
+    }
+
+    /// Takes tokens only if at least `reserve` tokens stay in the bucket afterwards
+    pub fn try_take_keeping(&mut self, cost: f64, reserve: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens - cost < reserve {
+            return false;
+        }
+
+        self.tokens -= cost;
+        true
+    }
+
+    /// Time until `cost` tokens are available again
+    pub fn retry_after(&self, cost: f64) -> Duration {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        let tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        if tokens >= cost || self.refill_per_second <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((cost - tokens) / self.refill_per_second)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    /// Rejected, the client may try again after the given time
+    Limited(Duration),
+    /// Rejected too often within the violations window, the client should be disconnected
+    Exceeded,
+}
+
+/// Token bucket which also counts how often the client ran into it recently
+#[derive(Debug)]
+pub struct ViolationTrackingLimit {
+    bucket: TokenBucket,
+    max_violations: u32,
+    violations_window: Duration,
+    violations: u32,
+    window_start: Instant,
+}
+
+impl ViolationTrackingLimit {
+    pub fn new(bucket: TokenBucket, max_violations: u32, violations_window: Duration) -> Self {
+        ViolationTrackingLimit {
+            bucket,
+            max_violations,
+            violations_window,
+            violations: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self, cost: f64) -> RateLimitDecision {
+        if self.bucket.try_take(cost) {
+            return RateLimitDecision::Allowed;
+        }
+
+        if self.window_start.elapsed() > self.violations_window {
+            self.violations = 0;
+            self.window_start = Instant::now();
+        }
+        self.violations += 1;
+        if self.violations > self.max_violations {
+            RateLimitDecision::Exceeded
+        } else {
+            RateLimitDecision::Limited(self.bucket.retry_after(cost))
+        }
+    }
+}
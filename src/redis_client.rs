@@ -0,0 +1,153 @@
+//! Minimal Redis client speaking RESP2, only what the cluster bridge needs: plain commands on one
+//! connection and pub/sub messages on another.
+
+use std::future::Future;
+use std::pin::Pin;
+use anyhow::{anyhow, bail, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// Bulk strings and arrays longer than this are rejected instead of being allocated
+const MAX_LENGTH: i64 = 64 * 1024 * 1024;
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>),
+}
+
+impl RespValue {
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            RespValue::Simple(value) => Some(value.into_bytes()),
+            RespValue::Bulk(value) => value,
+            _ => None,
+        }
+    }
+
+    pub fn into_array(self) -> Option<Vec<RespValue>> {
+        match self {
+            RespValue::Array(values) => values,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RedisConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl RedisConnection {
+    /// `redis://[[user]:password@]host[:port][/db]`
+    pub async fn connect(url: &str) -> Result<Self> {
+        let address = url.strip_prefix("redis://").ok_or(anyhow!("Only redis:// URLs are supported"))?;
+        let (credentials, address) = match address.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, address),
+        };
+        let (host, db) = match address.split_once('/') {
+            Some((host, db)) => (host, Some(db).filter(|db| !db.is_empty())),
+            None => (address, None),
+        };
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:6379", host) };
+
+        let (reader, writer) = TcpStream::connect(host).await?.into_split();
+        let mut connection = RedisConnection { reader: BufReader::new(reader), writer };
+        match credentials.map(|credentials| credentials.split_once(':').unwrap_or(("", credentials))) {
+            Some(("", password)) => connection.command(&[b"AUTH", password.as_bytes()]).await?,
+            Some((user, password)) => connection.command(&[b"AUTH", user.as_bytes(), password.as_bytes()]).await?,
+            None => RespValue::Simple(String::new()),
+        };
+        if let Some(db) = db {
+            connection.command(&[b"SELECT", db.as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    pub async fn send(&mut self, args: &[&[u8]]) -> Result<()> {
+        let mut buffer = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            buffer.extend(format!("${}\r\n", arg.len()).into_bytes());
+            buffer.extend_from_slice(arg);
+            buffer.extend(b"\r\n");
+        }
+        self.writer.write_all(&buffer).await?;
+        Ok(())
+    }
+
+    /// Sends the command and waits for its reply, error replies become errors
+    pub async fn command(&mut self, args: &[&[u8]]) -> Result<RespValue> {
+        self.send(args).await?;
+        match self.read().await? {
+            RespValue::Error(message) => bail!("Redis error: {}", message),
+            value => Ok(value),
+        }
+    }
+
+    pub async fn read(&mut self) -> Result<RespValue> {
+        self.read_value(0).await
+    }
+
+    fn read_value(&mut self, depth: usize) -> Pin<Box<dyn Future<Output = Result<RespValue>> + Send + '_>> {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                bail!("Reply is nested too deep");
+            }
+
+            let line = self.read_line().await?;
+            let (marker, rest) = line.split_at(1);
+            Ok(match marker {
+                "+" => RespValue::Simple(rest.to_string()),
+                "-" => RespValue::Error(rest.to_string()),
+                ":" => RespValue::Integer(rest.parse()?),
+                "$" => {
+                    let len: i64 = rest.parse()?;
+                    if len < 0 {
+                        return Ok(RespValue::Bulk(None));
+                    }
+                    if len > MAX_LENGTH {
+                        bail!("Bulk string of {} bytes is too long", len);
+                    }
+                    let mut data = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut data).await?;
+                    data.truncate(len as usize);
+                    RespValue::Bulk(Some(data))
+                }
+                "*" => {
+                    let len: i64 = rest.parse()?;
+                    if len < 0 {
+                        return Ok(RespValue::Array(None));
+                    }
+                    if len > MAX_LENGTH {
+                        bail!("Array of {} items is too long", len);
+                    }
+                    let mut items = Vec::new();
+                    for _ in 0..len {
+                        items.push(self.read_value(depth + 1).await?);
+                    }
+                    RespValue::Array(Some(items))
+                }
+                _ => bail!("Unexpected reply marker {}", marker),
+            })
+        })
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            bail!("Connection closed");
+        }
+        let line = line.strip_suffix("\r\n").ok_or(anyhow!("Reply line is not terminated"))?;
+        if line.is_empty() {
+            bail!("Empty reply line");
+        }
+        Ok(line.to_string())
+    }
+}
@@ -0,0 +1,81 @@
+//! Closed rooms appended to `ServerConfig::archive_path`, one JSON line each, so operators can look
+//! up what happened to a room with `GET /api/archive` and members can open a recent one again with
+//! `RestoreRoom`. The file is only appended to, trimming it is left to the operator.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use crate::scheduler::unix_millis_now;
+use crate::ws_dto_models::ArchivedRoomDto;
+
+/// Rooms kept in memory for lookups, the oldest are dropped first
+const ARCHIVE_SIZE: usize = 10_000;
+/// Rooms closed longer ago can't be restored anymore
+pub const MAX_RESTORE_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug)]
+pub struct RoomArchive {
+    path: String,
+    /// Oldest first
+    rooms: Mutex<VecDeque<ArchivedRoomDto>>,
+}
+
+impl RoomArchive {
+    /// Reads the rooms archived by earlier runs, a missing file is not an error
+    pub fn load(path: &str) -> Self {
+        let mut rooms = VecDeque::new();
+        match std::fs::read_to_string(path) {
+            Ok(data) => {
+                for (index, line) in data.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                    match serde_json::from_str::<ArchivedRoomDto>(line) {
+                        Ok(room) => {
+                            if rooms.len() == ARCHIVE_SIZE {
+                                rooms.pop_front();
+                            }
+                            rooms.push_back(room);
+                        }
+                        Err(e) => tracing::warn!("Skipping malformed line {} of room archive {}: {}", index + 1, path, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::error!("Failed to read room archive {}: {}", path, e),
+        }
+        RoomArchive { path: path.to_string(), rooms: Mutex::new(rooms) }
+    }
+
+    pub async fn record(&self, room: ArchivedRoomDto) {
+        let mut rooms = self.rooms.lock().await;
+        // Written under the lock so lines of rooms closing at the same time don't interleave
+        if let Err(e) = self.append(&room).await {
+            tracing::error!(room_id = %room.room_id, "Failed to archive room: {:?}", e);
+        }
+        if rooms.len() == ARCHIVE_SIZE {
+            rooms.pop_front();
+        }
+        rooms.push_back(room);
+    }
+
+    async fn append(&self, room: &ArchivedRoomDto) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(room)?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Newest first, `room_id` picks the closings of one room
+    pub async fn list(&self, namespace: Option<&str>, room_id: Option<&str>) -> Vec<ArchivedRoomDto> {
+        self.rooms.lock().await.iter().rev()
+            .filter(|room| room.namespace.as_deref() == namespace && room_id.is_none_or(|room_id| room.room_id == room_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Latest closing of the room if it is recent enough to be restored
+    pub async fn restorable(&self, namespace: Option<&str>, room_id: &str) -> Option<ArchivedRoomDto> {
+        let oldest = unix_millis_now().saturating_sub(MAX_RESTORE_AGE.as_millis() as u64);
+        self.list(namespace, Some(room_id)).await.into_iter().next().filter(|room| room.closed_at >= oldest)
+    }
+}
@@ -0,0 +1,18 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::ws_app_state::WsAppState;
+use crate::ws_handler::send_room_digest;
+
+/// Sends the members of every open room the `RoomDigest` of their room. Clients which drifted
+/// apart from the server because of a missed or misapplied event notice it and ask for a snapshot,
+/// the others don't need to download the whole room again.
+pub async fn run_room_digests(state: Arc<WsAppState>, digest_interval: Duration) {
+    let mut interval = tokio::time::interval(digest_interval);
+    loop {
+        interval.tick().await;
+        for room in state.store.rooms().await {
+            // Rooms closed in the meantime are skipped
+            let _ = room.try_run(|room_data| send_room_digest(room_data)).await;
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Membership, ownership and playback rules of a room, free of connections and clocks, so they
+//! can be checked without sockets. `RoomData` and the message handlers apply them to the rooms.
+
+use crate::ws_dto_models::{OwnerSuccession, Role};
+
+/// What the rules need to know about a member of a room
+pub trait Member {
+    type JoinOrder: Ord;
+
+    fn role(&self) -> Role;
+    fn set_role(&mut self, role: Role);
+    fn is_spectator(&self) -> bool;
+    fn joined_at(&self) -> Self::JoinOrder;
+}
+
+/// Role of a member joining `members`. Rooms opened by the scheduler have no owner until somebody
+/// joins, spectators don't count.
+pub fn joining_member_role<M: Member>(members: &[M], admin_by_default: bool) -> Role {
+    if !members.iter().any(|member| member.role() == Role::Owner) {
+        Role::Owner
+    } else if admin_by_default {
+        Role::Admin
+    } else {
+        Role::Member
+    }
+}
+
+/// Makes the successor `succession` picks the owner and returns its index, nobody for `CloseRoom`
+pub fn hand_over_ownership<M: Member>(members: &mut [M], succession: OwnerSuccession) -> Option<usize> {
+    let longest_present = |admins_only: bool| members.iter()
+        .enumerate()
+        .filter(|(_, member)| !member.is_spectator() && (!admins_only || member.role() >= Role::Admin))
+        .min_by_key(|(_, member)| member.joined_at())
+        .map(|(index, _)| index);
+    let successor = match succession {
+        OwnerSuccession::LongestPresentMember => longest_present(false),
+        OwnerSuccession::LongestPresentAdmin => longest_present(true).or_else(|| longest_present(false)),
+        OwnerSuccession::CloseRoom => None,
+    };
+    if let Some(index) = successor {
+        members[index].set_role(Role::Owner);
+    }
+    successor
+}
+
+/// Makes `members[index]` the owner, the previous owner stays an admin
+pub fn transfer_ownership<M: Member>(members: &mut [M], index: usize) {
+    for (i, member) in members.iter_mut().enumerate() {
+        if i == index {
+            member.set_role(Role::Owner);
+        } else if member.role() == Role::Owner {
+            member.set_role(Role::Admin);
+        }
+    }
+}
+
+/// Whether the room is closed for everybody because its owner left
+pub fn closes_when_owner_left(succession: OwnerSuccession, members_left: usize) -> bool {
+    succession == OwnerSuccession::CloseRoom && members_left > 0
+}
+
+/// Play state changes the server makes on its own while members buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferingTransition {
+    PauseForBuffering,
+    ResumeAfterBuffering,
+}
+
+/// Rooms which allow stopping for video loading pause while anybody buffers and resume once
+/// nobody does, unless somebody paused in the meantime
+pub fn buffering_transition(anybody_buffering: bool, playing: bool, paused_for_buffering: bool) -> Option<BufferingTransition> {
+    if anybody_buffering && playing {
+        Some(BufferingTransition::PauseForBuffering)
+    } else if !anybody_buffering && paused_for_buffering {
+        Some(BufferingTransition::ResumeAfterBuffering)
+    } else {
+        None
+    }
+}
+
+/// Whether a member leaving a room with `pause_on_leave` pauses it. A room paused for buffering
+/// stays paused until somebody plays, not until the others are done buffering.
+pub fn pauses_on_leave(playing: bool, paused_for_buffering: bool) -> bool {
+    playing || paused_for_buffering
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::atomic::Ordering;
+use crate::ws_app_state::{Client, DisconnectReason, Room, RoomKey, WsAppState};
+use crate::ws_dto_models::{OwnerSuccession, Role, RoomHistoryEventDto};
+use crate::scheduler::unix_millis_now;
+use crate::protocol::OutgoingMessage;
+use crate::ws_handler::{broadcast_client_change, broadcast_room_change, close_room, handle_client_disconnect, response_with_json, send_signing_secret_to_controllers};
+
+const MAINTENANCE_TICK: Duration = Duration::from_secs(15);
+
+/// Periodic housekeeping of open rooms
+pub async fn run_room_maintenance(state: Arc<WsAppState>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_TICK);
+    loop {
+        interval.tick().await;
+        if let Some(timeout) = state.config().client_inactivity_timeout() {
+            disconnect_inactive_clients(&state, timeout, state.config().client_inactivity_warning()).await;
+        }
+
+        reap_ghost_clients(&state).await;
+        reap_orphaned_rooms(&state).await;
+        if let Some(ttl) = state.config().room_idle_ttl() {
+            close_idle_rooms(&state, ttl).await;
+        }
+
+        for room in state.store.rooms().await {
+            promote_long_present_members(&room).await;
+            let _ = room.run(|room_data| room_data.drop_expired_invites()).await;
+        }
+    }
+}
+
+/// Cleans up clients whose connection task is gone without running the regular disconnect, and
+/// clients pointing to a room which no longer exists or doesn't list them
+async fn reap_ghost_clients(state: &Arc<WsAppState>) {
+    let clients: Vec<Arc<Client>> = state.clients.snapshot();
+    // Snapshot, the rooms map must not be locked while holding client data
+    let rooms: HashMap<RoomKey, Arc<Room>> = state.store.rooms().await.into_iter().map(|room| (room.key(), room)).collect();
+    for client in clients {
+        // Detached clients are waiting to be resumed, their connection task removes them later
+        if client.detached.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        if client.is_connection_closed() {
+            tracing::warn!(client_uid = %client.uid, "Removing ghost client");
+            handle_client_disconnect(state, &client).await;
+            client.disconnect_signal.notify_one();
+            continue;
+        }
+
+        let mut client_data = client.data.lock().await;
+        let Some(room) = client_data.room.clone() else {
+            continue;
+        };
+        let room_exists = rooms.get(&room.key()).is_some_and(|existing_room| Arc::ptr_eq(existing_room, &room));
+        let member_client = client.clone();
+        let is_member = room_exists && room.run(move |room_data| room_data.find_room_client(&member_client).is_some()).await.unwrap_or(false);
+        if !is_member {
+            tracing::warn!(client_uid = %client.uid, room_id = %room.room_id, "Clearing stale room of client");
+            client.set_room(&mut client_data, None);
+        }
+    }
+}
+
+/// Drops members which are no longer connected and removes rooms left empty, except rooms opened
+/// for scheduled sessions which wait for their members
+async fn reap_orphaned_rooms(state: &Arc<WsAppState>) {
+    let scheduled_room_ids: Vec<String> = state
+        .scheduled_sessions
+        .lock()
+        .await
+        .values()
+        .map(|session| session.room_id.clone())
+        .collect();
+
+    for room in state.store.rooms().await {
+        let scheduled = scheduled_room_ids.contains(&room.room_id);
+        let now = Instant::now();
+        let connected_state = state.clone();
+        let result = room.run(move |room_data| {
+            let members_count = room_data.clients.len();
+            let had_owner = room_data.clients.iter().any(|room_client| room_client.is_owner());
+            room_data.clients.retain(|room_client| connected_state.clients.contains(&room_client.client));
+
+            let mut owner_gone = false;
+            if !room_data.clients.is_empty() && room_data.clients.len() != members_count {
+                if !room_data.clients.iter().any(|room_client| room_client.is_owner()) {
+                    owner_gone = had_owner && room_data.owner_succession == OwnerSuccession::CloseRoom;
+                    room_data.hand_over_ownership();
+                }
+                broadcast_room_change(room_data);
+            }
+            let waiting_for_members = room_data.starts_at.is_some() || room_data.restored_until.is_some_and(|until| until > now);
+            (!scheduled && !waiting_for_members && room_data.close_if_empty(), owner_gone)
+        }).await.unwrap_or((true, false));
+
+        match result {
+            (true, _) => {
+                if state.store.remove_room(&room).await {
+                    tracing::warn!(room_id = %room.room_id, "Removing orphaned room");
+                    state.forget_room(&room).await;
+                    state.archive_room(&room).await;
+                }
+            }
+            (false, true) => {
+                tracing::info!(room_id = %room.room_id, "Closing the room the owner left");
+                if let Err(e) = close_room(state, &room).await {
+                    tracing::error!("Error while closing the room the owner left: {:?}", e);
+                }
+            }
+            (false, false) => {}
+        }
+    }
+}
+
+/// Closes rooms nobody has used for `ttl`, members still in them are told with `RoomClosed`
+async fn close_idle_rooms(state: &Arc<WsAppState>, ttl: Duration) {
+    let scheduled_room_ids: Vec<String> = state
+        .scheduled_sessions
+        .lock()
+        .await
+        .values()
+        .map(|session| session.room_id.clone())
+        .collect();
+    let idle_since = unix_millis_now().saturating_sub(ttl.as_millis() as u64);
+
+    for room in state.store.rooms().await {
+        if scheduled_room_ids.contains(&room.room_id) {
+            continue;
+        }
+        let Ok((last_activity_at, member_uids)) = room.run(|room_data| {
+            (room_data.last_activity_at, room_data.clients.iter().map(|room_client| room_client.client.uid).collect::<Vec<_>>())
+        }).await else {
+            continue;
+        };
+        if last_activity_at > idle_since {
+            continue;
+        }
+
+        tracing::info!(room_id = %room.room_id, "Closing idle room");
+        if let Err(e) = close_room(state, &room).await {
+            tracing::error!("Error while closing idle room: {:?}", e);
+            continue;
+        }
+        // Members waiting to be resumed would come back to nothing, they are let go right away
+        for client in member_uids.into_iter().filter_map(|uid| state.find_client(uid)) {
+            if client.detached.load(Ordering::SeqCst) {
+                handle_client_disconnect(state, &client).await;
+            }
+        }
+        state.restored_members.lock().await.retain(|_, member| member.namespace != room.namespace || member.room_id != room.room_id);
+    }
+}
+
+async fn disconnect_inactive_clients(state: &WsAppState, timeout: Duration, warning_lead_time: Option<Duration>) {
+    let warning_lead_time = warning_lead_time.map(|warning_lead_time| warning_lead_time.min(timeout / 2));
+    let clients: Vec<Arc<Client>> = state.clients.snapshot();
+    for client in clients.iter().filter(|client| !client.detached.load(Ordering::SeqCst)) {
+        let idle_for = client.idle_for();
+        if idle_for >= timeout {
+            client.disconnect(DisconnectReason::Inactive, "Inactivity timeout", Some(state.reconnect_retry_after()));
+        } else if warning_lead_time.is_some_and(|warning_lead_time| idle_for + warning_lead_time >= timeout)
+            && !client.inactivity_warned.swap(true, Ordering::Relaxed) {
+            response_with_json(client, OutgoingMessage::InactivityWarning {
+                disconnect_in_secs: (timeout - idle_for).as_secs(),
+            });
+        }
+    }
+}
+
+async fn promote_long_present_members(room: &Room) {
+    let result = room.run(|room_data| {
+        let Some(auto_admin_after) = room_data.auto_admin_after else {
+            return;
+        };
+
+        let mut promoted_uids = Vec::new();
+        for room_client in room_data.clients.iter_mut().filter(|room_client| room_client.role == Role::Member) {
+            if room_client.joined_at.elapsed() >= auto_admin_after {
+                room_client.role = Role::Admin;
+                promoted_uids.push(room_client.client.uid);
+            }
+        }
+
+        if !promoted_uids.is_empty() {
+            send_signing_secret_to_controllers(room_data);
+            for client_uid in promoted_uids {
+                room_data.record_history(None, RoomHistoryEventDto::RoleChanged { target_uid: client_uid, role: Role::Admin });
+                broadcast_client_change(room_data, client_uid);
+            }
+        }
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Error while promoting members: {:?}", e);
+    }
+}
@@ -0,0 +1,285 @@
+//! Rooms written to `ServerConfig::snapshot_path` periodically and on shutdown, then opened again
+//! when the server starts. The key signing resume tokens is saved with them, so members who
+//! reconnect with `Resume` land back in their room under their old uid.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use rocket::serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::scheduler::unix_millis_now;
+use crate::command_signing::{from_hex, to_hex, SIGNING_SECRET_SIZE};
+use crate::ws_app_state::{Room, RoomBan, RoomData, RoomKey, WsAppState};
+use crate::ws_dto_models::{ControlMode, DuplicateNames, OwnerSuccession, PermissionPreset, Role, RoomRoleDto};
+
+const SNAPSHOT_VERSION: u32 = 1;
+/// Restored rooms wait this long for their members before they are reaped like any empty room
+pub const RESTORED_ROOM_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct ServerSnapshot {
+    version: u32,
+    /// Hex, see `WsAppState::resume_token`
+    resume_secret: String,
+    rooms: Vec<RoomSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct RoomSnapshot {
+    #[serde(default)]
+    namespace: Option<String>,
+    room_id: String,
+    creator_uid: Option<Uuid>,
+    creator_ip: Option<IpAddr>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    public: bool,
+    #[serde(default)]
+    voice_enabled: bool,
+    #[serde(default)]
+    pause_on_leave: bool,
+    #[serde(default)]
+    locked: bool,
+    #[serde(default)]
+    max_clients: Option<usize>,
+    #[serde(default)]
+    duplicate_names: DuplicateNames,
+    #[serde(default)]
+    owner_succession: OwnerSuccession,
+    /// Snapshots of older versions don't have it, the restored room counts as new then
+    #[serde(default)]
+    created_at: Option<u64>,
+    page_url: Option<String>,
+    queue: Vec<String>,
+    allow_stop_due_to_video_loading: bool,
+    end_to_end_encrypted: bool,
+    require_signed_commands: bool,
+    aliases: Vec<String>,
+    bans: Vec<RoomBan>,
+    roles: Vec<RoomRoleDto>,
+    permission_preset: PermissionPreset,
+    /// Snapshots of older versions don't have it, the preset decides then
+    #[serde(default)]
+    control_mode: Option<ControlMode>,
+    auto_admin_after_secs: Option<u64>,
+    /// Seconds, the room is restored paused there
+    position: f64,
+    members: Vec<MemberSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct MemberSnapshot {
+    uid: Uuid,
+    name: Option<String>,
+    /// Missing in snapshots written before roles, the flags are used then
+    #[serde(default)]
+    role: Option<Role>,
+    owner: bool,
+    admin: bool,
+    roles: Vec<String>,
+}
+
+/// Member of a restored room who hasn't reconnected yet
+#[derive(Debug, Clone)]
+pub struct RestoredMember {
+    pub namespace: Option<String>,
+    pub room_id: String,
+    pub name: Option<String>,
+    pub role: Role,
+    pub roles: Vec<String>,
+    pub expires_at: Instant,
+}
+
+/// Reads the snapshot left by the previous run, a missing file is not an error
+pub fn load(path: &str) -> Option<ServerSnapshot> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::error!("Failed to read room snapshot {}: {}", path, e);
+            return None;
+        }
+    };
+    match serde_json::from_slice::<ServerSnapshot>(&data) {
+        Ok(snapshot) if snapshot.version == SNAPSHOT_VERSION => Some(snapshot),
+        Ok(snapshot) => {
+            tracing::error!("Ignoring room snapshot {} of unsupported version {}", path, snapshot.version);
+            None
+        }
+        Err(e) => {
+            tracing::error!("Ignoring malformed room snapshot {}: {}", path, e);
+            None
+        }
+    }
+}
+
+impl ServerSnapshot {
+    pub fn resume_secret(&self) -> Option<[u8; SIGNING_SECRET_SIZE]> {
+        from_hex(&self.resume_secret)?.try_into().ok()
+    }
+}
+
+/// Opens the rooms of the snapshot which had members, their members may come back with `Resume`
+pub async fn restore(state: &WsAppState, snapshot: ServerSnapshot) {
+    let expires_at = Instant::now() + RESTORED_ROOM_GRACE_PERIOD;
+    let mut restored_rooms = 0;
+    for room_snapshot in snapshot.rooms.into_iter().filter(|room_snapshot| !room_snapshot.members.is_empty()) {
+        let mut room_data = RoomData {
+            title: room_snapshot.title,
+            description: room_snapshot.description,
+            public: room_snapshot.public,
+            voice_enabled: room_snapshot.voice_enabled,
+            pause_on_leave: room_snapshot.pause_on_leave,
+            locked: room_snapshot.locked,
+            max_clients: room_snapshot.max_clients,
+            duplicate_names: room_snapshot.duplicate_names,
+            owner_succession: room_snapshot.owner_succession,
+            created_at: room_snapshot.created_at.unwrap_or_else(unix_millis_now),
+            page_url: room_snapshot.page_url,
+            queue: room_snapshot.queue,
+            allow_stop_due_to_video_loading: room_snapshot.allow_stop_due_to_video_loading,
+            end_to_end_encrypted: room_snapshot.end_to_end_encrypted,
+            require_signed_commands: room_snapshot.require_signed_commands,
+            aliases: room_snapshot.aliases.clone(),
+            bans: room_snapshot.bans,
+            roles: room_snapshot.roles,
+            permission_preset: room_snapshot.permission_preset,
+            control_mode: room_snapshot.control_mode.unwrap_or(room_snapshot.permission_preset.control_mode()),
+            auto_admin_after: room_snapshot.auto_admin_after_secs.map(Duration::from_secs),
+            restored_until: Some(expires_at),
+            ..RoomData::new()
+        };
+        room_data.playback.update(Some(room_snapshot.position), Some(false));
+
+        let (namespace, room_id) = (room_snapshot.namespace, room_snapshot.room_id);
+        let room = Arc::new(Room::spawn(namespace.clone(), room_id.clone(), room_data, room_snapshot.creator_uid, room_snapshot.creator_ip));
+        if state.store.insert_room(room.clone()).await.is_err() {
+            continue;
+        }
+        state.attach_room(&room);
+        restored_rooms += 1;
+
+        {
+            let mut room_aliases = state.room_aliases.lock().await;
+            for alias in room_snapshot.aliases {
+                room_aliases.insert((namespace.clone(), alias), room_id.clone());
+            }
+        }
+        let mut restored_members = state.restored_members.lock().await;
+        for member in room_snapshot.members {
+            restored_members.insert(member.uid, RestoredMember {
+                namespace: namespace.clone(),
+                room_id: room_id.clone(),
+                name: member.name,
+                role: member.role.unwrap_or(Role::from_flags(member.owner, member.admin)),
+                roles: member.roles,
+                expires_at,
+            });
+        }
+    }
+    tracing::info!("Restored {} rooms", restored_rooms);
+}
+
+async fn snapshot(state: &WsAppState) -> ServerSnapshot {
+    let now = Instant::now();
+    let mut pending_members: HashMap<RoomKey, Vec<MemberSnapshot>> = HashMap::new();
+    {
+        let mut restored_members = state.restored_members.lock().await;
+        restored_members.retain(|_, member| member.expires_at > now);
+        for (uid, member) in restored_members.iter() {
+            pending_members.entry((member.namespace.clone(), member.room_id.clone())).or_default().push(MemberSnapshot {
+                uid: *uid,
+                name: member.name.clone(),
+                role: Some(member.role),
+                owner: member.role == Role::Owner,
+                admin: member.role >= Role::Admin,
+                roles: member.roles.clone(),
+            });
+        }
+    }
+
+    let mut rooms = Vec::new();
+    for room in state.store.rooms().await {
+        let pending_members = pending_members.remove(&room.key()).unwrap_or_default();
+        let (namespace, room_id, creator_uid, creator_ip) = (room.namespace.clone(), room.room_id.clone(), room.creator_uid, room.creator_ip);
+        let room_snapshot = room.run(move |room_data| {
+            let mut members: Vec<MemberSnapshot> = room_data.clients.iter().map(|room_client| MemberSnapshot {
+                uid: room_client.client.uid,
+                name: room_client.name.clone(),
+                role: Some(room_client.role),
+                owner: room_client.is_owner(),
+                admin: room_client.is_admin(),
+                roles: room_client.roles.clone(),
+            }).collect();
+            members.extend(pending_members);
+            RoomSnapshot {
+                namespace,
+                room_id,
+                creator_uid,
+                creator_ip,
+                title: room_data.title.clone(),
+                description: room_data.description.clone(),
+                public: room_data.public,
+                voice_enabled: room_data.voice_enabled,
+                pause_on_leave: room_data.pause_on_leave,
+                locked: room_data.locked,
+                max_clients: room_data.max_clients,
+                duplicate_names: room_data.duplicate_names,
+                owner_succession: room_data.owner_succession,
+                created_at: Some(room_data.created_at),
+                page_url: room_data.page_url.clone(),
+                queue: room_data.queue.clone(),
+                allow_stop_due_to_video_loading: room_data.allow_stop_due_to_video_loading,
+                end_to_end_encrypted: room_data.end_to_end_encrypted,
+                require_signed_commands: room_data.require_signed_commands,
+                aliases: room_data.aliases.clone(),
+                bans: room_data.bans.clone(),
+                roles: room_data.roles.clone(),
+                permission_preset: room_data.permission_preset,
+                control_mode: Some(room_data.control_mode),
+                auto_admin_after_secs: room_data.auto_admin_after.map(|after| after.as_secs()),
+                position: room_data.playback.current_position(),
+                members,
+            }
+        }).await;
+        // A room whose task is gone is about to be dropped anyway
+        if let Ok(room_snapshot) = room_snapshot {
+            rooms.push(room_snapshot);
+        }
+    }
+
+    ServerSnapshot {
+        version: SNAPSHOT_VERSION,
+        resume_secret: to_hex(state.resume_secret()),
+        rooms,
+    }
+}
+
+/// Replaces the file in one step, a crash while writing leaves the previous snapshot intact
+pub async fn save(state: &WsAppState, path: &str) -> Result<()> {
+    let data = serde_json::to_vec(&snapshot(state).await)?;
+    let temp_path = format!("{}.tmp", path);
+    tokio::fs::write(&temp_path, data).await.map_err(|e| anyhow!("Failed to write {}: {}", temp_path, e))?;
+    tokio::fs::rename(&temp_path, path).await.map_err(|e| anyhow!("Failed to replace {}: {}", path, e))?;
+    Ok(())
+}
+
+pub async fn run_room_snapshots(state: Arc<WsAppState>, path: String, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes right away, there is nothing new to save yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = save(&state, &path).await {
+            tracing::error!("Failed to save room snapshot: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use crate::push_notifications::PushNotification;
+use crate::ws_app_state::{Room, RoomData, ScheduledSession, WsAppState};
+use crate::ws_dto_models::ScheduledSessionDto;
+use crate::protocol::OutgoingMessage;
+use crate::ws_handler::{broadcast_room_change, response_with_json};
+
+/// How long before `starts_at` the room is opened and members are notified
+pub const SESSION_OPEN_LEAD_TIME: Duration = Duration::from_secs(5 * 60);
+/// Activated sessions are forgotten (and their room removed if nobody showed up) after this long
+pub const SESSION_RETENTION_AFTER_START: Duration = Duration::from_secs(60 * 60);
+const SCHEDULER_TICK: Duration = Duration::from_secs(5);
+
+pub fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Sessions which have not been forgotten yet, passing `filter` and visible to `viewer`, soonest first
+pub async fn upcoming_sessions(state: &WsAppState, viewer: Option<Uuid>, filter: impl Fn(&ScheduledSession) -> bool) -> Vec<ScheduledSessionDto> {
+    let mut sessions: Vec<ScheduledSessionDto> = state
+        .scheduled_sessions
+        .lock()
+        .await
+        .values()
+        .filter(|session| session.visible_to(viewer) && filter(session))
+        .map(|session| ScheduledSessionDto::for_viewer(session, viewer))
+        .collect();
+    sessions.sort_by_key(|session| session.starts_at);
+    sessions
+}
+
+pub async fn run_session_scheduler(state: Arc<WsAppState>) {
+    let mut interval = tokio::time::interval(SCHEDULER_TICK);
+    loop {
+        interval.tick().await;
+        activate_due_sessions(&state).await;
+        start_scheduled_rooms(&state).await;
+        expire_past_sessions(&state).await;
+    }
+}
+
+async fn activate_due_sessions(state: &Arc<WsAppState>) {
+    let open_before = unix_millis_now() + SESSION_OPEN_LEAD_TIME.as_millis() as u64;
+    let due_sessions: Vec<ScheduledSession> = state
+        .scheduled_sessions
+        .lock()
+        .await
+        .values_mut()
+        .filter(|session| !session.activated && session.starts_at <= open_before)
+        .map(|session| {
+            session.activated = true;
+            session.clone()
+        })
+        .collect();
+
+    for session in due_sessions {
+        // Subscribers of a room somebody else opened in the meantime aren't bothered
+        let mut opened = false;
+        if state.store.room(session.namespace.as_deref(), &session.room_id).await.is_none() {
+            let room = Arc::new(Room::with_data(session.namespace.clone(), session.room_id.clone(), RoomData {
+                page_url: session.page_url.clone(),
+                ..RoomData::new()
+            }));
+            if state.store.insert_room(room.clone()).await.is_ok() {
+                state.attach_room(&room);
+                opened = true;
+            }
+        }
+
+        // The title of a private session is only for its invitees
+        if opened && session.public {
+            state.push_notifier.notify_room(PushNotification {
+                namespace: session.namespace.clone(),
+                room_id: session.room_id.clone(),
+                title: format!("{} starts soon", session.title),
+                body: format!("Room {} is open, join before the start", session.room_id),
+            }).await;
+        }
+
+        for uid in session.invited_uids.iter() {
+            if let Some(client) = state.find_client(*uid) {
+                let session_dto = ScheduledSessionDto::for_viewer(&session, Some(*uid));
+                response_with_json(&client, OutgoingMessage::ScheduledSessionStarting { session: session_dto });
+            }
+        }
+    }
+}
+
+/// Opens the rooms created with `ScheduleRoom` whose start has come
+async fn start_scheduled_rooms(state: &Arc<WsAppState>) {
+    let now = unix_millis_now();
+    let mut due_rooms = Vec::new();
+    state.scheduled_rooms.lock().await.retain(|key, starts_at| {
+        let due = *starts_at <= now;
+        if due {
+            due_rooms.push(key.clone());
+        }
+        !due
+    });
+
+    for (namespace, room_id) in due_rooms {
+        let Some(room) = state.store.room(namespace.as_deref(), &room_id).await else {
+            continue;
+        };
+        let started = room.run(|room_data| {
+            room_data.starts_at = None;
+            room_data.restored_until = Some(Instant::now() + SESSION_RETENTION_AFTER_START);
+            broadcast_room_change(room_data);
+        }).await;
+        if let Err(e) = started {
+            tracing::error!(%room_id, "Failed to start scheduled room: {:?}", e);
+            continue;
+        }
+        tracing::info!(%room_id, "Scheduled room started");
+
+        state.push_notifier.notify_room(PushNotification {
+            namespace: namespace.clone(),
+            room_id: room_id.clone(),
+            title: "Watch party is live".to_string(),
+            body: format!("Room {} is open, join now", room_id),
+        }).await;
+    }
+}
+
+async fn expire_past_sessions(state: &Arc<WsAppState>) {
+    let now = unix_millis_now();
+    let retention = SESSION_RETENTION_AFTER_START.as_millis() as u64;
+
+    let mut expired_rooms = Vec::new();
+    state.scheduled_sessions.lock().await.retain(|_, session| {
+        let expired = session.activated && session.starts_at + retention <= now;
+        if expired {
+            expired_rooms.push((session.namespace.clone(), session.room_id.clone()));
+        }
+        !expired
+    });
+
+    for (namespace, room_id) in expired_rooms {
+        let Some(room) = state.store.room(namespace.as_deref(), &room_id).await else {
+            state.push_notifier.remove_room(&(namespace, room_id)).await;
+            continue;
+        };
+        if room.run(|room_data| room_data.close_if_empty()).await.unwrap_or(true) && state.store.remove_room(&room).await {
+            state.forget_room(&room).await;
+            state.archive_room(&room).await;
+        }
+    }
+}
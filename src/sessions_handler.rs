@@ -0,0 +1,38 @@
+use std::sync::Arc;
+use rocket::http::ContentType;
+use rocket::serde::json::Json;
+use rocket::State;
+use uuid::Uuid;
+use crate::calendar::sessions_to_ics;
+use crate::scheduler::upcoming_sessions;
+use crate::ws_app_state::WsAppState;
+use crate::ws_dto_models::ScheduledSessionDto;
+
+/// Public sessions of the namespace
+#[get("/sessions?<namespace>")]
+pub async fn list_upcoming_sessions(namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> Json<Vec<ScheduledSessionDto>> {
+    Json(upcoming_sessions(state, None, |session| session.namespace.as_deref() == namespace).await)
+}
+
+#[get("/rooms/<room_id>/calendar.ics?<namespace>")]
+pub async fn room_calendar(room_id: &str, namespace: Option<&str>, state: &State<Arc<WsAppState>>) -> (ContentType, String) {
+    let sessions = upcoming_sessions(state, None, |session| {
+        session.namespace.as_deref() == namespace && session.room_id == room_id
+    }).await;
+
+    (ContentType::Calendar, sessions_to_ics(&format!("Room {}", room_id), &sessions))
+}
+
+/// Only reachable with the token of the `CalendarUrl` handed to the client
+#[get("/users/<uid>/calendar.ics?<token>")]
+pub async fn user_calendar(uid: &str, token: Option<&str>, state: &State<Arc<WsAppState>>) -> Option<(ContentType, String)> {
+    let uid = Uuid::parse_str(uid).ok()?;
+    if !state.verify_calendar_token(uid, token?) {
+        return None;
+    }
+    let sessions = upcoming_sessions(state, Some(uid), |session| {
+        session.created_by == uid || session.invited_uids.contains(&uid)
+    }).await;
+
+    Some((ContentType::Calendar, sessions_to_ics("Watch parties", &sessions)))
+}
@@ -0,0 +1,191 @@
+//! Machine-readable description of the server for client authors: AsyncAPI for the WebSocket
+//! protocol and OpenAPI for the `/api` routes. The schemas come from the ts-rs declarations of the
+//! types, see `json_schema`, and the paths from the mounted routes.
+
+use anyhow::Result;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+use serde_json::{json, Map, Value};
+use crate::admin_handler::ServerNoticeRequest;
+use crate::json_schema::Schemas;
+use crate::protocol::{IncomingMessage, OutgoingMessage};
+use crate::ws_dto_models::{AbuseReportDto, AdminClientDto, AdminRoomDetailsDto, AdminRoomDto, ArchivedRoomDto, ConfigReloadDto, DrainStatusDto, PublicRoomDto, RoomHistoryEntryDto, RoomInfoDto, ServerStatsDto};
+
+const TITLE: &str = "Sent sync server";
+
+/// Status codes an operation answers with, with the schema of the body if it has one
+type Responses = Vec<(Status, Option<Value>)>;
+
+/// Documents built once the routes are mounted, see `build_rocket`
+pub struct ApiSpec {
+    asyncapi: Value,
+    openapi: Value,
+}
+
+impl ApiSpec {
+    pub fn new<'a>(routes: impl Iterator<Item = &'a Route>) -> Result<Self> {
+        Ok(ApiSpec { asyncapi: asyncapi_document()?, openapi: openapi_document(routes)? })
+    }
+}
+
+#[get("/api/spec")]
+pub fn spec_index() -> Json<Value> {
+    Json(json!({
+        "asyncapi": "/api/spec/asyncapi.json",
+        "openapi": "/api/spec/openapi.json",
+    }))
+}
+
+#[get("/api/spec/asyncapi.json")]
+pub fn asyncapi_spec(spec: &State<ApiSpec>) -> Json<Value> {
+    Json(spec.asyncapi.clone())
+}
+
+#[get("/api/spec/openapi.json")]
+pub fn openapi_spec(spec: &State<ApiSpec>) -> Json<Value> {
+    Json(spec.openapi.clone())
+}
+
+fn asyncapi_document() -> Result<Value> {
+    let mut schemas = Schemas::default();
+    let incoming = schemas.reference::<IncomingMessage>();
+    let outgoing = schemas.reference::<OutgoingMessage>();
+    let request_id = json!({
+        "type": "object",
+        "properties": {
+            "id": { "type": "integer", "description": "Echoed by the Success, Error or data message answering it" },
+        },
+    });
+
+    Ok(json!({
+        "asyncapi": "2.6.0",
+        "info": {
+            "title": TITLE,
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Watch party synchronization. Messages are JSON text frames, or MessagePack binary frames once negotiated in Hello.",
+        },
+        "defaultContentType": "application/json",
+        "channels": {
+            "/ws": {
+                "publish": {
+                    "summary": "Messages sent by clients",
+                    "message": { "name": "IncomingMessage", "payload": { "allOf": [incoming, request_id] } },
+                },
+                "subscribe": {
+                    "summary": "Messages sent by the server",
+                    "message": { "name": "OutgoingMessage", "payload": { "allOf": [outgoing, request_id] } },
+                },
+            },
+        },
+        "components": { "schemas": schemas.into_map()? },
+    }))
+}
+
+fn openapi_document<'a>(routes: impl Iterator<Item = &'a Route>) -> Result<Value> {
+    let mut schemas = Schemas::default();
+    let mut paths = Map::new();
+    for route in routes.filter(|route| route.uri.path().starts_with("/api/")) {
+        let (path, mut parameters) = path_parameters(route.uri.path());
+        parameters.extend(query_parameters(route.uri.query()));
+        let mut operation = route.name.as_deref().map(|name| api_operation(name, &mut schemas)).unwrap_or_else(|| json!({ "responses": {} }));
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        let path_item = paths.entry(path).or_insert_with(|| json!({}));
+        path_item[route.method.as_str().to_ascii_lowercase()] = operation;
+    }
+
+    Ok(json!({
+        "openapi": "3.1.0",
+        "info": { "title": TITLE, "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": {
+            "schemas": schemas.into_map()?,
+            "securitySchemes": {
+                "adminToken": { "type": "http", "scheme": "bearer", "description": "The admin_token of the configuration" },
+            },
+        },
+    }))
+}
+
+/// `/api/rooms/<room_id>` as `/api/rooms/{room_id}` and its parameters
+fn path_parameters(path: &str) -> (String, Vec<Value>) {
+    let mut parameters = Vec::new();
+    let segments: Vec<String> = path.split('/').map(|segment| {
+        match segment.strip_prefix('<').and_then(|segment| segment.strip_suffix('>')) {
+            Some(name) => {
+                let name = name.trim_end_matches("..");
+                parameters.push(json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }));
+                format!("{{{}}}", name)
+            }
+            None => segment.to_string(),
+        }
+    }).collect();
+    (segments.join("/"), parameters)
+}
+
+/// The query parameters of the routes are all optional
+fn query_parameters(query: Option<&str>) -> Vec<Value> {
+    query.unwrap_or_default()
+        .split('&')
+        .filter_map(|field| field.strip_prefix('<')?.strip_suffix('>'))
+        .map(|name| json!({ "name": name, "in": "query", "required": false, "schema": { "type": "string" } }))
+        .collect()
+}
+
+/// Operation of the route with the given handler name, with the types it reads and answers with
+fn api_operation(handler: &str, schemas: &mut Schemas) -> Value {
+    let (summary, admin, request, responses): (&str, bool, Option<Value>, Responses) = match handler {
+        "list_public_rooms" => ("Rooms listed in the public directory", false, None, vec![(Status::Ok, Some(schemas.array_of::<PublicRoomDto>()))]),
+        "get_room_info" => ("Summary of a room shown before joining it", false, None, vec![(Status::Ok, Some(schemas.reference::<RoomInfoDto>())), (Status::NotFound, None)]),
+        "get_stats" => ("Server or namespace statistics, rooms are listed with the admin token", false, None, vec![(Status::Ok, Some(schemas.reference::<ServerStatsDto>()))]),
+        "list_rooms" => ("Open rooms", true, None, vec![(Status::Ok, Some(schemas.array_of::<AdminRoomDto>()))]),
+        "get_room" => ("Room with its members", true, None, vec![(Status::Ok, Some(schemas.reference::<AdminRoomDetailsDto>())), (Status::NotFound, None)]),
+        "get_room_history" => ("Recent events of a room", true, None, vec![(Status::Ok, Some(schemas.array_of::<RoomHistoryEntryDto>())), (Status::NotFound, None)]),
+        "delete_room" => ("Closes a room, the members stay connected", true, None, vec![(Status::NoContent, None), (Status::NotFound, None)]),
+        "list_clients" => ("Connected clients", true, None, vec![(Status::Ok, Some(schemas.array_of::<AdminClientDto>()))]),
+        "delete_client" => ("Disconnects a client", true, None, vec![(Status::NoContent, None), (Status::NotFound, None)]),
+        "reload_server_config" => (
+            "Applies the changed settings of the config file",
+            true,
+            None,
+            vec![(Status::Ok, Some(schemas.reference::<ConfigReloadDto>())), (Status::UnprocessableEntity, None), (Status::NotImplemented, None)],
+        ),
+        "list_abuse_reports" => ("Reports of clients by other members", true, None, vec![(Status::Ok, Some(schemas.array_of::<AbuseReportDto>()))]),
+        "send_server_notice" => (
+            "Sends a notice to every client or to the members of a room",
+            true,
+            Some(schemas.reference::<ServerNoticeRequest>()),
+            vec![(Status::NoContent, None), (Status::NotFound, None), (Status::UnprocessableEntity, None)],
+        ),
+        "start_draining" => ("Stops new rooms from being joined or opened", true, None, vec![(Status::Ok, Some(schemas.reference::<DrainStatusDto>()))]),
+        "stop_draining" => ("Leaves drain mode", true, None, vec![(Status::Ok, Some(schemas.reference::<DrainStatusDto>()))]),
+        "list_archived_rooms" => (
+            "Rooms saved when they closed",
+            true,
+            None,
+            vec![(Status::Ok, Some(schemas.array_of::<ArchivedRoomDto>())), (Status::NotImplemented, None)],
+        ),
+        "spec_index" | "asyncapi_spec" | "openapi_spec" => ("Description of the API", false, None, vec![(Status::Ok, Some(json!({ "type": "object" })))]),
+        _ => (handler, false, None, Vec::new()),
+    };
+
+    let mut responses: Map<String, Value> = responses.into_iter().map(|(status, body)| {
+        let mut response = json!({ "description": status.reason().unwrap_or_default() });
+        if let Some(body) = body {
+            response["content"] = json!({ "application/json": { "schema": body } });
+        }
+        (status.code.to_string(), response)
+    }).collect();
+    let mut operation = json!({ "operationId": handler, "summary": summary });
+    if admin {
+        responses.insert(Status::Unauthorized.code.to_string(), json!({ "description": "Missing or wrong admin token" }));
+        operation["security"] = json!([{ "adminToken": [] }]);
+    }
+    if let Some(request) = request {
+        operation["requestBody"] = json!({ "required": true, "content": { "application/json": { "schema": request } } });
+    }
+    operation["responses"] = Value::Object(responses);
+    operation
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::ws_app_state::{Room, RoomKey};
+use crate::ws_dto_models::PlaybackStateDto;
+
+/// What is shown about a room outside of it
+#[derive(Debug, Clone)]
+pub struct RoomSummary {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub members_count: usize,
+    pub page_url: Option<String>,
+    pub playback: PlaybackStateDto,
+}
+
+/// Where the open rooms are kept, by namespace and id. Membership and playback state live in the data
+/// of each room and are reached through its task, see `Room::run`.
+///
+/// Rooms are taken out of the store only once they are closed (see `RoomData::closed`), so whoever
+/// finds a closed room can remove it and look again.
+#[rocket::async_trait]
+pub trait StateStore: Send + Sync + Debug {
+    async fn room(&self, namespace: Option<&str>, room_id: &str) -> Option<Arc<Room>>;
+
+    async fn rooms(&self) -> Vec<Arc<Room>>;
+
+    async fn rooms_count(&self) -> usize;
+
+    /// Lists the room under its namespace and id, the room already listed there is returned if the
+    /// id is taken
+    async fn insert_room(&self, room: Arc<Room>) -> Result<(), Arc<Room>>;
+
+    /// Removes the room if it's still the one listed under its id
+    async fn remove_room(&self, room: &Arc<Room>) -> bool;
+
+    /// `None` when there is no such room or its task is gone
+    async fn room_summary(&self, namespace: Option<&str>, room_id: &str) -> Option<RoomSummary> {
+        let room = self.room(namespace, room_id).await?;
+        room.run(|room_data| RoomSummary {
+            title: room_data.title.clone(),
+            description: room_data.description.clone(),
+            members_count: room_data.clients.len(),
+            page_url: room_data.page_url.clone(),
+            playback: PlaybackStateDto::from(&room_data.playback),
+        }).await.ok()
+    }
+}
+
+/// Rooms of this instance only, lost on restart
+#[derive(Debug, Default)]
+pub struct InMemoryStateStore {
+    rooms: Mutex<HashMap<RoomKey, Arc<Room>>>,
+}
+
+#[rocket::async_trait]
+impl StateStore for InMemoryStateStore {
+    async fn room(&self, namespace: Option<&str>, room_id: &str) -> Option<Arc<Room>> {
+        self.rooms.lock().await.get(&(namespace.map(str::to_string), room_id.to_string())).cloned()
+    }
+
+    async fn rooms(&self) -> Vec<Arc<Room>> {
+        self.rooms.lock().await.values().cloned().collect()
+    }
+
+    async fn rooms_count(&self) -> usize {
+        self.rooms.lock().await.len()
+    }
+
+    async fn insert_room(&self, room: Arc<Room>) -> Result<(), Arc<Room>> {
+        let mut rooms = self.rooms.lock().await;
+        let key = room.key();
+        if let Some(existing_room) = rooms.get(&key) {
+            return Err(existing_room.clone());
+        }
+        rooms.insert(key, room);
+        Ok(())
+    }
+
+    async fn remove_room(&self, room: &Arc<Room>) -> bool {
+        let mut rooms = self.rooms.lock().await;
+        let key = room.key();
+        if !rooms.get(&key).is_some_and(|existing_room| Arc::ptr_eq(existing_room, room)) {
+            return false;
+        }
+        rooms.remove(&key);
+        true
+    }
+}
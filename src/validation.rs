@@ -0,0 +1,138 @@
+//! Rules for display names, room ids, namespaces and page URLs chosen by clients, see the `ServerConfig` fields they take
+//! their limits from. Lengths are counted in user-perceived characters, so a flag or an accented
+//! letter written with a combining mark counts once.
+
+use rocket::serde::{Deserialize, Serialize};
+use url::Url;
+use crate::config::ServerConfig;
+use crate::display_name::is_combining_mark;
+use crate::protocol::ErrorKind;
+
+/// Characters a name or a room id may consist of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum CharacterPolicy {
+    /// Anything printable
+    #[default]
+    Any,
+    /// Letters and digits of any script, spaces, `-`, `_` and `.`
+    Alphanumeric,
+    /// ASCII letters and digits, `-` and `_`, for ids that end up in URLs unescaped
+    UrlSafe,
+}
+
+impl CharacterPolicy {
+    fn allows(self, c: char) -> bool {
+        match self {
+            CharacterPolicy::Any => !c.is_control(),
+            CharacterPolicy::Alphanumeric => c.is_alphanumeric() || is_combining_mark(c) || matches!(c, ' ' | '-' | '_' | '.'),
+            CharacterPolicy::UrlSafe => c.is_ascii_alphanumeric() || matches!(c, '-' | '_'),
+        }
+    }
+}
+
+/// Number of user-perceived characters. Approximates the extended grapheme clusters of UAX #29
+/// for what shows up in names: combining marks, emoji modifiers and sequences, and flags.
+pub fn grapheme_count(text: &str) -> usize {
+    let mut count = 0;
+    let mut joined = false;
+    let mut regional_indicator_pending = false;
+    for c in text.chars() {
+        let extends = is_combining_mark(c) || is_emoji_modifier(c) || matches!(c, '\u{200D}' | '\u{FE00}'..='\u{FE0F}' | '\u{E0020}'..='\u{E007F}');
+        if extends || joined {
+            joined = c == '\u{200D}';
+            continue;
+        }
+
+        // Flags are pairs of regional indicators
+        if is_regional_indicator(c) {
+            regional_indicator_pending = !regional_indicator_pending;
+            if !regional_indicator_pending {
+                continue;
+            }
+        } else {
+            regional_indicator_pending = false;
+        }
+        count += 1;
+    }
+    count
+}
+
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(c, '\u{1F3FB}'..='\u{1F3FF}')
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+}
+
+/// Name as it is stored, `name` is expected to be sanitized already
+pub fn validate_name(config: &ServerConfig, name: &str) -> Result<String, ErrorKind> {
+    let name = name.trim();
+    let length = grapheme_count(name);
+    if length < config.min_name_length {
+        Err(ErrorKind::ClientNameTooShort)
+    } else if length > config.max_name_length {
+        Err(ErrorKind::ClientNameTooLong)
+    } else if !name.chars().all(|c| config.name_characters.allows(c)) {
+        Err(ErrorKind::ClientNameInvalidCharacters)
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+/// Room id as it is looked up, also used for aliases
+pub fn validate_room_id(config: &ServerConfig, room_id: &str) -> Result<String, ErrorKind> {
+    let room_id = room_id.trim();
+    let length = grapheme_count(room_id);
+    if length < config.min_room_id_length {
+        Err(ErrorKind::RoomIdTooShort)
+    } else if length > config.max_room_id_length {
+        Err(ErrorKind::RoomIdTooLong)
+    } else if !room_id.chars().all(|c| config.room_id_characters.allows(c)) {
+        Err(ErrorKind::RoomIdInvalidCharacters)
+    } else if config.reserved_room_ids.iter().any(|reserved| reserved.eq_ignore_ascii_case(room_id)) {
+        Err(ErrorKind::RoomIdReserved)
+    } else {
+        Ok(room_id.to_string())
+    }
+}
+
+const MAX_NAMESPACE_LENGTH: usize = 32;
+
+/// Namespaces are `UrlSafe`, they name communities sharing the server rather than rooms
+pub fn validate_namespace(namespace: &str) -> Result<String, ErrorKind> {
+    let namespace = namespace.trim();
+    if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LENGTH || !namespace.chars().all(|c| CharacterPolicy::UrlSafe.allows(c)) {
+        Err(ErrorKind::InvalidNamespace)
+    } else {
+        Ok(namespace.to_string())
+    }
+}
+
+/// Page URL as it is stored. Too long ones are `InvalidPageUrl`, anything but an http(s) URL of an
+/// allowed domain is `InvalidUrl`. The URL is kept as it was sent, signatures are made over it.
+pub fn validate_page_url(config: &ServerConfig, url: &str) -> Result<String, ErrorKind> {
+    let url = url.trim();
+    if url.is_empty() || url.len() > config.max_page_url_length {
+        return Err(ErrorKind::InvalidPageUrl);
+    }
+    // `Url::parse` drops line breaks and tabs silently, the URL is stored as sent
+    if url.chars().any(char::is_control) {
+        return Err(ErrorKind::InvalidUrl);
+    }
+    let parsed = Url::parse(url).map_err(|_| ErrorKind::InvalidUrl)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(ErrorKind::InvalidUrl);
+    }
+    let Some(host) = parsed.host_str() else {
+        return Err(ErrorKind::InvalidUrl);
+    };
+    let allowed = config.allowed_page_domains.is_empty() || config.allowed_page_domains.iter().any(|domain| {
+        host == domain || host.strip_suffix(domain.as_str()).is_some_and(|subdomain| subdomain.ends_with('.'))
+    });
+    if !allowed {
+        return Err(ErrorKind::InvalidUrl);
+    }
+    Ok(url.to_string())
+}
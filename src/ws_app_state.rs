@@ -1,142 +1,1876 @@
 use rocket_ws as ws;
-use std::collections::HashMap;
-use std::sync::Arc;
-use serde::Serialize;
-use tokio::sync::{Mutex, mpsc};
+use rocket::http::RawStr;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock, PoisonError, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::panic::AssertUnwindSafe;
+use anyhow::{anyhow, Result};
+use tokio::sync::{Mutex, Notify, broadcast, mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
 use uuid::Uuid;
+use crate::push_notifications::PushNotifier;
+use crate::abuse_reports::AbuseReports;
+use crate::room_archive::RoomArchive;
+use crate::command_signing::{generate_signing_secret, hmac_sha256, to_hex, verify_signature_bytes, SIGNING_SECRET_SIZE};
+use crate::scheduler::unix_millis_now;
+use crate::rate_limit::{RateLimitDecision, TokenBucket, ViolationTrackingLimit};
+use crate::localization::Locale;
+use crate::client_registry::ClientRegistry;
+use crate::metrics::{Metrics, Traffic};
+use crate::config::{ConfigLoader, ServerConfig};
+use crate::content_filter::ContentFilter;
+use crate::auth::Authenticator;
+use crate::state_store::{InMemoryStateStore, StateStore};
+use crate::room_snapshots::RestoredMember;
+use crate::listeners::ForwardedPeers;
+use crate::room_logic::{self, Member};
+use rocket::serde::{Deserialize, Serialize};
+#[cfg(feature = "redis")]
+use crate::cluster::{ClusterBridge, ClusterLink, RemoteClient};
+use tracing::Instrument;
+use crate::protocol::{ClientCapability, OutgoingMessage};
+use crate::ws_handler::{flush_pending_broadcasts, response_with_json, PlaybackCommand};
+use crate::ws_dto_models::{AdminRoomDto, ArchivedRoomDto, ChatMessageDto, ControlMode, DepartedClientDto, DuplicateNames, OwnerSuccession, LobbyChatMessageDto, MarkerDto, NetworkReportDto, PermissionPreset, PollKind, PublicRoomDto, RoomHistoryEntryDto, RoomInfoDto, RoomHistoryEventDto, RoomPermission, Role, RoomRoleDto, ServerStatsDto, TrackKind, WatchProgressDto};
+use rand::distributions::Slice;
+use rand::Rng;
+use ts_rs::TS;
 
-pub type Tx = mpsc::UnboundedSender<ws::Message>;
+/// Bounded, see `ServerConfig::outgoing_queue_capacity`
+pub type Tx = mpsc::Sender<ws::Message>;
+/// Messages sent to every member of a room, see `broadcast_room_change`
+pub type RoomEvents = broadcast::Sender<ws::Message>;
+/// Operation executed by the task owning a room's data, see `Room::run`
+pub type RoomCommand = Box<dyn FnOnce(&mut RoomData) + Send>;
+/// Namespace and id, rooms are unique by both. `None` is the default namespace.
+pub type RoomKey = (Option<String>, String);
 
 #[derive(Debug)]
 pub struct WsAppState {
-    pub clients: Mutex<Vec<Arc<Client>>>,
-    pub rooms: Mutex<HashMap<String, Arc<Room>>>,
+    pub clients: ClientRegistry,
+    pub store: Arc<dyn StateStore>,
+    /// Secondary index of additional join codes, (namespace, alias) -> canonical room id
+    pub room_aliases: Mutex<HashMap<RoomKey, String>>,
+    pub push_notifier: Arc<PushNotifier>,
+    pub abuse_reports: Arc<AbuseReports>,
+    /// Closed rooms, set when `archive_path` is configured
+    pub room_archive: Option<Arc<RoomArchive>>,
+    pub scheduled_sessions: Mutex<HashMap<Uuid, ScheduledSession>>,
+    /// Rooms created with `ScheduleRoom` which haven't started yet and when they start, Unix
+    /// milliseconds
+    pub scheduled_rooms: Mutex<HashMap<RoomKey, u64>>,
+    /// Externally reachable base URL used to build invite links
+    pub public_url: String,
+    pub invite_links: Mutex<HashMap<String, InviteLink>>,
+    pub lobby: Lobby,
+    /// Last watched position of every known user, keyed by their verified identity
+    pub watch_progress: Mutex<HashMap<String, WatchProgressDto>>,
+    /// Replaced as a whole by a reload, see `config_reload`
+    config: RwLock<Arc<ServerConfig>>,
+    /// Reads the settings again for a reload, the server can't be reloaded without one
+    pub config_loader: Option<ConfigLoader>,
+    /// Checks names, room titles and chat messages, see `content_filter::filter_content`
+    pub content_filter: Option<Arc<dyn ContentFilter>>,
+    /// Key of the resume token signatures, tokens become invalid when the server restarts
+    resume_secret: [u8; SIGNING_SECRET_SIZE],
+    pub metrics: Metrics,
+    pub started_at: Instant,
+    /// Set with `POST /api/drain`, rooms keep working but none are joined or opened anymore
+    draining: AtomicBool,
+    pub authenticator: Authenticator,
+    /// Members of rooms restored from a snapshot who may still come back with `Resume`
+    pub restored_members: Mutex<HashMap<Uuid, RestoredMember>>,
+    /// Connections accepted on one of `ServerConfig::listeners`
+    pub forwarded_peers: ForwardedPeers,
+    /// Bridge to the other instances, set when `redis_url` is configured
+    #[cfg(feature = "redis")]
+    pub cluster: Option<Arc<ClusterBridge>>,
+}
+
+/// Channels read by the task writing to a websocket connection
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub tx: Tx,
+    /// Counted by the tasks reading and writing the connection
+    pub traffic: Arc<ConnectionTraffic>,
+    /// Subscriptions to the room events, the writer task switches to the latest one
+    pub room_events: mpsc::UnboundedSender<Option<broadcast::Receiver<ws::Message>>>,
+    /// Set by the `Hello` the connection starts with
+    pub client_info: Arc<OnceLock<ClientInfo>>,
+    /// `id` of the message being handled, echoed back in the answers to it
+    pub request_id: Arc<RwLock<Option<u64>>>,
+}
+
+/// Bytes of one connection, counted towards the room its client is in as well
+#[derive(Debug, Default)]
+pub struct ConnectionTraffic {
+    pub connection: Traffic,
+    room: RwLock<Option<Arc<Traffic>>>,
+}
+
+impl ConnectionTraffic {
+    pub fn record_received(&self, bytes: usize) {
+        self.connection.record_received(bytes);
+        if let Some(room) = self.room.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+            room.record_received(bytes);
+        }
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.connection.record_sent(bytes);
+        if let Some(room) = self.room.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+            room.record_sent(bytes);
+        }
+    }
+
+    pub fn set_room(&self, room: Option<&Room>) {
+        *self.room.write().unwrap_or_else(PoisonError::into_inner) = room.map(|room| room.traffic.clone());
+    }
+}
+
+/// What the client said about itself in `Hello`
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// Negotiated version, not necessarily the one the client asked for
+    pub protocol_version: u32,
+    pub client_name: String,
+    pub client_version: String,
+    /// Rooms are only found within the namespace of the client, `None` is the default one
+    pub namespace: Option<String>,
+    /// `None` when the client didn't announce any, it is sent everything then
+    pub capabilities: Option<Vec<ClientCapability>>,
+    /// `None` when the client named no supported language
+    pub locale: Option<Locale>,
 }
 
 #[derive(Debug)]
 pub struct Client {
-    pub tx: Tx,
+    /// Replaced when a new connection resumes the client, see `Client::try_resume`
+    connection: RwLock<Connection>,
     pub uid: Uuid,
+    pub ip: Option<IpAddr>,
+    /// From the `locale` query parameter or `Accept-Language`, used until `Hello` names a language
+    pub default_locale: Locale,
     pub data: Mutex<ClientData>,
+    /// Unix time in milliseconds of the last message received from the client
+    pub last_activity: AtomicU64,
+    /// Unix time in milliseconds of the last frame received, unlike `last_activity` including pongs
+    pub last_seen: AtomicU64,
+    /// Set once the client has been told it is about to be disconnected for inactivity
+    pub inactivity_warned: AtomicBool,
+    /// Round trip time in milliseconds reported with `Ping`, smoothed, 0 until the first report
+    smoothed_rtt_ms: AtomicU64,
+    /// Ends the connection from the server side, see `Client::disconnect`
+    pub disconnect_signal: Notify,
+    /// Set while the connection is gone but the client may still be resumed
+    pub detached: AtomicBool,
+    /// Presented the `admin_token`, may join any room hidden, kick, ban and close rooms
+    pub moderator: AtomicBool,
+    /// Incremented on every resume, tells a pending removal whether the client came back meanwhile
+    pub connection_generation: AtomicU64,
+    /// Unix time in milliseconds since when the outgoing queue is full, 0 while it is not
+    queue_full_since: AtomicU64,
+    slow_client_timeout: Duration,
+    message_rate_limit: std::sync::Mutex<ViolationTrackingLimit>,
+}
+
+/// Why the server closes a connection, sent in `OutgoingMessage::Disconnecting` right before the
+/// close frame. Clients which miss it tell the reasons apart by the close code, codes specific to
+/// this server are in the 4000 range.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum DisconnectReason {
+    ServerShuttingDown,
+    ServerOverloaded,
+    UnsupportedProtocolVersion,
+    Inactive,
+    HeartbeatTimeout,
+    RateLimited,
+    TooManyConnections,
+    RemovedByAdmin,
+    /// Text frame which is not valid UTF-8
+    InvalidFrame,
+    /// Frame above the size the websocket library reads at all
+    MessageTooLarge,
+    /// Nothing was sent within `ServerConfig::handshake_timeout_secs` of connecting
+    HandshakeTimeout,
+}
+
+impl DisconnectReason {
+    pub fn close_code(self) -> ws::frame::CloseCode {
+        match self {
+            DisconnectReason::ServerShuttingDown => ws::frame::CloseCode::Away,
+            DisconnectReason::ServerOverloaded => ws::frame::CloseCode::Again,
+            DisconnectReason::UnsupportedProtocolVersion => ws::frame::CloseCode::Policy,
+            DisconnectReason::Inactive => ws::frame::CloseCode::Library(4000),
+            DisconnectReason::HeartbeatTimeout => ws::frame::CloseCode::Library(4002),
+            DisconnectReason::RateLimited => ws::frame::CloseCode::Library(4008),
+            DisconnectReason::TooManyConnections => ws::frame::CloseCode::Library(4009),
+            DisconnectReason::RemovedByAdmin => ws::frame::CloseCode::Library(4010),
+            DisconnectReason::InvalidFrame => ws::frame::CloseCode::Invalid,
+            DisconnectReason::MessageTooLarge => ws::frame::CloseCode::Size,
+            DisconnectReason::HandshakeTimeout => ws::frame::CloseCode::Library(4011),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ClientData {
     pub name: Option<String>,
     pub room: Option<Arc<Room>>,
+    /// Verified identity of an authenticated user, anonymous clients have none
+    pub user_id: Option<String>,
+    /// Published with `SetClientMeta` for the member lists of other clients
+    pub meta: BTreeMap<String, String>,
+    /// Rooms followed with `Subscribe`, canonical ids
+    pub subscriptions: Vec<String>,
 }
 
 #[derive(Debug)]
 pub struct Room {
+    /// The same id may be used by another room in another namespace
+    pub namespace: Option<String>,
     pub room_id: String,
-    pub data: Mutex<RoomData>,
+    /// Queue of the task which owns the room data, the task stops once the room is dropped
+    commands: mpsc::UnboundedSender<RoomCommand>,
+    pub events: RoomEvents,
+    /// Client which opened the room, rooms opened by the server itself have none
+    pub creator_uid: Option<Uuid>,
+    pub creator_ip: Option<IpAddr>,
+    /// Messages of the connections of the members while they were in the room
+    pub traffic: Arc<Traffic>,
 }
 
 #[derive(Debug)]
 pub struct RoomData {
     pub clients: Vec<RoomClient>,
+    pub events: RoomEvents,
+    /// Number of the latest event broadcast to the room, a gap tells a member it missed something
+    pub events_seq: u64,
+    /// `ServerConfig::max_listed_members` as of the latest join, 0 lists everybody
+    pub max_listed_members: usize,
+    /// Latest events with their numbers, oldest first, limited to `ROOM_EVENTS_REPLAY_SIZE`, replayed
+    /// to members who ask with `ResyncFrom`
+    pub recent_events: VecDeque<(u64, String)>,
+    /// `RoomChanged` and `RoomSettingsUpdated` waiting for the end of `ROOM_BROADCAST_DEBOUNCE`, so
+    /// a burst of changes is sent once
+    pub room_change_pending: bool,
+    pub settings_change_pending: bool,
+    /// Clients following the room with `Subscribe` without being members, sent its state with the
+    /// pending broadcasts. Gone clients are dropped on the next send.
+    pub subscribers: Vec<Weak<Client>>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Listed in the public room directory
+    pub public: bool,
+    /// Members may set up voice chat with the `Rtc*` signaling messages
+    pub voice_enabled: bool,
+    /// Playback is paused for everybody when a member leaves or loses their connection
+    pub pause_on_leave: bool,
+    /// Set with `LockRoom`, nobody new may join
+    pub locked: bool,
+    /// Limit of members from the template of the room, on top of `ServerConfig::max_clients_per_room`
+    pub max_clients: Option<usize>,
+    pub duplicate_names: DuplicateNames,
+    pub owner_succession: OwnerSuccession,
+    /// Unix time in milliseconds
+    pub created_at: u64,
+    /// Unix time in milliseconds of the latest message of a member to the room
+    pub last_activity_at: u64,
     pub page_url: Option<String>,
+    /// Page urls played after the current one, in order
+    pub queue: Vec<String>,
+    /// Named positions in the current video, ordered by position
+    pub markers: Vec<MarkerDto>,
+    /// Page urls the room played to the end, oldest first, limited to `WATCHED_HISTORY_SIZE`
+    pub watched: VecDeque<String>,
+    /// Members who reported the end of the current video
+    pub video_ended_uids: Vec<Uuid>,
     pub allow_stop_due_to_video_loading: bool,
+    pub shared_files_quota: SharedFilesQuota,
+    pub relay_quota: RelayQuota,
+    /// Aggregate budget of events broadcast to the room, shared by all members
+    pub event_rate_limit: TokenBucket,
+    /// Payloads are relayed as opaque ciphertext, features inspecting plaintext are disabled
+    pub end_to_end_encrypted: bool,
+    /// Secret handed to controllers, used to sign page URL changes when `require_signed_commands` is set
+    pub signing_secret: [u8; SIGNING_SECRET_SIZE],
+    pub require_signed_commands: bool,
+    pub last_signed_nonce: u64,
+    pub aliases: Vec<String>,
+    /// Room id of the room which asked to absorb this one, waiting for the owner's confirmation
+    pub merge_requested_by_room: Option<String>,
+    /// Breakout rooms split off this room, members return here when recalled
+    pub breakout_room_ids: Vec<String>,
+    pub breakout_parent_room_id: Option<String>,
+    pub playback: PlaybackState,
+    /// Accumulated play time, not including the currently running stretch
+    pub play_time: Duration,
+    pub playing_since: Option<Instant>,
+    /// Watch time of the members who left, the ones still here are counted by `total_watch_time`
+    pub departed_watch_time: Duration,
+    /// Most members and spectators in the room at once, hidden moderators aside
+    pub peak_viewers: usize,
+    /// Videos the room switched to
+    pub videos_played: u32,
+    /// Members who left the room, oldest first, limited to `DEPARTED_CLIENTS_HISTORY_SIZE`
+    pub departed_clients: VecDeque<DepartedClientDto>,
+    /// Audit log of the room, oldest first, limited to `ROOM_HISTORY_SIZE`
+    pub history: VecDeque<RoomHistoryEntryDto>,
+    pub roles: Vec<RoomRoleDto>,
+    pub bans: Vec<RoomBan>,
+    /// Outstanding `CreateInvite` invites, expired ones are dropped by the room maintenance
+    pub invites: Vec<RoomInvite>,
+    /// Recent chat messages replayed to joiners, oldest first, limited to `ROOM_CHAT_HISTORY_SIZE`
+    pub chat_history: VecDeque<ChatMessageDto>,
+    /// Only one poll runs at a time
+    pub poll: Option<Poll>,
+    /// Cleared when playback starts through it or the video changes
+    pub ready_check: Option<ReadyCheck>,
+    pub countdown: Option<Countdown>,
+    pub permission_preset: PermissionPreset,
+    pub control_mode: ControlMode,
+    /// Playback commands collected during the current democracy mode vote window
+    pub playback_votes: Vec<PlaybackVote>,
+    /// Members present for this long are promoted to admin by the room maintenance task
+    pub auto_admin_after: Option<Duration>,
+    /// Nominee uid -> uids of the members who voted for their promotion
+    pub admin_nominations: HashMap<Uuid, Vec<Uuid>>,
+    /// The room was paused because a member is buffering and resumes once everybody is ready
+    pub paused_for_buffering: bool,
+    /// Set right before the room is taken out of the store, nobody can join it anymore
+    pub closed: bool,
+    /// Rooms restored from a snapshot or started by the scheduler are kept while empty until then,
+    /// waiting for their members
+    pub restored_until: Option<Instant>,
+    /// Unix time in milliseconds a room created with `ScheduleRoom` opens for joining, cleared once
+    /// it started
+    pub starts_at: Option<u64>,
+    /// Set once the room is relayed to the other instances through Redis
+    #[cfg(feature = "redis")]
+    pub cluster: Option<ClusterLink>,
+    /// Members connected to other instances, listed to the local ones
+    #[cfg(feature = "redis")]
+    pub remote_clients: Vec<RemoteClient>,
+}
+
+/// Server side playback clock, the position advances with `rate` while playing
+#[derive(Debug, Clone)]
+pub struct PlaybackState {
+    /// Position in seconds at `last_update`
+    pub position: f64,
+    pub playing: bool,
+    pub rate: f64,
+    pub last_update: Instant,
+    /// Dub and subtitles everybody watches with, chosen by `SetTrack`
+    pub audio_track: Option<String>,
+    pub subtitle_track: Option<String>,
+}
+
+/// Client uids change with every connection, so the address is the part of a ban that sticks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct RoomBan {
+    pub client_uid: Uuid,
+    pub name: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Invite handed out as a signed token, see `WsAppState::invite_token`
+#[derive(Debug, Clone, Copy)]
+pub struct RoomInvite {
+    pub invite_id: Uuid,
+    pub uses_left: u32,
+    /// Unix time in milliseconds
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub poll_id: Uuid,
+    pub kind: PollKind,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Voter uid and the index of their option, one vote per member
+    pub votes: Vec<(Uuid, usize)>,
+    pub started_by: Uuid,
+    /// Unix time in milliseconds
+    pub ends_at: u64,
+}
+
+/// Started by `RequestReadyCheck`, the room starts playing once enough members are ready
+#[derive(Debug, Clone)]
+pub struct ReadyCheck {
+    pub started_by: Uuid,
+    pub ready_uids: Vec<Uuid>,
+    /// Share of the members, spectators aside, who have to be ready
+    pub quorum_percent: u8,
+}
+
+/// Started by `StartCountdown`, the room starts playing at `starts_at`
+#[derive(Debug, Clone)]
+pub struct Countdown {
+    pub countdown_id: Uuid,
+    pub started_by: Uuid,
+    /// Unix time in milliseconds
+    pub starts_at: u64,
+}
+
+/// File announced with `OfferFile`, the server relays its chunks and never keeps the content
+#[derive(Debug, Clone)]
+pub struct FileOffer {
+    pub hash: String,
+    pub name: String,
+    pub size: u64,
+    /// Members who asked for it with `RequestFile` and haven't been sent its last chunk yet
+    pub requested_by: Vec<Uuid>,
+}
+
+#[derive(Debug)]
+pub struct PlaybackVote {
+    pub client_uid: Uuid,
+    pub command: PlaybackCommand,
 }
 
 #[derive(Debug)]
 pub struct RoomClient {
     pub client: Arc<Client>,
-    pub owner: bool,
-    pub admin: bool,
+    /// Copy of the client's name, so the room task never has to lock the client data
+    pub name: Option<String>,
+    /// Copy of the client's metadata, like `name`
+    pub meta: BTreeMap<String, String>,
+    pub role: Role,
+    /// Joined with `spectator`, only counted in `RoomDataDto::spectator_count`
+    pub spectator: bool,
+    /// Moderator of the server who joined with `hidden`, a spectator nobody is told about
+    pub hidden: bool,
+    /// Names of roles from `RoomData::roles` assigned by the owner
+    pub roles: Vec<String>,
+    pub network_report: Option<NetworkReportDto>,
+    /// Set by `ReportBufferState` while the client's video is loading
+    pub buffering: bool,
+    pub reaction_rate_limit: TokenBucket,
+    /// Set by `TypingStart` until `TypingStop` or the typing timeout, renewed by every `TypingStart`
+    pub typing: Option<Uuid>,
+    pub typing_rate_limit: TokenBucket,
+    /// Channel name -> rate limit of the member's `Custom` messages on it
+    pub custom_rate_limits: HashMap<String, TokenBucket>,
+    /// Files the member offered with `OfferFile`, they go away with the member
+    pub file_offers: Vec<FileOffer>,
+    /// Bytes of `FileChunk` the member may relay
+    pub file_chunk_rate_limit: TokenBucket,
+    /// Set by `MuteClient`, Unix time in milliseconds until which chat and reactions are refused
+    pub muted_until: Option<u64>,
+    pub joined_at: Instant,
+    /// Room play time when the client joined, the difference to the current value is their watch time
+    pub play_time_at_join: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledSession {
+    pub session_id: Uuid,
+    pub namespace: Option<String>,
+    pub room_id: String,
+    pub title: String,
+    pub page_url: Option<String>,
+    /// Unix time in milliseconds
+    pub starts_at: u64,
+    pub invited_uids: Vec<Uuid>,
+    pub created_by: Uuid,
+    pub public: bool,
+    /// Set once the room has been opened by the scheduler
+    pub activated: bool,
+}
+
+impl ScheduledSession {
+    pub fn visible_to(&self, viewer: Option<Uuid>) -> bool {
+        self.public || viewer.is_some_and(|uid| uid == self.created_by || self.invited_uids.contains(&uid))
+    }
+}
+
+/// Short typeable link (`/i/<slug>`) pointing to a room together with its invite token
+#[derive(Debug, Clone)]
+pub struct InviteLink {
+    pub namespace: Option<String>,
+    pub room_id: String,
+    /// Invite of the room the link redeems, revoked together with the link
+    pub invite_id: Uuid,
+    pub invite_token: String,
+    /// Unix time in milliseconds
+    pub expires_at: u64,
+}
+
+impl InviteLink {
+    pub fn room_key(&self) -> RoomKey {
+        (self.namespace.clone(), self.room_id.clone())
+    }
+}
+
+/// Server-wide chat channel for finding co-watchers before entering a room
+#[derive(Debug)]
+pub struct Lobby {
+    pub enabled: bool,
+    pub data: Mutex<LobbyData>,
+}
+
+#[derive(Debug)]
+pub struct LobbyData {
+    pub members: Vec<LobbyMember>,
+    pub recent_messages: VecDeque<LobbyChatMessageDto>,
+}
+
+#[derive(Debug)]
+pub struct LobbyMember {
+    pub client: Arc<Client>,
+    pub rate_limit: TokenBucket,
+    /// Rate limit violations since the last mute
+    pub violations: u32,
+    pub muted_until: Option<Instant>,
+}
+
+pub const LOBBY_HISTORY_SIZE: usize = 50;
+pub const DEPARTED_CLIENTS_HISTORY_SIZE: usize = 20;
+pub const ROOM_CHAT_HISTORY_SIZE: usize = 30;
+pub const ROOM_HISTORY_SIZE: usize = 200;
+pub const WATCHED_HISTORY_SIZE: usize = 50;
+pub const ROOM_EVENTS_REPLAY_SIZE: usize = 128;
+/// Members may vote for new admins once the owner has been idle for this long
+pub const OWNER_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Events relayed to all members of a room, sustained rate and burst
+pub const ROOM_EVENTS_PER_SECOND: f64 = 20.0;
+pub const ROOM_EVENTS_BURST: f64 = 40.0;
+/// Window of `ServerConfig::client_rate_limit_max_violations`
+const CLIENT_RATE_LIMIT_VIOLATIONS_WINDOW: Duration = Duration::from_secs(10);
+/// Part of the burst low priority events can't use, so they never starve playback commands
+const LOW_PRIORITY_EVENTS_RESERVE: f64 = 15.0;
+/// Room events a connection may fall behind by, it skips the oldest ones beyond that and the
+/// client notices the gap in the sequence numbers
+const ROOM_EVENTS_CAPACITY: usize = 64;
+/// Full state broadcasts made within this window after the first one are sent together
+const ROOM_BROADCAST_DEBOUNCE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventPriority {
+    /// Superseded by the next event of the same kind, dropped first when the room is busy
+    Low,
+    Normal,
+}
+
+/// Byte budget for binary files shared in a room, refilled every `SHARED_FILES_QUOTA_WINDOW`
+#[derive(Debug)]
+pub struct SharedFilesQuota {
+    pub window_start: Instant,
+    pub bytes_used: usize,
+}
+
+/// Bytes relayed between the members of a room within the current minute, see
+/// `ServerConfig::room_relay_quota_bytes_per_minute`
+#[derive(Debug)]
+pub struct RelayQuota {
+    pub window_start: Instant,
+    pub bytes_used: u64,
+    /// Operators are warned once per window
+    pub warned: bool,
+}
+
+/// Reported round trip times are capped, a client can't push itself further ahead than this
+const MAX_RTT_MS: u64 = 2000;
+/// Lowercase letters and digits without the easily confused `0`, `o`, `1`, `l`
+const INVITE_SLUG_ALPHABET: &[char] = &[
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+    '2', '3', '4', '5', '6', '7', '8', '9',
+];
+const INVITE_SLUG_LENGTH: usize = 6;
+
+pub const SHARED_FILES_QUOTA_BYTES: usize = 8 * 1024 * 1024;
+pub const SHARED_FILES_QUOTA_WINDOW: Duration = Duration::from_secs(10 * 60);
+pub const RELAY_QUOTA_WINDOW: Duration = Duration::from_secs(60);
+/// Range of the reconnect delay hinted to clients disconnected by the server, the upper end grows
+/// with the number of connected clients up to the ceiling
+const RECONNECT_RETRY_AFTER_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_RETRY_AFTER_MAX: Duration = Duration::from_secs(30);
+const RECONNECT_RETRY_AFTER_CEILING: Duration = Duration::from_secs(10 * 60);
+
+fn invite_message(namespace: Option<&str>, room_id: &str, invite_id: Uuid) -> String {
+    match namespace {
+        Some(namespace) => format!("invite:{}/{}:{}", namespace, room_id, invite_id),
+        None => format!("invite:{}:{}", room_id, invite_id),
+    }
+}
+
+fn qr_code_message(namespace: Option<&str>, room_id: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("qr:{}/{}", namespace, room_id),
+        None => format!("qr:{}", room_id),
+    }
 }
 
 impl WsAppState {
-    pub fn new() -> Self {
+    pub fn new(config: Arc<ServerConfig>, push_notifier: PushNotifier, public_url: String) -> Self {
         WsAppState {
-            clients: Mutex::new(Vec::new()),
-            rooms: Mutex::new(HashMap::new()),
+            clients: ClientRegistry::new(),
+            store: Arc::new(InMemoryStateStore::default()),
+            room_aliases: Mutex::new(HashMap::new()),
+            push_notifier: Arc::new(push_notifier),
+            abuse_reports: Arc::new(AbuseReports::new()),
+            room_archive: None,
+            scheduled_sessions: Mutex::new(HashMap::new()),
+            scheduled_rooms: Mutex::new(HashMap::new()),
+            public_url,
+            invite_links: Mutex::new(HashMap::new()),
+            lobby: Lobby::new(config.lobby_enabled),
+            watch_progress: Mutex::new(HashMap::new()),
+            resume_secret: generate_signing_secret(),
+            metrics: Metrics::default(),
+            started_at: Instant::now(),
+            draining: AtomicBool::new(false),
+            authenticator: Authenticator::new(&config),
+            restored_members: Mutex::new(HashMap::new()),
+            forwarded_peers: ForwardedPeers::default(),
+            #[cfg(feature = "redis")]
+            cluster: config.redis_url.clone().map(|redis_url| Arc::new(ClusterBridge::new(redis_url))),
+            config: RwLock::new(config),
+            config_loader: None,
+            content_filter: None,
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    /// Randomized so clients disconnected at the same moment don't all come back at once. The
+    /// window is wide enough for every connected client to come back at `reconnects_per_second`.
+    pub fn reconnect_retry_after(&self) -> Duration {
+        let reconnects_per_second = self.config().reconnects_per_second;
+        let max = if reconnects_per_second > 0 {
+            Duration::from_secs_f64(self.clients.len() as f64 / reconnects_per_second as f64)
+                .clamp(RECONNECT_RETRY_AFTER_MAX, RECONNECT_RETRY_AFTER_CEILING)
+        } else {
+            RECONNECT_RETRY_AFTER_MAX
+        };
+        Duration::from_millis(rand::thread_rng().gen_range(RECONNECT_RETRY_AFTER_MIN.as_millis() as u64..=max.as_millis() as u64))
+    }
+
+    /// Settings as of now, a reload doesn't change the returned ones
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn replace_config(&self, config: Arc<ServerConfig>) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    /// Relays the room to the other instances when running with Redis, call once it's listed in `rooms`
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    pub fn attach_room(&self, room: &Arc<Room>) {
+        #[cfg(feature = "redis")]
+        if let Some(cluster) = &self.cluster {
+            cluster.attach_room(room.clone());
+        }
+    }
+
+    /// Whether opening `new_rooms` more rooms in the namespace would go over `ServerConfig::max_rooms`
+    /// or the limit of the namespace
+    pub async fn rooms_limit_reached(&self, namespace: Option<&str>, new_rooms: usize) -> bool {
+        if let Some(max_rooms) = self.config().max_rooms() && self.store.rooms_count().await + new_rooms > max_rooms {
+            return true;
+        }
+        match self.config().namespace_max_rooms(namespace) {
+            Some(max_rooms) => {
+                let namespace_rooms = self.store.rooms().await.iter().filter(|room| room.namespace.as_deref() == namespace).count();
+                namespace_rooms + new_rooms > max_rooms
+            }
+            None => false,
+        }
+    }
+
+    /// Maps an alias to the canonical room id, other codes are returned unchanged
+    pub async fn resolve_room_id(&self, namespace: Option<&str>, room_id: &str) -> String {
+        let key = (namespace.map(str::to_string), room_id.to_string());
+        self.room_aliases.lock().await.get(&key).cloned().unwrap_or(key.1)
+    }
+
+    /// Drops the aliases and the push subscriptions of a removed room
+    pub async fn forget_room(&self, room: &Room) {
+        self.room_aliases.lock().await.retain(|(namespace, _), canonical_room_id| *namespace != room.namespace || *canonical_room_id != room.room_id);
+        self.push_notifier.remove_room(&room.key()).await;
+    }
+
+    pub fn join_url(&self, namespace: Option<&str>, room_id: &str) -> String {
+        let url = format!("{}/join/{}", self.public_url.trim_end_matches('/'), RawStr::new(room_id).percent_encode());
+        match namespace {
+            Some(namespace) => format!("{}?namespace={}", url, RawStr::new(namespace).percent_encode()),
+            None => url,
         }
     }
+
+    /// Image of the join link QR code, signed so that only members who may invite can hand it out
+    pub fn qr_code_url(&self, room: &Room) -> String {
+        let mut url = format!(
+            "{}/join/{}/qr.svg?token={}",
+            self.public_url.trim_end_matches('/'),
+            RawStr::new(&room.room_id).percent_encode(),
+            self.qr_code_token(room.namespace.as_deref(), &room.room_id)
+        );
+        if let Some(namespace) = &room.namespace {
+            url.push_str(&format!("&namespace={}", RawStr::new(namespace).percent_encode()));
+        }
+        url
+    }
+
+    pub fn qr_code_token(&self, namespace: Option<&str>, room_id: &str) -> String {
+        to_hex(&hmac_sha256(&self.resume_secret, qr_code_message(namespace, room_id).as_bytes()))
+    }
+
+    pub fn verify_qr_code_token(&self, namespace: Option<&str>, room_id: &str, token: &str) -> bool {
+        verify_signature_bytes(&self.resume_secret, qr_code_message(namespace, room_id).as_bytes(), token)
+    }
+
+    /// Feed of the sessions of the client, signed so that knowing the uid isn't enough to read it
+    pub fn calendar_url(&self, uid: Uuid) -> String {
+        format!("{}/users/{}/calendar.ics?token={}", self.public_url.trim_end_matches('/'), uid, self.calendar_token(uid))
+    }
+
+    pub fn calendar_token(&self, uid: Uuid) -> String {
+        to_hex(&hmac_sha256(&self.resume_secret, format!("calendar:{}", uid).as_bytes()))
+    }
+
+    pub fn verify_calendar_token(&self, uid: Uuid, token: &str) -> bool {
+        verify_signature_bytes(&self.resume_secret, format!("calendar:{}", uid).as_bytes(), token)
+    }
+
+    pub fn invite_link_url(&self, slug: &str) -> String {
+        format!("{}/i/{}", self.public_url.trim_end_matches('/'), slug)
+    }
+
+    /// Mints a new invite link for the room, dropping expired ones on the way
+    pub async fn create_invite_link(&self, room: &Room, invite: RoomInvite) -> (String, InviteLink) {
+        let now = unix_millis_now();
+        let mut invite_links = self.invite_links.lock().await;
+        invite_links.retain(|_, link| link.expires_at > now);
+
+        let mut rng = rand::thread_rng();
+        let slug_alphabet = Slice::new(INVITE_SLUG_ALPHABET).unwrap_or_else(|_| unreachable!());
+        let slug = loop {
+            let slug: String = (&mut rng).sample_iter(&slug_alphabet).take(INVITE_SLUG_LENGTH).collect();
+            if !invite_links.contains_key(&slug) {
+                break slug;
+            }
+        };
+
+        let link = InviteLink {
+            namespace: room.namespace.clone(),
+            room_id: room.room_id.clone(),
+            invite_id: invite.invite_id,
+            invite_token: self.invite_token(room, invite.invite_id),
+            expires_at: invite.expires_at,
+        };
+        invite_links.insert(slug.clone(), link.clone());
+
+        (slug, link)
+    }
+
+    /// `None` when no room is open under the id or alias
+    pub async fn room_info(&self, namespace: Option<&str>, room_id: &str) -> Option<RoomInfoDto> {
+        let room_id = self.resolve_room_id(namespace, room_id).await;
+        let summary = self.store.room_summary(namespace, &room_id).await?;
+        Some(RoomInfoDto {
+            room_id,
+            title: summary.title,
+            description: summary.description,
+            members_count: summary.members_count,
+            playing: summary.playback.playing,
+        })
+    }
+
+    /// Public rooms of the namespace with members, the fullest first
+    pub async fn public_rooms(&self, namespace: Option<&str>) -> Vec<PublicRoomDto> {
+        let mut public_rooms = Vec::new();
+        for room in self.store.rooms().await.into_iter().filter(|room| room.namespace.as_deref() == namespace) {
+            let room_id = room.room_id.clone();
+            let public_room = room.run(move |room_data| {
+                (room_data.public && !room_data.clients.is_empty()).then(|| PublicRoomDto {
+                    room_id,
+                    title: room_data.title.clone(),
+                    members_count: room_data.clients.len(),
+                    page_url: room_data.page_url.clone(),
+                })
+            }).await;
+            // A room whose task is gone is about to be dropped anyway
+            if let Ok(Some(public_room)) = public_room {
+                public_rooms.push(public_room);
+            }
+        }
+        public_rooms.sort_by(|a, b| b.members_count.cmp(&a.members_count).then_with(|| a.room_id.cmp(&b.room_id)));
+        public_rooms
+    }
+
+    /// Every room with its member count, sorted by namespace and id
+    pub async fn admin_rooms(&self) -> Vec<AdminRoomDto> {
+        let rooms: Vec<Arc<Room>> = self.store.rooms().await;
+        let mut room_dtos = Vec::with_capacity(rooms.len());
+        for room in rooms {
+            let summary = room.run(|room_data| (room_data.clients.len(), room_data.page_url.clone(), room_data.breakout_parent_room_id.clone())).await;
+            // A room whose task is gone is about to be dropped by the maintenance
+            let Ok((members_count, page_url, breakout_parent_room_id)) = summary else {
+                continue;
+            };
+            room_dtos.push(AdminRoomDto {
+                namespace: room.namespace.clone(),
+                room_id: room.room_id.clone(),
+                members_count,
+                page_url,
+                creator_uid: room.creator_uid,
+                breakout_parent_room_id,
+                bytes_received: room.traffic.received(),
+                bytes_sent: room.traffic.sent(),
+            });
+        }
+        room_dtos.sort_by(|a, b| a.namespace.cmp(&b.namespace).then_with(|| a.room_id.cmp(&b.room_id)));
+        room_dtos
+    }
+
+    /// Totals of the instance, `with_rooms` adds the rooms for moderators and the admin API
+    pub async fn server_stats(&self, with_rooms: bool) -> ServerStatsDto {
+        let rooms = self.admin_rooms().await;
+        self.stats_of(rooms, self.clients.len(), with_rooms)
+    }
+
+    /// `server_stats` counting only the rooms and clients of the namespace
+    pub async fn namespace_stats(&self, namespace: Option<&str>, with_rooms: bool) -> ServerStatsDto {
+        let mut rooms = self.admin_rooms().await;
+        rooms.retain(|room| room.namespace.as_deref() == namespace);
+        let clients_count = self.clients.snapshot().iter()
+            .filter(|client| client.client_info().is_some_and(|client_info| client_info.namespace.as_deref() == namespace))
+            .count();
+        self.stats_of(rooms, clients_count, with_rooms)
+    }
+
+    fn stats_of(&self, rooms: Vec<AdminRoomDto>, clients_count: usize, with_rooms: bool) -> ServerStatsDto {
+        ServerStatsDto {
+            rooms_count: rooms.len(),
+            clients_count,
+            watching_count: rooms.iter().map(|room| room.members_count).sum(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            messages_per_second: self.metrics.messages_received.per_second(),
+            rooms: with_rooms.then_some(rooms),
+        }
+    }
+
+    /// Random room id for `CreateRoom`, not used by any room or alias of the namespace at the moment
+    pub async fn unused_room_code(&self, namespace: Option<&str>) -> String {
+        let alphabet: Vec<char> = self.config().room_code_alphabet.chars().collect();
+        let alphabet = Slice::new(&alphabet).unwrap_or_else(|_| unreachable!());
+        loop {
+            let room_code: String = rand::thread_rng().sample_iter(&alphabet).take(self.config().room_code_length).collect();
+            let reserved = self.config().reserved_room_ids.iter().any(|reserved| reserved.eq_ignore_ascii_case(&room_code));
+            let alias_key = (namespace.map(str::to_string), room_code.clone());
+            if !reserved && self.store.room(namespace, &room_code).await.is_none() && !self.room_aliases.lock().await.contains_key(&alias_key) {
+                return room_code;
+            }
+        }
+    }
+
+    pub async fn resolve_invite_link(&self, slug: &str) -> Option<InviteLink> {
+        let invite_links = self.invite_links.lock().await;
+        invite_links.get(slug).filter(|link| link.expires_at > unix_millis_now()).cloned()
+    }
+
+    pub fn find_client(&self, uid: Uuid) -> Option<Arc<Client>> {
+        self.clients.get(uid)
+    }
+
+    /// Keeps the resume tokens of a previous run valid, see `room_snapshots`
+    pub fn with_resume_secret(self, resume_secret: [u8; SIGNING_SECRET_SIZE]) -> Self {
+        WsAppState { resume_secret, ..self }
+    }
+
+    /// Lets the settings be reloaded with SIGHUP or `POST /api/config/reload`
+    pub fn with_config_loader(self, config_loader: ConfigLoader) -> Self {
+        WsAppState { config_loader: Some(config_loader), ..self }
+    }
+
+    pub fn with_content_filter(self, content_filter: Arc<dyn ContentFilter>) -> Self {
+        WsAppState { content_filter: Some(content_filter), ..self }
+    }
+
+    pub fn with_room_archive(self, room_archive: RoomArchive) -> Self {
+        WsAppState { room_archive: Some(Arc::new(room_archive)), ..self }
+    }
+
+    /// Records the room in the archive when there is one, called once the room is removed
+    pub async fn archive_room(&self, room: &Arc<Room>) {
+        let Some(room_archive) = &self.room_archive else {
+            return;
+        };
+        let namespace = room.namespace.clone();
+        let room_id = room.room_id.clone();
+        match room.run(move |room_data| ArchivedRoomDto::from(namespace, &room_id, room_data)).await {
+            Ok(archived_room) => room_archive.record(archived_room).await,
+            Err(e) => tracing::error!(room_id = %room.room_id, "Failed to archive room: {:?}", e),
+        }
+    }
+
+    pub fn resume_secret(&self) -> &[u8; SIGNING_SECRET_SIZE] {
+        &self.resume_secret
+    }
+
+    /// Token which lets a new connection take over the client, `<uid>.<hex signature>`
+    pub fn resume_token(&self, uid: Uuid) -> String {
+        format!("{}.{}", uid, to_hex(&hmac_sha256(&self.resume_secret, uid.as_bytes())))
+    }
+
+    pub fn verify_resume_token(&self, token: &str) -> Option<Uuid> {
+        let (uid, signature) = token.split_once('.')?;
+        let uid = Uuid::parse_str(uid).ok()?;
+        verify_signature_bytes(&self.resume_secret, uid.as_bytes(), signature).then_some(uid)
+    }
+
+    /// Token of a room invite, `<invite id>.<hex signature>`. The signature covers the namespace and
+    /// the room id so an invite only opens the room it was made for.
+    pub fn invite_token(&self, room: &Room, invite_id: Uuid) -> String {
+        let message = invite_message(room.namespace.as_deref(), &room.room_id, invite_id);
+        format!("{}.{}", invite_id, to_hex(&hmac_sha256(&self.resume_secret, message.as_bytes())))
+    }
+
+    pub fn verify_invite_token(&self, namespace: Option<&str>, room_id: &str, token: &str) -> Option<Uuid> {
+        let (invite_id, signature) = token.split_once('.')?;
+        let invite_id = Uuid::parse_str(invite_id).ok()?;
+        verify_signature_bytes(&self.resume_secret, invite_message(namespace, room_id, invite_id).as_bytes(), signature).then_some(invite_id)
+    }
+
+    pub async fn record_watch_progress(&self, user_id: &str, progress: WatchProgressDto) {
+        self.watch_progress.lock().await.insert(user_id.to_string(), progress);
+    }
 }
 
 impl Client {
-    pub fn new(tx: Tx) -> Self {
+    pub fn new(connection: Connection, ip: Option<IpAddr>, locale: Locale, config: &ServerConfig) -> Self {
+        Client::with_uid(Uuid::new_v4(), connection, ip, locale, config)
+    }
+
+    /// Client taking the uid of one from before a restart, see `RestoredMember`
+    pub fn with_uid(uid: Uuid, connection: Connection, ip: Option<IpAddr>, default_locale: Locale, config: &ServerConfig) -> Self {
         Client {
-            tx,
-            uid: Uuid::new_v4(),
+            connection: RwLock::new(connection),
+            uid,
+            ip,
+            default_locale,
             data: Mutex::new(ClientData {
                 name: None,
                 room: None,
+                user_id: None,
+                meta: BTreeMap::new(),
+                subscriptions: Vec::new(),
             }),
+            last_activity: AtomicU64::new(unix_millis_now()),
+            last_seen: AtomicU64::new(unix_millis_now()),
+            smoothed_rtt_ms: AtomicU64::new(0),
+            inactivity_warned: AtomicBool::new(false),
+            disconnect_signal: Notify::new(),
+            detached: AtomicBool::new(false),
+            moderator: AtomicBool::new(false),
+            connection_generation: AtomicU64::new(0),
+            queue_full_since: AtomicU64::new(0),
+            slow_client_timeout: config.slow_client_timeout(),
+            message_rate_limit: std::sync::Mutex::new(ViolationTrackingLimit::new(
+                TokenBucket::new(config.client_messages_burst, config.client_messages_per_second),
+                config.client_rate_limit_max_violations,
+                CLIENT_RATE_LIMIT_VIOLATIONS_WINDOW,
+            )),
+        }
+    }
+
+    /// Never waits, a client which can't keep up loses the message and is eventually disconnected
+    pub fn send(&self, message: ws::Message) -> Result<(), TrySendError<ws::Message>> {
+        let result = self.connection.read().unwrap_or_else(PoisonError::into_inner).tx.try_send(message);
+        match result {
+            Ok(()) => self.queue_full_since.store(0, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => self.handle_queue_full(),
+            Err(TrySendError::Closed(_)) => {}
+        }
+        result
+    }
+
+    fn handle_queue_full(&self) {
+        let now = unix_millis_now();
+        let full_since = match self.queue_full_since.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => now,
+            Err(full_since) => full_since,
+        };
+        if now.saturating_sub(full_since) >= self.slow_client_timeout.as_millis() as u64 {
+            tracing::warn!(client_uid = %self.uid, "Disconnecting client which doesn't keep up with its messages");
+            // A close frame wouldn't fit into the queue either
+            self.disconnect_signal.notify_one();
+        }
+    }
+
+    pub fn connection(&self) -> Connection {
+        self.connection.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn client_info(&self) -> Option<ClientInfo> {
+        self.connection.read().unwrap_or_else(PoisonError::into_inner).client_info.get().cloned()
+    }
+
+    /// Language of human readable texts sent to the client
+    pub fn locale(&self) -> Locale {
+        self.client_info().and_then(|client_info| client_info.locale).unwrap_or(self.default_locale)
+    }
+
+    /// Version agreed on in `Hello`, 1 until then
+    pub fn protocol_version(&self) -> u32 {
+        self.client_info().map_or(1, |client_info| client_info.protocol_version)
+    }
+
+    /// Namespace chosen in `Hello`, the default one until then
+    pub fn namespace(&self) -> Option<String> {
+        self.client_info().and_then(|client_info| client_info.namespace)
+    }
+
+    /// Whether the client announced the capability the message needs, see `ClientCapability`
+    pub fn supports(&self, message: &OutgoingMessage) -> bool {
+        let Some(capability) = message.required_capability() else {
+            return true;
+        };
+        let connection = self.connection.read().unwrap_or_else(PoisonError::into_inner);
+        connection.client_info.get()
+            .and_then(|client_info| client_info.capabilities.as_ref())
+            .is_none_or(|capabilities| capabilities.contains(&capability))
+    }
+
+    /// The first `Hello` on a connection wins, returns the info which is in effect
+    pub fn set_client_info(&self, client_info: ClientInfo) -> ClientInfo {
+        self.connection.read().unwrap_or_else(PoisonError::into_inner).client_info.get_or_init(|| client_info).clone()
+    }
+
+    pub fn request_id(&self) -> Option<u64> {
+        *self.connection.read().unwrap_or_else(PoisonError::into_inner).request_id.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn set_request_id(&self, request_id: Option<u64>) {
+        *self.connection.read().unwrap_or_else(PoisonError::into_inner).request_id.write().unwrap_or_else(PoisonError::into_inner) = request_id;
+    }
+
+    pub fn traffic(&self) -> Arc<ConnectionTraffic> {
+        self.connection.read().unwrap_or_else(PoisonError::into_inner).traffic.clone()
+    }
+
+    /// Whether the task writing to the connection has stopped
+    pub fn is_connection_closed(&self) -> bool {
+        self.connection.read().unwrap_or_else(PoisonError::into_inner).tx.is_closed()
+    }
+
+    /// Points the client to another room and subscribes its connection to the room events right
+    /// away, so nothing broadcast after this call is missed. Returns the previous room.
+    pub fn set_room(&self, client_data: &mut ClientData, room: Option<Arc<Room>>) -> Option<Arc<Room>> {
+        self.follow_room_events(room.as_ref().map(|room| room.events.subscribe()));
+        self.traffic().set_room(room.as_deref());
+        std::mem::replace(&mut client_data.room, room)
+    }
+
+    /// Replaces the room events the connection receives without changing the room of the client
+    pub fn follow_room_events(&self, room_events: Option<broadcast::Receiver<ws::Message>>) {
+        let _ = self.connection.read().unwrap_or_else(PoisonError::into_inner).room_events.send(room_events);
+    }
+
+    /// Marks the connection as lost, returns the generation to pass to `Client::is_detached_since`
+    pub fn is_moderator(&self) -> bool {
+        self.moderator.load(Ordering::SeqCst)
+    }
+
+    pub fn set_moderator(&self) {
+        self.moderator.store(true, Ordering::SeqCst)
+    }
+
+    pub fn detach(&self) -> u64 {
+        self.detached.store(true, Ordering::SeqCst);
+        self.connection_generation.load(Ordering::SeqCst)
+    }
+
+    pub fn is_detached_since(&self, generation: u64) -> bool {
+        self.detached.load(Ordering::SeqCst) && self.connection_generation.load(Ordering::SeqCst) == generation
+    }
+
+    /// Moves a detached client over to a new connection, fails if it is not detached
+    pub fn try_resume(&self, connection: Connection) -> bool {
+        if self.detached.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
         }
+        *self.connection.write().unwrap_or_else(PoisonError::into_inner) = connection;
+        self.connection_generation.fetch_add(1, Ordering::SeqCst);
+        self.touch();
+        self.mark_seen();
+        true
+    }
+
+    pub fn touch(&self) {
+        self.last_activity.store(unix_millis_now(), Ordering::Relaxed);
+        self.inactivity_warned.store(false, Ordering::Relaxed);
+    }
+
+    /// Sends a close frame and stops reading from the connection, which then goes through the
+    /// regular disconnect cleanup even if the peer never answers. `retry_after` is appended to the
+    /// close reason as well for clients which only see the close frame.
+    pub fn disconnect(&self, reason: DisconnectReason, details: &str, retry_after: Option<Duration>) {
+        let retry_after = retry_after.map(|retry_after| retry_after.as_millis() as u64);
+        response_with_json(self, OutgoingMessage::Disconnecting { reason, retry_after });
+        let details = match retry_after {
+            Some(retry_after) => format!("{}, retry_after={}", details, retry_after),
+            None => details.to_string(),
+        };
+        let _ = self.send(ws::Message::Close(Some(ws::frame::CloseFrame {
+            code: reason.close_code(),
+            reason: details.into(),
+        })));
+        self.disconnect_signal.notify_one();
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        Duration::from_millis(unix_millis_now().saturating_sub(self.last_activity.load(Ordering::Relaxed)))
+    }
+
+    /// Takes `cost` from the message budget of the client
+    pub fn check_message_rate_limit(&self, cost: f64) -> RateLimitDecision {
+        self.message_rate_limit.lock().unwrap_or_else(PoisonError::into_inner).check(cost)
+    }
+
+    /// Folds a measurement into the smoothed round trip time the way TCP does, 1/8 per sample
+    pub fn record_rtt(&self, rtt_ms: u64) {
+        let rtt_ms = rtt_ms.clamp(1, MAX_RTT_MS);
+        let _ = self.smoothed_rtt_ms.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |smoothed_rtt_ms| {
+            Some(if smoothed_rtt_ms == 0 { rtt_ms } else { (smoothed_rtt_ms * 7 + rtt_ms) / 8 })
+        });
+    }
+
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        Some(self.smoothed_rtt_ms.load(Ordering::Relaxed)).filter(|rtt_ms| *rtt_ms > 0).map(Duration::from_millis)
+    }
+
+    pub fn mark_seen(&self) {
+        self.last_seen.store(unix_millis_now(), Ordering::Relaxed);
+    }
+
+    pub fn unseen_for(&self) -> Duration {
+        Duration::from_millis(unix_millis_now().saturating_sub(self.last_seen.load(Ordering::Relaxed)))
     }
 }
 
 impl Room {
-    pub fn new(room_id: String) -> Self {
+    /// Room opened by the server itself
+    pub fn with_data(namespace: Option<String>, room_id: String, room_data: RoomData) -> Self {
+        Room::spawn(namespace, room_id, room_data, None, None)
+    }
+
+    /// Room of the client's namespace
+    pub fn new_with_owner(room_id: String, client: Arc<Client>, name: Option<String>) -> Self {
+        let namespace = client.namespace();
+        let creator_uid = Some(client.uid);
+        let creator_ip = client.ip;
+        let mut room_data = RoomData::new();
+        room_data.record_history(creator_uid, RoomHistoryEventDto::Joined { name: name.clone() });
+        room_data.clients.push(RoomClient::new(client, name, Role::Owner, Duration::ZERO));
+        Room::spawn(namespace, room_id, room_data, creator_uid, creator_ip)
+    }
+
+    pub fn spawn(namespace: Option<String>, room_id: String, mut room_data: RoomData, creator_uid: Option<Uuid>, creator_ip: Option<IpAddr>) -> Self {
+        let (commands, mut commands_rx) = mpsc::unbounded_channel::<RoomCommand>();
+        let events = room_data.events.clone();
+        let span = tracing::info_span!(parent: None, "room", room_id = %room_id, namespace = namespace.as_deref().map(tracing::field::display));
+        let task_room_id = room_id.clone();
+        tokio::spawn(async move {
+            let mut flush_at = None;
+            loop {
+                if flush_at.is_none() && (room_data.room_change_pending || room_data.settings_change_pending) {
+                    flush_at = Some(tokio::time::Instant::now() + ROOM_BROADCAST_DEBOUNCE);
+                }
+                let command = match flush_at {
+                    Some(deadline) => tokio::select! {
+                        command = commands_rx.recv() => command,
+                        _ = tokio::time::sleep_until(deadline) => {
+                            flush_at = None;
+                            flush_pending_broadcasts(&task_room_id, &mut room_data);
+                            continue;
+                        }
+                    },
+                    None => commands_rx.recv().await,
+                };
+                let Some(command) = command else {
+                    break;
+                };
+                // A bug in one command must not take the whole room down
+                if std::panic::catch_unwind(AssertUnwindSafe(|| command(&mut room_data))).is_err() {
+                    tracing::error!("Room command panicked");
+                }
+            }
+        }.instrument(span));
+
         Room {
+            namespace,
             room_id,
-            data: Mutex::new(RoomData {
-                clients: Vec::new(),
-                page_url: None,
-                allow_stop_due_to_video_loading: true,
-            }),
+            commands,
+            events,
+            creator_uid,
+            creator_ip,
+            traffic: Arc::new(Traffic::default()),
         }
     }
 
-    pub fn new_with_owner(room_id: String, client: Arc<Client>) -> Self {
-        Room {
-            room_id,
-            data: Mutex::new(RoomData {
-                clients: vec![RoomClient {
-                    client,
-                    owner: true,
-                    admin: true,
-                }],
-                page_url: None,
-                allow_stop_due_to_video_loading: true,
-            }),
+    pub fn key(&self) -> RoomKey {
+        (self.namespace.clone(), self.room_id.clone())
+    }
+
+    /// Runs `command` on the task owning the room data. Commands of one room run one after another,
+    /// they must not wait for anything, so other locks are taken before or after, never inside.
+    pub async fn run<R: Send + 'static>(&self, command: impl FnOnce(&mut RoomData) -> R + Send + 'static) -> Result<R> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.commands
+            .send(Box::new(move |room_data| {
+                let _ = result_tx.send(command(room_data));
+            }))
+            .map_err(|_| anyhow!("Room {} is closed", self.room_id))?;
+        result_rx.await.map_err(|_| anyhow!("Room {} command failed", self.room_id))
+    }
+
+    /// `run` for commands which can fail themselves
+    pub async fn try_run<R: Send + 'static>(&self, command: impl FnOnce(&mut RoomData) -> Result<R> + Send + 'static) -> Result<R> {
+        self.run(command).await?
+    }
+
+    /// Rooms are attributed to the creator's IP address when known, to the client otherwise
+    pub fn created_by(&self, client: &Client) -> bool {
+        match (self.creator_ip, client.ip) {
+            (Some(creator_ip), Some(ip)) => creator_ip == ip,
+            _ => self.creator_uid == Some(client.uid),
         }
     }
 }
 
+impl Default for RoomData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RoomData {
-    pub fn add_client(&mut self, client: Arc<Client>) {
-        self.clients.push(RoomClient {
-            client,
-            owner: false,
-            admin: false,
-        })
+    pub fn new() -> Self {
+        RoomData {
+            clients: Vec::new(),
+            events: broadcast::channel(ROOM_EVENTS_CAPACITY).0,
+            events_seq: 0,
+            max_listed_members: 0,
+            recent_events: VecDeque::new(),
+            room_change_pending: false,
+            settings_change_pending: false,
+            subscribers: Vec::new(),
+            title: None,
+            description: None,
+            public: false,
+            voice_enabled: false,
+            pause_on_leave: false,
+            locked: false,
+            max_clients: None,
+            duplicate_names: DuplicateNames::Allow,
+            owner_succession: OwnerSuccession::LongestPresentMember,
+            created_at: unix_millis_now(),
+            last_activity_at: unix_millis_now(),
+            page_url: None,
+            queue: Vec::new(),
+            markers: Vec::new(),
+            watched: VecDeque::new(),
+            video_ended_uids: Vec::new(),
+            allow_stop_due_to_video_loading: true,
+            shared_files_quota: SharedFilesQuota::new(),
+            relay_quota: RelayQuota::default(),
+            event_rate_limit: TokenBucket::new(ROOM_EVENTS_BURST, ROOM_EVENTS_PER_SECOND),
+            end_to_end_encrypted: false,
+            signing_secret: generate_signing_secret(),
+            require_signed_commands: false,
+            last_signed_nonce: 0,
+            aliases: Vec::new(),
+            merge_requested_by_room: None,
+            breakout_room_ids: Vec::new(),
+            breakout_parent_room_id: None,
+            playback: PlaybackState::new(),
+            play_time: Duration::ZERO,
+            playing_since: None,
+            departed_watch_time: Duration::ZERO,
+            peak_viewers: 0,
+            videos_played: 0,
+            departed_clients: VecDeque::new(),
+            history: VecDeque::new(),
+            roles: Vec::new(),
+            bans: Vec::new(),
+            invites: Vec::new(),
+            chat_history: VecDeque::new(),
+            poll: None,
+            ready_check: None,
+            countdown: None,
+            permission_preset: PermissionPreset::StrictHost,
+            control_mode: ControlMode::Admins,
+            playback_votes: Vec::new(),
+            auto_admin_after: None,
+            admin_nominations: HashMap::new(),
+            paused_for_buffering: false,
+            closed: false,
+            restored_until: None,
+            starts_at: None,
+            #[cfg(feature = "redis")]
+            cluster: None,
+            #[cfg(feature = "redis")]
+            remote_clients: Vec::new(),
+        }
     }
 
-    pub fn remove_client(&mut self, client: &Arc<Client>) {
-        let index = self
+    pub fn record_departure(&mut self, departed_client: DepartedClientDto) {
+        // Somebody reconnecting several times should only be listed once
+        self.departed_clients.retain(|c| c.uid != departed_client.uid);
+        if self.departed_clients.len() == DEPARTED_CLIENTS_HISTORY_SIZE {
+            self.departed_clients.pop_front();
+        }
+        self.departed_clients.push_back(departed_client);
+    }
+
+    /// `actor_uid` is the member who caused the event, `None` for the server itself
+    pub fn record_history(&mut self, actor_uid: Option<Uuid>, event: RoomHistoryEventDto) {
+        if self.history.len() == ROOM_HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(RoomHistoryEntryDto { at: unix_millis_now(), actor_uid, event });
+    }
+
+    pub fn push_recent_event(&mut self, seq: u64, payload: String) {
+        if self.recent_events.len() == ROOM_EVENTS_REPLAY_SIZE {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back((seq, payload));
+    }
+
+    /// Events numbered after `seq`, `None` when some of them are not kept anymore
+    pub fn events_after(&self, seq: u64) -> Option<Vec<String>> {
+        if seq > self.events_seq {
+            return None;
+        }
+        let oldest_seq = self.recent_events.front().map_or(self.events_seq + 1, |(oldest_seq, _)| *oldest_seq);
+        if seq + 1 < oldest_seq {
+            return None;
+        }
+        Some(self.recent_events.iter().filter(|(event_seq, _)| *event_seq > seq).map(|(_, payload)| payload.clone()).collect())
+    }
+
+    pub fn mark_watched(&mut self, url: String) {
+        if self.watched.len() == WATCHED_HISTORY_SIZE {
+            self.watched.pop_front();
+        }
+        self.watched.push_back(url);
+    }
+
+    pub fn push_chat_message(&mut self, message: ChatMessageDto) {
+        if self.chat_history.len() == ROOM_CHAT_HISTORY_SIZE {
+            self.chat_history.pop_front();
+        }
+        self.chat_history.push_back(message);
+    }
+
+    pub fn try_broadcast_event(&mut self, priority: EventPriority) -> bool {
+        match priority {
+            EventPriority::Low => self.event_rate_limit.try_take_keeping(1.0, LOW_PRIORITY_EVENTS_RESERVE),
+            EventPriority::Normal => self.event_rate_limit.try_take(1.0),
+        }
+    }
+
+    /// Total time the room has spent playing
+    pub fn total_play_time(&self) -> Duration {
+        self.play_time + self.playing_since.map(|since| since.elapsed()).unwrap_or_default()
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        match (playing, self.playing_since) {
+            (true, None) => self.playing_since = Some(Instant::now()),
+            (false, Some(since)) => {
+                self.play_time += since.elapsed();
+                self.playing_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Closes the room if nobody is in it, see `StateStore`
+    /// Scheduled rooms wait for their start even when the members preparing them left
+    pub fn close_if_empty(&mut self) -> bool {
+        if self.clients.is_empty() && self.starts_at.is_none() {
+            self.closed = true;
+        }
+        self.closed
+    }
+
+    pub fn touch(&mut self) {
+        self.last_activity_at = unix_millis_now();
+    }
+
+    /// Name `client` gets in the room according to `duplicate_names`, `None` when it is taken and
+    /// duplicates are rejected
+    pub fn resolve_name(&self, client: &Client, name: Option<String>) -> Option<Option<String>> {
+        let Some(name) = name else {
+            return Some(None);
+        };
+        let taken = |name: &str| self.clients.iter()
+            .filter(|room_client| room_client.client.uid != client.uid && !room_client.hidden)
+            .any(|room_client| room_client.name.as_deref().is_some_and(|other| other.to_lowercase() == name.to_lowercase()));
+        match self.duplicate_names {
+            DuplicateNames::Allow => Some(Some(name)),
+            _ if !taken(&name) => Some(Some(name)),
+            DuplicateNames::Reject => None,
+            DuplicateNames::Suffix => (2..).map(|n| format!("{} ({})", name, n)).find(|suffixed| !taken(suffixed)).map(Some),
+        }
+    }
+
+    pub fn add_client(&mut self, client: Arc<Client>, name: Option<String>) {
+        self.touch();
+        let role = room_logic::joining_member_role(&self.clients, self.permission_preset.admin_by_default());
+        self.clients.push(RoomClient::new(client, name, role, self.total_play_time()));
+        self.update_peak_viewers();
+    }
+
+    /// Spectators watch without any rights and are left out of the member list
+    pub fn add_spectator(&mut self, client: Arc<Client>, name: Option<String>) {
+        self.touch();
+        let mut room_client = RoomClient::new(client, name, Role::Viewer, self.total_play_time());
+        room_client.spectator = true;
+        self.clients.push(room_client);
+        self.update_peak_viewers();
+    }
+
+    pub fn add_hidden_moderator(&mut self, client: Arc<Client>, name: Option<String>) {
+        let mut room_client = RoomClient::new(client, name, Role::Viewer, self.total_play_time());
+        room_client.spectator = true;
+        room_client.hidden = true;
+        self.clients.push(room_client)
+    }
+
+    fn update_peak_viewers(&mut self) {
+        let viewers = self.clients.iter().filter(|room_client| !room_client.hidden).count();
+        self.peak_viewers = self.peak_viewers.max(viewers);
+    }
+
+    /// Time the members spent watching together, summed over everybody who was in the room
+    pub fn total_watch_time(&self) -> Duration {
+        let room_play_time = self.total_play_time();
+        self.departed_watch_time + self.clients.iter()
+            .filter(|room_client| !room_client.hidden)
+            .map(|room_client| room_client.watch_time(room_play_time))
+            .sum::<Duration>()
+    }
+
+    pub fn spectator_count(&self) -> usize {
+        self.clients.iter().filter(|room_client| room_client.spectator && !room_client.hidden).count()
+    }
+
+    /// Members who aren't spectators
+    pub fn members_count(&self) -> usize {
+        self.clients.iter().filter(|room_client| !room_client.spectator).count()
+    }
+
+    /// The owner of the room or a moderator of the server
+    pub fn can_moderate(&self, client: &Client) -> bool {
+        client.is_moderator() || self.find_room_client(client).is_some_and(|room_client| room_client.is_owner())
+    }
+
+    /// Members who have to be ready for the running ready check to pass
+    pub fn ready_check_required_count(&self) -> usize {
+        let Some(ready_check) = self.ready_check.as_ref() else {
+            return 0;
+        };
+        let members_count = self.members_count();
+        (members_count * ready_check.quorum_percent as usize).div_ceil(100).max(1)
+    }
+
+    /// `true` when the client was the owner
+    pub fn remove_client(&mut self, client: &Arc<Client>) -> bool {
+        // The client may already be gone if the room was merged into another one concurrently
+        let Some(index) = self
             .clients
             .iter()
             .position(|x| Arc::ptr_eq(&x.client, client))
-            .unwrap();
+        else {
+            return false;
+        };
 
-        let owner_left = self.clients[index].owner;
+        let owner_left = self.clients[index].is_owner();
 
-        self.clients.remove(index);
+        let room_client = self.clients.remove(index);
+        if !room_client.hidden {
+            self.departed_watch_time += room_client.watch_time(self.total_play_time());
+        }
 
-        if owner_left && !self.clients.is_empty() {
-            self.clients[0].owner = true;
+        self.video_ended_uids.retain(|uid| *uid != client.uid);
+        if let Some(ready_check) = self.ready_check.as_mut() {
+            ready_check.ready_uids.retain(|uid| *uid != client.uid);
+        }
+        self.admin_nominations.remove(&client.uid);
+        for voters in self.admin_nominations.values_mut() {
+            voters.retain(|uid| *uid != client.uid);
         }
+
+        if owner_left {
+            self.hand_over_ownership();
+        }
+        owner_left
+    }
+
+    /// Makes the successor `owner_succession` picks the owner, nobody for `CloseRoom`
+    pub fn hand_over_ownership(&mut self) {
+        room_logic::hand_over_ownership(&mut self.clients, self.owner_succession);
+    }
+
+    pub fn drop_expired_invites(&mut self) {
+        let now = unix_millis_now();
+        self.invites.retain(|invite| invite.expires_at > now);
+    }
+
+    /// Counts down the uses of the invite, `false` when it is unknown, expired or used up
+    pub fn redeem_invite(&mut self, invite_id: Uuid) -> bool {
+        self.drop_expired_invites();
+        let Some(index) = self.invites.iter().position(|invite| invite.invite_id == invite_id) else {
+            return false;
+        };
+        self.invites[index].uses_left -= 1;
+        if self.invites[index].uses_left == 0 {
+            self.invites.remove(index);
+        }
+        true
+    }
+
+    pub fn is_banned(&self, client: &Client) -> bool {
+        self.bans.iter().any(|ban| {
+            ban.client_uid == client.uid || (ban.ip.is_some() && ban.ip == client.ip)
+        })
+    }
+
+    /// Whether an owner is present who has done something recently
+    pub fn has_active_owner(&self) -> bool {
+        self.clients
+            .iter()
+            .any(|room_client| room_client.is_owner() && room_client.client.idle_for() < OWNER_INACTIVITY_TIMEOUT)
     }
 
     pub fn find_room_client(&self, client: &Client) -> Option<&RoomClient> {
         self.clients.iter().find(|c| c.client.uid == client.uid)
     }
 
-    pub fn can_control(&self, client: &Client) -> bool {
-        let room_client = self.find_room_client(client);
-        if let Some(room_client) = room_client {
-            room_client.can_control()
+    pub fn has_permission(&self, client: &Client, permission: RoomPermission) -> bool {
+        self.find_room_client(client)
+            .map(|room_client| self.room_client_has_permission(room_client, permission))
+            .unwrap_or(false)
+    }
+
+    /// Anybody who controls playback or changes the page URL needs the signing secret
+    pub fn can_sign_commands(&self, room_client: &RoomClient) -> bool {
+        self.room_client_has_permission(room_client, RoomPermission::ControlPlayback)
+            || self.room_client_has_permission(room_client, RoomPermission::ChangePageUrl)
+            || self.room_client_has_permission(room_client, RoomPermission::ChangeRoomPreferences)
+    }
+
+    fn room_client_has_permission(&self, room_client: &RoomClient, permission: RoomPermission) -> bool {
+        if room_client.role == Role::Viewer {
+            return false;
+        }
+        if permission == RoomPermission::ControlPlayback {
+            return match self.control_mode {
+                ControlMode::Owner => room_client.is_owner(),
+                ControlMode::Admins => room_client.has_permission(&self.roles, permission),
+                ControlMode::Everyone | ControlMode::Vote => true,
+            };
+        }
+        self.permission_preset.everyone_permissions().contains(&permission)
+            || room_client.has_permission(&self.roles, permission)
+    }
+
+    /// Whether the client is in the room with at least the role, like `has_role(client, Role::Admin)`
+    pub fn has_role(&self, client: &Client, role: Role) -> bool {
+        self.find_room_client(client).is_some_and(|room_client| room_client.role >= role)
+    }
+
+    /// Switches the preset and brings the control mode and the roles of current members in line
+    /// with its defaults, viewers stay viewers
+    pub fn apply_permission_preset(&mut self, preset: PermissionPreset) {
+        self.permission_preset = preset;
+        self.control_mode = preset.control_mode();
+        match preset {
+            PermissionPreset::StrictHost => {
+                for room_client in self.clients.iter_mut().filter(|room_client| room_client.role == Role::Admin) {
+                    room_client.role = Role::Member;
+                }
+            }
+            PermissionPreset::CoOp => {
+                for room_client in self.clients.iter_mut().filter(|room_client| room_client.role == Role::Member) {
+                    room_client.role = Role::Admin;
+                }
+            }
+            PermissionPreset::Anarchy | PermissionPreset::Democracy => {}
+        }
+    }
+}
+
+impl Lobby {
+    pub fn new(enabled: bool) -> Self {
+        Lobby {
+            enabled,
+            data: Mutex::new(LobbyData {
+                members: Vec::new(),
+                recent_messages: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl Poll {
+    pub fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for (_, option) in self.votes.iter() {
+            counts[*option] += 1;
+        }
+        counts
+    }
+
+    /// Option with the most votes, `None` without votes or on a tie
+    pub fn winning_option(&self) -> Option<usize> {
+        let counts = self.tally();
+        let max = counts.iter().copied().max().filter(|max| *max > 0)?;
+        let mut winners = counts.iter().enumerate().filter(|(_, count)| **count == max);
+        let (winner, _) = winners.next()?;
+        winners.next().is_none().then_some(winner)
+    }
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackState {
+    pub fn new() -> Self {
+        PlaybackState {
+            position: 0.0,
+            playing: false,
+            rate: 1.0,
+            last_update: Instant::now(),
+            audio_track: None,
+            subtitle_track: None,
+        }
+    }
+
+    pub fn set_track(&mut self, kind: TrackKind, track_id: Option<String>) {
+        match kind {
+            TrackKind::Audio => self.audio_track = track_id,
+            TrackKind::Subtitle => self.subtitle_track = track_id,
+        }
+    }
+
+    pub fn current_position(&self) -> f64 {
+        if self.playing {
+            self.position + self.last_update.elapsed().as_secs_f64() * self.rate
         } else {
-            false
+            self.position
         }
     }
+
+    /// Moves the clock to `position` (or keeps the current one) and changes the play state
+    pub fn update(&mut self, position: Option<f64>, playing: Option<bool>) {
+        self.position = position.unwrap_or_else(|| self.current_position());
+        if let Some(playing) = playing {
+            self.playing = playing;
+        }
+        self.last_update = Instant::now();
+    }
+}
+
+impl LobbyData {
+    pub fn find_member_mut(&mut self, client: &Client) -> Option<&mut LobbyMember> {
+        self.members.iter_mut().find(|member| member.client.uid == client.uid)
+    }
+
+    pub fn remove_member(&mut self, client: &Client) -> bool {
+        let members_count = self.members.len();
+        self.members.retain(|member| member.client.uid != client.uid);
+        self.members.len() != members_count
+    }
+
+    pub fn push_message(&mut self, message: LobbyChatMessageDto) {
+        if self.recent_messages.len() == LOBBY_HISTORY_SIZE {
+            self.recent_messages.pop_front();
+        }
+        self.recent_messages.push_back(message);
+    }
+}
+
+impl LobbyMember {
+    pub fn new(client: Arc<Client>) -> Self {
+        LobbyMember {
+            client,
+            // Bursts of 5 messages, one message per two seconds sustained
+            rate_limit: TokenBucket::new(5.0, 0.5),
+            violations: 0,
+            muted_until: None,
+        }
+    }
+}
+
+impl Default for SharedFilesQuota {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedFilesQuota {
+    pub fn new() -> Self {
+        SharedFilesQuota {
+            window_start: Instant::now(),
+            bytes_used: 0,
+        }
+    }
+
+    pub fn try_consume(&mut self, bytes: usize) -> bool {
+        if self.window_start.elapsed() >= SHARED_FILES_QUOTA_WINDOW {
+            self.window_start = Instant::now();
+            self.bytes_used = 0;
+        }
+
+        if self.bytes_used + bytes > SHARED_FILES_QUOTA_BYTES {
+            return false;
+        }
+
+        self.bytes_used += bytes;
+        true
+    }
+}
+
+impl Default for RelayQuota {
+    fn default() -> Self {
+        RelayQuota {
+            window_start: Instant::now(),
+            bytes_used: 0,
+            warned: false,
+        }
+    }
+}
+
+impl RelayQuota {
+    /// Whether `bytes` more fit within `limit` bytes per window, they are counted only if they do
+    pub fn try_consume(&mut self, bytes: u64, limit: u64) -> bool {
+        if self.window_start.elapsed() >= RELAY_QUOTA_WINDOW {
+            *self = RelayQuota::default();
+        }
+
+        if self.bytes_used + bytes > limit {
+            return false;
+        }
+
+        self.bytes_used += bytes;
+        true
+    }
+
+    /// Counts `bytes` which are relayed even though they don't fit
+    pub fn consume(&mut self, bytes: u64) {
+        self.bytes_used += bytes;
+    }
+
+    /// Time until the window starts over
+    pub fn retry_after(&self) -> Duration {
+        RELAY_QUOTA_WINDOW.saturating_sub(self.window_start.elapsed())
+    }
 }
 
 impl RoomClient {
-    pub fn can_control(&self) -> bool {
-        self.owner || self.admin
+    pub fn new(client: Arc<Client>, name: Option<String>, role: Role, room_play_time: Duration) -> Self {
+        RoomClient {
+            client,
+            name,
+            meta: BTreeMap::new(),
+            role,
+            spectator: false,
+            hidden: false,
+            roles: Vec::new(),
+            network_report: None,
+            buffering: false,
+            // Bursts of 5 reactions, one reaction per second sustained
+            reaction_rate_limit: TokenBucket::new(5.0, 1.0),
+            typing: None,
+            typing_rate_limit: TokenBucket::new(5.0, 1.0),
+            custom_rate_limits: HashMap::new(),
+            file_offers: Vec::new(),
+            // Bursts of 256 KiB, 64 KiB per second sustained
+            file_chunk_rate_limit: TokenBucket::new(256.0 * 1024.0, 64.0 * 1024.0),
+            muted_until: None,
+            joined_at: Instant::now(),
+            play_time_at_join: room_play_time,
+        }
+    }
+
+    /// Time left of the mute, `None` when the member may chat
+    pub fn muted_for(&self) -> Option<Duration> {
+        let now = unix_millis_now();
+        self.muted_until.filter(|muted_until| *muted_until > now).map(|muted_until| Duration::from_millis(muted_until - now))
+    }
+
+    pub fn is_owner(&self) -> bool {
+        self.role == Role::Owner
+    }
+
+    /// Owners count as admins too
+    pub fn is_admin(&self) -> bool {
+        self.role >= Role::Admin
+    }
+
+    /// Restarts the statistics when the client is moved to another room
+    pub fn reset_stats(&mut self, room_play_time: Duration) {
+        self.joined_at = Instant::now();
+        self.play_time_at_join = room_play_time;
     }
-}
\ No newline at end of file
+
+    pub fn watch_time(&self, room_play_time: Duration) -> Duration {
+        room_play_time.saturating_sub(self.play_time_at_join)
+    }
+
+    /// The owner may do everything, admins keep playback and page URL control and any role adds its permissions
+    pub fn has_permission(&self, roles: &[RoomRoleDto], permission: RoomPermission) -> bool {
+        if self.is_owner() {
+            return true;
+        }
+        if self.is_admin() && matches!(permission, RoomPermission::ControlPlayback | RoomPermission::ChangePageUrl | RoomPermission::ViewMemberInfo) {
+            return true;
+        }
+        roles
+            .iter()
+            .filter(|role| self.roles.contains(&role.name))
+            .any(|role| role.permissions.contains(&permission))
+    }
+}
+impl Member for RoomClient {
+    type JoinOrder = Instant;
+
+    fn role(&self) -> Role {
+        self.role
+    }
+
+    fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+    fn joined_at(&self) -> Instant {
+        self.joined_at
+    }
+}
@@ -1,46 +1,915 @@
-use rocket::futures::future::join_all;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use rocket::serde::{Deserialize, Serialize};
 use ts_rs::TS;
 use uuid::Uuid;
-use crate::ws_app_state::{RoomClient, RoomData};
+use crate::ws_app_state::{PlaybackState, Poll, RoomBan, RoomClient, RoomData, ScheduledSession};
+use crate::scheduler::unix_millis_now;
+use crate::protocol::ClientCapability;
 
+/// The whole room as sent in `RoomChanged`: members, page url, settings and playback state
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[serde(rename_all = "camelCase")]
 #[ts(export)]
 pub struct RoomDataDto {
+    /// Members except spectators. Rooms with more than `max_listed_members` list the first ones,
+    /// the others are fetched with `GetMembers`.
     pub clients: Vec<RoomClientDto>,
+    /// Members except spectators, listed or not
+    #[serde(default)]
+    pub member_count: usize,
+    pub spectator_count: usize,
+    #[serde(flatten)]
+    pub settings: RoomSettingsDto,
+}
+
+/// Everything about the room except its members, sent as a whole in `RoomSettingsUpdated`
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct RoomSettingsDto {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Listed in the public room directory
+    pub public: bool,
+    /// Unix time in milliseconds
+    pub created_at: u64,
+    /// Unix time in milliseconds a scheduled room opens for joining, `None` once it is open
+    #[serde(default)]
+    pub starts_at: Option<u64>,
+    /// Unix time in milliseconds of the latest message of a member, as of when this was sent
+    pub last_activity_at: u64,
     pub page_url: Option<String>,
+    pub queue: Vec<String>,
+    pub markers: Vec<MarkerDto>,
+    /// Page urls played to the end, oldest first
+    pub watched: Vec<String>,
     pub allow_stop_due_to_video_loading: bool,
+    /// Whether the `Rtc*` signaling messages are relayed
+    pub voice_enabled: bool,
+    /// Playback is paused for everybody when a member other than a spectator leaves or loses their
+    /// connection, the `Pause` then has their name
+    #[serde(default)]
+    pub pause_on_leave: bool,
+    /// Set with `LockRoom`, `JoinRoom` is refused with `RoomLocked` while members already in the
+    /// room can still reconnect
+    #[serde(default)]
+    pub locked: bool,
+    /// Limit of members set by the template the room was created with, the server may have a
+    /// lower one
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    pub end_to_end_encrypted: bool,
+    pub require_signed_commands: bool,
+    pub aliases: Vec<String>,
+    pub breakout_room_ids: Vec<String>,
+    pub breakout_parent_room_id: Option<String>,
+    pub roles: Vec<RoomRoleDto>,
+    pub bans: Vec<RoomBanDto>,
+    pub permission_preset: PermissionPreset,
+    pub control_mode: ControlMode,
+    pub duplicate_names: DuplicateNames,
+    pub owner_succession: OwnerSuccession,
+    pub auto_admin_after_minutes: Option<u64>,
+    pub playback: PlaybackStateDto,
+    pub poll: Option<PollDto>,
+    pub ready_check: Option<ReadyCheckDto>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+/// Playback state computed by the server when the message was sent, late joiners start from here
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PlaybackStateDto {
+    /// Seconds
+    pub position: f64,
+    pub playing: bool,
+    pub rate: f64,
+    /// Ids of the tracks picked with `SetTrack`, `None` leaves the player's own choice
+    #[serde(default)]
+    pub audio_track: Option<String>,
+    #[serde(default)]
+    pub subtitle_track: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum TrackKind {
+    Audio,
+    Subtitle,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
 #[serde(rename_all = "camelCase")]
 #[ts(export)]
 pub struct RoomClientDto {
     pub name: Option<String>,
     #[ts(type = "string")]
     pub uid: Uuid,
+    pub role: Role,
+    /// Same as `role` being `Owner`, kept for older clients
     pub owner: bool,
+    /// Same as `role` being `Owner` or `Admin`, kept for older clients
     pub admin: bool,
+    pub connected_secs: u64,
+    /// Time spent in the room while it was playing
+    pub watched_secs: u64,
+    pub roles: Vec<String>,
+    /// The connection was lost, the member is removed unless they reconnect in time
+    pub disconnected: bool,
+    /// Unix time in milliseconds until which the member can't chat or react
+    pub muted_until: Option<u64>,
+    /// Published by the client itself with `SetClientMeta`
+    pub meta: BTreeMap<String, String>,
+    /// Announced in `Hello`, `None` when the client handles everything
+    #[serde(default)]
+    pub capabilities: Option<Vec<ClientCapability>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ScheduledSessionDto {
+    #[ts(type = "string")]
+    pub session_id: Uuid,
+    pub room_id: String,
+    pub title: String,
+    pub page_url: Option<String>,
+    pub starts_at: u64,
+    /// Only listed to the client who scheduled the session
+    #[ts(type = "string[]")]
+    pub invited_uids: Vec<Uuid>,
+    #[ts(type = "string")]
+    pub created_by: Uuid,
+    pub public: bool,
+}
+
+/// Room as listed by `GET /api/rooms`
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct AdminRoomDto {
+    pub namespace: Option<String>,
+    pub room_id: String,
+    pub members_count: usize,
+    pub page_url: Option<String>,
+    #[ts(type = "string | null")]
+    pub creator_uid: Option<Uuid>,
+    pub breakout_parent_room_id: Option<String>,
+    /// Bytes of the messages received from and sent to its members since the room was opened
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Answer to `GetStats` and `GET /api/stats`. `rooms` is only filled for moderators and requests
+/// with the admin token.
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ServerStatsDto {
+    pub rooms_count: usize,
+    /// Connected clients, including the ones in no room
+    pub clients_count: usize,
+    /// Members of all rooms
+    pub watching_count: usize,
+    pub uptime_secs: u64,
+    /// Messages received from clients, averaged over the last seconds
+    pub messages_per_second: f64,
+    pub rooms: Option<Vec<AdminRoomDto>>,
+}
+
+/// Room with its members and settings, returned by `GET /api/rooms/<room_id>`
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct AdminRoomDetailsDto {
+    pub room_id: String,
+    #[ts(type = "string | null")]
+    pub creator_uid: Option<Uuid>,
+    pub creator_ip: Option<String>,
+    pub summary: SessionSummaryDto,
+    #[serde(flatten)]
+    pub data: RoomDataDto,
+}
+
+/// Report of a member about another member of their room, see `ReportClient`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct AbuseReportDto {
+    #[ts(type = "string")]
+    pub report_id: Uuid,
+    /// Unix time in milliseconds
+    pub at: u64,
+    pub namespace: Option<String>,
+    pub room_id: String,
+    #[ts(type = "string")]
+    pub reporter_uid: Uuid,
+    pub reporter_name: Option<String>,
+    #[ts(type = "string")]
+    pub target_uid: Uuid,
+    pub target_name: Option<String>,
+    /// Identity the target authenticated with, stays the same across connections unlike the uid
+    pub target_user_id: Option<String>,
+    pub target_ip: Option<String>,
+    pub reason: String,
+    /// Latest chat messages of the room, oldest first
+    pub chat: Vec<ChatMessageDto>,
+    /// Latest entries of the audit log of the room, oldest first
+    pub history: Vec<RoomHistoryEntryDto>,
+}
+
+/// Answer to `POST /api/config/reload`, settings as `name: old -> new`
+#[derive(Serialize, Deserialize, Debug, Default, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ConfigReloadDto {
+    pub applied: Vec<String>,
+    /// Changed in the config but only read at startup, the old values stay in effect
+    pub needs_restart: Vec<String>,
+}
+
+/// Answer to `POST /api/drain` and `DELETE /api/drain`, the rooms and clients still on the server
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct DrainStatusDto {
+    pub draining: bool,
+    pub rooms_count: usize,
+    pub clients_count: usize,
+}
+
+/// Connected client as listed by `GET /api/clients`
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct AdminClientDto {
+    #[ts(type = "string")]
+    pub uid: Uuid,
+    pub name: Option<String>,
+    pub ip: Option<String>,
+    pub room_id: Option<String>,
+    pub user_id: Option<String>,
+    /// Waiting to be resumed after the connection was lost
+    pub detached: bool,
+    pub idle_secs: u64,
+    pub protocol_version: Option<u32>,
+    pub client_name: Option<String>,
+    pub client_version: Option<String>,
+    /// Bytes of the messages of the current connection
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct LobbyChatMessageDto {
+    #[ts(type = "string")]
+    pub from_uid: Uuid,
+    pub from_name: Option<String>,
+    pub text: String,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ChatMessageDto {
+    #[serde(default)]
+    #[ts(type = "string")]
+    pub message_id: Uuid,
+    #[ts(type = "string")]
+    pub from_uid: Uuid,
+    pub from_name: Option<String>,
+    pub text: String,
+    pub timestamp: u64,
+    /// Changed with `EditChatMessage` since it was sent
+    #[serde(default)]
+    pub edited: bool,
+}
+
+/// Standing of a member in the room, ordered from the fewest rights up
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum Role {
+    /// Watches along, never gets permissions from the preset or from named roles
+    Viewer,
+    Member,
+    /// Controls playback and the page URL and sees member info
+    Admin,
+    /// May do everything, exactly one per room
+    Owner,
+}
+
+impl Role {
+    /// Role of the flags used before roles, still found in old snapshots
+    pub fn from_flags(owner: bool, admin: bool) -> Self {
+        if owner {
+            Role::Owner
+        } else if admin {
+            Role::Admin
+        } else {
+            Role::Member
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum RoomPermission {
+    ControlPlayback,
+    ChangePageUrl,
+    ChangeRoomPreferences,
+    InviteMembers,
+    /// Statistics and the list of departed members
+    ViewMemberInfo,
+}
+
+/// Partial update of room settings, settings left out keep their value
+#[derive(Serialize, Deserialize, Debug, Default, TS)]
+#[serde(rename_all = "camelCase", default)]
+#[ts(export)]
+pub struct RoomSettingsUpdateDto {
+    pub allow_stop_due_to_video_loading: Option<bool>,
+    pub public: Option<bool>,
+    pub control_mode: Option<ControlMode>,
+    pub voice_enabled: Option<bool>,
+    pub pause_on_leave: Option<bool>,
+    pub duplicate_names: Option<DuplicateNames>,
+    pub owner_succession: Option<OwnerSuccession>,
+}
+
+/// What is shown before joining a room, see `IncomingMessage::GetRoomInfo`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct RoomInfoDto {
+    /// The room the id or alias asked for leads to
+    pub room_id: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub members_count: usize,
+    pub playing: bool,
+}
+
+/// Room of the public directory, see `IncomingMessage::ListPublicRooms`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PublicRoomDto {
+    pub room_id: String,
+    pub title: Option<String>,
+    pub members_count: usize,
+    pub page_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum PollKind {
+    /// Question and options chosen by the member who started it
+    Custom,
+    /// Yes or no, a majority of the room for yes moves on to the next queued video
+    SkipVideo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct PollDto {
+    #[ts(type = "string")]
+    pub poll_id: Uuid,
+    pub kind: PollKind,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Number of votes for each option
+    pub votes: Vec<usize>,
+    #[ts(type = "string")]
+    pub started_by: Uuid,
+    /// Unix time in milliseconds
+    pub ends_at: u64,
+}
+
+/// Chapter or bookmark added with `AddMarker`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct MarkerDto {
+    #[ts(type = "string")]
+    pub marker_id: Uuid,
+    /// Seconds
+    pub position: f64,
+    pub label: String,
+    #[ts(type = "string")]
+    pub added_by: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ReadyCheckDto {
+    #[ts(type = "string")]
+    pub started_by: Uuid,
+    #[ts(type = "string[]")]
+    pub ready_uids: Vec<Uuid>,
+    pub quorum_percent: u8,
+    /// Members who have to be ready for the room to start playing
+    pub required_count: usize,
+}
+
+/// Who may play, pause and seek. Viewers never do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum ControlMode {
+    Owner,
+    /// The owner, admins and roles with `ControlPlayback`
+    #[default]
+    Admins,
+    Everyone,
+    /// Everybody, commands sent within a short window are decided by majority
+    Vote,
+}
+
+/// What happens when a member picks a name somebody else in the room already has, compared
+/// case-insensitively
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum DuplicateNames {
+    #[default]
+    Allow,
+    /// Refused with `ErrorKind::NameTaken`
+    Reject,
+    /// The first free of "Alex (2)", "Alex (3)"... is used in the room
+    Suffix,
+}
+
+/// Who becomes the owner when the owner leaves. Spectators never do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum OwnerSuccession {
+    #[default]
+    LongestPresentMember,
+    /// The longest present member when there are no admins
+    LongestPresentAdmin,
+    /// The room is closed for everybody
+    CloseRoom,
+}
+
+/// Built-in combinations of member permissions and admin defaults
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum PermissionPreset {
+    /// Only the owner and the admins they pick control the room
+    StrictHost,
+    /// Everybody joins as an admin
+    CoOp,
+    /// Everybody may control playback, change the video and invite others
+    Anarchy,
+    /// Everybody may control playback, conflicting commands are decided by majority
+    Democracy,
+}
+
+impl PermissionPreset {
+    /// Playback control is left to `control_mode`
+    pub fn everyone_permissions(self) -> &'static [RoomPermission] {
+        match self {
+            PermissionPreset::StrictHost | PermissionPreset::CoOp | PermissionPreset::Democracy => &[],
+            PermissionPreset::Anarchy => &[
+                RoomPermission::ChangePageUrl,
+                RoomPermission::ChangeRoomPreferences,
+                RoomPermission::InviteMembers,
+            ],
+        }
+    }
+
+    pub fn control_mode(self) -> ControlMode {
+        match self {
+            PermissionPreset::StrictHost | PermissionPreset::CoOp => ControlMode::Admins,
+            PermissionPreset::Anarchy => ControlMode::Everyone,
+            PermissionPreset::Democracy => ControlMode::Vote,
+        }
+    }
+
+    pub fn admin_by_default(self) -> bool {
+        self == PermissionPreset::CoOp
+    }
+}
+
+/// Named permission set defined by the room owner
+/// Addresses of banned clients are never sent out
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct RoomBanDto {
+    #[ts(type = "string")]
+    pub client_uid: Uuid,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct RoomRoleDto {
+    pub name: String,
+    pub permissions: Vec<RoomPermission>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct DepartedClientDto {
+    pub name: Option<String>,
+    #[ts(type = "string")]
+    pub uid: Uuid,
+    /// Unix time in milliseconds
+    pub left_at: u64,
+}
+
+/// Entry of the room's audit log, see `IncomingMessage::GetRoomHistory`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct RoomHistoryEntryDto {
+    /// Unix time in milliseconds
+    pub at: u64,
+    /// Member who caused the event, `null` for the server itself
+    #[ts(type = "string | null")]
+    pub actor_uid: Option<Uuid>,
+    pub event: RoomHistoryEventDto,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
+#[ts(export)]
+pub enum RoomHistoryEventDto {
+    Joined { name: Option<String> },
+    Left,
+    Kicked {
+        #[ts(type = "string")]
+        target_uid: Uuid,
+    },
+    Banned {
+        #[ts(type = "string")]
+        target_uid: Uuid,
+    },
+    RoleChanged {
+        #[ts(type = "string")]
+        target_uid: Uuid,
+        role: Role,
+    },
+    /// `until` is `None` when the mute was lifted
+    Muted {
+        #[ts(type = "string")]
+        target_uid: Uuid,
+        until: Option<u64>,
+    },
+    OwnershipTransferred {
+        #[ts(type = "string")]
+        target_uid: Uuid,
+    },
+    PageUrlChanged { url: Option<String> },
+    Play { position: Option<f64> },
+    Pause { position: Option<f64> },
+    Seek { position: f64 },
+    PausedForBuffering,
+    ResumedAfterBuffering,
+    /// The member paused the room by leaving, see `RoomSettingsDto::pause_on_leave`
+    PausedOnLeave,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct WatchProgressDto {
+    pub room_id: String,
+    pub page_url: Option<String>,
+    pub at_second: f64,
+    /// Unix time in milliseconds
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct RoomStatsDto {
+    pub room_id: String,
+    pub total_play_secs: u64,
+    pub clients: Vec<RoomClientDto>,
+    /// Latest network report of every member who sent one
+    pub network_reports: Vec<ClientNetworkReportDto>,
+    pub average_rtt: Option<f64>,
+}
+
+/// Numbers of a watch party so far, sent as `SessionSummary` when the room closes
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct SessionSummaryDto {
+    pub room_id: String,
+    /// Unix time in milliseconds the room was opened
+    pub started_at: u64,
+    pub duration_secs: u64,
+    pub total_play_secs: u64,
+    /// Play time of every member added up
+    pub total_watch_secs: u64,
+    pub peak_viewers: usize,
+    pub videos_played: u32,
+}
+
+impl SessionSummaryDto {
+    pub fn from(room_id: &str, value: &RoomData) -> Self {
+        SessionSummaryDto {
+            room_id: room_id.to_string(),
+            started_at: value.created_at,
+            duration_secs: unix_millis_now().saturating_sub(value.created_at) / 1000,
+            total_play_secs: value.total_play_time().as_secs(),
+            total_watch_secs: value.total_watch_time().as_secs(),
+            peak_viewers: value.peak_viewers,
+            videos_played: value.videos_played,
+        }
+    }
+}
+
+/// Closed room as kept in the archive, see `room_archive`
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ArchivedRoomDto {
+    pub namespace: Option<String>,
+    pub room_id: String,
+    /// Unix time in milliseconds
+    pub closed_at: u64,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub public: bool,
+    pub permission_preset: PermissionPreset,
+    pub control_mode: ControlMode,
+    pub page_url: Option<String>,
+    pub queue: Vec<String>,
+    /// Page urls played to the end, oldest first
+    pub watched: Vec<String>,
+    pub summary: SessionSummaryDto,
+}
+
+impl ArchivedRoomDto {
+    pub fn from(namespace: Option<String>, room_id: &str, value: &RoomData) -> Self {
+        ArchivedRoomDto {
+            namespace,
+            room_id: room_id.to_string(),
+            closed_at: unix_millis_now(),
+            title: value.title.clone(),
+            description: value.description.clone(),
+            public: value.public,
+            permission_preset: value.permission_preset,
+            control_mode: value.control_mode,
+            page_url: value.page_url.clone(),
+            queue: value.queue.clone(),
+            watched: value.watched.iter().cloned().collect(),
+            summary: SessionSummaryDto::from(room_id, value),
+        }
+    }
+
+    /// Settings and playlist of the archived room, members and playback start over
+    pub fn restore_into(self, room_data: &mut RoomData) {
+        room_data.title = self.title;
+        room_data.description = self.description;
+        room_data.public = self.public;
+        room_data.permission_preset = self.permission_preset;
+        room_data.control_mode = self.control_mode;
+        room_data.page_url = self.page_url;
+        room_data.queue = self.queue;
+        room_data.watched = self.watched.into();
+    }
+}
+
+/// Connection quality measured by the client
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct NetworkReportDto {
+    /// Round trip time in milliseconds
+    pub rtt: f64,
+    /// Round trip time variation in milliseconds
+    pub jitter: f64,
+    /// Messages lost since the previous report
+    pub dropped: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct ClientNetworkReportDto {
+    #[ts(type = "string")]
+    pub client_uid: Uuid,
+    pub report: NetworkReportDto,
 }
 
 impl RoomDataDto {
-    pub async fn from(value: &RoomData) -> Self {
+    /// Lists up to `RoomData::max_listed_members` members
+    pub fn from(value: &RoomData) -> Self {
+        let max_listed_members = Some(value.max_listed_members).filter(|max_listed_members| *max_listed_members > 0);
+        RoomDataDto::with_members(value, max_listed_members.unwrap_or(usize::MAX))
+    }
+
+    /// Lists every member, for the admin API
+    pub fn with_all_members(value: &RoomData) -> Self {
+        RoomDataDto::with_members(value, usize::MAX)
+    }
+
+    fn with_members(value: &RoomData, limit: usize) -> Self {
         RoomDataDto {
-            clients: join_all(value.clients.iter().map(RoomClientDto::from)).await,
+            clients: room_members(value).take(limit).collect(),
+            member_count: room_member_count(value),
+            spectator_count: value.spectator_count(),
+            settings: RoomSettingsDto::from(value),
+        }
+    }
+}
+
+/// Members except spectators in the order they are listed and paged through
+pub fn room_members(value: &RoomData) -> impl Iterator<Item = RoomClientDto> + '_ {
+    let clients = value.clients.iter()
+        .filter(|room_client| !room_client.spectator)
+        .map(|room_client| RoomClientDto::from(room_client, value.total_play_time()));
+    // Members connected to other instances are listed after the local ones
+    #[cfg(feature = "redis")]
+    let clients = clients.chain(value.remote_clients.iter().map(|remote_client| remote_client.client.clone()));
+    clients
+}
+
+pub fn room_member_count(value: &RoomData) -> usize {
+    let count = value.clients.iter().filter(|room_client| !room_client.spectator).count();
+    #[cfg(feature = "redis")]
+    let count = count + value.remote_clients.len();
+    count
+}
+
+impl RoomSettingsDto {
+    pub fn from(value: &RoomData) -> Self {
+        RoomSettingsDto {
+            title: value.title.clone(),
+            description: value.description.clone(),
+            public: value.public,
+            voice_enabled: value.voice_enabled,
+            pause_on_leave: value.pause_on_leave,
+            locked: value.locked,
+            max_clients: value.max_clients,
+            created_at: value.created_at,
+            starts_at: value.starts_at,
+            last_activity_at: value.last_activity_at,
             page_url: value.page_url.clone(),
+            queue: value.queue.clone(),
+            markers: value.markers.clone(),
+            watched: value.watched.iter().cloned().collect(),
             allow_stop_due_to_video_loading: value.allow_stop_due_to_video_loading,
+            end_to_end_encrypted: value.end_to_end_encrypted,
+            require_signed_commands: value.require_signed_commands,
+            aliases: value.aliases.clone(),
+            breakout_room_ids: value.breakout_room_ids.clone(),
+            breakout_parent_room_id: value.breakout_parent_room_id.clone(),
+            roles: value.roles.clone(),
+            bans: value.bans.iter().map(RoomBanDto::from).collect(),
+            permission_preset: value.permission_preset,
+            control_mode: value.control_mode,
+            duplicate_names: value.duplicate_names,
+            owner_succession: value.owner_succession,
+            auto_admin_after_minutes: value.auto_admin_after.map(|after| after.as_secs() / 60),
+            playback: PlaybackStateDto::from(&value.playback),
+            poll: value.poll.as_ref().map(PollDto::from),
+            ready_check: ReadyCheckDto::from(value),
+        }
+    }
+}
+
+impl ReadyCheckDto {
+    pub fn from(value: &RoomData) -> Option<Self> {
+        let ready_check = value.ready_check.as_ref()?;
+        Some(ReadyCheckDto {
+            started_by: ready_check.started_by,
+            ready_uids: ready_check.ready_uids.clone(),
+            quorum_percent: ready_check.quorum_percent,
+            required_count: value.ready_check_required_count(),
+        })
+    }
+}
+
+impl PlaybackStateDto {
+    pub fn from(value: &PlaybackState) -> Self {
+        PlaybackStateDto {
+            position: value.current_position(),
+            playing: value.playing,
+            rate: value.rate,
+            audio_track: value.audio_track.clone(),
+            subtitle_track: value.subtitle_track.clone(),
         }
     }
 }
 
 impl RoomClientDto {
-    pub async fn from(value: &RoomClient) -> Self {
+    pub fn from(value: &RoomClient, room_play_time: Duration) -> Self {
         RoomClientDto {
-            name: value.client.data.lock().await.name.clone(),
+            name: value.name.clone(),
             uid: value.client.uid,
-            owner: value.owner,
-            admin: value.admin,
+            role: value.role,
+            owner: value.is_owner(),
+            admin: value.is_admin(),
+            connected_secs: value.joined_at.elapsed().as_secs(),
+            watched_secs: value.watch_time(room_play_time).as_secs(),
+            roles: value.roles.clone(),
+            disconnected: value.client.detached.load(Ordering::SeqCst),
+            muted_until: value.muted_for().and(value.muted_until),
+            meta: value.meta.clone(),
+            capabilities: value.client.client_info().and_then(|client_info| client_info.capabilities),
+        }
+    }
+}
+
+impl ScheduledSessionDto {
+    /// The session as `viewer` gets to see it
+    pub fn for_viewer(value: &ScheduledSession, viewer: Option<Uuid>) -> Self {
+        ScheduledSessionDto {
+            session_id: value.session_id,
+            room_id: value.room_id.clone(),
+            title: value.title.clone(),
+            page_url: value.page_url.clone(),
+            starts_at: value.starts_at,
+            invited_uids: if viewer == Some(value.created_by) { value.invited_uids.clone() } else { Vec::new() },
+            created_by: value.created_by,
+            public: value.public,
+        }
+    }
+}
+
+impl RoomBanDto {
+    pub fn from(value: &RoomBan) -> Self {
+        RoomBanDto {
+            client_uid: value.client_uid,
+            name: value.name.clone(),
         }
     }
-}
\ No newline at end of file
+}
+
+impl PollDto {
+    pub fn from(value: &Poll) -> Self {
+        PollDto {
+            poll_id: value.poll_id,
+            kind: value.kind,
+            question: value.question.clone(),
+            options: value.options.clone(),
+            votes: value.tally(),
+            started_by: value.started_by,
+            ends_at: value.ends_at,
+        }
+    }
+}
+
+impl RoomStatsDto {
+    pub fn from(room_id: &str, value: &RoomData) -> Self {
+        let room_play_time = value.total_play_time();
+        let network_reports: Vec<ClientNetworkReportDto> = value
+            .clients
+            .iter()
+            .filter_map(|room_client| {
+                room_client.network_report.clone().map(|report| ClientNetworkReportDto {
+                    client_uid: room_client.client.uid,
+                    report,
+                })
+            })
+            .collect();
+        let average_rtt = (!network_reports.is_empty())
+            .then(|| network_reports.iter().map(|network_report| network_report.report.rtt).sum::<f64>() / network_reports.len() as f64);
+
+        RoomStatsDto {
+            room_id: room_id.to_string(),
+            total_play_secs: room_play_time.as_secs(),
+            clients: value.clients.iter().filter(|room_client| !room_client.hidden).map(|room_client| RoomClientDto::from(room_client, room_play_time)).collect(),
+            network_reports,
+            average_rtt,
+        }
+    }
+}
+
+const MAX_DRIFT_TOLERANCE_MS: f64 = 2000.0;
+
+impl NetworkReportDto {
+    pub fn is_valid(&self) -> bool {
+        self.rtt.is_finite() && self.rtt >= 0.0 && self.jitter.is_finite() && self.jitter >= 0.0
+    }
+
+    /// Members on slow or unstable connections get a looser threshold than `base_ms`, so they
+    /// aren't resynchronized over and over because of network delay alone
+    pub fn drift_tolerance_ms(&self, base_ms: u64) -> u64 {
+        let base_ms = base_ms as f64;
+        let loss_penalty = if self.dropped > 0 { base_ms } else { 0.0 };
+        (base_ms + self.rtt / 2.0 + 2.0 * self.jitter + loss_penalty).min(MAX_DRIFT_TOLERANCE_MS.max(base_ms)) as u64
+    }
+}
@@ -1,315 +1,3895 @@
-use std::ops::{Deref, DerefMut};
-use std::sync::{Arc};
+use std::ops::DerefMut;
+use std::sync::{Arc, OnceLock, RwLock, Weak};
+use std::time::{Duration, Instant};
 use rocket::futures::{SinkExt, StreamExt};
 use rocket::serde::{Deserialize, Serialize};
+use rocket::http::Status;
 use rocket::State;
-use tokio::sync::{mpsc, MutexGuard};
+use tokio::sync::{broadcast, mpsc, oneshot, MutexGuard};
 use rocket_ws as ws;
-use rocket_ws::{Message};
-use tokio::sync::mpsc::error::SendError;
+use rocket_ws::Message;
+use rocket_ws::frame::CloseCode;
+use tokio::sync::mpsc::error::TrySendError;
 use uuid::Uuid;
-use crate::ws_app_state::{Client, ClientData, Room, RoomData, WsAppState};
-use crate::ws_dto_models::{RoomDataDto};
+use crate::ws_app_state::{Client, ClientData, ClientInfo, Connection, ConnectionTraffic, DisconnectReason, EventPriority, LobbyMember, PlaybackVote, Poll, ReadyCheck, Countdown, FileOffer, Room, PlaybackState, RoomBan, RoomClient, RoomData, RoomInvite, RoomKey, ScheduledSession, WsAppState};
+use crate::ws_dto_models::{AbuseReportDto, ArchivedRoomDto, ChatMessageDto, ControlMode, DepartedClientDto, LobbyChatMessageDto, MarkerDto, PollDto, PollKind, ReadyCheckDto, RoomClientDto, RoomDataDto, RoomHistoryEventDto, RoomPermission, Role, RoomRoleDto, RoomSettingsDto, RoomStatsDto, ScheduledSessionDto, room_member_count, room_members, SessionSummaryDto, TrackKind, WatchProgressDto};
+use crate::scheduler::{unix_millis_now, upcoming_sessions};
+use crate::qr_code::QrCode;
+use crate::command_signing::{generate_signing_secret, to_hex, verify_page_url_change_signature};
+use crate::push_notifications::PushNotification;
+use crate::display_name::sanitize_display_name;
+use crate::localization::{error_message, AcceptLanguage, Locale};
+use crate::client_address::ClientAddress;
+use crate::auth::{is_admin_token, BearerToken};
+use crate::origin::AllowedOrigin;
+use tracing::Instrument;
+use crate::client_registry::RegistrationRefused;
+use crate::protocol::{negotiate_protocol_version, supported_features, ClientCapability, ErrorKind, ErrorParams, IncomingMessage, NoticeLevel, OutgoingMessage, PlayerEvent, WireFormat, SUPPORTED_PROTOCOL_VERSIONS};
+use crate::msgpack;
+use crate::json_validation;
+use crate::config::{CustomChannelLimits, RelayQuotaAction, RoomTemplate, ServerConfig};
+use crate::validation::{validate_name, validate_namespace, validate_page_url, validate_room_id};
+use crate::content_filter::{filter_content, ContentKind};
+use crate::room_logic::{self, BufferingTransition};
+#[cfg(feature = "redis")]
+use crate::cluster::{self, ClusterEvent};
+use crate::rate_limit::{RateLimitDecision, TokenBucket};
 use anyhow::{anyhow, Result};
-use ts_rs::TS;
-
-#[derive(Serialize, Deserialize, Debug, TS)]
-#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
-#[ts(export)]
-enum IncomingMessage {
-    Ping,
-    ChangeName { new_name: String },
-    JoinRoom { room_id: String },
-    PlayerEvent { event: PlayerEvent },
-    ReportPlayerStatus { player_status: PlayerStatus },
-    ChangeClientAdminStatus { #[ts(type = "string")] client_uid: Uuid, admin: bool },
-    ChangeRoomPreferences {  page_url: String, allow_stop_due_to_video_loading: bool },
-    QuitRoom,
-}
-
-#[derive(Serialize, Deserialize, Debug, TS)]
-#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
-#[ts(export)]
-enum OutgoingMessage {
-    Pong,
-    ClientUid { #[ts(type = "string")] client_uid: Uuid },
-    Success,
-    Error { kind: ErrorKind, msg: Option<String> },
-    RoomChanged { data: RoomDataDto },
-    PlayerEvent { event: PlayerEvent, #[ts(type = "string")] client_uid: Uuid },
-    ReportPlayerStatus {  player_status: PlayerStatus, #[ts(type = "string")] client_uid: Uuid },
-}
-
-#[derive(Serialize, Deserialize, Debug, TS)]
-#[serde(rename_all = "camelCase", rename_all_fields = "camelCase", tag = "type")]
-enum PlayerEvent {
-    StartPlaying { at_second: f64 },
-    StopPlaying { at_second: f64 },
-    StopDueToVideoLoading { at_second: f64 },
-    Seek { to_second: f64 },
-}
-
-#[derive(Serialize, Deserialize, Debug, TS)]
-#[serde(rename_all = "camelCase")]
-struct PlayerStatus {
-    playing: bool,
-    loading: bool,
-    at_second: f64,
-}
-
-
-#[derive(Serialize, Deserialize, Debug, TS)]
-#[serde(rename_all = "camelCase", rename_all_fields = "camelCase")]
-enum ErrorKind {
-    InternalServerError,
-    JsonError,
-    ClientNotInAnyRoom,
-    ClientNameNotSet,
-    ClientNameTooShort,
-    RoomIdTooShort,
-    NoSuchClient,
-    Forbidden,
-}
-
-#[deny(
-    clippy::unwrap_used,
-    clippy::expect_used,
-    clippy::panic
-)]
-#[get("/ws")]
-pub fn ws_handler(ws: ws::WebSocket, state: &State<Arc<WsAppState>>) -> ws::Channel<'static> {
+use sha1::{Digest, Sha1};
+
+impl IncomingMessage {
+    /// Share of the client's message budget, messages taking locks of other clients or creating
+    /// rooms cost more than the frequent playback reports
+    fn rate_limit_cost(&self) -> f64 {
+        match self {
+            IncomingMessage::Ping { .. }
+            | IncomingMessage::ReportPlayerStatus { .. }
+            | IncomingMessage::NetworkReport { .. }
+            | IncomingMessage::ReportPosition { .. }
+            | IncomingMessage::RtcIceCandidate { .. } => 0.5,
+            IncomingMessage::ChangeName { .. }
+            | IncomingMessage::RequestRoomSnapshot
+            | IncomingMessage::ResyncFrom { .. }
+            | IncomingMessage::StartCountdown { .. }
+            | IncomingMessage::GetMembers { .. }
+            | IncomingMessage::GetRoomStats
+            | IncomingMessage::GetStats
+            | IncomingMessage::GetDepartedClients
+            | IncomingMessage::GetRoomHistory
+            | IncomingMessage::ListPublicRooms
+            | IncomingMessage::GetRoomInfo { .. }
+            | IncomingMessage::Subscribe { .. } => 3.0,
+            IncomingMessage::Resume { .. }
+            | IncomingMessage::JoinRoom { .. }
+            | IncomingMessage::Authenticate { .. }
+            | IncomingMessage::CreateRoom { .. }
+            | IncomingMessage::RestoreRoom { .. }
+            | IncomingMessage::RequestRoomMerge { .. }
+            | IncomingMessage::ScheduleSession { .. }
+            | IncomingMessage::ScheduleRoom { .. }
+            | IncomingMessage::RequestInviteQrCode
+            | IncomingMessage::CreateInviteLink { .. }
+            | IncomingMessage::CreateInvite { .. }
+            | IncomingMessage::ReportClient { .. } => 5.0,
+            IncomingMessage::CreateBreakoutRooms { .. } | IncomingMessage::RecallBreakoutRooms => 10.0,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Any incoming message may carry an `id`, the `Success`, `Error` or data message answering it
+/// carries the same `id`
+#[derive(Deserialize, Debug)]
+struct IncomingEnvelope {
+    id: Option<u64>,
+    #[serde(flatten)]
+    message: IncomingMessage,
+}
+
+#[derive(Serialize, Debug)]
+struct OutgoingEnvelope<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(flatten)]
+    message: &'a OutgoingMessage,
+}
+
+/// Playback change sent either as a `PlayerEvent` or as one of the `Play`, `Pause` and `Seek` messages
+#[derive(Debug, Clone, Copy)]
+pub enum PlaybackCommand {
+    PlayerEvent(PlayerEvent),
+    Play,
+    Pause { position: f64 },
+    Seek { position: f64 },
+}
+
+impl PlaybackCommand {
+    /// New play state, `None` for seeking
+    pub fn playing(&self) -> Option<bool> {
+        match self {
+            PlaybackCommand::PlayerEvent(PlayerEvent::StartPlaying { .. }) | PlaybackCommand::Play => Some(true),
+            PlaybackCommand::PlayerEvent(PlayerEvent::StopPlaying { .. } | PlayerEvent::StopDueToVideoLoading { .. })
+            | PlaybackCommand::Pause { .. } => Some(false),
+            PlaybackCommand::PlayerEvent(PlayerEvent::Seek { .. }) | PlaybackCommand::Seek { .. } => None,
+        }
+    }
+
+    pub fn position(&self) -> Option<f64> {
+        match *self {
+            PlaybackCommand::PlayerEvent(
+                PlayerEvent::StartPlaying { at_second } | PlayerEvent::StopPlaying { at_second } | PlayerEvent::StopDueToVideoLoading { at_second }
+            ) => Some(at_second),
+            PlaybackCommand::PlayerEvent(PlayerEvent::Seek { to_second }) => Some(to_second),
+            PlaybackCommand::Play => None,
+            PlaybackCommand::Pause { position } | PlaybackCommand::Seek { position } => Some(position),
+        }
+    }
+
+    fn to_history_event(self) -> RoomHistoryEventDto {
+        match (self.playing(), self.position()) {
+            (Some(true), position) => RoomHistoryEventDto::Play { position },
+            (Some(false), position) => RoomHistoryEventDto::Pause { position },
+            (None, position) => RoomHistoryEventDto::Seek { position: position.unwrap_or_default() },
+        }
+    }
+
+    /// The command as it should reach a member whose messages take `delay` to arrive. The room
+    /// keeps playing meanwhile, so the positions to start playing from are moved ahead by that much.
+    fn to_outgoing_message(self, client_uid: Uuid, playback: &PlaybackState, delay: Duration) -> OutgoingMessage {
+        let ahead = |position: f64| if playback.playing { position + delay.as_secs_f64() * playback.rate } else { position };
+        match self {
+            PlaybackCommand::PlayerEvent(PlayerEvent::StartPlaying { at_second }) => {
+                OutgoingMessage::PlayerEvent { event: PlayerEvent::StartPlaying { at_second: ahead(at_second) }, client_uid }
+            }
+            PlaybackCommand::PlayerEvent(PlayerEvent::Seek { to_second }) => {
+                OutgoingMessage::PlayerEvent { event: PlayerEvent::Seek { to_second: ahead(to_second) }, client_uid }
+            }
+            PlaybackCommand::PlayerEvent(event) => OutgoingMessage::PlayerEvent { event, client_uid },
+            PlaybackCommand::Play => OutgoingMessage::Play { client_uid, position: Some(ahead(playback.current_position())) },
+            PlaybackCommand::Pause { position } => OutgoingMessage::Pause { position, client_uid, left_name: None },
+            PlaybackCommand::Seek { position } => OutgoingMessage::Seek { position: ahead(position), client_uid },
+        }
+    }
+}
+
+const SHARED_FILE_RATE_LIMIT_COST: f64 = 5.0;
+/// Clients which keep sending messages above `ServerConfig::max_message_size` run out of budget fast
+/// and are disconnected
+const OVERSIZED_MESSAGE_RATE_LIMIT_COST: f64 = 10.0;
+/// Frames up to this many times `ServerConfig::max_frame_size` are read and answered with
+/// `PayloadTooLarge`, larger ones close the connection before they are buffered whole
+const OVERSIZED_FRAME_READ_FACTOR: usize = 2;
+/// Key material and encrypted chat messages, encrypted `Custom` messages get the limits of their channel
+const MAX_ENCRYPTED_PAYLOAD_SIZE: usize = 64 * 1024;
+/// Conflicting playback commands sent within this window are resolved by majority in democracy mode
+const PLAYBACK_VOTE_WINDOW: Duration = Duration::from_millis(1500);
+/// How long the old id of a renamed room still leads to it
+const RENAMED_ROOM_ALIAS_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// A member who stopped sending `TypingStart` for this long is no longer shown as typing
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_QUEUE_LENGTH: usize = 100;
+const MAX_AUTO_ADMIN_DELAY_MINUTES: u32 = 24 * 60;
+const MAX_ROLE_NAME_LENGTH: usize = 32;
+const MAX_TRACK_ID_LENGTH: usize = 128;
+const MAX_MARKERS: usize = 100;
+const MAX_ROOM_SUBSCRIPTIONS: usize = 10;
+const MAX_MARKER_LABEL_LENGTH: usize = 64;
+const MAX_CUSTOM_CHANNEL_LENGTH: usize = 64;
+/// Channels one member may use within a room
+const MAX_CUSTOM_CHANNELS_PER_MEMBER: usize = 16;
+const MAX_CLIENT_META_ENTRIES: usize = 16;
+const MAX_CLIENT_META_KEY_LENGTH: usize = 32;
+const MAX_CLIENT_META_VALUE_LENGTH: usize = 256;
+const MAX_RTC_SDP_SIZE: usize = 16 * 1024;
+const MAX_RTC_CANDIDATE_SIZE: usize = 1024;
+/// Files offered with `OfferFile` are relayed through the server, this is plenty for subtitles
+const MAX_OFFERED_FILE_SIZE: u64 = 1024 * 1024;
+/// Decoded bytes of one `FileChunk`
+const MAX_FILE_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_FILE_OFFERS_PER_MEMBER: usize = 8;
+const MAX_OFFERED_FILE_NAME_LENGTH: usize = 255;
+const MAX_FILE_HASH_LENGTH: usize = 128;
+const MAX_ROOM_ROLES: usize = 16;
+const MAX_BREAKOUT_ROOMS: usize = 10;
+const MAX_MEMBERS_PAGE_SIZE: usize = 100;
+/// Client names and versions from `Hello` are only logged, longer ones are cut
+const MAX_CLIENT_INFO_LENGTH: usize = 64;
+const DEFAULT_POLL_DURATION: Duration = Duration::from_secs(60);
+const MAX_POLL_DURATION: Duration = Duration::from_secs(10 * 60);
+const MAX_POLL_QUESTION_LENGTH: usize = 200;
+const MAX_POLL_OPTION_LENGTH: usize = 100;
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_COUNTDOWN_SECONDS: u32 = 10;
+const MAX_REPORT_REASON_LENGTH: usize = 500;
+/// Chat messages and audit log entries of the room attached to an abuse report
+const REPORT_CONTEXT_SIZE: usize = 20;
+const ALLOWED_REACTIONS: &[&str] = &["👍", "👎", "❤️", "😂", "😮", "😢", "😡", "🔥", "👏", "🎉"];
+/// Rate limit violations after which a lobby member is muted
+const LOBBY_VIOLATIONS_BEFORE_MUTE: u32 = 3;
+const LOBBY_MUTE_DURATION: Duration = Duration::from_secs(60);
+const MAX_ROOM_MUTE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_INVITE_LINK_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_INVITE_LINK_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// `locale` takes precedence over the `Accept-Language` header for human readable texts, `format`
+/// is `json` (default) or `msgpack`, see `WireFormat`. `token` authenticates the connection like
+/// an `Authorization: Bearer` header, see `auth`. Pages and extensions outside `allowed_origins`
+/// are refused with 403, see `origin`.
+#[get("/ws?<locale>&<format>&<token>")]
+#[allow(clippy::too_many_arguments)]
+pub fn ws_handler(ws: ws::WebSocket, locale: Option<&str>, format: Option<&str>, token: Option<&str>, _origin: AllowedOrigin, bearer_token: BearerToken, accept_language: AcceptLanguage, client_address: ClientAddress, state: &State<Arc<WsAppState>>) -> Result<ws::Channel<'static>, Status> {
     let state = state.inner().clone();
+    let token = token.or(bearer_token.0.as_deref());
+    let moderator = token.is_some_and(|token| is_admin_token(&state.config(), token));
+    // The admin token passes even when it isn't one of the `auth_` tokens
+    let Ok(user_id) = (if moderator { Ok(None) } else { state.authenticator.authenticate(token) }) else {
+        tracing::info!(ip = ?client_address.0, "Refused unauthenticated connection");
+        return Err(Status::Unauthorized);
+    };
+    let locale = locale
+        .and_then(Locale::negotiate)
+        .or_else(|| accept_language.0.as_deref().and_then(Locale::negotiate))
+        .unwrap_or(Locale::En);
+    let format = WireFormat::from_query(format);
+    let ip = client_address.0;
+    let span = tracing::info_span!("connection", client_uid = tracing::field::Empty, ip = ip.map(tracing::field::display));
+
+    let max_read_size = state.config().max_frame_size() * OVERSIZED_FRAME_READ_FACTOR;
+    let ws = ws.config(ws::Config { max_message_size: Some(max_read_size), max_frame_size: Some(max_read_size), ..ws::Config::default() });
+    Ok(ws.channel(move|stream| {
+        Box::pin(async move {
+            let (mut sink, mut stream) = stream.split();
+            // Create a channel for this client
+            let (tx, mut rx) = mpsc::channel::<Message>(state.config().outgoing_queue_capacity);
+            let (room_events, mut room_events_rx) = mpsc::unbounded_channel::<Option<broadcast::Receiver<Message>>>();
+            // Register this client
+            let slow_client_timeout = state.config().slow_client_timeout();
+            let traffic = Arc::new(ConnectionTraffic::default());
+            let writer_traffic = traffic.clone();
+            let current_client = Arc::new(Client::new(Connection { tx, traffic, room_events, client_info: Arc::new(OnceLock::new()), request_id: Arc::new(RwLock::new(None)) }, ip, locale, &state.config()));
+            current_client.data.lock().await.user_id = user_id;
+            if moderator {
+                current_client.set_moderator();
+            }
+            tracing::Span::current().record("client_uid", tracing::field::display(current_client.uid));
+            tracing::debug!("Connected");
+
+            // spawn a task for outgoing messages to this client. It tells the loop reading the
+            // connection when writing fails, so the client is cleaned up right away.
+            let (send_failed_tx, mut send_failed) = oneshot::channel::<()>();
+            let writer_state = state.clone();
+            tokio::spawn(async move {
+                let mut room_events: Option<broadcast::Receiver<Message>> = None;
+                loop {
+                    let msg = tokio::select! {
+                        biased;
+                        // Disabled once the client is gone or resumed elsewhere, queued messages still go out
+                        Some(subscription) = room_events_rx.recv() => {
+                            room_events = subscription;
+                            continue;
+                        }
+                        msg = rx.recv() => match msg {
+                            Some(msg) => msg,
+                            None => break,
+                        },
+                        event = next_room_event(&mut room_events) => match event {
+                            Ok(msg) => msg,
+                            // The client notices the gap in the event numbers and asks for the missed ones
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => {
+                                room_events = None;
+                                continue;
+                            }
+                        },
+                    };
+                    let msg = encode_outgoing(format, msg);
+                    let size = msg.len();
+                    match tokio::time::timeout(slow_client_timeout, sink.send(msg)).await {
+                        Ok(Ok(())) => {
+                            writer_traffic.record_sent(size);
+                            writer_state.metrics.traffic.record_sent(size);
+                            continue;
+                        }
+                        Ok(Err(e)) => tracing::debug!("Failed to send a message: {:?}", e),
+                        Err(_) => tracing::warn!("Closing a connection which stopped accepting messages"),
+                    }
+                    writer_state.metrics.send_failures.increment();
+                    let _ = send_failed_tx.send(());
+                    break;
+                }
+            }.instrument(tracing::Span::current()));
+
+            response_with_json(&current_client, OutgoingMessage::ClientUid {
+                client_uid: current_client.uid,
+                resume_token: state.resume_token(current_client.uid),
+            });
+            if let Some(motd) = state.config().motd.clone() {
+                response_with_json(&current_client, OutgoingMessage::ServerNotice { text: motd, level: NoticeLevel::Info });
+            }
+            send_continue_watching(&state, &current_client).await;
+
+            // Registered once the client sends something, connections which never do are closed
+            // without taking up a slot. Pings and pongs of the websocket don't count.
+            let handshake_deadline = state.config().handshake_timeout().map(|timeout| tokio::time::Instant::now() + timeout);
+            let first_msg = loop {
+                let msg = match handshake_deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                        Ok(msg) => msg,
+                        Err(_) => {
+                            tracing::debug!("Closing a connection which didn't send anything in time");
+                            state.metrics.handshake_timeouts.increment();
+                            current_client.disconnect(DisconnectReason::HandshakeTimeout, "Handshake timeout", Some(state.reconnect_retry_after()));
+                            return Ok(());
+                        }
+                    },
+                    None => stream.next().await,
+                };
+                match msg {
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    msg => break msg,
+                }
+            };
+
+            match state.clients.try_insert(current_client.clone(), state.config().max_connections(), state.config().max_connections_per_ip()) {
+                Ok(()) => {}
+                Err(RegistrationRefused::ServerFull) => {
+                    state.metrics.connections_rejected.increment();
+                    let retry_after = state.reconnect_retry_after();
+                    response_with_error_retry_after(&current_client, ErrorKind::ServerOverloaded, retry_after);
+                    current_client.disconnect(DisconnectReason::ServerOverloaded, "Server overloaded", Some(retry_after));
+                    return Ok(());
+                }
+                Err(RegistrationRefused::TooManyFromAddress) => {
+                    state.metrics.connections_per_ip_rejected.increment();
+                    let retry_after = state.reconnect_retry_after();
+                    response_with_error_retry_after(&current_client, ErrorKind::TooManyConnections, retry_after);
+                    current_client.disconnect(DisconnectReason::TooManyConnections, "Too many connections from the address", Some(retry_after));
+                    return Ok(());
+                }
+            }
+
+            // handle incoming messages, starting with the one which ended the handshake
+            let mut first_msg = Some(first_msg);
+            let mut current_client = current_client;
+            let mut disconnected_by_server = false;
+            let mut left_on_purpose = false;
+            let mut writing_failed = false;
+            loop {
+                let msg = match first_msg.take() {
+                    Some(msg) => msg,
+                    None => tokio::select! {
+                        msg = stream.next() => msg,
+                        _ = current_client.disconnect_signal.notified() => {
+                            disconnected_by_server = true;
+                            break;
+                        },
+                        // Not taken when the writer ends without failing
+                        Ok(()) = &mut send_failed => {
+                            writing_failed = true;
+                            break;
+                        }
+                    },
+                };
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    // The library already gave up on the frame, the connection can't read the
+                    // next one from where this one ends
+                    Some(Err(ws::result::Error::Capacity(_))) => {
+                        tracing::warn!("Closing the connection of a client which sent an oversized frame");
+                        state.metrics.oversized_messages.increment();
+                        response_with_error(&current_client, ErrorKind::PayloadTooLarge);
+                        current_client.disconnect(DisconnectReason::MessageTooLarge, "Message too large", None);
+                        disconnected_by_server = true;
+                        break;
+                    }
+                    // A fragmented message may have been cut off in the middle, what follows can't
+                    // be trusted
+                    Some(Err(ws::result::Error::Utf8)) => {
+                        tracing::debug!("Closing the connection of a client which sent invalid UTF-8");
+                        response_with_error(&current_client, ErrorKind::InvalidUtf8);
+                        current_client.disconnect(DisconnectReason::InvalidFrame, "Invalid UTF-8", None);
+                        disconnected_by_server = true;
+                        break;
+                    }
+                    _ => break,
+                };
+                current_client.mark_seen();
+                if matches!(msg, Message::Text(_) | Message::Binary(_)) {
+                    state.metrics.messages_received.record();
+                    current_client.traffic().record_received(msg.len());
+                    state.metrics.traffic.record_received(msg.len());
+                }
+                let max_size = match &msg {
+                    Message::Text(_) => state.config().max_message_size,
+                    _ => state.config().max_frame_size(),
+                };
+                let oversized = match &msg {
+                    Message::Text(text) => text.len() > max_size,
+                    Message::Binary(data) => data.len() > max_size,
+                    _ => false,
+                };
+                if oversized {
+                    state.metrics.oversized_messages.increment();
+                    if check_rate_limit(&state, &current_client, OVERSIZED_MESSAGE_RATE_LIMIT_COST) {
+                        response_with_error_params(&current_client, ErrorKind::PayloadTooLarge, ErrorParams::max(max_size));
+                    }
+                    continue;
+                }
+                let Ok(msg) = decode_incoming(format, msg) else {
+                    response_with_error(&current_client, ErrorKind::JsonError);
+                    continue;
+                };
+                match &msg {
+                    // Answers to the heartbeat only prove the connection is alive, not that anybody uses it
+                    Message::Pong(_) => continue,
+                    // The websocket library answers with a pong itself, a ping keeps the client
+                    // active like `IncomingMessage::Ping`
+                    Message::Ping(_) => {
+                        current_client.touch();
+                        continue;
+                    }
+                    // Echoed by the websocket library. Leaving on purpose skips the grace period
+                    // meant for lost connections.
+                    Message::Close(close_frame) => {
+                        left_on_purpose = close_frame.as_ref().is_some_and(|close_frame| close_frame.code == CloseCode::Normal);
+                        break;
+                    }
+                    _ => {}
+                }
+                match handle_message(&current_client, msg, &state).await {
+                    Ok(Some(resumed_client)) => current_client = resumed_client,
+                    Ok(None) => {}
+                    Err(e) => match e.downcast::<Denied>() {
+                        Ok(Denied(error_kind)) => response_with_error(&current_client, error_kind),
+                        Err(e) => {
+                            tracing::error!("Error while handling ws client message: {:?}", e);
+                            response_with_error(&current_client, ErrorKind::InternalServerError);
+                        }
+                    },
+                }
+                current_client.set_request_id(None);
+            }
+
+            if disconnected_by_server || left_on_purpose || writing_failed || state.config().disconnect_grace_period().is_zero() {
+                handle_client_disconnect(&state, &current_client).await;
+            } else {
+                // The connection may have just blinked, the client gets a chance to resume
+                let generation = current_client.detach();
+                handle_client_detached(&current_client).await;
+                tokio::spawn(async move {
+                    tokio::time::sleep(state.config().disconnect_grace_period()).await;
+                    if current_client.is_detached_since(generation) {
+                        handle_client_disconnect(&state, &current_client).await;
+                    }
+                }.instrument(tracing::Span::current()));
+            }
+
+            tracing::debug!("Disconnected");
+            Ok(())
+        }.instrument(span))
+    }))
+}
+
+/// Returns the resumed client when the connection took over another one with `Resume`
+async fn handle_message(current_client: &Arc<Client>, msg: Message, state: &Arc<WsAppState>) -> Result<Option<Arc<Client>>> {
+    current_client.touch();
+
+    if let Message::Text(txt) = msg {
+        match serde_json::from_str::<IncomingEnvelope>(&txt) {
+            Ok(IncomingEnvelope { id, message: inc }) => {
+                current_client.set_request_id(id);
+                if !check_rate_limit(state, current_client, inc.rate_limit_cost()) {
+                    return Ok(None);
+                }
+                if state.config().strict_messages
+                    && let Some(field) = json_validation::unknown_field(&txt, &inc, &["id"])
+                {
+                    response_with_json_error(current_client, format!("Unknown field {}", field), Some(field));
+                    return Ok(None);
+                }
+                if current_client.client_info().is_none() && !matches!(inc, IncomingMessage::Hello { .. } | IncomingMessage::Ping { .. }) {
+                    response_with_error(current_client, ErrorKind::HelloRequired);
+                    return Ok(None);
+                }
+
+                let message_type = inc.kind();
+                let span = tracing::debug_span!("message", r#type = message_type, room_id = tracing::field::Empty);
+                let started_at = Instant::now();
+                let result = dispatch_message(current_client, inc, state).instrument(span.clone()).await;
+                let elapsed = started_at.elapsed();
+                if !span.is_disabled() {
+                    // The room after handling, the one joined by `JoinRoom`
+                    if let Some(room) = &current_client.data.lock().await.room {
+                        span.record("room_id", room.room_id.as_str());
+                    }
+                    tracing::debug!(parent: &span, elapsed_ms = elapsed.as_secs_f64() * 1000.0, "Handled message");
+                }
+                state.metrics.message_handling.record(message_type, elapsed);
+                return result;
+            }
+            Err(e) => {
+                // The `id` of a message which is otherwise invalid is still echoed if it can be found
+                let id = serde_json::from_str::<serde_json::Value>(&txt).ok().and_then(|value| value.get("id")?.as_u64());
+                current_client.set_request_id(id);
+                if !check_rate_limit(state, current_client, 1.0) {
+                    return Ok(None);
+                }
+                response_with_json_error(current_client, format!("Invalid JSON: {}", e), json_validation::failed_field(&e))
+            }
+        }
+    } else if let Message::Binary(data) = msg
+        && check_rate_limit(state, current_client, SHARED_FILE_RATE_LIMIT_COST)
+    {
+        handle_shared_file(state, current_client, data).await?;
+    }
+
+    Ok(None)
+}
+
+/// Handles a message which passed the rate limit and the checks of its envelope. Returns the
+/// resumed client when the connection took over another one with `Resume`.
+async fn dispatch_message(current_client: &Arc<Client>, inc: IncomingMessage, state: &Arc<WsAppState>) -> Result<Option<Arc<Client>>> {
+    let mut resumed_client = None;
+
+    match inc {
+        IncomingMessage::Ping { client_time, rtt_ms } => {
+            if let Some(rtt_ms) = rtt_ms {
+                current_client.record_rtt(rtt_ms);
+            }
+            reply_with_json(current_client, OutgoingMessage::Pong { client_time, server_time: unix_millis_now() })
+        }
+        IncomingMessage::Hello { protocol_version, client_name, client_version, namespace, capabilities, locale } => 'label: {
+            let Some(protocol_version) = negotiate_protocol_version(protocol_version) else {
+                response_with_error(current_client, ErrorKind::UnsupportedProtocolVersion);
+                current_client.disconnect(DisconnectReason::UnsupportedProtocolVersion, "Unsupported protocol version", None);
+                break 'label;
+            };
+            let namespace = match namespace.as_deref().map(validate_namespace).transpose() {
+                Ok(namespace) => namespace,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            let client_info = current_client.set_client_info(ClientInfo {
+                protocol_version,
+                client_name: client_name.chars().take(MAX_CLIENT_INFO_LENGTH).collect(),
+                client_version: client_version.chars().take(MAX_CLIENT_INFO_LENGTH).collect(),
+                namespace,
+                capabilities: capabilities.map(|mut capabilities| {
+                    capabilities.retain(|capability| *capability != ClientCapability::Unknown);
+                    capabilities
+                }),
+                locale: locale.as_deref().and_then(Locale::negotiate),
+            });
+            tracing::info!(
+                protocol_version = client_info.protocol_version,
+                client_name = %client_info.client_name,
+                client_version = %client_info.client_version,
+                namespace = client_info.namespace.as_deref().map(tracing::field::display),
+                "Client said hello"
+            );
+            reply_with_json(current_client, OutgoingMessage::Welcome {
+                protocol_version: client_info.protocol_version,
+                supported_versions: SUPPORTED_PROTOCOL_VERSIONS.collect(),
+                features: supported_features(state),
+            });
+        }
+        IncomingMessage::Resume { token } => 'label: {
+            let uid = state.verify_resume_token(&token).filter(|uid| *uid != current_client.uid);
+            let Some(client_to_resume) = uid.and_then(|uid| state.find_client(uid)) else {
+                // Members of rooms restored after a restart have no client to take over
+                match uid {
+                    Some(uid) => resumed_client = resume_restored_member(state, current_client, uid).await?,
+                    None => response_with_error(current_client, ErrorKind::ResumeFailed),
+                }
+                break 'label;
+            };
+
+            // The connection's own fresh client is dropped, its channel goes to the resumed one.
+            // Its room is only found in the namespace the client said hello with.
+            if client_to_resume.namespace() != current_client.namespace() || !client_to_resume.try_resume(current_client.connection()) {
+                response_with_error(current_client, ErrorKind::ResumeFailed);
+                break 'label;
+            }
+            if current_client.is_moderator() {
+                client_to_resume.set_moderator();
+            }
+            handle_client_disconnect(state, current_client).await;
+            tracing::info!(resumed_client_uid = %client_to_resume.uid, "Resumed client");
+
+            reply_with_json(&client_to_resume, OutgoingMessage::ClientUid {
+                client_uid: client_to_resume.uid,
+                resume_token: state.resume_token(client_to_resume.uid),
+            });
+            let room = client_to_resume.data.lock().await.room.clone();
+            if let Some(room) = room {
+                // The subscription to the room events went away with the old connection
+                let resumed_client = client_to_resume.clone();
+                room.run(move |room_data| {
+                    broadcast_client_change(room_data, resumed_client.uid);
+                    send_room_snapshot(room_data, &resumed_client);
+                }).await?;
+            }
+            resumed_client = Some(client_to_resume);
+        }
+        IncomingMessage::Authenticate { token } => {
+            if is_admin_token(&state.config(), &token) {
+                current_client.set_moderator();
+                tracing::info!("Client became a moderator");
+                response_with_success(current_client);
+            } else {
+                response_with_error(current_client, ErrorKind::AuthenticationFailed);
+            }
+        }
+        IncomingMessage::ChangeName { new_name } => 'label: {
+            let new_name = match validate_name(&state.config(), &sanitize_display_name(&new_name))
+                .and_then(|new_name| filter_content(state, ContentKind::Name, new_name))
+            {
+                Ok(new_name) => new_name,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+
+            // Held until the room took the name, so it can still be refused there
+            let mut client_data = current_client.data.lock().await;
+            if let Some(room) = client_data.room.clone() {
+                let renamed_client = current_client.clone();
+                let room_name = new_name.clone();
+                let renamed = room.run(move |room_data| {
+                    let Some(room_name) = room_data.resolve_name(&renamed_client, Some(room_name)) else {
+                        return false;
+                    };
+                    if let Some(room_client) = room_data.clients.iter_mut().find(|x| Arc::ptr_eq(&x.client, &renamed_client)) {
+                        room_client.name = room_name;
+                    }
+                    broadcast_client_change(room_data, renamed_client.uid);
+                    true
+                }).await?;
+                if !renamed {
+                    response_with_error(current_client, ErrorKind::NameTaken);
+                    break 'label;
+                }
+            }
+            client_data.name = Some(new_name);
+            response_with_success(current_client);
+        }
+        IncomingMessage::RtcOffer { to_uid, sdp } => 'label: {
+            if sdp.len() > MAX_RTC_SDP_SIZE {
+                response_with_error(current_client, ErrorKind::PayloadTooLarge);
+                break 'label;
+            }
+            relay_rtc_signal(current_client, to_uid, OutgoingMessage::RtcOffer { from_uid: current_client.uid, sdp }).await?;
+        },
+        IncomingMessage::RtcAnswer { to_uid, sdp } => 'label: {
+            if sdp.len() > MAX_RTC_SDP_SIZE {
+                response_with_error(current_client, ErrorKind::PayloadTooLarge);
+                break 'label;
+            }
+            relay_rtc_signal(current_client, to_uid, OutgoingMessage::RtcAnswer { from_uid: current_client.uid, sdp }).await?;
+        },
+        IncomingMessage::RtcIceCandidate { to_uid, candidate, sdp_mid, sdp_m_line_index } => 'label: {
+            if candidate.len() + sdp_mid.as_ref().map_or(0, String::len) > MAX_RTC_CANDIDATE_SIZE {
+                response_with_error(current_client, ErrorKind::PayloadTooLarge);
+                break 'label;
+            }
+            let message = OutgoingMessage::RtcIceCandidate { from_uid: current_client.uid, candidate, sdp_mid, sdp_m_line_index };
+            relay_rtc_signal(current_client, to_uid, message).await?;
+        },
+        IncomingMessage::SetClientMeta { key, value } => 'label: {
+            let valid_key = !key.is_empty()
+                && key.len() <= MAX_CLIENT_META_KEY_LENGTH
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            let valid_value = value.as_ref().is_none_or(|value| value.chars().count() <= MAX_CLIENT_META_VALUE_LENGTH && !value.chars().any(char::is_control));
+            if !valid_key || !valid_value {
+                response_with_error(current_client, ErrorKind::InvalidClientMeta);
+                break 'label;
+            }
+
+            let mut client_data = current_client.data.lock().await;
+            match value {
+                Some(value) => {
+                    if !client_data.meta.contains_key(&key) && client_data.meta.len() >= MAX_CLIENT_META_ENTRIES {
+                        response_with_error(current_client, ErrorKind::InvalidClientMeta);
+                        break 'label;
+                    }
+                    client_data.meta.insert(key, value);
+                }
+                None => {
+                    client_data.meta.remove(&key);
+                }
+            }
+            response_with_success(current_client);
+            if let Some(room) = client_data.room.clone() {
+                let meta = client_data.meta.clone();
+                drop(client_data);
+                let current_client = current_client.clone();
+                room.run(move |room_data| {
+                    if let Some(room_client) = room_data.clients.iter_mut().find(|x| Arc::ptr_eq(&x.client, &current_client)) {
+                        room_client.meta = meta;
+                    }
+                    broadcast_client_change(room_data, current_client.uid);
+                }).await?;
+            }
+        },
+        IncomingMessage::JoinRoom { room_id, invite, spectator, hidden } => 'label: {
+            if state.is_draining() {
+                response_with_error_retry_after(current_client, ErrorKind::ServerDraining, state.reconnect_retry_after());
+                break 'label;
+            }
+            if !validate_client_name(current_client).await {
+                break 'label;
+            }
+            if hidden {
+                require_moderator(current_client)?;
+            }
+
+            let room_id = match validate_room_id(&state.config(), &room_id) {
+                Ok(room_id) => room_id,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+
+            let name = current_client.data.lock().await.name.clone();
+            let namespace = current_client.namespace();
+            let room_id = state.resolve_room_id(namespace.as_deref(), &room_id).await;
+            let invite_id = match invite.map(|invite| state.verify_invite_token(namespace.as_deref(), &room_id, &invite)) {
+                Some(None) => {
+                    response_with_error(current_client, ErrorKind::InvalidInvite);
+                    break 'label;
+                }
+                Some(Some(invite_id)) => Some(invite_id),
+                None => None,
+            };
+            // Looked up again when the room found closes in the meantime, or when
+            // somebody else opens a room with the same id first
+            loop {
+                if let Some(room) = state.store.room(namespace.as_deref(), &room_id).await {
+                    // The client who scheduled the room may prepare it before the start
+                    let starts_at = state.scheduled_rooms.lock().await.get(&room.key()).copied();
+                    if let Some(starts_at) = starts_at && room.creator_uid != Some(current_client.uid) {
+                        let retry_after = Duration::from_millis(starts_at.saturating_sub(unix_millis_now()));
+                        response_with_error_retry_after(current_client, ErrorKind::RoomNotStarted { starts_at }, retry_after);
+                        break 'label;
+                    }
+                    // Join existing room
+                    // The room command subscribes the connection, the lock keeps the room of
+                    // the client from being changed in the meantime
+                    let mut client_data = current_client.data.lock().await;
+                    let joining_client = current_client.clone();
+                    let name = name.clone();
+                    let meta = client_data.meta.clone();
+                    let max_clients_per_room = state.config().max_clients_per_room(namespace.as_deref());
+                    let max_listed_members = state.config().max_listed_members;
+                    let chat_history = room.run(move |room_data| {
+                        if room_data.closed {
+                            return Ok(None);
+                        }
+                        // Joining the room again changes nothing
+                        if room_data.find_room_client(&joining_client).is_some() {
+                            response_with_success(&joining_client);
+                            send_room_snapshot(room_data, &joining_client);
+                            return Ok(Some(Vec::new()));
+                        }
+                        room_data.max_listed_members = max_listed_members;
+                        if hidden {
+                            room_data.add_hidden_moderator(joining_client.clone(), name);
+                            tracing::info!(client_uid = %joining_client.uid, "Moderator joined the room hidden");
+                            response_with_success(&joining_client);
+                            send_room_snapshot(room_data, &joining_client);
+                            return Ok(Some(room_data.chat_history.iter().cloned().collect::<Vec<ChatMessageDto>>()));
+                        }
+                        if room_data.is_banned(&joining_client) {
+                            return Err(ErrorKind::Banned);
+                        }
+                        if room_data.locked {
+                            return Err(ErrorKind::RoomLocked);
+                        }
+                        let max_clients = max_clients_per_room.into_iter().chain(room_data.max_clients).min();
+                        if max_clients.is_some_and(|max_clients| room_data.clients.len() >= max_clients) {
+                            return Err(ErrorKind::RoomFull);
+                        }
+                        let name = room_data.resolve_name(&joining_client, name).ok_or(ErrorKind::NameTaken)?;
+                        if invite_id.is_some_and(|invite_id| !room_data.redeem_invite(invite_id)) {
+                            return Err(ErrorKind::InvalidInvite);
+                        }
+                        if spectator {
+                            room_data.add_spectator(joining_client.clone(), name);
+                        } else {
+                            room_data.add_client(joining_client.clone(), name);
+                        }
+                        if let Some(room_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == joining_client.uid) {
+                            room_client.meta = meta;
+                        }
+                        tracing::info!(client_uid = %joining_client.uid, spectator, "Member joined the room");
+                        broadcast_client_joined(room_data, joining_client.uid);
+                        response_with_success(&joining_client);
+                        send_room_snapshot(room_data, &joining_client);
+                        Ok(Some(room_data.chat_history.iter().cloned().collect::<Vec<ChatMessageDto>>()))
+                    }).await?;
+                    let chat_history = match chat_history {
+                        Ok(Some(chat_history)) => chat_history,
+                        Ok(None) => {
+                            drop(client_data);
+                            state.store.remove_room(&room).await;
+                            continue;
+                        }
+                        Err(error_kind) => {
+                            if matches!(error_kind, ErrorKind::RoomFull) {
+                                state.metrics.room_joins_rejected.increment();
+                            }
+                            response_with_error(current_client, error_kind);
+                            break 'label;
+                        }
+                    };
+                    current_client.traffic().set_room(Some(&room));
+                    let previous_room = client_data.room.replace(room.clone());
+                    drop(client_data);
+                    // A client is in one room at a time, once in the new one it leaves the previous one
+                    if let Some(previous_room) = previous_room.filter(|previous_room| !Arc::ptr_eq(previous_room, &room)) {
+                        handle_quit_room(state, current_client, previous_room).await;
+                    }
+
+                    if !chat_history.is_empty() {
+                        reply_with_json(current_client, OutgoingMessage::ChatHistory { messages: chat_history });
+                    }
+                } else if !state.config().join_creates_rooms || invite_id.is_some() || spectator || hidden {
+                    response_with_error(current_client, ErrorKind::NoSuchRoom);
+                } else if open_room(state, current_client, room_id.clone(), name.clone(), OutgoingMessage::Success, None, None).await? == OpenRoomResult::IdTaken {
+                    continue;
+                }
+                break;
+            }
+        },
+        IncomingMessage::CloseRoom { room_id } => 'label: {
+            require_moderator(current_client)?;
+            let namespace = current_client.namespace();
+            let room_id = state.resolve_room_id(namespace.as_deref(), &room_id).await;
+            let Some(room) = state.store.room(namespace.as_deref(), &room_id).await else {
+                response_with_error(current_client, ErrorKind::NoSuchRoom);
+                break 'label;
+            };
+            tracing::info!(%room_id, "Moderator closed the room");
+            close_room(state, &room).await?;
+            response_with_success(current_client);
+        }
+        IncomingMessage::ListPublicRooms => {
+            reply_with_json(current_client, OutgoingMessage::PublicRooms { rooms: state.public_rooms(current_client.namespace().as_deref()).await });
+        }
+        IncomingMessage::GetRoomInfo { room_id } => {
+            match state.room_info(current_client.namespace().as_deref(), &room_id).await {
+                Some(info) => reply_with_json(current_client, OutgoingMessage::RoomInfo { info }),
+                None => response_with_error(current_client, ErrorKind::NoSuchRoom),
+            }
+        }
+        IncomingMessage::RestoreRoom { room_id } => 'label: {
+            if state.is_draining() {
+                response_with_error_retry_after(current_client, ErrorKind::ServerDraining, state.reconnect_retry_after());
+                break 'label;
+            }
+            if !validate_client_name(current_client).await {
+                break 'label;
+            }
+            let namespace = current_client.namespace();
+            let Some(room_archive) = state.room_archive.as_ref() else {
+                response_with_error(current_client, ErrorKind::NoSuchRoom);
+                break 'label;
+            };
+            let Some(archived_room) = room_archive.restorable(namespace.as_deref(), room_id.trim()).await else {
+                response_with_error(current_client, ErrorKind::NoSuchRoom);
+                break 'label;
+            };
+
+            let name = current_client.data.lock().await.name.clone();
+            let room_id = archived_room.room_id.clone();
+            if open_room(state, current_client, room_id, name, OutgoingMessage::Success, Some(archived_room), None).await? == OpenRoomResult::IdTaken {
+                response_with_error(current_client, ErrorKind::RoomAlreadyOpen);
+            }
+        }
+        IncomingMessage::CreateRoom { template } => 'label: {
+            if state.is_draining() {
+                response_with_error_retry_after(current_client, ErrorKind::ServerDraining, state.reconnect_retry_after());
+                break 'label;
+            }
+            if !validate_client_name(current_client).await {
+                break 'label;
+            }
+            let template = match template.map(|template| state.config().room_templates.get(&template).cloned()) {
+                Some(None) => {
+                    response_with_error(current_client, ErrorKind::NoSuchTemplate);
+                    break 'label;
+                }
+                Some(Some(template)) => Some(template),
+                None => None,
+            };
+
+            let name = current_client.data.lock().await.name.clone();
+            loop {
+                let room_id = state.unused_room_code(current_client.namespace().as_deref()).await;
+                let reply = OutgoingMessage::RoomCreated { room_id: room_id.clone() };
+                if open_room(state, current_client, room_id, name.clone(), reply, None, template.clone()).await? != OpenRoomResult::IdTaken {
+                    break;
+                }
+            }
+        }
+        IncomingMessage::PlayerEvent {event} => {
+            handle_playback_command(state, current_client, PlaybackCommand::PlayerEvent(event)).await?;
+        },
+        IncomingMessage::UpdateRoomSettings { settings } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                if let Some(allow_stop_due_to_video_loading) = settings.allow_stop_due_to_video_loading {
+                    room_data.allow_stop_due_to_video_loading = allow_stop_due_to_video_loading;
+                }
+                if let Some(public) = settings.public {
+                    room_data.public = public;
+                }
+                if let Some(voice_enabled) = settings.voice_enabled {
+                    room_data.voice_enabled = voice_enabled;
+                }
+                if let Some(pause_on_leave) = settings.pause_on_leave {
+                    room_data.pause_on_leave = pause_on_leave;
+                }
+                if let Some(duplicate_names) = settings.duplicate_names {
+                    room_data.duplicate_names = duplicate_names;
+                }
+                if let Some(owner_succession) = settings.owner_succession {
+                    room_data.owner_succession = owner_succession;
+                }
+                if let Some(control_mode) = settings.control_mode.filter(|control_mode| *control_mode != room_data.control_mode) {
+                    room_data.control_mode = control_mode;
+                    // Members who lost playback control must not keep signing commands
+                    room_data.signing_secret = generate_signing_secret();
+                    send_signing_secret_to_controllers(room_data);
+                }
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::LockRoom { locked } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                room_data.locked = locked;
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::UpdateRoomMetadata { title, description } => 'label: {
+            let title = title.map(|title| title.trim().to_string()).filter(|title| !title.is_empty());
+            let description = description.map(|description| description.trim().to_string()).filter(|description| !description.is_empty());
+            if title.as_ref().is_some_and(|title| title.chars().count() > state.config().max_room_title_length)
+                || description.as_ref().is_some_and(|description| description.chars().count() > state.config().max_room_description_length)
+            {
+                response_with_error(current_client, ErrorKind::RoomMetadataTooLong);
+                break 'label;
+            }
+            let title = match title.map(|title| filter_content(state, ContentKind::RoomTitle, title)).transpose() {
+                Ok(title) => title,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+            let description = match description.map(|description| filter_content(state, ContentKind::RoomDescription, description)).transpose() {
+                Ok(description) => description,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                room_data.title = title;
+                room_data.description = description;
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::SetPageUrl { url, nonce, signature } => 'label: {
+            let url = match validate_page_url(&state.config(), &url) {
+                Ok(url) => url,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            with_current_room(current_client, move |current_client, room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ChangePageUrl)?;
+
+                if !verify_page_url_change(room, room_data, &url, nonce, signature) {
+                    response_with_error(current_client, ErrorKind::InvalidSignature);
+                    return Ok(());
+                }
+
+                response_with_success(current_client);
+                change_page_url(room_data, url, current_client.uid)?;
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::QueueAdd { url, nonce, signature } => 'label: {
+            let url = match validate_page_url(&state.config(), &url) {
+                Ok(url) => url,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            with_current_room(current_client, move |current_client, room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ChangePageUrl)?;
+
+                if room_data.queue.len() >= MAX_QUEUE_LENGTH {
+                    response_with_error(current_client, ErrorKind::QueueFull);
+                    return Ok(());
+                }
+                // Checked on the way in, QueueNext and VideoEnded only move on to signed URLs
+                if !verify_page_url_change(room, room_data, &url, nonce, signature) {
+                    response_with_error(current_client, ErrorKind::InvalidSignature);
+                    return Ok(());
+                }
+
+                room_data.queue.push(url);
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::QueueRemove { index } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ChangePageUrl)?;
+
+                if index >= room_data.queue.len() {
+                    response_with_error(current_client, ErrorKind::InvalidQueueIndex);
+                    return Ok(());
+                }
+
+                room_data.queue.remove(index);
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::QueueMove { from, to } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ChangePageUrl)?;
+
+                if from >= room_data.queue.len() || to >= room_data.queue.len() {
+                    response_with_error(current_client, ErrorKind::InvalidQueueIndex);
+                    return Ok(());
+                }
+
+                let url = room_data.queue.remove(from);
+                room_data.queue.insert(to, url);
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::QueueNext => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ChangePageUrl)?;
+
+                if room_data.queue.is_empty() {
+                    response_with_error(current_client, ErrorKind::QueueEmpty);
+                    return Ok(());
+                }
+
+                let url = room_data.queue.remove(0);
+
+                response_with_success(current_client);
+                change_page_url(room_data, url, current_client.uid)?;
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::VideoEnded { url } => {
+            let quorum_percent = state.config().video_ended_quorum_percent.clamp(1, 100);
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                // Late reports of the previous video and reports of spectators don't count
+                if room_data.page_url.as_deref() != Some(url.as_str()) || room_client_is_spectator(room_data, current_client.uid) {
+                    response_with_success(current_client);
+                    return Ok(());
+                }
+
+                if !room_data.video_ended_uids.contains(&current_client.uid) {
+                    room_data.video_ended_uids.push(current_client.uid);
+                }
+                let members_count = room_data.members_count();
+                let required_count = (members_count * quorum_percent as usize).div_ceil(100).max(1);
+                if room_data.video_ended_uids.len() >= required_count {
+                    room_data.video_ended_uids.clear();
+                    room_data.mark_watched(url);
+                    if room_data.queue.is_empty() {
+                        room_data.set_playing(false);
+                        room_data.playback.update(None, Some(false));
+                    } else {
+                        let url = room_data.queue.remove(0);
+                        change_page_url(room_data, url, current_client.uid)?;
+                    }
+                    broadcast_settings_change(room_data);
+                }
+
+                response_with_success(current_client);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::Play => {
+            handle_playback_command(state, current_client, PlaybackCommand::Play).await?;
+        },
+        IncomingMessage::Pause { position } => {
+            handle_playback_command(state, current_client, PlaybackCommand::Pause { position }).await?;
+        },
+        IncomingMessage::Seek { position } => {
+            handle_playback_command(state, current_client, PlaybackCommand::Seek { position }).await?;
+        },
+        IncomingMessage::AddMarker { position, label } => 'label: {
+            let label = label.trim().to_string();
+            if !position.is_finite() || position < 0.0 || label.is_empty() || label.chars().count() > MAX_MARKER_LABEL_LENGTH {
+                response_with_error(current_client, ErrorKind::InvalidMarker);
+                break 'label;
+            }
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Admin)?;
+
+                if room_data.markers.len() >= MAX_MARKERS {
+                    response_with_error(current_client, ErrorKind::TooManyMarkers);
+                    return Ok(());
+                }
+
+                let marker = MarkerDto { marker_id: Uuid::new_v4(), position, label, added_by: current_client.uid };
+                let index = room_data.markers.partition_point(|existing| existing.position <= position);
+                room_data.markers.insert(index, marker);
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::RemoveMarker { marker_id } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Admin)?;
+
+                let Some(index) = room_data.markers.iter().position(|marker| marker.marker_id == marker_id) else {
+                    response_with_error(current_client, ErrorKind::NoSuchMarker);
+                    return Ok(());
+                };
+                room_data.markers.remove(index);
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::JumpToMarker { marker_id } => {
+            let position = with_current_room(current_client, move |_current_client, _room, room_data| {
+                Ok(room_data.markers.iter().find(|marker| marker.marker_id == marker_id).map(|marker| marker.position))
+            }).await?;
+
+            match position {
+                Some(Some(position)) => handle_playback_command(state, current_client, PlaybackCommand::Seek { position }).await?,
+                Some(None) => response_with_error(current_client, ErrorKind::NoSuchMarker),
+                None => {}
+            }
+        },
+        IncomingMessage::SetTrack { kind, track_id } => 'label: {
+            let track_id = track_id.trim().to_string();
+            if track_id.chars().count() > MAX_TRACK_ID_LENGTH || track_id.chars().any(char::is_control) {
+                response_with_error(current_client, ErrorKind::InvalidTrack);
+                break 'label;
+            }
+            let track_id = (!track_id.is_empty()).then_some(track_id);
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ControlPlayback)?;
+
+                room_data.playback.set_track(kind, track_id.clone());
+                response_with_success(current_client);
+
+                let payload = serde_json::to_string(&OutgoingMessage::TrackChanged { kind, track_id, client_uid: current_client.uid })?;
+                for room_client in room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid) {
+                    let _ = response_with_text(&room_client.client, payload.clone());
+                }
+                #[cfg(feature = "redis")]
+                cluster::publish_playback(room_data, &payload);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::ReportBufferState { buffering } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                if let Some(room_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == current_client.uid) {
+                    room_client.buffering = buffering;
+                }
+                update_buffering_pause(room_data, current_client.uid)?;
+
+                response_with_success(current_client);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::ReportPlayerStatus { player_status } => {
+            let user_id = current_client.data.lock().await.user_id.clone();
+            let progress = with_current_room(current_client, move |current_client, room, room_data| {
+                let progress = user_id.map(|user_id| (user_id, watch_progress(room, room_data, player_status.at_second)));
+
+                // The next report supersedes this one, so it's fine to skip it in a busy room
+                if !room_data.try_broadcast_event(EventPriority::Low) {
+                    response_with_success(current_client);
+                    return Ok(progress);
+                }
+
+                let outgoing_message = OutgoingMessage::ReportPlayerStatus {
+                    player_status,
+                    client_uid: current_client.uid,
+                };
+                let payload = serde_json::to_string(&outgoing_message)?;
+
+                for room_client in room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid) {
+                    let _ = response_with_text(&room_client.client, payload.clone());
+                }
+                response_with_success(current_client);
+                Ok(progress)
+            }).await?;
+
+            if let Some((user_id, progress)) = progress.flatten() {
+                state.record_watch_progress(&user_id, progress).await;
+            }
+        },
+        IncomingMessage::SetRole { client_uid, role } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                set_member_role(room_data, current_client, client_uid, role)
+            }).await?;
+        },
+        IncomingMessage::ChangeClientAdminStatus { client_uid, admin } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let role = if admin { Role::Admin } else { Role::Member };
+                set_member_role(room_data, current_client, client_uid, role)
+            }).await?;
+        },
+        IncomingMessage::MuteClient { client_uid, duration_secs } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let Some(room_target_client) = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                };
+                require_outranks(room_data, current_client, room_target_client)?;
+
+                let duration = Duration::from_secs(duration_secs).min(MAX_ROOM_MUTE_DURATION);
+                let muted_until = (!duration.is_zero()).then(|| unix_millis_now() + duration.as_millis() as u64);
+                if let Some(room_target_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == client_uid) {
+                    room_target_client.muted_until = muted_until;
+                }
+                room_data.record_history(Some(current_client.uid), RoomHistoryEventDto::Muted { target_uid: client_uid, until: muted_until });
+                response_with_success(current_client);
+                broadcast_client_change(room_data, client_uid);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::KickClient { client_uid } => {
+            let target = with_current_room(current_client, move |current_client, room, room_data| {
+                require_can_moderate(room_data, current_client, client_uid)?;
+
+                let Some(target_client) = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid).map(|room_client| room_client.client.clone()) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(None);
+                };
+                Ok(Some((room.clone(), target_client)))
+            }).await?;
+
+            if let Some((room, target_client)) = target.flatten() {
+                kick_client(state, &room, &target_client, current_client.uid).await?;
+                response_with_success(current_client);
+            }
+        },
+        IncomingMessage::BanClient { client_uid, ban_ip } => {
+            let target = with_current_room(current_client, move |current_client, room, room_data| {
+                require_can_moderate(room_data, current_client, client_uid)?;
+
+                // Clients who aren't members are banned by uid alone, their name and address are none of the room's business
+                let room_target_client = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid);
+                let target_client = room_target_client.map(|room_client| room_client.client.clone());
+                let ban = RoomBan {
+                    client_uid,
+                    name: room_target_client.and_then(|room_client| room_client.name.clone()),
+                    ip: target_client.as_ref().and_then(|target_client| target_client.ip).filter(|_| ban_ip),
+                };
+
+                tracing::info!(%client_uid, room_id = %room.room_id, ip_banned = ban.ip.is_some(), "Banned client");
+                room_data.bans.retain(|ban| ban.client_uid != client_uid);
+                room_data.bans.push(ban);
+                room_data.record_history(Some(current_client.uid), RoomHistoryEventDto::Banned { target_uid: client_uid });
+                broadcast_settings_change(room_data);
+                Ok(Some((room.clone(), target_client)))
+            }).await?;
+
+            if let Some((room, target_client)) = target.flatten() {
+                if let Some(target_client) = target_client {
+                    kick_client(state, &room, &target_client, current_client.uid).await?;
+                }
+                response_with_success(current_client);
+            }
+        },
+        IncomingMessage::UnbanClient { client_uid } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                let bans_count = room_data.bans.len();
+                room_data.bans.retain(|ban| ban.client_uid != client_uid);
+                if room_data.bans.len() == bans_count {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                }
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::TransferOwnership { client_uid } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                let Some(index) = room_data.clients.iter().position(|room_client| room_client.client.uid == client_uid) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                };
+                room_logic::transfer_ownership(&mut room_data.clients, index);
+                room_data.record_history(Some(current_client.uid), RoomHistoryEventDto::OwnershipTransferred { target_uid: client_uid });
+                send_signing_secret_to_controllers(room_data);
+
+                response_with_success(current_client);
+                broadcast_client_change(room_data, current_client.uid);
+                broadcast_client_change(room_data, client_uid);
+                let from_uid = current_client.uid;
+                broadcast_room_event(room_data, |seq| OutgoingMessage::OwnershipTransferred { seq, from_uid, to_uid: client_uid, automatic: false });
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::ChangeRoomPreferences { page_url, allow_stop_due_to_video_loading, nonce, signature } => 'label: {
+            let page_url = match validate_page_url(&state.config(), &page_url) {
+                Ok(page_url) => page_url,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            with_current_room(current_client, move |current_client, room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ChangeRoomPreferences)?;
+
+                if !verify_page_url_change(room, room_data, &page_url, nonce, signature) {
+                    response_with_error(current_client, ErrorKind::InvalidSignature);
+                    return Ok(());
+                }
+
+                response_with_success(current_client);
+                if room_data.page_url.as_ref() != Some(&page_url) {
+                    change_page_url(room_data, page_url, current_client.uid)?;
+                }
+                room_data.allow_stop_due_to_video_loading = allow_stop_due_to_video_loading;
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::QuitRoom => {
+            if let Ok(mut current_client_data) = client_in_room(current_client).await {
+                let room = current_client.set_room(&mut current_client_data, None).ok_or(anyhow!("Unexpected error"))?;
+                drop(current_client_data);
+                handle_quit_room(state, current_client, room).await;
+                response_with_success(current_client);
+            }
+        }
+        IncomingMessage::RequestCalendarUrl => {
+            reply_with_json(current_client, OutgoingMessage::CalendarUrl { url: state.calendar_url(current_client.uid) });
+        }
+        IncomingMessage::RequestInviteQrCode => 'label: {
+            let room = current_room_if(current_client, |room_data, room_client| {
+                room_data.has_permission(&room_client.client, RoomPermission::InviteMembers)
+            }).await?;
+            if let Some(room) = room {
+                let join_url = state.join_url(room.namespace.as_deref(), &room.room_id);
+                let Some(qr_code) = QrCode::encode(join_url.as_bytes()) else {
+                    response_with_error(current_client, ErrorKind::InviteLinkTooLong);
+                    break 'label;
+                };
+
+                reply_with_json(current_client, OutgoingMessage::InviteQrCode {
+                    svg: qr_code.to_svg(),
+                    join_url,
+                    image_url: state.qr_code_url(&room),
+                });
+            }
+        }
+        IncomingMessage::CreateInviteLink { expires_in_secs } => {
+            let expires_in = expires_in_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_INVITE_LINK_LIFETIME)
+                .min(MAX_INVITE_LINK_LIFETIME);
+            // Anybody who has the link may follow it until it expires or is revoked
+            let invite = RoomInvite {
+                invite_id: Uuid::new_v4(),
+                uses_left: u32::MAX,
+                expires_at: unix_millis_now() + expires_in.as_millis() as u64,
+            };
+            let room = with_current_room(current_client, move |current_client, room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::InviteMembers)?;
+                room_data.drop_expired_invites();
+                room_data.invites.push(invite);
+                Ok(Some(room.clone()))
+            }).await?;
+            if let Some(room) = room.flatten() {
+                let (slug, link) = state.create_invite_link(&room, invite).await;
+
+                reply_with_json(current_client, OutgoingMessage::InviteLinkCreated {
+                    url: state.invite_link_url(&slug),
+                    slug,
+                    expires_at: link.expires_at,
+                });
+            }
+        }
+        IncomingMessage::RevokeInviteLink { slug } => {
+            let room = current_room_if(current_client, |room_data, room_client| {
+                room_data.has_permission(&room_client.client, RoomPermission::InviteMembers)
+            }).await?;
+            if let Some(room) = room {
+                let link = {
+                    let mut invite_links = state.invite_links.lock().await;
+                    if invite_links.get(&slug).is_some_and(|link| link.room_key() == room.key()) {
+                        invite_links.remove(&slug)
+                    } else {
+                        None
+                    }
+                };
+                if let Some(link) = link {
+                    room.run(move |room_data| {
+                        room_data.invites.retain(|invite| invite.invite_id != link.invite_id);
+                    }).await?;
+                    response_with_success(current_client);
+                } else {
+                    response_with_error(current_client, ErrorKind::NoSuchInviteLink);
+                }
+            }
+        }
+        IncomingMessage::CreateInvite { max_uses, expires_in_secs } => {
+            let max_uses = max_uses.unwrap_or(1).max(1);
+            let expires_in = expires_in_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_INVITE_LINK_LIFETIME)
+                .min(MAX_INVITE_LINK_LIFETIME);
+            let invite = RoomInvite {
+                invite_id: Uuid::new_v4(),
+                uses_left: max_uses,
+                expires_at: unix_millis_now() + expires_in.as_millis() as u64,
+            };
+            let room = with_current_room(current_client, move |current_client, room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+                room_data.drop_expired_invites();
+                room_data.invites.push(invite);
+                Ok(Some(room.clone()))
+            }).await?;
+            if let Some(room) = room.flatten() {
+                reply_with_json(current_client, OutgoingMessage::InviteCreated {
+                    token: state.invite_token(&room, invite.invite_id),
+                    max_uses,
+                    expires_at: invite.expires_at,
+                });
+            }
+        }
+        IncomingMessage::SetEndToEndEncryption { enabled } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                room_data.end_to_end_encrypted = enabled;
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::KeyExchange { to_uid, public_key } => {
+            relay_encrypted_message(state, current_client, to_uid, EncryptedRelay::KeyExchange, public_key.len(), OutgoingMessage::KeyExchange {
+                from_uid: current_client.uid,
+                public_key,
+            }).await?;
+        }
+        IncomingMessage::EncryptedPayload { to_uid, ciphertext, channel } => 'label: {
+            let relay = match &channel {
+                Some(channel) if !is_valid_custom_channel(channel) => {
+                    response_with_error(current_client, ErrorKind::InvalidCustomChannel);
+                    break 'label;
+                }
+                Some(channel) => EncryptedRelay::Channel(channel.clone(), state.config().custom_channel_limits(channel)),
+                None => EncryptedRelay::Chat,
+            };
+            relay_encrypted_message(state, current_client, to_uid, relay, ciphertext.len(), OutgoingMessage::EncryptedPayload {
+                from_uid: current_client.uid,
+                ciphertext,
+                channel,
+            }).await?;
+        }
+        IncomingMessage::RequestSigningSecret => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let room_current_client = room_data.find_room_client(current_client).ok_or(anyhow!("Unexpected error"))?;
+                if !room_data.can_sign_commands(room_current_client) {
+                    return Err(Denied(ErrorKind::Forbidden).into());
+                }
+
+                reply_with_json(current_client, OutgoingMessage::SigningSecret { secret: to_hex(&room_data.signing_secret) });
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::SetCommandSigning { required } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                if required && !room_data.require_signed_commands {
+                    room_data.queue.clear();
+                }
+                room_data.require_signed_commands = required;
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::AddRoomAlias { alias } => 'label: {
+            if let Some(room) = current_room_if(current_client, |_, room_client| room_client.is_owner()).await? {
+                let alias = match validate_room_id(&state.config(), &alias) {
+                    Ok(alias) => alias,
+                    Err(error_kind) => {
+                        response_with_validation_error(current_client, &state.config(), error_kind);
+                        break 'label;
+                    }
+                };
+
+                let alias_is_room = state.store.room(room.namespace.as_deref(), &alias).await.is_some();
+                let alias_key = (room.namespace.clone(), alias.clone());
+                let mut room_aliases = state.room_aliases.lock().await;
+                if alias_is_room || room_aliases.contains_key(&alias_key) {
+                    response_with_error(current_client, ErrorKind::AliasTaken);
+                    break 'label;
+                }
+                room_aliases.insert(alias_key, room.room_id.clone());
+                drop(room_aliases);
+
+                room.run(move |room_data| {
+                    room_data.aliases.push(alias);
+                    broadcast_settings_change(room_data);
+                }).await?;
+                response_with_success(current_client);
+            }
+        }
+        IncomingMessage::RemoveRoomAlias { alias } => {
+            let removed_alias = alias.clone();
+            let removed_from = with_current_room(current_client, move |current_client, room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                let Some(index) = room_data.aliases.iter().position(|a| *a == removed_alias) else {
+                    response_with_error(current_client, ErrorKind::NoSuchAlias);
+                    return Ok(None);
+                };
+                room_data.aliases.remove(index);
+
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(Some(room.namespace.clone()))
+            }).await?;
+
+            if let Some(namespace) = removed_from.flatten() {
+                state.room_aliases.lock().await.remove(&(namespace, alias));
+            }
+        }
+        IncomingMessage::RenameRoom { new_room_id } => 'label: {
+            // Breakout rooms and their parent room refer to each other by id
+            let room = current_room_if(current_client, |room_data, room_client| {
+                room_client.is_owner() && room_data.breakout_room_ids.is_empty() && room_data.breakout_parent_room_id.is_none()
+            }).await?;
+            let Some(room) = room else {
+                break 'label;
+            };
+            let new_room_id = match validate_room_id(&state.config(), &new_room_id) {
+                Ok(new_room_id) => new_room_id,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+
+            let alias_taken = state.room_aliases.lock().await.contains_key(&(room.namespace.clone(), new_room_id.clone()));
+            if alias_taken || !rename_room(state, &room, new_room_id).await? {
+                response_with_error(current_client, ErrorKind::AliasTaken);
+                break 'label;
+            }
+            response_with_success(current_client);
+        }
+        IncomingMessage::RequestRoomMerge { room_id } => 'label: {
+            let requested_by_name = current_client.data.lock().await.name.clone();
+            if let Some(room) = current_room_if(current_client, |_, room_client| room_client.is_owner()).await? {
+                let other_room_id = state.resolve_room_id(room.namespace.as_deref(), &room_id).await;
+                let other_room = state.store.room(room.namespace.as_deref(), &other_room_id).await;
+                let Some(other_room) = other_room.filter(|other_room| !Arc::ptr_eq(other_room, &room)) else {
+                    response_with_error(current_client, ErrorKind::NoSuchRoom);
+                    break 'label;
+                };
+
+                let into_room_id = room.room_id.clone();
+                other_room.run(move |other_room_data| {
+                    other_room_data.merge_requested_by_room = Some(into_room_id.clone());
+                    for room_client in other_room_data.clients.iter().filter(|room_client| room_client.is_owner()) {
+                        response_with_json(&room_client.client, OutgoingMessage::RoomMergeRequested {
+                            into_room_id: into_room_id.clone(),
+                            requested_by_name: requested_by_name.clone(),
+                        });
+                    }
+                }).await?;
+                response_with_success(current_client);
+            }
+        }
+        IncomingMessage::RespondRoomMerge { accept } => 'label: {
+            let merge_request = with_current_room(current_client, move |current_client, room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                let Some(into_room_id) = room_data.merge_requested_by_room.take() else {
+                    response_with_error(current_client, ErrorKind::NoPendingMergeRequest);
+                    return Ok(None);
+                };
+                Ok(Some((room.clone(), into_room_id)))
+            }).await?;
+            let Some((room, into_room_id)) = merge_request.flatten() else {
+                break 'label;
+            };
+
+            // Merges are only requested within a namespace
+            let into_room = state.store.room(room.namespace.as_deref(), &into_room_id).await;
+            let Some(into_room) = into_room else {
+                response_with_error(current_client, ErrorKind::NoSuchRoom);
+                break 'label;
+            };
+
+            if accept {
+                response_with_success(current_client);
+                merge_rooms(state, &room, &into_room).await?;
+            } else {
+                let room_id = room.room_id.clone();
+                into_room.run(move |into_room_data| {
+                    for room_client in into_room_data.clients.iter().filter(|room_client| room_client.is_owner()) {
+                        response_with_json(&room_client.client, OutgoingMessage::RoomMergeDeclined { room_id: room_id.clone() });
+                    }
+                }).await?;
+                response_with_success(current_client);
+            }
+        }
+        IncomingMessage::CreateBreakoutRooms { count } => 'label: {
+            // Checked up front since the members can't be put back once they are taken out
+            // of the room, a race with other new rooms may go slightly over the limit
+            if state.rooms_limit_reached(current_client.namespace().as_deref(), count).await {
+                state.metrics.rooms_rejected.increment();
+                response_with_error(current_client, ErrorKind::ServerRoomLimitReached);
+                break 'label;
+            }
+
+            let breakout = with_current_room(current_client, move |current_client, room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                if !room_data.breakout_room_ids.is_empty() || room_data.breakout_parent_room_id.is_some() {
+                    response_with_error(current_client, ErrorKind::BreakoutRoomsAlreadyOpen);
+                    return Ok(None);
+                }
+
+                // Every breakout room gets a member to own it, spectators are only spread among them
+                let members_count = room_data.clients.iter().filter(|room_client| !room_client.spectator && !room_client.is_owner()).count();
+                if !(2..=MAX_BREAKOUT_ROOMS).contains(&count) || count > members_count {
+                    response_with_error(current_client, ErrorKind::InvalidBreakoutRoomCount);
+                    return Ok(None);
+                }
+
+                // Hidden moderators stay in the parent room
+                let (staying, mut members): (Vec<RoomClient>, Vec<RoomClient>) = std::mem::take(&mut room_data.clients)
+                    .into_iter()
+                    .partition(|room_client| room_client.is_owner() || room_client.hidden);
+                room_data.clients = staying;
+                members.sort_by_key(|room_client| room_client.spectator);
+
+                let settings = (
+                    room_data.page_url.clone(),
+                    room_data.allow_stop_due_to_video_loading,
+                    room_data.end_to_end_encrypted,
+                    room_data.require_signed_commands,
+                );
+                Ok(Some((room.clone(), members, settings)))
+            }).await?;
+            let Some((room, members, settings)) = breakout.flatten() else {
+                break 'label;
+            };
+
+            let mut breakout_rooms = Vec::new();
+            let mut suffix = 1;
+            while breakout_rooms.len() < count {
+                let breakout_room_id = format!("{}-breakout-{}", room.room_id, suffix);
+                suffix += 1;
+
+                let (page_url, allow_stop_due_to_video_loading, end_to_end_encrypted, require_signed_commands) = settings.clone();
+                let breakout_room = Arc::new(Room::with_data(room.namespace.clone(), breakout_room_id.clone(), RoomData {
+                    page_url,
+                    allow_stop_due_to_video_loading,
+                    end_to_end_encrypted,
+                    require_signed_commands,
+                    breakout_parent_room_id: Some(room.room_id.clone()),
+                    ..RoomData::new()
+                }));
+                if state.store.insert_room(breakout_room.clone()).await.is_err() {
+                    continue;
+                }
+                state.attach_room(&breakout_room);
+                breakout_rooms.push((breakout_room, Vec::new()));
+            }
+
+            for (index, room_client) in members.into_iter().enumerate() {
+                breakout_rooms[index % count].1.push(room_client);
+            }
+
+            let breakout_room_ids = breakout_rooms.iter().map(|(breakout_room, _)| breakout_room.room_id.clone()).collect();
+            room.run(move |room_data| {
+                room_data.breakout_room_ids = breakout_room_ids;
+                broadcast_room_change(room_data);
+            }).await?;
+            response_with_success(current_client);
+
+            for (breakout_room, room_clients) in breakout_rooms {
+                let mut room_clients = reassign_clients_room(room_clients, &room, &breakout_room).await;
+                if let Some(first_member) = room_clients.iter_mut().find(|room_client| !room_client.spectator) {
+                    first_member.role = Role::Owner;
+                }
+
+                let parent_room_id = room.room_id.clone();
+                let breakout_room_id = breakout_room.room_id.clone();
+                breakout_room.run(move |breakout_room_data| {
+                    for room_client in room_clients.iter_mut() {
+                        room_client.reset_stats(breakout_room_data.total_play_time());
+                    }
+
+                    for room_client in room_clients.iter() {
+                        response_with_json(&room_client.client, OutgoingMessage::MovedToBreakoutRoom {
+                            room_id: breakout_room_id.clone(),
+                            parent_room_id: parent_room_id.clone(),
+                        });
+                    }
+                    breakout_room_data.clients = room_clients;
+                    broadcast_room_change(breakout_room_data);
+                }).await?;
+            }
+        }
+        IncomingMessage::RecallBreakoutRooms => 'label: {
+            let recall = with_current_room(current_client, move |current_client, room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                if room_data.breakout_room_ids.is_empty() {
+                    response_with_error(current_client, ErrorKind::NoBreakoutRooms);
+                    return Ok(None);
+                }
+
+                Ok(Some((room.clone(), std::mem::take(&mut room_data.breakout_room_ids))))
+            }).await?;
+            let Some((room, breakout_room_ids)) = recall.flatten() else {
+                break 'label;
+            };
+
+            let mut recalled_clients = Vec::new();
+            for breakout_room_id in breakout_room_ids {
+                let Some(breakout_room) = state.store.room(room.namespace.as_deref(), &breakout_room_id).await else {
+                    continue;
+                };
+                let room_clients = breakout_room.run(|breakout_room_data| {
+                    breakout_room_data.closed = true;
+                    std::mem::take(&mut breakout_room_data.clients)
+                }).await?;
+                state.store.remove_room(&breakout_room).await;
+                state.forget_room(&breakout_room).await;
+
+                let room_clients = reassign_clients_room(room_clients, &breakout_room, &room).await;
+                for room_client in room_clients.iter() {
+                    response_with_json(&room_client.client, OutgoingMessage::RecalledFromBreakoutRoom {
+                        room_id: breakout_room_id.clone(),
+                        parent_room_id: room.room_id.clone(),
+                    });
+                }
+                recalled_clients.extend(room_clients);
+            }
+
+            room.run(move |room_data| {
+                for mut room_client in recalled_clients {
+                    room_client.role = room_client.role.min(Role::Member);
+                    room_client.reset_stats(room_data.total_play_time());
+                    room_data.clients.push(room_client);
+                }
+                broadcast_room_change(room_data);
+            }).await?;
+            response_with_success(current_client);
+        }
+        IncomingMessage::JoinLobby => 'label: {
+            if !state.lobby.enabled {
+                response_with_error(current_client, ErrorKind::LobbyDisabled);
+                break 'label;
+            }
+
+            if !validate_client_name(current_client).await {
+                break 'label;
+            }
+
+            let mut lobby_data = state.lobby.data.lock().await;
+            if lobby_data.find_member_mut(current_client).is_none() {
+                lobby_data.members.push(LobbyMember::new(current_client.clone()));
+            }
+
+            reply_with_json(current_client, OutgoingMessage::LobbyJoined {
+                members_count: lobby_data.members.len(),
+                recent_messages: lobby_data.recent_messages.iter().cloned().collect(),
+            });
+        }
+        IncomingMessage::LeaveLobby => {
+            if state.lobby.data.lock().await.remove_member(current_client) {
+                response_with_success(current_client);
+            } else {
+                response_with_error(current_client, ErrorKind::NotInLobby);
+            }
+        }
+        IncomingMessage::SendLobbyMessage { text } => 'label: {
+            let text = text.trim().to_string();
+            if text.is_empty() {
+                response_with_error(current_client, ErrorKind::MessageEmpty);
+                break 'label;
+            }
+
+            if text.chars().count() > state.config().max_lobby_message_length {
+                response_with_error(current_client, ErrorKind::MessageTooLong);
+                break 'label;
+            }
+            let text = match filter_content(state, ContentKind::LobbyMessage, text) {
+                Ok(text) => text,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            let from_name = current_client.data.lock().await.name.clone();
+            let mut lobby_data = state.lobby.data.lock().await;
+            let Some(member) = lobby_data.find_member_mut(current_client) else {
+                response_with_error(current_client, ErrorKind::NotInLobby);
+                break 'label;
+            };
+
+            if let Some(muted_until) = member.muted_until.filter(|muted_until| *muted_until > Instant::now()) {
+                response_with_error_retry_after(current_client, ErrorKind::Muted, muted_until - Instant::now());
+                break 'label;
+            }
+
+            if !member.rate_limit.try_take(1.0) {
+                member.violations += 1;
+                if member.violations >= LOBBY_VIOLATIONS_BEFORE_MUTE {
+                    member.violations = 0;
+                    member.muted_until = Some(Instant::now() + LOBBY_MUTE_DURATION);
+                    reply_with_json(current_client, OutgoingMessage::LobbyMuted {
+                        until: unix_millis_now() + LOBBY_MUTE_DURATION.as_millis() as u64,
+                    });
+                }
+                response_with_error_retry_after(current_client, ErrorKind::RateLimited, member.rate_limit.retry_after(1.0));
+                break 'label;
+            }
+
+            let message = LobbyChatMessageDto {
+                from_uid: current_client.uid,
+                from_name,
+                text,
+                timestamp: unix_millis_now(),
+            };
+            lobby_data.push_message(message.clone());
+
+            response_with_success(current_client);
+            let payload = serde_json::to_string(&OutgoingMessage::LobbyMessage { message })?;
+            for member in lobby_data.members.iter() {
+                let _ = response_with_text(&member.client, payload.clone());
+            }
+        }
+        IncomingMessage::ChatMessage { text } => 'label: {
+            let text = match chat_message_text(state, text) {
+                Ok(text) => text,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                if room_data.end_to_end_encrypted {
+                    response_with_error(current_client, ErrorKind::DisabledInEncryptedRoom);
+                    return Ok(());
+                }
+                if !may_chat(room_data, current_client) {
+                    return Ok(());
+                }
+
+                let message = ChatMessageDto {
+                    message_id: Uuid::new_v4(),
+                    from_uid: current_client.uid,
+                    from_name: room_data.find_room_client(current_client).and_then(|room_client| room_client.name.clone()),
+                    text,
+                    timestamp: unix_millis_now(),
+                    edited: false,
+                };
+                room_data.push_chat_message(message.clone());
+                stop_typing(room_data, current_client.uid)?;
+
+                response_with_success(current_client);
+                let payload = serde_json::to_string(&OutgoingMessage::ChatMessage {
+                    message_id: message.message_id,
+                    from_uid: message.from_uid,
+                    from_name: message.from_name,
+                    text: message.text,
+                    timestamp: message.timestamp,
+                })?;
+                for room_client in room_data.clients.iter() {
+                    let _ = response_with_text(&room_client.client, payload.clone());
+                }
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::EditChatMessage { message_id, text } => 'label: {
+            let text = match chat_message_text(state, text) {
+                Ok(text) => text,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                if room_data.end_to_end_encrypted {
+                    response_with_error(current_client, ErrorKind::DisabledInEncryptedRoom);
+                    return Ok(());
+                }
+                if let Some(muted_for) = room_data.find_room_client(current_client).and_then(|room_client| room_client.muted_for()) {
+                    response_with_error_retry_after(current_client, ErrorKind::Muted, muted_for);
+                    return Ok(());
+                }
+                let is_admin = room_data.has_role(current_client, Role::Admin);
+                let Some(message) = room_data.chat_history.iter_mut().find(|message| message.message_id == message_id) else {
+                    response_with_error(current_client, ErrorKind::NoSuchChatMessage);
+                    return Ok(());
+                };
+                if message.from_uid != current_client.uid && !is_admin {
+                    return Err(Denied(ErrorKind::Forbidden).into());
+                }
+                message.text = text.clone();
+                message.edited = true;
+
+                response_with_success(current_client);
+                send_to_members(room_data.clients.iter(), &OutgoingMessage::ChatMessageEdited { message_id, text, client_uid: current_client.uid })
+            }).await?;
+        },
+        IncomingMessage::DeleteChatMessage { message_id } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let Some(index) = room_data.chat_history.iter().position(|message| message.message_id == message_id) else {
+                    response_with_error(current_client, ErrorKind::NoSuchChatMessage);
+                    return Ok(());
+                };
+                if room_data.chat_history[index].from_uid != current_client.uid {
+                    require_role(room_data, current_client, Role::Admin)?;
+                }
+                room_data.chat_history.remove(index);
+
+                response_with_success(current_client);
+                send_to_members(room_data.clients.iter(), &OutgoingMessage::ChatMessageDeleted { message_id, client_uid: current_client.uid })
+            }).await?;
+        },
+        IncomingMessage::StartPoll { kind, question, options, duration_secs } => 'label: {
+            let (question, options) = match kind {
+                PollKind::Custom => {
+                    let question = question.trim().to_string();
+                    let options: Vec<String> = options.iter().map(|option| option.trim().to_string()).collect();
+                    let valid = !question.is_empty()
+                        && question.chars().count() <= MAX_POLL_QUESTION_LENGTH
+                        && (2..=MAX_POLL_OPTIONS).contains(&options.len())
+                        && options.iter().all(|option| !option.is_empty() && option.chars().count() <= MAX_POLL_OPTION_LENGTH);
+                    if !valid {
+                        response_with_error(current_client, ErrorKind::InvalidPoll);
+                        break 'label;
+                    }
+                    (question, options)
+                }
+                PollKind::SkipVideo => ("Skip the video?".to_string(), vec!["Yes".to_string(), "No".to_string()]),
+            };
+            let duration = duration_secs.map(Duration::from_secs).unwrap_or(DEFAULT_POLL_DURATION);
+            if duration.is_zero() || duration > MAX_POLL_DURATION {
+                response_with_error(current_client, ErrorKind::InvalidPoll);
+                break 'label;
+            }
+
+            with_current_room(current_client, move |current_client, room, room_data| {
+                if room_data.poll.is_some() {
+                    response_with_error(current_client, ErrorKind::PollAlreadyRunning);
+                    return Ok(());
+                }
+
+                let poll = Poll {
+                    poll_id: Uuid::new_v4(),
+                    kind,
+                    question,
+                    options,
+                    votes: Vec::new(),
+                    started_by: current_client.uid,
+                    ends_at: unix_millis_now() + duration.as_millis() as u64,
+                };
+                tokio::spawn(end_poll_after(room.clone(), poll.poll_id, duration));
+
+                response_with_success(current_client);
+                send_to_members(room_data.clients.iter(), &OutgoingMessage::PollUpdated { poll: PollDto::from(&poll) })?;
+                room_data.poll = Some(poll);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::Vote { poll_id, option } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_not_spectator(room_data, current_client)?;
+                let Some(poll) = room_data.poll.as_mut().filter(|poll| poll.poll_id == poll_id) else {
+                    response_with_error(current_client, ErrorKind::NoSuchPoll);
+                    return Ok(());
+                };
+
+                if option >= poll.options.len() {
+                    response_with_error(current_client, ErrorKind::InvalidPoll);
+                    return Ok(());
+                }
+
+                poll.votes.retain(|(voter_uid, _)| *voter_uid != current_client.uid);
+                poll.votes.push((current_client.uid, option));
+                let poll = poll.clone();
+
+                response_with_success(current_client);
+
+                let skip_decided = poll.kind == PollKind::SkipVideo && poll.tally()[0] * 2 > room_data.members_count();
+                let everybody_voted = poll.votes.len() >= room_data.members_count();
+                if skip_decided || everybody_voted {
+                    finish_poll(room_data)?;
+                } else {
+                    send_to_members(room_data.clients.iter(), &OutgoingMessage::PollUpdated { poll: PollDto::from(&poll) })?;
+                }
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::RequestReadyCheck { quorum_percent } => 'label: {
+            let quorum_percent = quorum_percent.unwrap_or(100);
+            if !(1..=100).contains(&quorum_percent) {
+                response_with_error(current_client, ErrorKind::InvalidReadyCheck);
+                break 'label;
+            }
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                room_data.ready_check = Some(ReadyCheck { started_by: current_client.uid, ready_uids: Vec::new(), quorum_percent });
+                response_with_success(current_client);
+                broadcast_ready_check(room_data)
+            }).await?;
+        },
+        IncomingMessage::SetReady { ready } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_not_spectator(room_data, current_client)?;
+                let Some(ready_check) = room_data.ready_check.as_mut() else {
+                    response_with_error(current_client, ErrorKind::NoReadyCheck);
+                    return Ok(());
+                };
+
+                ready_check.ready_uids.retain(|uid| *uid != current_client.uid);
+                if ready {
+                    ready_check.ready_uids.push(current_client.uid);
+                }
+
+                response_with_success(current_client);
+                update_ready_check(room_data)
+            }).await?;
+        },
+        IncomingMessage::StartCountdown { seconds } => 'label: {
+            if !(1..=MAX_COUNTDOWN_SECONDS).contains(&seconds) {
+                response_with_error_params(current_client, ErrorKind::InvalidCountdown, ErrorParams::range(1, MAX_COUNTDOWN_SECONDS as usize));
+                break 'label;
+            }
+
+            with_current_room(current_client, move |current_client, room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ControlPlayback)?;
+                // Playback commands are voted on in that mode, a countdown would go around the vote
+                if room_data.control_mode == ControlMode::Vote {
+                    return Err(Denied(ErrorKind::Forbidden).into());
+                }
+                if !room_data.try_broadcast_event(EventPriority::Normal) {
+                    response_with_error_retry_after(current_client, ErrorKind::RateLimited, room_data.event_rate_limit.retry_after(1.0));
+                    return Ok(());
+                }
+
+                let duration = Duration::from_secs(seconds as u64);
+                let countdown = Countdown {
+                    countdown_id: Uuid::new_v4(),
+                    started_by: current_client.uid,
+                    starts_at: unix_millis_now() + duration.as_millis() as u64,
+                };
+                tokio::spawn(end_countdown_after(room.clone(), countdown.countdown_id, duration));
+
+                response_with_success(current_client);
+                send_to_members(room_data.clients.iter(), &OutgoingMessage::CountdownStarted { starts_at: countdown.starts_at, seconds, client_uid: current_client.uid })?;
+                room_data.countdown = Some(countdown);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::Custom { channel, payload } => 'label: {
+            if !is_valid_custom_channel(&channel) {
+                response_with_error(current_client, ErrorKind::InvalidCustomChannel);
+                break 'label;
+            }
+            let limits = state.config().custom_channel_limits(&channel);
+            let payload_size = serde_json::to_string(&payload)?.len();
+            if payload_size > limits.max_payload_size {
+                response_with_error(current_client, ErrorKind::PayloadTooLarge);
+                break 'label;
+            }
+
+            let state = state.clone();
+            with_current_room(current_client, move |current_client, room, room_data| {
+                if room_data.end_to_end_encrypted {
+                    response_with_error(current_client, ErrorKind::DisabledInEncryptedRoom);
+                    return Ok(());
+                }
+                if !take_custom_channel_token(room_data, current_client, &channel, limits)? {
+                    return Ok(());
+                }
+                let recipients_count = room_data.clients.len().saturating_sub(1);
+                if let Err(retry_after) = check_relay_quota(&state, room, room_data, payload_size * recipients_count) {
+                    response_with_error_retry_after(current_client, ErrorKind::RelayQuotaExceeded, retry_after);
+                    return Ok(());
+                }
+
+                response_with_success(current_client);
+                let others = room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid);
+                send_to_members(others, &OutgoingMessage::Custom { channel, payload, from_uid: current_client.uid })?;
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::ReportClient { client_uid, reason } => 'label: {
+            let reason = reason.trim().to_string();
+            if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+                response_with_error(current_client, ErrorKind::InvalidReport);
+                break 'label;
+            }
+
+            let report = with_current_room(current_client, move |current_client, room, room_data| {
+                let reporter = room_data.find_room_client(current_client).ok_or(anyhow!("Unexpected error"))?;
+                let Some(target) = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid && room_client.client.uid != current_client.uid && !room_client.hidden) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(None);
+                };
+                let report = AbuseReportDto {
+                    report_id: Uuid::new_v4(),
+                    at: unix_millis_now(),
+                    namespace: room.namespace.clone(),
+                    room_id: room.room_id.clone(),
+                    reporter_uid: current_client.uid,
+                    reporter_name: reporter.name.clone(),
+                    target_uid: target.client.uid,
+                    target_name: target.name.clone(),
+                    target_user_id: None,
+                    target_ip: target.client.ip.map(|ip| ip.to_string()),
+                    reason,
+                    chat: room_data.chat_history.iter().rev().take(REPORT_CONTEXT_SIZE).rev().cloned().collect(),
+                    history: room_data.history.iter().rev().take(REPORT_CONTEXT_SIZE).rev().cloned().collect(),
+                };
+                Ok(Some((report, target.client.clone())))
+            }).await?.flatten();
+            let Some((mut report, target)) = report else {
+                break 'label;
+            };
+
+            report.target_user_id = target.data.lock().await.user_id.clone();
+            tracing::warn!(report_id = %report.report_id, room_id = %report.room_id, reporter_uid = %report.reporter_uid, target_uid = %report.target_uid, "Member reported");
+            state.abuse_reports.record(report, state.config().report_webhook_url.as_deref()).await;
+            response_with_success(current_client);
+        }
+        IncomingMessage::SendReaction { emoji } => 'label: {
+            if !ALLOWED_REACTIONS.contains(&emoji.as_str()) {
+                response_with_error(current_client, ErrorKind::UnsupportedReaction);
+                break 'label;
+            }
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_not_spectator(room_data, current_client)?;
+                let room_current_client = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == current_client.uid).ok_or(anyhow!("Unexpected error"))?;
+                if let Some(muted_for) = room_current_client.muted_for() {
+                    response_with_error_retry_after(current_client, ErrorKind::Muted, muted_for);
+                    return Ok(());
+                }
+                if !room_current_client.reaction_rate_limit.try_take(1.0) {
+                    let retry_after = room_current_client.reaction_rate_limit.retry_after(1.0);
+                    response_with_error_retry_after(current_client, ErrorKind::RateLimited, retry_after);
+                    return Ok(());
+                }
+
+                // Reactions are decoration, they are the first thing to drop in a busy room
+                if !room_data.try_broadcast_event(EventPriority::Low) {
+                    response_with_success(current_client);
+                    return Ok(());
+                }
+
+                response_with_success(current_client);
+                send_to_members(room_data.clients.iter(), &OutgoingMessage::ReactionReceived { from_uid: current_client.uid, emoji })?;
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::TypingStart => {
+            with_current_room(current_client, move |current_client, room, room_data| {
+                require_not_spectator(room_data, current_client)?;
+                let room_current_client = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == current_client.uid).ok_or(anyhow!("Unexpected error"))?;
+                if let Some(muted_for) = room_current_client.muted_for() {
+                    response_with_error_retry_after(current_client, ErrorKind::Muted, muted_for);
+                    return Ok(());
+                }
+                if !room_current_client.typing_rate_limit.try_take(1.0) {
+                    let retry_after = room_current_client.typing_rate_limit.retry_after(1.0);
+                    response_with_error_retry_after(current_client, ErrorKind::RateLimited, retry_after);
+                    return Ok(());
+                }
+
+                let typing_id = Uuid::new_v4();
+                let started = room_current_client.typing.replace(typing_id).is_none();
+                tokio::spawn(stop_typing_after(room.clone(), current_client.uid, typing_id));
+
+                response_with_success(current_client);
+                if started {
+                    let others = room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid);
+                    send_to_members(others, &OutgoingMessage::TypingChanged { client_uid: current_client.uid, typing: true })?;
+                }
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::TypingStop => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                response_with_success(current_client);
+                stop_typing(room_data, current_client.uid)
+            }).await?;
+        },
+        IncomingMessage::OfferFile { name, size, hash } => 'label: {
+            let valid_name = !name.is_empty()
+                && name.chars().count() <= MAX_OFFERED_FILE_NAME_LENGTH
+                && !name.chars().any(|c| c.is_control() || matches!(c, '/' | '\\'));
+            let valid_hash = !hash.is_empty() && hash.len() <= MAX_FILE_HASH_LENGTH && hash.chars().all(|c| c.is_ascii_alphanumeric());
+            if !valid_name || !valid_hash || size == 0 {
+                response_with_error(current_client, ErrorKind::InvalidFileOffer);
+                break 'label;
+            }
+            if size > MAX_OFFERED_FILE_SIZE {
+                response_with_error_params(current_client, ErrorKind::FileTooLarge, ErrorParams::max(MAX_OFFERED_FILE_SIZE as usize));
+                break 'label;
+            }
+
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let room_current_client = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == current_client.uid).ok_or(anyhow!("Unexpected error"))?;
+                room_current_client.file_offers.retain(|offer| offer.hash != hash);
+                // The oldest offer makes room for the new one
+                if room_current_client.file_offers.len() >= MAX_FILE_OFFERS_PER_MEMBER {
+                    room_current_client.file_offers.remove(0);
+                }
+                room_current_client.file_offers.push(FileOffer { hash: hash.clone(), name: name.clone(), size, requested_by: Vec::new() });
+
+                response_with_success(current_client);
+                let others = room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid);
+                send_to_members(others, &OutgoingMessage::FileOffered { from_uid: current_client.uid, name, size, hash })
+            }).await?;
+        },
+        IncomingMessage::RequestFile { from_uid, hash } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let Some(offerer) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == from_uid && room_client.client.uid != current_client.uid) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                };
+                let message = OutgoingMessage::FileRequested { from_uid: current_client.uid, hash: hash.clone() };
+                if !offerer.client.supports(&message) {
+                    response_with_error(current_client, ErrorKind::UnsupportedByClient);
+                    return Ok(());
+                }
+                let Some(offer) = offerer.file_offers.iter_mut().find(|offer| offer.hash == hash) else {
+                    response_with_error(current_client, ErrorKind::NoSuchFile);
+                    return Ok(());
+                };
+                if !offer.requested_by.contains(&current_client.uid) {
+                    offer.requested_by.push(current_client.uid);
+                }
+
+                response_with_json(&offerer.client, message);
+                response_with_success(current_client);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::FileChunk { to_uid, hash, offset, data } => 'label: {
+            let Some(chunk_size) = base64_decoded_len(&data) else {
+                response_with_error(current_client, ErrorKind::InvalidFileChunk);
+                break 'label;
+            };
+            if chunk_size > MAX_FILE_CHUNK_SIZE {
+                response_with_error_params(current_client, ErrorKind::PayloadTooLarge, ErrorParams::max(MAX_FILE_CHUNK_SIZE));
+                break 'label;
+            }
+
+            let state = state.clone();
+            with_current_room(current_client, move |current_client, room, room_data| {
+                let Some(recipient) = room_data.clients.iter().find(|room_client| room_client.client.uid == to_uid).map(|room_client| room_client.client.clone()) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                };
+                let member_index = room_data.clients.iter().position(|room_client| room_client.client.uid == current_client.uid).ok_or(anyhow!("Unexpected error"))?;
+                let room_current_client = &mut room_data.clients[member_index];
+                let Some(offer_index) = room_current_client.file_offers.iter().position(|offer| offer.hash == hash) else {
+                    response_with_error(current_client, ErrorKind::NoSuchFile);
+                    return Ok(());
+                };
+                let offer = &room_current_client.file_offers[offer_index];
+                // Nobody is sent a file they didn't ask for
+                if !offer.requested_by.contains(&to_uid) {
+                    return Err(Denied(ErrorKind::Forbidden).into());
+                }
+                let end = offset.checked_add(chunk_size as u64).filter(|end| *end <= offer.size);
+                let Some(end) = end else {
+                    response_with_error(current_client, ErrorKind::InvalidFileChunk);
+                    return Ok(());
+                };
+                if !room_current_client.file_chunk_rate_limit.try_take(chunk_size as f64) {
+                    let retry_after = room_current_client.file_chunk_rate_limit.retry_after(chunk_size as f64);
+                    response_with_error_retry_after(current_client, ErrorKind::RateLimited, retry_after);
+                    return Ok(());
+                }
+                if let Err(retry_after) = check_relay_quota(&state, room, room_data, chunk_size) {
+                    response_with_error_retry_after(current_client, ErrorKind::RelayQuotaExceeded, retry_after);
+                    return Ok(());
+                }
+                let offer = &mut room_data.clients[member_index].file_offers[offer_index];
+                if end == offer.size {
+                    offer.requested_by.retain(|uid| *uid != to_uid);
+                }
+
+                response_with_json(&recipient, OutgoingMessage::FileChunk { from_uid: current_client.uid, hash, offset, data });
+                response_with_success(current_client);
+                Ok(())
+            }).await?;
+        },
+        IncomingMessage::GetStats => {
+            let stats = match current_client.namespace() {
+                Some(namespace) => state.namespace_stats(Some(&namespace), current_client.is_moderator()).await,
+                None => state.server_stats(current_client.is_moderator()).await,
+            };
+            reply_with_json(current_client, OutgoingMessage::Stats { stats });
+        }
+        IncomingMessage::Subscribe { room_id } => 'label: {
+            let namespace = current_client.namespace();
+            let room_id = state.resolve_room_id(namespace.as_deref(), &room_id).await;
+            let mut client_data = current_client.data.lock().await;
+            if client_data.subscriptions.contains(&room_id) {
+                response_with_success(current_client);
+                break 'label;
+            }
+            if client_data.subscriptions.len() >= MAX_ROOM_SUBSCRIPTIONS {
+                response_with_error(current_client, ErrorKind::TooManySubscriptions);
+                break 'label;
+            }
+            let Some(room) = state.store.room(namespace.as_deref(), &room_id).await else {
+                response_with_error(current_client, ErrorKind::NoSuchRoom);
+                break 'label;
+            };
+
+            let moderator = current_client.is_moderator();
+            let subscriber = Arc::downgrade(current_client);
+            let data = room.run(move |room_data| {
+                // Private rooms are not told apart from missing ones
+                if !room_data.public && !moderator {
+                    return None;
+                }
+                room_data.subscribers.push(subscriber);
+                Some(Box::new(RoomDataDto::from(room_data)))
+            }).await.ok().flatten();
+            let Some(data) = data else {
+                response_with_error(current_client, ErrorKind::NoSuchRoom);
+                break 'label;
+            };
+
+            client_data.subscriptions.push(room_id.clone());
+            reply_with_json(current_client, OutgoingMessage::SubscribedRoomChanged { room_id, data });
+        }
+        IncomingMessage::Unsubscribe { room_id } => {
+            let namespace = current_client.namespace();
+            let room_id = state.resolve_room_id(namespace.as_deref(), &room_id).await;
+            current_client.data.lock().await.subscriptions.retain(|subscription| *subscription != room_id);
+            if let Some(room) = state.store.room(namespace.as_deref(), &room_id).await {
+                let subscriber = Arc::downgrade(current_client);
+                let _ = room.run(move |room_data| room_data.subscribers.retain(|existing| !existing.ptr_eq(&subscriber))).await;
+            }
+            response_with_success(current_client);
+        }
+        IncomingMessage::GetRoomStats => {
+            with_current_room(current_client, move |current_client, room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ViewMemberInfo)?;
+
+                reply_with_json(current_client, OutgoingMessage::RoomStats { stats: RoomStatsDto::from(&room.room_id, room_data) });
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::GetMembers { offset, limit } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                if room_data.find_room_client(current_client).is_none() {
+                    response_with_error(current_client, ErrorKind::ClientNotInAnyRoom);
+                    return Ok(());
+                }
+
+                let members = room_members(room_data).skip(offset).take(limit.min(MAX_MEMBERS_PAGE_SIZE)).collect();
+                reply_with_json(current_client, OutgoingMessage::Members { offset, total: room_member_count(room_data), members });
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::RequestRoomSnapshot => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                // The member may have been kicked since its room was looked up
+                if room_data.find_room_client(current_client).is_none() {
+                    response_with_error(current_client, ErrorKind::ClientNotInAnyRoom);
+                    return Ok(());
+                }
+
+                send_room_snapshot(room_data, current_client);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::ResyncFrom { seq } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                if room_data.find_room_client(current_client).is_none() {
+                    response_with_error(current_client, ErrorKind::ClientNotInAnyRoom);
+                    return Ok(());
+                }
+
+                let Some(events) = room_data.events_after(seq) else {
+                    send_room_snapshot(room_data, current_client);
+                    return Ok(());
+                };
+                // Subscribing again drops the events still waiting in the old subscription,
+                // clients skip the numbers they already have
+                current_client.follow_room_events(Some(room_data.events.subscribe()));
+                for payload in events {
+                    let _ = response_with_text(current_client, payload);
+                }
+                response_with_success(current_client);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::GetDepartedClients => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ViewMemberInfo)?;
+
+                reply_with_json(current_client, OutgoingMessage::DepartedClients {
+                    clients: room_data.departed_clients.iter().rev().cloned().collect(),
+                });
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::GetRoomHistory => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_permission(room_data, current_client, RoomPermission::ViewMemberInfo)?;
+
+                reply_with_json(current_client, OutgoingMessage::RoomHistory {
+                    entries: room_data.history.iter().rev().cloned().collect(),
+                });
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::DefineRoomRole { name, permissions } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                let name = name.trim().to_string();
+                if name.is_empty() || name.chars().count() > MAX_ROLE_NAME_LENGTH {
+                    response_with_error(current_client, ErrorKind::InvalidRoleName);
+                    return Ok(());
+                }
+
+                if let Some(role) = room_data.roles.iter_mut().find(|role| role.name == name) {
+                    role.permissions = permissions;
+                } else if room_data.roles.len() >= MAX_ROOM_ROLES {
+                    response_with_error(current_client, ErrorKind::TooManyRoles);
+                    return Ok(());
+                } else {
+                    room_data.roles.push(RoomRoleDto { name, permissions });
+                }
+
+                // Permissions could have been taken away from members holding the role
+                room_data.signing_secret = generate_signing_secret();
+                send_signing_secret_to_controllers(room_data);
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::DeleteRoomRole { name } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                let Some(index) = room_data.roles.iter().position(|role| role.name == name) else {
+                    response_with_error(current_client, ErrorKind::NoSuchRole);
+                    return Ok(());
+                };
+                room_data.roles.remove(index);
+                for room_client in room_data.clients.iter_mut() {
+                    room_client.roles.retain(|role| *role != name);
+                }
+
+                room_data.signing_secret = generate_signing_secret();
+                send_signing_secret_to_controllers(room_data);
+                response_with_success(current_client);
+                broadcast_room_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::ChangeClientRole { client_uid, role, assigned } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                if !room_data.roles.iter().any(|room_role| room_role.name == role) {
+                    response_with_error(current_client, ErrorKind::NoSuchRole);
+                    return Ok(());
+                }
+
+                let Some(room_target_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == client_uid) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                };
+
+                if assigned {
+                    if !room_target_client.roles.contains(&role) {
+                        room_target_client.roles.push(role);
+                    }
+                    send_signing_secret_to_controllers(room_data);
+                } else {
+                    room_target_client.roles.retain(|r| *r != role);
+                    room_data.signing_secret = generate_signing_secret();
+                    send_signing_secret_to_controllers(room_data);
+                }
+                response_with_success(current_client);
+                broadcast_client_change(room_data, client_uid);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::SetPermissionPreset { preset } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                room_data.apply_permission_preset(preset);
+                room_data.signing_secret = generate_signing_secret();
+                send_signing_secret_to_controllers(room_data);
+                response_with_success(current_client);
+                broadcast_room_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::SetAutoAdminPromotion { after_minutes } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                require_role(room_data, current_client, Role::Owner)?;
+
+                if after_minutes.is_some_and(|minutes| !(1..=MAX_AUTO_ADMIN_DELAY_MINUTES).contains(&minutes)) {
+                    response_with_error(current_client, ErrorKind::InvalidAutoAdminDelay);
+                    return Ok(());
+                }
+
+                room_data.auto_admin_after = after_minutes.map(|minutes| Duration::from_secs(minutes as u64 * 60));
+                response_with_success(current_client);
+                broadcast_settings_change(room_data);
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::NominateAdmin { client_uid } => {
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                if room_data.has_active_owner() {
+                    response_with_error(current_client, ErrorKind::OwnerActive);
+                    return Ok(());
+                }
+                require_not_spectator(room_data, current_client)?;
+
+                let Some(nominee) = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid && !room_client.hidden) else {
+                    response_with_error(current_client, ErrorKind::NoSuchClient);
+                    return Ok(());
+                };
+                if nominee.spectator {
+                    return Err(Denied(ErrorKind::Forbidden).into());
+                }
+                if nominee.is_admin() {
+                    response_with_error(current_client, ErrorKind::AlreadyAdmin);
+                    return Ok(());
+                }
+
+                let member_uids: Vec<Uuid> = room_data.clients.iter().filter(|room_client| !room_client.spectator).map(|room_client| room_client.client.uid).collect();
+                let voters = room_data.admin_nominations.entry(client_uid).or_default();
+                // Members moved to other rooms by merges or breakouts no longer count
+                voters.retain(|uid| member_uids.contains(uid));
+                if !voters.contains(&current_client.uid) {
+                    voters.push(current_client.uid);
+                }
+                let votes = voters.len();
+                // Majority of the members except the nominee, spectators don't vote
+                let required_votes = (room_data.members_count() - 1) / 2 + 1;
+
+                response_with_success(current_client);
+                if votes >= required_votes {
+                    room_data.admin_nominations.remove(&client_uid);
+                    if let Some(nominee) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == client_uid) {
+                        nominee.role = Role::Admin;
+                    }
+                    // Elected by the members, not by whoever cast the last vote
+                    room_data.record_history(None, RoomHistoryEventDto::RoleChanged { target_uid: client_uid, role: Role::Admin });
+                    send_signing_secret_to_controllers(room_data);
+                    broadcast_client_change(room_data, client_uid);
+                } else {
+                    let payload = serde_json::to_string(&OutgoingMessage::AdminNominated { client_uid, votes, required_votes })?;
+                    for room_client in room_data.clients.iter() {
+                        let _ = response_with_text(&room_client.client, payload.clone());
+                    }
+                }
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::NetworkReport { report } => 'label: {
+            if !report.is_valid() {
+                response_with_error(current_client, ErrorKind::InvalidNetworkReport);
+                break 'label;
+            }
+
+            let base_drift_tolerance_ms = state.config().drift_tolerance_ms;
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let drift_tolerance_ms = report.drift_tolerance_ms(base_drift_tolerance_ms);
+                if let Some(room_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == current_client.uid) {
+                    room_client.network_report = Some(report);
+                }
+                reply_with_json(current_client, OutgoingMessage::SyncTolerance { drift_tolerance_ms });
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::ReportPosition { position, playing } => 'label: {
+            if !position.is_finite() || position < 0.0 {
+                response_with_error(current_client, ErrorKind::InvalidPosition);
+                break 'label;
+            }
+
+            let base_drift_tolerance_ms = state.config().drift_tolerance_ms;
+            with_current_room(current_client, move |current_client, _room, room_data| {
+                let drift_tolerance_ms = room_data
+                    .find_room_client(current_client)
+                    .and_then(|room_client| room_client.network_report.as_ref())
+                    .map_or(base_drift_tolerance_ms, |report| report.drift_tolerance_ms(base_drift_tolerance_ms));
+                let room_position = room_data.playback.current_position();
+                let drift_ms = (position - room_position).abs() * 1000.0;
+                if playing != room_data.playback.playing || drift_ms > drift_tolerance_ms as f64 {
+                    reply_with_json(current_client, OutgoingMessage::CorrectPosition { position: room_position, playing: room_data.playback.playing });
+                }
+                Ok(())
+            }).await?;
+        }
+        IncomingMessage::ScheduleRoom { room_id, starts_at, page_url } => 'label: {
+            if state.is_draining() {
+                response_with_error_retry_after(current_client, ErrorKind::ServerDraining, state.reconnect_retry_after());
+                break 'label;
+            }
+            let room_id = match validate_room_id(&state.config(), &room_id) {
+                Ok(room_id) => room_id,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+            if starts_at <= unix_millis_now() {
+                response_with_error(current_client, ErrorKind::SessionStartInPast);
+                break 'label;
+            }
+            let page_url = match page_url.map(|page_url| validate_page_url(&state.config(), &page_url)).transpose() {
+                Ok(page_url) => page_url,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+            if !check_room_limits(state, current_client).await {
+                break 'label;
+            }
+
+            let namespace = current_client.namespace();
+            let room_data = RoomData { starts_at: Some(starts_at), page_url, ..RoomData::new() };
+            let room = Arc::new(Room::spawn(namespace.clone(), room_id.clone(), room_data, Some(current_client.uid), current_client.ip));
+            if state.store.insert_room(room.clone()).await.is_err() {
+                response_with_error(current_client, ErrorKind::RoomAlreadyOpen);
+                break 'label;
+            }
+            state.scheduled_rooms.lock().await.insert((namespace, room_id.clone()), starts_at);
+            state.attach_room(&room);
+            tracing::info!(%room_id, starts_at, "Scheduled room");
+            response_with_success(current_client);
+        }
+        IncomingMessage::ScheduleSession { room_id, title, page_url, starts_at, mut invited_uids, public } => 'label: {
+            if !validate_client_name(current_client).await {
+                break 'label;
+            }
+
+            let room_id = match validate_room_id(&state.config(), &room_id) {
+                Ok(room_id) => room_id,
+                Err(error_kind) => {
+                    response_with_validation_error(current_client, &state.config(), error_kind);
+                    break 'label;
+                }
+            };
+
+            if starts_at <= unix_millis_now() {
+                response_with_error(current_client, ErrorKind::SessionStartInPast);
+                break 'label;
+            }
+            let title = match filter_content(state, ContentKind::RoomTitle, title) {
+                Ok(title) => title,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+            let page_url = match page_url.map(|page_url| validate_page_url(&state.config(), &page_url)).transpose() {
+                Ok(page_url) => page_url,
+                Err(error_kind) => {
+                    response_with_error(current_client, error_kind);
+                    break 'label;
+                }
+            };
+
+            invited_uids.sort();
+            invited_uids.dedup();
+            invited_uids.retain(|uid| *uid != current_client.uid);
+            if invited_uids.len() > state.config().max_session_invitees {
+                response_with_error(current_client, ErrorKind::TooManyInvitees);
+                break 'label;
+            }
+
+            let namespace = current_client.namespace();
+            // Somebody else's room would get the notifications of the session
+            if state.resolve_room_id(namespace.as_deref(), &room_id).await != room_id
+                || state.store.room(namespace.as_deref(), &room_id).await.is_some()
+            {
+                response_with_error(current_client, ErrorKind::RoomAlreadyOpen);
+                break 'label;
+            }
+
+            let session = ScheduledSession {
+                session_id: Uuid::new_v4(),
+                namespace,
+                room_id,
+                title,
+                page_url,
+                starts_at,
+                invited_uids,
+                created_by: current_client.uid,
+                public,
+                activated: false,
+            };
+            {
+                let mut sessions = state.scheduled_sessions.lock().await;
+                let created_count = sessions.values().filter(|session| session.created_by == current_client.uid).count();
+                if created_count >= state.config().max_sessions_per_creator
+                    || state.config().max_scheduled_sessions().is_some_and(|max_sessions| sessions.len() >= max_sessions)
+                {
+                    response_with_error(current_client, ErrorKind::TooManySessions);
+                    break 'label;
+                }
+                sessions.insert(session.session_id, session.clone());
+            }
+
+            reply_with_json(current_client, OutgoingMessage::SessionScheduled {
+                session: ScheduledSessionDto::for_viewer(&session, Some(current_client.uid)),
+            });
+            for uid in session.invited_uids.iter() {
+                if let Some(client) = state.find_client(*uid) {
+                    response_with_json(&client, OutgoingMessage::SessionInvitation {
+                        session: ScheduledSessionDto::for_viewer(&session, Some(*uid)),
+                    });
+                }
+            }
+        }
+        IncomingMessage::ListUpcomingSessions => {
+            let namespace = current_client.namespace();
+            let sessions = upcoming_sessions(state, Some(current_client.uid), |session| session.namespace == namespace).await;
+            reply_with_json(current_client, OutgoingMessage::UpcomingSessions { sessions });
+        }
+        IncomingMessage::CancelScheduledSession { session_id } => {
+            let mut sessions = state.scheduled_sessions.lock().await;
+            match sessions.get(&session_id) {
+                None => response_with_error(current_client, ErrorKind::NoSuchSession),
+                Some(session) if session.created_by != current_client.uid => response_with_error(current_client, ErrorKind::Forbidden),
+                Some(_) => {
+                    if let Some(session) = sessions.remove(&session_id) {
+                        let room_key = (session.namespace, session.room_id);
+                        let still_announced = sessions.values().any(|session| session.public && session.namespace == room_key.0 && session.room_id == room_key.1);
+                        drop(sessions);
+                        // Subscriptions were only taken for the session while the room isn't open
+                        if !still_announced && state.store.room(room_key.0.as_deref(), &room_key.1).await.is_none() {
+                            state.push_notifier.remove_room(&room_key).await;
+                        }
+                    }
+                    response_with_success(current_client);
+                }
+            }
+        }
+    }
+
+    Ok(resumed_client)
+}
+
+/// Answers with `RateLimited` when the client is out of budget and disconnects clients which keep
+/// going anyway
+fn check_rate_limit(state: &WsAppState, current_client: &Client, cost: f64) -> bool {
+    match current_client.check_message_rate_limit(cost) {
+        RateLimitDecision::Allowed => true,
+        RateLimitDecision::Limited(retry_after) => {
+            response_with_error_retry_after(current_client, ErrorKind::RateLimited, retry_after);
+            false
+        }
+        RateLimitDecision::Exceeded => {
+            tracing::warn!(client_uid = %current_client.uid, "Disconnecting client which keeps exceeding its rate limit");
+            response_with_error(current_client, ErrorKind::RateLimited);
+            current_client.disconnect(DisconnectReason::RateLimited, "Rate limit exceeded", Some(state.reconnect_retry_after()));
+            false
+        }
+    }
+}
+
+async fn handle_shared_file(state: &Arc<WsAppState>, current_client: &Arc<Client>, data: Vec<u8>) -> Result<()> {
+    let max_size = state.config().max_shared_file_size;
+    let state = state.clone();
+    with_current_room(current_client, move |current_client, room, room_data| {
+        if data.len() > max_size {
+            response_with_error(current_client, ErrorKind::FileTooLarge);
+            return Ok(());
+        }
+
+        let Some(mime_type) = detect_image_mime_type(&data) else {
+            response_with_error(current_client, ErrorKind::UnsupportedFileType);
+            return Ok(());
+        };
+
+        if room_data.end_to_end_encrypted {
+            // Files are inspected to detect their type, which requires plaintext
+            response_with_error(current_client, ErrorKind::DisabledInEncryptedRoom);
+            return Ok(());
+        }
+
+        if !room_data.shared_files_quota.try_consume(data.len()) {
+            response_with_error(current_client, ErrorKind::SharedFilesQuotaExceeded);
+            return Ok(());
+        }
+
+        let recipients_count = room_data.clients.len().saturating_sub(1);
+        if let Err(retry_after) = check_relay_quota(&state, room, room_data, data.len() * recipients_count) {
+            response_with_error_retry_after(current_client, ErrorKind::RelayQuotaExceeded, retry_after);
+            return Ok(());
+        }
+
+        if !room_data.try_broadcast_event(EventPriority::Normal) {
+            response_with_error_retry_after(current_client, ErrorKind::RateLimited, room_data.event_rate_limit.retry_after(1.0));
+            return Ok(());
+        }
+
+        let header = OutgoingMessage::FileShared {
+            client_uid: current_client.uid,
+            mime_type: mime_type.to_string(),
+            size: data.len(),
+        };
+        let recipients = room_data.clients.iter()
+            .filter(|room_client| room_client.client.uid != current_client.uid && room_client.client.supports(&header));
+        let header = serde_json::to_string(&header)?;
+        for room_client in recipients {
+            let _ = response_with_text(&room_client.client, header.clone());
+            let _ = room_client.client.send(Message::Binary(data.clone()));
+        }
+        response_with_success(current_client);
+
+        Ok(())
+    }).await?;
+
+    Ok(())
+}
+
+/// Counts `bytes` about to be relayed between members against the relay quota of the room. Over it
+/// operators are warned once a minute, and with `RelayQuotaAction::Throttle` the relay is refused
+/// until the minute is over.
+fn check_relay_quota(state: &WsAppState, room: &Room, room_data: &mut RoomData, bytes: usize) -> Result<(), Duration> {
+    let config = state.config();
+    let limit = config.room_relay_quota_bytes_per_minute;
+    if limit == 0 || room_data.relay_quota.try_consume(bytes as u64, limit) {
+        return Ok(());
+    }
+
+    state.metrics.relay_quota_exceeded.increment();
+    if !std::mem::replace(&mut room_data.relay_quota.warned, true) {
+        tracing::warn!(room_id = %room.room_id, action = ?config.room_relay_quota_action, "Room exceeds its relay quota of {} bytes per minute", limit);
+    }
+    match config.room_relay_quota_action {
+        RelayQuotaAction::Warn => {
+            room_data.relay_quota.consume(bytes as u64);
+            Ok(())
+        }
+        RelayQuotaAction::Throttle => Err(room_data.relay_quota.retry_after()),
+    }
+}
+
+/// What an end-to-end encrypted message is for, which decides the limits it gets
+enum EncryptedRelay {
+    KeyExchange,
+    Chat,
+    Channel(String, CustomChannelLimits),
+}
+
+/// Relays an opaque end-to-end encrypted message without looking into it
+async fn relay_encrypted_message(state: &Arc<WsAppState>, current_client: &Arc<Client>, to_uid: Option<Uuid>, relay: EncryptedRelay, payload_size: usize, message: OutgoingMessage) -> Result<()> {
+    let state = state.clone();
+    with_current_room(current_client, move |current_client, room, room_data| {
+        if !room_data.end_to_end_encrypted {
+            response_with_error(current_client, ErrorKind::RoomNotEncrypted);
+            return Ok(());
+        }
+
+        let max_payload_size = match &relay {
+            EncryptedRelay::Channel(_, limits) => limits.max_payload_size,
+            EncryptedRelay::KeyExchange | EncryptedRelay::Chat => MAX_ENCRYPTED_PAYLOAD_SIZE,
+        };
+        if payload_size > max_payload_size {
+            response_with_error(current_client, ErrorKind::PayloadTooLarge);
+            return Ok(());
+        }
+        match &relay {
+            EncryptedRelay::KeyExchange => {}
+            EncryptedRelay::Chat => {
+                if !may_chat(room_data, current_client) {
+                    return Ok(());
+                }
+            }
+            EncryptedRelay::Channel(channel, limits) => {
+                if !take_custom_channel_token(room_data, current_client, channel, *limits)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        let recipients: Vec<Arc<Client>> = room_data
+            .clients
+            .iter()
+            .filter(|room_client| room_client.client.uid != current_client.uid)
+            .filter(|room_client| to_uid.is_none_or(|to_uid| room_client.client.uid == to_uid))
+            .map(|room_client| room_client.client.clone())
+            .collect();
+        if to_uid.is_some() && recipients.is_empty() {
+            response_with_error(current_client, ErrorKind::NoSuchClient);
+            return Ok(());
+        }
+        if let Err(retry_after) = check_relay_quota(&state, room, room_data, payload_size * recipients.len()) {
+            response_with_error_retry_after(current_client, ErrorKind::RelayQuotaExceeded, retry_after);
+            return Ok(());
+        }
+
+        let payload = serde_json::to_string(&message)?;
+        for client in recipients {
+            let _ = response_with_text(&client, payload.clone());
+        }
+        response_with_success(current_client);
+        Ok(())
+    }).await?;
+
+    Ok(())
+}
+
+fn is_valid_custom_channel(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel.len() <= MAX_CUSTOM_CHANNEL_LENGTH
+        && channel.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Takes a message from the member's budget on `channel`, answers why when it is used up
+fn take_custom_channel_token(room_data: &mut RoomData, current_client: &Client, channel: &str, limits: CustomChannelLimits) -> Result<bool> {
+    let room_current_client = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == current_client.uid).ok_or(anyhow!("Unexpected error"))?;
+    if !room_current_client.custom_rate_limits.contains_key(channel) && room_current_client.custom_rate_limits.len() >= MAX_CUSTOM_CHANNELS_PER_MEMBER {
+        response_with_error(current_client, ErrorKind::InvalidCustomChannel);
+        return Ok(false);
+    }
+    let rate_limit = room_current_client.custom_rate_limits.entry(channel.to_string())
+        .or_insert_with(|| TokenBucket::new(limits.messages_burst, limits.messages_per_second));
+    if !rate_limit.try_take(1.0) {
+        response_with_error_retry_after(current_client, ErrorKind::RateLimited, rate_limit.retry_after(1.0));
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Spectators and muted members don't chat, busy rooms refuse chat messages before reactions
+fn may_chat(room_data: &mut RoomData, current_client: &Client) -> bool {
+    if room_data.find_room_client(current_client).is_some_and(|room_client| room_client.spectator) {
+        response_with_error(current_client, ErrorKind::Forbidden);
+        return false;
+    }
+    if let Some(muted_for) = room_data.find_room_client(current_client).and_then(|room_client| room_client.muted_for()) {
+        response_with_error_retry_after(current_client, ErrorKind::Muted, muted_for);
+        return false;
+    }
+    if !room_data.try_broadcast_event(EventPriority::Normal) {
+        response_with_error_retry_after(current_client, ErrorKind::RateLimited, room_data.event_rate_limit.retry_after(1.0));
+        return false;
+    }
+    true
+}
+
+/// Checks the signature of a page URL change if the room requires signed commands and consumes its nonce
+fn verify_page_url_change(room: &Room, room_data: &mut RoomData, page_url: &str, nonce: Option<u64>, signature: Option<String>) -> bool {
+    if !room_data.require_signed_commands {
+        return true;
+    }
+
+    let valid = match (nonce, signature) {
+        (Some(nonce), Some(signature)) => verify_page_url_change_signature(&room_data.signing_secret, room_data.last_signed_nonce, &room.room_id, nonce, page_url, &signature),
+        _ => false,
+    };
+    if valid {
+        room_data.last_signed_nonce = nonce.unwrap_or_default();
+    }
+    valid
+}
+
+pub fn send_signing_secret_to_controllers(room_data: &RoomData) {
+    let secret = to_hex(&room_data.signing_secret);
+    for room_client in room_data.clients.iter().filter(|room_client| room_data.can_sign_commands(room_client)) {
+        response_with_json(&room_client.client, OutgoingMessage::SigningSecret { secret: secret.clone() });
+    }
+}
+
+/// Only small images (screenshots, thumbnails) are allowed to be shared, recognized by their magic bytes
+fn detect_image_mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some("image/png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+async fn handle_playback_command(state: &Arc<WsAppState>, current_client: &Arc<Client>, command: PlaybackCommand) -> Result<()> {
+    let user_id = current_client.data.lock().await.user_id.clone();
+    let progress = with_current_room(current_client, move |current_client, room, room_data| {
+        let can_control = match command {
+            // Reported by the players of the members, spectators and viewers don't drive playback
+            PlaybackCommand::PlayerEvent(PlayerEvent::StopDueToVideoLoading { .. } | PlayerEvent::StartPlaying { .. }) => room_data.allow_stop_due_to_video_loading
+                && room_data.find_room_client(current_client).is_some_and(|room_client| !room_client.spectator && room_client.role != Role::Viewer),
+            _ => room_data.has_permission(current_client, RoomPermission::ControlPlayback)
+        };
+
+        if !can_control {
+            return Err(Denied(ErrorKind::Forbidden).into());
+        }
+
+        let progress = user_id.zip(command.position()).map(|(user_id, position)| (user_id, watch_progress(room, room_data, position)));
+
+        // Buffering pauses are automatic, only deliberate commands are voted on
+        if room_data.control_mode == ControlMode::Vote && !matches!(command, PlaybackCommand::PlayerEvent(PlayerEvent::StopDueToVideoLoading { .. })) {
+            let window_opened = room_data.playback_votes.is_empty();
+            room_data.playback_votes.retain(|vote| vote.client_uid != current_client.uid);
+            room_data.playback_votes.push(PlaybackVote { client_uid: current_client.uid, command });
+            if window_opened {
+                tokio::spawn(resolve_playback_vote(room.clone()));
+            }
+            response_with_success(current_client);
+            return Ok(progress);
+        }
+
+        if !room_data.try_broadcast_event(EventPriority::Normal) {
+            response_with_error_retry_after(current_client, ErrorKind::RateLimited, room_data.event_rate_limit.retry_after(1.0));
+            return Ok(progress);
+        }
+
+        apply_playback_command(room_data, command, current_client.uid)?;
+        response_with_success(current_client);
+        Ok(progress)
+    }).await?;
+
+    if let Some((user_id, progress)) = progress.flatten() {
+        state.record_watch_progress(&user_id, progress).await;
+    }
+
+    Ok(())
+}
+
+/// Hands a signaling message to the one member it is addressed to
+async fn relay_rtc_signal(current_client: &Arc<Client>, to_uid: Uuid, message: OutgoingMessage) -> Result<()> {
+    with_current_room(current_client, move |current_client, _room, room_data| {
+        if !room_data.voice_enabled {
+            response_with_error(current_client, ErrorKind::VoiceDisabled);
+            return Ok(());
+        }
+        let Some(room_client) = room_data.clients.iter().find(|room_client| room_client.client.uid == to_uid && room_client.client.uid != current_client.uid) else {
+            response_with_error(current_client, ErrorKind::NoSuchClient);
+            return Ok(());
+        };
+        if !room_client.client.supports(&message) {
+            response_with_error(current_client, ErrorKind::UnsupportedByClient);
+            return Ok(());
+        }
+
+        response_with_json(&room_client.client, message);
+        response_with_success(current_client);
+        Ok(())
+    }).await?;
+    Ok(())
+}
+
+/// Updates the play state and relays the command to everybody except its sender
+fn apply_playback_command(room_data: &mut RoomData, command: PlaybackCommand, client_uid: Uuid) -> Result<()> {
+    // A deliberate command overrides the automatic resume and the countdown
+    room_data.paused_for_buffering = false;
+    cancel_countdown(room_data)?;
+    if let Some(playing) = command.playing() {
+        room_data.set_playing(playing);
+    }
+    room_data.playback.update(command.position(), command.playing());
+    room_data.record_history(Some(client_uid), command.to_history_event());
+
+    let payload = serde_json::to_string(&command.to_outgoing_message(client_uid, &room_data.playback, Duration::ZERO))?;
+    for room_client in room_data.clients.iter().filter(|room_client| room_client.client.uid != client_uid) {
+        // Half the round trip is how long the message takes to get there
+        match room_client.client.smoothed_rtt() {
+            Some(rtt) => response_with_json(&room_client.client, command.to_outgoing_message(client_uid, &room_data.playback, rtt / 2)),
+            None => {
+                let _ = response_with_text(&room_client.client, payload.clone());
+            }
+        }
+    }
+    #[cfg(feature = "redis")]
+    cluster::publish_playback(room_data, &payload);
+
+    Ok(())
+}
+
+async fn end_poll_after(room: Arc<Room>, poll_id: Uuid, duration: Duration) {
+    tokio::time::sleep(duration).await;
+
+    let result = room.try_run(move |room_data| {
+        if room_data.poll.as_ref().is_some_and(|poll| poll.poll_id == poll_id) {
+            finish_poll(room_data)?;
+        }
+        Ok(())
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Error while ending poll: {:?}", e);
+    }
+}
+
+/// Announces the result of the running poll and applies it for well-known kinds of polls
+fn finish_poll(room_data: &mut RoomData) -> Result<()> {
+    let Some(poll) = room_data.poll.take() else {
+        return Ok(());
+    };
+    let winning_option = poll.winning_option();
+
+    send_to_members(room_data.clients.iter(), &OutgoingMessage::PollEnded { poll: PollDto::from(&poll), winning_option })?;
+
+    if poll.kind == PollKind::SkipVideo && winning_option == Some(0) && !room_data.queue.is_empty() {
+        let url = room_data.queue.remove(0);
+        change_page_url(room_data, url, poll.started_by)?;
+        broadcast_settings_change(room_data);
+    }
+
+    Ok(())
+}
+
+fn broadcast_ready_check(room_data: &RoomData) -> Result<()> {
+    send_to_members(room_data.clients.iter(), &OutgoingMessage::ReadyCheckUpdated { ready_check: ReadyCheckDto::from(room_data) })
+}
+
+/// Announces the state of the running ready check, starting playback for everybody once enough
+/// members are ready
+fn update_ready_check(room_data: &mut RoomData) -> Result<()> {
+    let Some(ready_check) = room_data.ready_check.as_ref() else {
+        return Ok(());
+    };
+    if ready_check.ready_uids.len() < room_data.ready_check_required_count() {
+        return broadcast_ready_check(room_data);
+    }
+
+    let started_by = ready_check.started_by;
+    room_data.ready_check = None;
+    broadcast_ready_check(room_data)?;
+    play_for_everybody(room_data, started_by)
+}
+
+/// Plays on behalf of `started_by`, who is sent the `Play` too
+fn play_for_everybody(room_data: &mut RoomData, started_by: Uuid) -> Result<()> {
+    apply_playback_command(room_data, PlaybackCommand::Play, started_by)?;
+    // Commands are relayed to everybody except their sender, but this one was sent by nobody
+    if let Some(room_client) = room_data.clients.iter().find(|room_client| room_client.client.uid == started_by) {
+        response_with_json(&room_client.client, PlaybackCommand::Play.to_outgoing_message(started_by, &room_data.playback, Duration::ZERO));
+    }
+    Ok(())
+}
+
+async fn end_countdown_after(room: Arc<Room>, countdown_id: Uuid, duration: Duration) {
+    tokio::time::sleep(duration).await;
+
+    let result = room.try_run(move |room_data| {
+        if let Some(countdown) = room_data.countdown.take_if(|countdown| countdown.countdown_id == countdown_id) {
+            play_for_everybody(room_data, countdown.started_by)?;
+        }
+        Ok(())
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Error while ending countdown: {:?}", e);
+    }
+}
+
+fn cancel_countdown(room_data: &mut RoomData) -> Result<()> {
+    if room_data.countdown.take().is_some() {
+        send_to_members(room_data.clients.iter(), &OutgoingMessage::CountdownCancelled)?;
+    }
+    Ok(())
+}
+
+/// A ready check is about the current video, so it is dropped when the video changes
+fn cancel_ready_check(room_data: &mut RoomData) -> Result<()> {
+    if room_data.ready_check.take().is_some() {
+        broadcast_ready_check(room_data)?;
+    }
+    Ok(())
+}
+
+/// Switches the room to another video, starting it from the beginning
+fn change_page_url(room_data: &mut RoomData, url: String, client_uid: Uuid) -> Result<()> {
+    room_data.page_url = Some(url.clone());
+    room_data.videos_played += 1;
+    room_data.playback.update(Some(0.0), None);
+    // Track ids, markers and end reports belong to the previous video
+    room_data.playback.set_track(TrackKind::Audio, None);
+    room_data.playback.set_track(TrackKind::Subtitle, None);
+    room_data.markers.clear();
+    room_data.video_ended_uids.clear();
+    cancel_ready_check(room_data)?;
+    cancel_countdown(room_data)?;
+    room_data.record_history(Some(client_uid), RoomHistoryEventDto::PageUrlChanged { url: Some(url.clone()) });
+
+    let payload = serde_json::to_string(&OutgoingMessage::PageUrlChanged { url, client_uid })?;
+    for room_client in room_data.clients.iter() {
+        let _ = response_with_text(&room_client.client, payload.clone());
+    }
+    #[cfg(feature = "redis")]
+    cluster::publish_playback(room_data, &payload);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum OpenRoomResult {
+    Opened,
+    /// Somebody else opened a room with the id first
+    IdTaken,
+    /// Answered with an error
+    Refused,
+}
+
+/// Opens a room owned by the client and answers with `reply`, unless a limit is reached
+/// `archived_room` gives the new room the settings and playlist it had when it was closed
+async fn open_room(state: &Arc<WsAppState>, current_client: &Arc<Client>, room_id: String, name: Option<String>, reply: OutgoingMessage, archived_room: Option<ArchivedRoomDto>, template: Option<RoomTemplate>) -> Result<OpenRoomResult> {
+    if !check_room_limits(state, current_client).await {
+        return Ok(OpenRoomResult::Refused);
+    }
+
+    let host_name = name.clone().unwrap_or_default();
+    let new_room = Arc::new(Room::new_with_owner(room_id.clone(), current_client.clone(), name));
+    if state.store.insert_room(new_room.clone()).await.is_err() {
+        return Ok(OpenRoomResult::IdTaken);
+    }
+    let previous_room = current_client.set_room(current_client.data.lock().await.deref_mut(), Some(new_room.clone()));
+    if let Some(previous_room) = previous_room {
+        handle_quit_room(state, current_client, previous_room).await;
+    }
+
+    tracing::info!(room_id = %room_id, "Opened room");
+    reply_with_json(current_client, reply);
+    let meta = current_client.data.lock().await.meta.clone();
+    let owner = current_client.clone();
+    let max_listed_members = state.config().max_listed_members;
+    new_room.run(move |room_data| {
+        room_data.max_listed_members = max_listed_members;
+        if let Some(archived_room) = archived_room {
+            archived_room.restore_into(room_data);
+        }
+        if let Some(template) = template {
+            apply_room_template(room_data, &template);
+        }
+        if let Some(room_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == owner.uid) {
+            room_client.meta = meta;
+        }
+        send_room_snapshot(room_data, &owner);
+        broadcast_room_change(room_data);
+    }).await?;
+    state.attach_room(&new_room);
+
+    state.push_notifier.notify_room(PushNotification {
+        namespace: new_room.namespace.clone(),
+        room_id: room_id.clone(),
+        title: "Watch party is live".to_string(),
+        body: format!("{} has opened room {}", host_name, room_id),
+    }).await;
+    Ok(OpenRoomResult::Opened)
+}
+
+fn apply_room_template(room_data: &mut RoomData, template: &RoomTemplate) {
+    if let Some(permission_preset) = template.permission_preset {
+        room_data.apply_permission_preset(permission_preset);
+    }
+    if let Some(control_mode) = template.control_mode {
+        room_data.control_mode = control_mode;
+    }
+    if let Some(public) = template.public {
+        room_data.public = public;
+    }
+    if let Some(voice_enabled) = template.voice_enabled {
+        room_data.voice_enabled = voice_enabled;
+    }
+    if let Some(pause_on_leave) = template.pause_on_leave {
+        room_data.pause_on_leave = pause_on_leave;
+    }
+    if let Some(duplicate_names) = template.duplicate_names {
+        room_data.duplicate_names = duplicate_names;
+    }
+    if let Some(owner_succession) = template.owner_succession {
+        room_data.owner_succession = owner_succession;
+    }
+    if let Some(end_to_end_encrypted) = template.end_to_end_encrypted {
+        room_data.end_to_end_encrypted = end_to_end_encrypted;
+    }
+    room_data.max_clients = template.max_clients;
+}
+
+/// Whether the client may open one more room, answers the reason when it may not
+async fn check_room_limits(state: &WsAppState, current_client: &Client) -> bool {
+    if state.store.rooms().await.iter().filter(|room| room.created_by(current_client)).count() >= state.config().max_rooms_per_creator {
+        response_with_error(current_client, ErrorKind::TooManyRooms);
+        return false;
+    }
+    if state.rooms_limit_reached(current_client.namespace().as_deref(), 1).await {
+        state.metrics.rooms_rejected.increment();
+        response_with_error(current_client, ErrorKind::ServerRoomLimitReached);
+        return false;
+    }
+    true
+}
 
-    ws.channel(move|stream| {
-        Box::pin(async move {
-            let (mut sink, mut stream) = stream.split();
-            // Create a channel for this client
-            let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
-            // Register this client
-            let current_client = Arc::new(Client::new(tx.clone()));
-            state.clients.lock().await.push(current_client.clone());
+/// `SetRole` on behalf of `current_client`, answers success or the reason it was refused
+fn set_member_role(room_data: &mut RoomData, current_client: &Arc<Client>, client_uid: Uuid, role: Role) -> Result<()> {
+    require_role(room_data, current_client, Role::Owner)?;
+    if role == Role::Owner || client_uid == current_client.uid {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    let Some(index) = room_data.clients.iter().position(|room_client| room_client.client.uid == client_uid) else {
+        response_with_error(current_client, ErrorKind::NoSuchClient);
+        return Ok(());
+    };
 
-            // spawn a task for outgoing messages to this client
-            tokio::spawn(async move {
-                while let Some(msg) = rx.recv().await {
-                    if let Err(e) = sink.send(msg).await {
-                        eprintln!("send error: {:?}", e);
-                        break;
-                    }
-                }
-            });
+    let could_sign = room_data.can_sign_commands(&room_data.clients[index]);
+    room_data.clients[index].role = role;
+    let can_sign = room_data.can_sign_commands(&room_data.clients[index]);
+    room_data.record_history(Some(current_client.uid), RoomHistoryEventDto::RoleChanged { target_uid: client_uid, role });
+    if could_sign && !can_sign {
+        // A demoted member must not be able to keep signing commands
+        room_data.signing_secret = generate_signing_secret();
+        send_signing_secret_to_controllers(room_data);
+    } else if can_sign && !could_sign {
+        send_signing_secret_to_controllers(room_data);
+    }
+    response_with_success(current_client);
+    broadcast_client_change(room_data, client_uid);
+    Ok(())
+}
 
-            response_with_json(&current_client, OutgoingMessage::ClientUid {client_uid: current_client.uid});
+/// Removes the member from the room if they are still in it and tells them who did it
+async fn kick_client(state: &WsAppState, room: &Arc<Room>, target_client: &Arc<Client>, by_uid: Uuid) -> Result<()> {
+    let removed_client = target_client.clone();
+    let removed = room.try_run(move |room_data| {
+        if room_data.find_room_client(&removed_client).is_none() {
+            return Ok(None);
+        }
+        room_data.record_history(Some(by_uid), RoomHistoryEventDto::Kicked { target_uid: removed_client.uid });
+        let close = remove_room_member(room_data, &removed_client);
+        update_buffering_pause(room_data, removed_client.uid)?;
+        Ok(Some(close))
+    }).await?;
+    let Some(close) = removed else {
+        return Ok(());
+    };
 
-            // handle incoming messages
-            while let Some(Ok(msg)) = stream.next().await {
-                let result = handle_message(&current_client, msg, &state).await;
-                if let Err(e) = result {
-                    rocket::error!("Error while handling ws client message: {:?}", e);
-                    response_with_error(&current_client, ErrorKind::InternalServerError);
-                }
-            }
+    {
+        let mut target_client_data = target_client.data.lock().await;
+        if target_client_data.room.as_ref().is_some_and(|target_room| Arc::ptr_eq(target_room, room)) {
+            target_client.set_room(&mut target_client_data, None);
+        }
+    }
+    tracing::info!(client_uid = %target_client.uid, room_id = %room.room_id, %by_uid, "Kicked member");
+    response_with_json(target_client, OutgoingMessage::Kicked { room_id: room.room_id.clone(), by_uid });
+    if close {
+        close_room(state, room).await?;
+    }
 
-            handle_client_disconnect(&state, &current_client).await;
+    Ok(())
+}
 
-            Ok(())
-        })
-    })
+/// Pauses everybody while any member is buffering and resumes once all of them are ready again,
+/// `client_uid` is the member whose report (or departure) caused the change
+fn update_buffering_pause(room_data: &mut RoomData, client_uid: Uuid) -> Result<()> {
+    if !room_data.allow_stop_due_to_video_loading {
+        return Ok(());
+    }
+
+    let anybody_buffering = room_data.clients.iter().any(|room_client| room_client.buffering);
+    let (message, event) = match room_logic::buffering_transition(anybody_buffering, room_data.playback.playing, room_data.paused_for_buffering) {
+        Some(BufferingTransition::PauseForBuffering) => {
+            room_data.paused_for_buffering = true;
+            (OutgoingMessage::Pause { position: room_data.playback.current_position(), client_uid, left_name: None }, RoomHistoryEventDto::PausedForBuffering)
+        }
+        Some(BufferingTransition::ResumeAfterBuffering) => {
+            room_data.paused_for_buffering = false;
+            (OutgoingMessage::Play { client_uid, position: None }, RoomHistoryEventDto::ResumedAfterBuffering)
+        }
+        None => return Ok(()),
+    };
+    room_data.record_history(Some(client_uid), event);
+
+    let playing = !room_data.paused_for_buffering;
+    room_data.set_playing(playing);
+    room_data.playback.update(None, Some(playing));
+
+    let payload = serde_json::to_string(&message)?;
+    for room_client in room_data.clients.iter() {
+        let _ = response_with_text(&room_client.client, payload.clone());
+    }
+    #[cfg(feature = "redis")]
+    cluster::publish_playback(room_data, &payload);
+
+    Ok(())
 }
 
-#[deny(
-    clippy::unwrap_used,
-    clippy::expect_used,
-    clippy::panic
-)]
-async fn handle_message(current_client: &Arc<Client>, msg: Message, state: &Arc<WsAppState>) -> Result<()> {
-    if let Message::Text(txt) = msg {
-        match serde_json::from_str::<IncomingMessage>(&txt) {
-            Ok(inc) => {
-                match inc {
-                    IncomingMessage::Ping => {
-                        response_with_json(current_client, OutgoingMessage::Pong)
-                    }
-                    IncomingMessage::ChangeName { new_name } => 'label: {
-                        if new_name.len() <= 2 {
-                            response_with_error(current_client, ErrorKind::ClientNameTooShort);
-                            break 'label;
-                        }
+async fn stop_typing_after(room: Arc<Room>, client_uid: Uuid, typing_id: Uuid) {
+    tokio::time::sleep(TYPING_TIMEOUT).await;
 
-                        let mut client_data = current_client.data.lock().await;
-                        client_data.name = Some(new_name);
-                        response_with_success(current_client);
-                        if client_data.room.is_some() {
-                            let room = client_data.room.as_ref().ok_or(anyhow!("Unexpected error"))?.clone();
-                            drop(client_data);
-                            broadcast_room_change(room.data.lock().await.deref()).await;
-                        }
-                    }
-                    IncomingMessage::JoinRoom { room_id } => 'label: {
-                        if !validate_client_name(current_client).await {
-                            break 'label;
-                        }
+    let result = room.try_run(move |room_data| {
+        if room_data.clients.iter().any(|room_client| room_client.client.uid == client_uid && room_client.typing == Some(typing_id)) {
+            stop_typing(room_data, client_uid)?;
+        }
+        Ok(())
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Error while ending typing: {:?}", e);
+    }
+}
 
-                        if room_id.len() <= 2 {
-                            response_with_error(current_client, ErrorKind::RoomIdTooShort);
-                            break 'label;
-                        }
+fn stop_typing(room_data: &mut RoomData, client_uid: Uuid) -> Result<()> {
+    let Some(room_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == client_uid) else {
+        return Ok(());
+    };
+    if room_client.typing.take().is_some() {
+        let others = room_data.clients.iter().filter(|room_client| room_client.client.uid != client_uid);
+        send_to_members(others, &OutgoingMessage::TypingChanged { client_uid, typing: false })?;
+    }
+    Ok(())
+}
 
-                        let mut rooms = state.rooms.lock().await;
-                        if let Some(room) = rooms.get_mut(&room_id) {
-                            // Join existing room
-                            room.data.lock().await.add_client(current_client.clone());
-                            current_client.data.lock().await.room = Some(room.clone());
+/// Pauses everybody in a room with `pause_on_leave`, so nobody watches ahead of the member who left
+fn pause_on_leave(room_data: &mut RoomData, client_uid: Uuid, left_name: Option<String>) -> Result<()> {
+    if !room_data.pause_on_leave || room_data.clients.is_empty() {
+        return Ok(());
+    }
+    cancel_countdown(room_data)?;
+    if !room_logic::pauses_on_leave(room_data.playback.playing, room_data.paused_for_buffering) {
+        return Ok(());
+    }
 
-                            response_with_success(current_client);
-                            broadcast_room_change(room.data.lock().await.deref()).await;
-                        } else {
-                            // Create new one
-                            let new_room = Room::new_with_owner(room_id.clone(), current_client.clone());
-                            let new_room = Arc::new(new_room);
-                            current_client.data.lock().await.room = Some(new_room.clone());
+    room_data.paused_for_buffering = false;
+    room_data.set_playing(false);
+    room_data.playback.update(None, Some(false));
+    room_data.record_history(Some(client_uid), RoomHistoryEventDto::PausedOnLeave);
 
-                            response_with_success(current_client);
-                            broadcast_room_change(new_room.data.lock().await.deref()).await;
+    let payload = serde_json::to_string(&OutgoingMessage::Pause { position: room_data.playback.current_position(), client_uid, left_name })?;
+    for room_client in room_data.clients.iter() {
+        let _ = response_with_text(&room_client.client, payload.clone());
+    }
+    #[cfg(feature = "redis")]
+    cluster::publish_playback(room_data, &payload);
 
-                            rooms.insert(room_id, new_room);
-                        }
-                    },
-                    IncomingMessage::PlayerEvent {event} => 'label:  {
-                        if let Ok(current_client_data) = client_in_room(current_client).await {
-                            let room = current_client_data.room.as_ref().ok_or(anyhow!("Unexpected error"))?.clone();
-                            drop(current_client_data);
-                            let room_data = room.data.lock().await;
-
-                            let can_control = match event {
-                                PlayerEvent::StopDueToVideoLoading { .. } | PlayerEvent::StartPlaying { .. } => room_data.allow_stop_due_to_video_loading,
-                                _ => room_data.can_control(current_client)
-                            };
-
-                            if !can_control {
-                                response_with_error(current_client, ErrorKind::Forbidden);
-                                break 'label;
-                            }
+    Ok(())
+}
 
-                            let outgoing_message = OutgoingMessage::PlayerEvent {
-                                event,
-                                client_uid: current_client.uid,
-                            };
-                            let payload = serde_json::to_string(&outgoing_message)?;
+/// Applies the kind of command most members voted for during the window, ties go to the earliest vote
+async fn resolve_playback_vote(room: Arc<Room>) {
+    tokio::time::sleep(PLAYBACK_VOTE_WINDOW).await;
 
-                            for room_client in room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid) {
-                                let _ = response_with_text(&room_client.client, payload.clone());
-                            }
-                            response_with_success(current_client);
-                        }
-                    },
-                    IncomingMessage::ReportPlayerStatus { player_status } => {
-                        if let Ok(current_client_data) = client_in_room(current_client).await {
-                            let room = current_client_data.room.as_ref().ok_or(anyhow!("Unexpected error"))?.clone();
-                            drop(current_client_data);
-                            let room_data = room.data.lock().await;
-
-                            let outgoing_message = OutgoingMessage::ReportPlayerStatus {
-                                player_status,
-                                client_uid: current_client.uid,
-                            };
-                            let payload = serde_json::to_string(&outgoing_message)?;
-
-                            for room_client in room_data.clients.iter().filter(|room_client| room_client.client.uid != current_client.uid) {
-                                let _ = response_with_text(&room_client.client, payload.clone());
-                            }
-                            response_with_success(current_client);
-                        }
-                    },
-                    IncomingMessage::ChangeClientAdminStatus { client_uid, admin } => 'label: {
-                        if let Ok(current_client_data) = client_in_room(current_client).await {
-                            let room = current_client_data.room.as_ref().ok_or(anyhow!("Unexpected error"))?.clone();
-                            drop(current_client_data);
-                            let mut room_data = room.data.lock().await;
-
-                            let room_current_client = room_data.find_room_client(current_client).ok_or(anyhow!("Unexpected error"))?;
-                            if !room_current_client.owner {
-                                response_with_error(current_client, ErrorKind::Forbidden);
-                                break 'label;
-                            }
+    let result = room.try_run(|room_data| {
+        let votes = std::mem::take(&mut room_data.playback_votes);
 
-                            let room_target_client = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == client_uid);
+        // Commands with the same effect on the play state are the same choice
+        let mut tally: Vec<(&PlaybackVote, usize)> = Vec::new();
+        for vote in votes.iter() {
+            let kind = vote.command.playing();
+            match tally.iter_mut().find(|(first_vote, _)| first_vote.command.playing() == kind) {
+                Some((_, count)) => *count += 1,
+                None => tally.push((vote, 1)),
+            }
+        }
 
-                            if let Some(room_target_client) = room_target_client {
-                                room_target_client.admin = admin;
-                                response_with_success(current_client);
-                                broadcast_room_change(&room_data).await;
-                            } else {
-                                response_with_error(current_client, ErrorKind::NoSuchClient);
-                            }
-                        }
-                    },
-                    IncomingMessage::ChangeRoomPreferences { page_url, allow_stop_due_to_video_loading } => 'label: {
-                        if let Ok(current_client_data) = client_in_room(current_client).await {
-                            let room = current_client_data.room.as_ref().ok_or(anyhow!("Unexpected error"))?.clone();
-                            drop(current_client_data);
-                            let mut room_data = room.data.lock().await;
-
-                            let room_current_client = room_data.find_room_client(current_client).ok_or(anyhow!("Unexpected error"))?;
-                            if !room_current_client.owner {
-                                response_with_error(current_client, ErrorKind::Forbidden);
-                                break 'label;
-                            }
+        let mut winner: Option<(&PlaybackVote, usize)> = None;
+        for (vote, count) in tally {
+            if winner.is_none_or(|(_, winner_count)| count > winner_count) {
+                winner = Some((vote, count));
+            }
+        }
 
-                            room_data.page_url = Some(page_url);
-                            room_data.allow_stop_due_to_video_loading = allow_stop_due_to_video_loading;
+        if let Some((vote, _)) = winner {
+            apply_playback_command(room_data, vote.command, vote.client_uid)?;
+        }
+        Ok(())
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Error while applying playback vote: {:?}", e);
+    }
+}
 
-                            response_with_success(current_client);
-                            broadcast_room_change(&room_data).await;
-                        }
-                    }
-                    IncomingMessage::QuitRoom => {
-                        if let Ok(mut current_client_data) = client_in_room(current_client).await {
-                            handle_quit_room(state, current_client, current_client_data.deref_mut()).await;
-                            response_with_success(current_client);
-                        }
-                    }
-                }
+async fn send_continue_watching(state: &Arc<WsAppState>, current_client: &Arc<Client>) {
+    let Some(user_id) = current_client.data.lock().await.user_id.clone() else {
+        return;
+    };
+    let progress = state.watch_progress.lock().await.get(&user_id).cloned();
+    if let Some(progress) = progress {
+        response_with_json(current_client, OutgoingMessage::ContinueWatching { progress });
+    }
+}
+
+fn watch_progress(room: &Room, room_data: &RoomData, at_second: f64) -> WatchProgressDto {
+    WatchProgressDto {
+        room_id: room.room_id.clone(),
+        page_url: room_data.page_url.clone(),
+        at_second,
+        updated_at: unix_millis_now(),
+    }
+}
+
+/// Shows the member as disconnected to the rest of the room, a member who was buffering no longer
+/// holds the room paused
+async fn handle_client_detached(current_client: &Arc<Client>) {
+    let Some(room) = current_client.data.lock().await.room.clone() else {
+        return;
+    };
+    let client_uid = current_client.uid;
+    let result = room.run(move |room_data| {
+        if let Some(room_client) = room_data.clients.iter_mut().find(|room_client| room_client.client.uid == client_uid) {
+            room_client.buffering = false;
+        }
+        if let Err(e) = update_buffering_pause(room_data, client_uid) {
+            tracing::error!("Error while resuming after buffering: {:?}", e);
+        }
+        broadcast_client_change(room_data, client_uid);
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Error while detaching client: {:?}", e);
+    }
+}
+
+/// Safe to call more than once, the maintenance task also uses it for clients whose connection died
+pub async fn handle_client_disconnect(state: &Arc<WsAppState>, current_client: &Arc<Client>) {
+    let room = current_client.set_room(current_client.data.lock().await.deref_mut(), None);
+    if let Some(room) = room {
+        handle_quit_room(state, current_client, room).await;
+    }
+
+    state.lobby.data.lock().await.remove_member(current_client);
+
+    state.clients.remove(current_client);
+}
+
+/// Removes the client from `room`, which has already been taken out of its `ClientData`
+async fn handle_quit_room(state: &Arc<WsAppState>, current_client: &Arc<Client>, room: Arc<Room>) {
+    let quitting_client = current_client.clone();
+    let left = room.run(move |room_data| {
+        let room_client = room_data.find_room_client(&quitting_client);
+        let name = room_client.and_then(|room_client| room_client.name.clone());
+        let hidden = room_client.is_some_and(|room_client| room_client.hidden);
+        let spectator = room_client.is_some_and(|room_client| room_client.spectator);
+        let close = remove_room_member(room_data, &quitting_client);
+        if !hidden && !spectator && let Err(e) = pause_on_leave(room_data, quitting_client.uid, name.clone()) {
+            tracing::error!("Error while pausing for the member who left: {:?}", e);
+        }
+        if let Err(e) = update_buffering_pause(room_data, quitting_client.uid) {
+            tracing::error!("Error while resuming after buffering: {:?}", e);
+        }
+        if !hidden {
+            room_data.record_departure(DepartedClientDto {
+                name,
+                uid: quitting_client.uid,
+                left_at: unix_millis_now(),
+            });
+        }
+
+        (room_data.clients.is_empty(), close)
+    }).await;
+
+    match left {
+        Ok((true, _)) => remove_room_if_empty(state, &room).await,
+        Ok((false, true)) => {
+            tracing::info!(room_id = %room.room_id, "Closing the room the owner left");
+            if let Err(e) = close_room(state, &room).await {
+                tracing::error!("Error while closing the room the owner left: {:?}", e);
             }
-            Err(e) => {
-                response_with_error_msg(current_client, ErrorKind::JsonError, format!("Invalid JSON: {}", e))
+        }
+        Ok((false, false)) => {}
+        Err(e) => tracing::error!("Error while leaving room: {:?}", e),
+    }
+}
+
+/// Puts a member of a room restored from a snapshot back in their room under their old uid. The
+/// connection's own fresh client is dropped like in a regular resume.
+async fn resume_restored_member(state: &Arc<WsAppState>, current_client: &Arc<Client>, uid: Uuid) -> Result<Option<Arc<Client>>> {
+    let restored_member = state.restored_members.lock().await.remove(&uid).filter(|member| member.expires_at > Instant::now());
+    let room = match &restored_member {
+        Some(restored_member) if restored_member.namespace == current_client.namespace() => {
+            state.store.room(restored_member.namespace.as_deref(), &restored_member.room_id).await
+        }
+        _ => None,
+    };
+    let (Some(restored_member), Some(room)) = (restored_member, room) else {
+        response_with_error(current_client, ErrorKind::ResumeFailed);
+        return Ok(None);
+    };
+
+    let client = Arc::new(Client::with_uid(uid, current_client.connection(), current_client.ip, current_client.locale(), &state.config()));
+    let user_id = current_client.data.lock().await.user_id.clone();
+    let mut client_data = client.data.lock().await;
+    client_data.name = restored_member.name.clone();
+    client_data.user_id = user_id;
+    let joining_client = client.clone();
+    let joined = room.run(move |room_data| {
+        if room_data.closed {
+            return false;
+        }
+        room_data.add_client(joining_client.clone(), restored_member.name);
+        // The owner takes the room back from whoever got it by coming back first
+        let mut previous_owner_uid = None;
+        for room_client in room_data.clients.iter_mut() {
+            if room_client.client.uid == joining_client.uid {
+                room_client.role = room_client.role.max(restored_member.role);
+                room_client.roles = restored_member.roles.clone();
+            } else if restored_member.role == Role::Owner && room_client.is_owner() {
+                room_client.role = Role::Admin;
+                previous_owner_uid = Some(room_client.client.uid);
             }
         }
+        tracing::info!(client_uid = %joining_client.uid, "Member came back to the restored room");
+        broadcast_client_joined(room_data, joining_client.uid);
+        if let Some(previous_owner_uid) = previous_owner_uid {
+            broadcast_client_change(room_data, previous_owner_uid);
+        }
+        true
+    }).await?;
+    if !joined {
+        response_with_error(current_client, ErrorKind::ResumeFailed);
+        return Ok(None);
+    }
+
+    // Registered without the limits, it replaces the connection's client which is already counted
+    let _ = state.clients.try_insert(client.clone(), None, None);
+    handle_client_disconnect(state, current_client).await;
+    client.traffic().set_room(Some(&room));
+    client_data.room = Some(room.clone());
+    drop(client_data);
+
+    reply_with_json(&client, OutgoingMessage::ClientUid {
+        client_uid: client.uid,
+        resume_token: state.resume_token(client.uid),
+    });
+    let snapshot_client = client.clone();
+    room.run(move |room_data| send_room_snapshot(room_data, &snapshot_client)).await?;
+    Ok(Some(client))
+}
+
+/// A room closed while empty stays empty, members can't join closed rooms
+async fn remove_room_if_empty(state: &WsAppState, room: &Arc<Room>) {
+    if room.run(|room_data| room_data.close_if_empty()).await.unwrap_or(true) && state.store.remove_room(room).await {
+        state.forget_room(room).await;
+        state.archive_room(room).await;
     }
+}
+
+/// Removes the room with its members, used by the admin API
+pub async fn close_room(state: &WsAppState, room: &Arc<Room>) -> Result<()> {
+    let room_id = room.room_id.clone();
+    let (members, summary) = room.run(move |room_data| {
+        room_data.closed = true;
+        let summary = SessionSummaryDto::from(&room_id, room_data);
+        (std::mem::take(&mut room_data.clients), summary)
+    }).await?;
+    if state.store.remove_room(room).await {
+        state.forget_room(room).await;
+        state.archive_room(room).await;
+    }
+    state.scheduled_rooms.lock().await.remove(&room.key());
 
+    for room_client in members {
+        let client = room_client.client;
+        {
+            let mut client_data = client.data.lock().await;
+            if client_data.room.as_ref().is_some_and(|client_room| Arc::ptr_eq(client_room, room)) {
+                client.set_room(&mut client_data, None);
+            }
+        }
+        response_with_json(&client, OutgoingMessage::SessionSummary { summary: summary.clone() });
+        response_with_json(&client, OutgoingMessage::RoomClosed { room_id: room.room_id.clone() });
+    }
+    tracing::info!(
+        room_id = %room.room_id,
+        play_secs = summary.total_play_secs,
+        watch_secs = summary.total_watch_secs,
+        peak_viewers = summary.peak_viewers,
+        videos_played = summary.videos_played,
+        "Closed room"
+    );
     Ok(())
 }
 
-async fn handle_client_disconnect(state: &Arc<WsAppState>, current_client: &Arc<Client>) {
+/// Moves every member of `from_room` into `into_room` and removes `from_room`. The old room id
+/// and its aliases become aliases of `into_room`, so late joiners with the old code land there too.
+async fn merge_rooms(state: &Arc<WsAppState>, from_room: &Arc<Room>, into_room: &Arc<Room>) -> Result<()> {
+    let (moved_clients, mut moved_aliases) = from_room
+        .run(|from_room_data| {
+            from_room_data.closed = true;
+            (std::mem::take(&mut from_room_data.clients), std::mem::take(&mut from_room_data.aliases))
+        })
+        .await?;
+    moved_aliases.push(from_room.room_id.clone());
+
+    state.store.remove_room(from_room).await;
     {
-        let mut current_client_data = current_client.data.lock().await;
+        let mut room_aliases = state.room_aliases.lock().await;
+        for alias in moved_aliases.iter() {
+            room_aliases.insert((into_room.namespace.clone(), alias.clone()), into_room.room_id.clone());
+        }
+    }
+
+    let moved_clients = reassign_clients_room(moved_clients, from_room, into_room).await;
+
+    let from_room_id = from_room.room_id.clone();
+    let into_room_id = into_room.room_id.clone();
+    into_room.run(move |into_room_data| {
+        into_room_data.aliases.extend(moved_aliases);
+        for mut room_client in moved_clients {
+            // The owner of the merged room keeps control rights as an admin
+            if room_client.is_owner() {
+                room_client.role = Role::Admin;
+            }
+            room_client.reset_stats(into_room_data.total_play_time());
+            response_with_json(&room_client.client, OutgoingMessage::RoomMerged {
+                from_room_id: from_room_id.clone(),
+                into_room_id: into_room_id.clone(),
+            });
+            into_room_data.clients.push(room_client);
+        }
+        broadcast_room_change(into_room_data);
+    }).await
+}
 
-        if current_client_data.room.is_some() {
-            handle_quit_room(state, current_client, current_client_data.deref_mut()).await;
+/// Moves the data and the members of `room` to a new room listed under `new_room_id` and removes
+/// `room`, `false` when the id is taken. The old id stays an alias for `RENAMED_ROOM_ALIAS_LIFETIME`,
+/// so joiners who got it just before end up in the renamed room.
+async fn rename_room(state: &Arc<WsAppState>, room: &Arc<Room>, new_room_id: String) -> Result<bool> {
+    if state.store.room(room.namespace.as_deref(), &new_room_id).await.is_some() {
+        return Ok(false);
+    }
+
+    let mut room_data = room.run(|room_data| {
+        // The instances relaying the room know it by its old id
+        #[cfg(feature = "redis")]
+        for client_uid in room_data.clients.iter().map(|room_client| room_client.client.uid).collect::<Vec<_>>() {
+            cluster::publish(room_data, || ClusterEvent::MemberLeft { client_uid });
+        }
+        let moved_room_data = std::mem::take(room_data);
+        room_data.closed = true;
+        #[cfg(feature = "redis")]
+        let moved_room_data = RoomData { cluster: None, remote_clients: Vec::new(), ..moved_room_data };
+        moved_room_data
+    }).await?;
+    let room_clients = std::mem::take(&mut room_data.clients);
+    let renamed_room = Arc::new(Room::spawn(room.namespace.clone(), new_room_id.clone(), room_data, room.creator_uid, room.creator_ip));
+    if state.store.insert_room(renamed_room.clone()).await.is_err() {
+        // Somebody opened a room with the id in the meantime, the room goes back to its old id
+        let mut room_data = renamed_room.run(std::mem::take).await?;
+        room_data.clients = room_clients;
+        room.run(move |old_room_data| *old_room_data = room_data).await?;
+        state.attach_room(room);
+        return Ok(false);
+    }
+    state.store.remove_room(room).await;
+
+    let old_room_id = room.room_id.clone();
+    {
+        let mut room_aliases = state.room_aliases.lock().await;
+        for canonical_room_id in room_aliases.iter_mut()
+            .filter(|((namespace, _), canonical_room_id)| *namespace == room.namespace && **canonical_room_id == old_room_id)
+            .map(|(_, canonical_room_id)| canonical_room_id)
+        {
+            *canonical_room_id = new_room_id.clone();
+        }
+        room_aliases.insert((room.namespace.clone(), old_room_id.clone()), new_room_id.clone());
+    }
+    tokio::spawn(remove_alias_after(state.clone(), (room.namespace.clone(), old_room_id.clone()), new_room_id.clone(), RENAMED_ROOM_ALIAS_LIFETIME));
+    {
+        let mut scheduled_rooms = state.scheduled_rooms.lock().await;
+        if let Some(starts_at) = scheduled_rooms.remove(&room.key()) {
+            scheduled_rooms.insert(renamed_room.key(), starts_at);
         }
     }
 
-    let mut clients = state.clients.lock().await;
-    let index = clients
-        .iter()
-        .position(|x| Arc::ptr_eq(x, current_client))
-        .unwrap();
-    clients.remove(index);
+    let room_clients = reassign_clients_room(room_clients, room, &renamed_room).await;
+    tracing::info!(%old_room_id, %new_room_id, "Renamed room");
+    renamed_room.try_run(move |room_data| {
+        room_data.clients.extend(room_clients);
+        send_to_members(room_data.clients.iter(), &OutgoingMessage::RoomRenamed { old_room_id, new_room_id })?;
+        broadcast_room_change(room_data);
+        Ok(())
+    }).await?;
+    state.attach_room(&renamed_room);
+    Ok(true)
 }
 
-// Room existence must be checked before calling
-async fn handle_quit_room(state: &Arc<WsAppState>, current_client: &Arc<Client>, current_client_data: &mut ClientData) {
-    let room = current_client_data.room.as_ref().unwrap().clone();
-    current_client_data.room = None;
+async fn remove_alias_after(state: Arc<WsAppState>, alias_key: RoomKey, room_id: String, duration: Duration) {
+    tokio::time::sleep(duration).await;
 
-    let mut room_data = room.data.lock().await;
-    room_data.remove_client(current_client);
+    let mut room_aliases = state.room_aliases.lock().await;
+    if room_aliases.get(&alias_key) == Some(&room_id) {
+        room_aliases.remove(&alias_key);
+    }
+}
 
-    if room_data.clients.is_empty() {
-        state.rooms.lock().await.remove(&room.room_id);
-    } else {
-        broadcast_room_change(&room_data).await;
+/// Points the members' `ClientData.room` to another room. Members which have left in the meantime
+/// are dropped from the returned list.
+async fn reassign_clients_room(room_clients: Vec<RoomClient>, from_room: &Arc<Room>, into_room: &Arc<Room>) -> Vec<RoomClient> {
+    let mut reassigned_clients = Vec::with_capacity(room_clients.len());
+    for room_client in room_clients {
+        let mut client_data = room_client.client.data.lock().await;
+        if client_data.room.as_ref().is_some_and(|room| Arc::ptr_eq(room, from_room)) {
+            room_client.client.set_room(&mut client_data, Some(into_room.clone()));
+            drop(client_data);
+            reassigned_clients.push(room_client);
+        }
     }
+    reassigned_clients
 }
 
 async fn client_in_room<'a>(current_client: &'a Arc<Client>) -> Result<MutexGuard<'a, ClientData>, ()> {
@@ -323,10 +3903,307 @@ async fn client_in_room<'a>(current_client: &'a Arc<Client>) -> Result<MutexGuar
     }
 }
 
-async fn broadcast_room_change(room_data: &RoomData) {
-    let payload = serde_json::to_string(&OutgoingMessage::RoomChanged { data: RoomDataDto::from(room_data).await }).unwrap();
-    for client in room_data.clients.iter() {
-        let _ = response_with_text(&client.client, payload.clone());
+/// Room of the client if `allowed` holds for them, answers `Forbidden` otherwise. For checks
+/// followed by work which has to happen outside of the room task.
+async fn current_room_if(
+    current_client: &Arc<Client>,
+    allowed: impl FnOnce(&RoomData, &RoomClient) -> bool + Send + 'static,
+) -> Result<Option<Arc<Room>>> {
+    let room = with_current_room(current_client, move |current_client, room, room_data| {
+        let room_current_client = room_data.find_room_client(current_client).ok_or(anyhow!("Unexpected error"))?;
+        if !allowed(room_data, room_current_client) {
+            response_with_error(current_client, ErrorKind::Forbidden);
+            return Ok(None);
+        }
+        Ok(Some(room.clone()))
+    }).await?;
+    Ok(room.flatten())
+}
+
+/// Refusal of a message, answered with its error kind rather than `InternalServerError`. Returned
+/// with `?` from anywhere in a handler, so a failed check can't fall through to the change.
+#[derive(Debug)]
+pub struct Denied(pub ErrorKind);
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Denied with {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+fn require_permission(room_data: &RoomData, client: &Client, permission: RoomPermission) -> Result<()> {
+    if !room_data.has_permission(client, permission) {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    Ok(())
+}
+
+fn require_role(room_data: &RoomData, client: &Client, role: Role) -> Result<()> {
+    if !room_data.has_role(client, role) {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    Ok(())
+}
+
+/// Admins moderate members, only the owner moderates admins and nobody the owner
+fn require_outranks(room_data: &RoomData, client: &Client, target: &RoomClient) -> Result<()> {
+    let room_client = room_data.find_room_client(client).ok_or(anyhow!("Unexpected error"))?;
+    let allowed = target.client.uid != client.uid
+        && !target.is_owner()
+        && (room_client.is_owner() || (room_client.is_admin() && !target.is_admin()));
+    if !allowed {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    Ok(())
+}
+
+fn require_moderator(client: &Client) -> Result<()> {
+    if !client.is_moderator() {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    Ok(())
+}
+
+/// Kicking and banning is up to the owner and the moderators of the server, hidden moderators are
+/// out of their reach
+fn require_can_moderate(room_data: &RoomData, client: &Client, target_uid: Uuid) -> Result<()> {
+    let target_hidden = room_data.clients.iter().any(|room_client| room_client.client.uid == target_uid && room_client.hidden);
+    if !room_data.can_moderate(client) || target_uid == client.uid || target_hidden {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    Ok(())
+}
+
+/// Spectators, hidden moderators included, only watch
+fn require_not_spectator(room_data: &RoomData, client: &Client) -> Result<()> {
+    if room_client_is_spectator(room_data, client.uid) {
+        return Err(Denied(ErrorKind::Forbidden).into());
+    }
+    Ok(())
+}
+
+/// Runs `command` on the room of the client, answers `ClientNotInAnyRoom` and returns `None` when
+/// the client is not in any room
+async fn with_current_room<R: Send + 'static>(
+    current_client: &Arc<Client>,
+    command: impl FnOnce(&Arc<Client>, &Arc<Room>, &mut RoomData) -> Result<R> + Send + 'static,
+) -> Result<Option<R>> {
+    let Ok(current_client_data) = client_in_room(current_client).await else {
+        return Ok(None);
+    };
+    let room = current_client_data.room.as_ref().ok_or(anyhow!("Unexpected error"))?.clone();
+    drop(current_client_data);
+
+    let current_client = current_client.clone();
+    let command_room = room.clone();
+    room.run(move |room_data| {
+        room_data.touch();
+        command(&current_client, &command_room, room_data)
+    }).await?.map(Some)
+}
+
+/// Numbers the event and sends it to the room events the connections of members are subscribed to
+pub fn broadcast_room_event(room_data: &mut RoomData, event: impl FnOnce(u64) -> OutgoingMessage) {
+    room_data.events_seq += 1;
+    let Some(payload) = encode_json(&event(room_data.events_seq)) else {
+        return;
+    };
+    room_data.push_recent_event(room_data.events_seq, payload.clone());
+    let _ = room_data.events.send(Message::Text(payload));
+}
+
+/// Full snapshot for changes touching many members at once, such as merges and breakout rooms
+/// Sent once the debounce window of the room task ends, see `flush_pending_broadcasts`
+pub fn broadcast_room_change(room_data: &mut RoomData) {
+    room_data.room_change_pending = true;
+}
+
+fn broadcast_settings_change(room_data: &mut RoomData) {
+    room_data.settings_change_pending = true;
+}
+
+/// Sends the broadcasts queued by `broadcast_room_change` and `broadcast_settings_change`, built
+/// from the state at the time of sending. Subscribers get the whole room after either.
+pub fn flush_pending_broadcasts(room_id: &str, room_data: &mut RoomData) {
+    let room_changed = std::mem::take(&mut room_data.room_change_pending);
+    let settings_changed = std::mem::take(&mut room_data.settings_change_pending);
+    if room_changed {
+        let data = Box::new(RoomDataDto::from(room_data));
+        broadcast_room_event(room_data, |seq| OutgoingMessage::RoomChanged { seq, data });
+    }
+    if settings_changed {
+        let settings = Box::new(RoomSettingsDto::from(room_data));
+        broadcast_room_event(room_data, |seq| OutgoingMessage::RoomSettingsUpdated { seq, settings });
+    }
+
+    room_data.subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+    if (room_changed || settings_changed) && !room_data.subscribers.is_empty() {
+        let message = OutgoingMessage::SubscribedRoomChanged { room_id: room_id.to_string(), data: Box::new(RoomDataDto::from(room_data)) };
+        let Some(payload) = encode_json(&message) else {
+            return;
+        };
+        for subscriber in room_data.subscribers.iter().filter_map(Weak::upgrade) {
+            let _ = response_with_text(&subscriber, payload.clone());
+        }
+    }
+}
+
+/// Sends the current state of the member, nothing is sent if it is not in the room
+pub fn broadcast_client_change(room_data: &mut RoomData, client_uid: Uuid) {
+    let Some(room_client) = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid && !room_client.spectator) else {
+        return;
+    };
+    let client = RoomClientDto::from(room_client, room_data.total_play_time());
+    broadcast_room_event(room_data, |seq| OutgoingMessage::ClientUpdated { seq, client });
+}
+
+fn broadcast_client_joined(room_data: &mut RoomData, client_uid: Uuid) {
+    let Some(room_client) = room_data.clients.iter().find(|room_client| room_client.client.uid == client_uid) else {
+        return;
+    };
+    let client = RoomClientDto::from(room_client, room_data.total_play_time());
+    room_data.record_history(Some(client_uid), RoomHistoryEventDto::Joined { name: client.name.clone() });
+    if room_client_is_spectator(room_data, client_uid) {
+        broadcast_spectator_count(room_data);
+        return;
+    }
+    #[cfg(feature = "redis")]
+    cluster::publish(room_data, || ClusterEvent::MemberJoined { client: client.clone() });
+    broadcast_room_event(room_data, |seq| OutgoingMessage::ClientJoined { seq, client });
+}
+
+/// Removes the member and tells the others, including who took over if it was the owner. `true`
+/// when the owner left a room which closes then, see `OwnerSuccession::CloseRoom`.
+#[must_use]
+fn remove_room_member(room_data: &mut RoomData, client: &Arc<Client>) -> bool {
+    let owner_uid = |room_data: &RoomData| room_data.clients.iter().find(|room_client| room_client.is_owner()).map(|room_client| room_client.client.uid);
+    let previous_owner_uid = owner_uid(room_data);
+    let previous_members_count = room_data.clients.len();
+    let spectator = room_client_is_spectator(room_data, client.uid);
+    let hidden = room_data.find_room_client(client).is_some_and(|room_client| room_client.hidden);
+    let owner_left = room_data.remove_client(client);
+    if room_data.clients.len() == previous_members_count || hidden {
+        return false;
+    }
+
+    let client_uid = client.uid;
+    tracing::info!(%client_uid, "Member left the room");
+    room_data.record_history(Some(client_uid), RoomHistoryEventDto::Left);
+    if spectator {
+        broadcast_spectator_count(room_data);
+        return false;
+    }
+    #[cfg(feature = "redis")]
+    cluster::publish(room_data, || ClusterEvent::MemberLeft { client_uid });
+    broadcast_room_event(room_data, |seq| OutgoingMessage::ClientLeft { seq, client_uid });
+    // The member may have been the last one everybody was waiting for
+    if let Err(e) = update_ready_check(room_data) {
+        tracing::error!("Error while updating the ready check: {:?}", e);
+    }
+    if let Some(new_owner_uid) = owner_uid(room_data)
+        && Some(new_owner_uid) != previous_owner_uid
+    {
+        broadcast_client_change(room_data, new_owner_uid);
+        broadcast_room_event(room_data, |seq| OutgoingMessage::OwnershipTransferred { seq, from_uid: client_uid, to_uid: new_owner_uid, automatic: true });
+    }
+    owner_left && room_logic::closes_when_owner_left(room_data.owner_succession, room_data.clients.len())
+}
+
+/// Trimmed text of a chat message, refused when empty, too long or caught by the content filter
+fn chat_message_text(state: &WsAppState, text: String) -> Result<String, ErrorKind> {
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return Err(ErrorKind::MessageEmpty);
+    }
+    if text.chars().count() > state.config().max_chat_message_length {
+        return Err(ErrorKind::MessageTooLong);
+    }
+    filter_content(state, ContentKind::ChatMessage, text)
+}
+
+fn room_client_is_spectator(room_data: &RoomData, client_uid: Uuid) -> bool {
+    room_data.clients.iter().any(|room_client| room_client.client.uid == client_uid && room_client.spectator)
+}
+
+fn broadcast_spectator_count(room_data: &mut RoomData) {
+    let spectator_count = room_data.spectator_count();
+    broadcast_room_event(room_data, |seq| OutgoingMessage::SpectatorCountChanged { seq, spectator_count });
+}
+
+/// Subscribes the connection of the member to the room events and sends it the snapshot numbered
+/// like the latest event, both happen inside the room command so no event falls in between
+fn send_room_snapshot(room_data: &RoomData, client: &Client) {
+    client.follow_room_events(Some(room_data.events.subscribe()));
+    let seq = room_data.events_seq;
+    let data = Box::new(RoomDataDto::from(room_data));
+    if client.protocol_version() >= 2 {
+        reply_with_json(client, OutgoingMessage::RoomSnapshot { seq, server_time: unix_millis_now(), data });
+    } else {
+        reply_with_json(client, OutgoingMessage::RoomChanged { seq, data });
+    }
+}
+
+/// Sends every member able to handle it the `RoomDigest` of the room as it is now
+pub fn send_room_digest(room_data: &RoomData) -> Result<()> {
+    let mut members: Vec<(Uuid, Role, Option<String>)> = room_members(room_data)
+        .map(|member| (member.uid, member.role, member.name))
+        .collect();
+    members.sort_by_key(|(uid, _, _)| *uid);
+    let members_digest = to_hex(&Sha1::digest(serde_json::to_vec(&members)?));
+    let digest = OutgoingMessage::RoomDigest {
+        seq: room_data.events_seq,
+        server_time: unix_millis_now(),
+        position: room_data.playback.current_position(),
+        playing: room_data.playback.playing,
+        member_count: members.len(),
+        members_digest,
+    };
+    send_to_members(room_data.clients.iter(), &digest)
+}
+
+/// Messages are built as JSON everywhere, connections speaking another format convert them right
+/// before writing
+fn encode_outgoing(format: WireFormat, msg: Message) -> Message {
+    match (format, msg) {
+        (WireFormat::MessagePack, Message::Text(payload)) => match serde_json::from_str(&payload) {
+            Ok(value) => Message::Binary(msgpack::encode(&value)),
+            Err(_) => Message::Text(payload),
+        },
+        (WireFormat::MessagePack, Message::Binary(data)) => Message::Binary(msgpack::encode_binary(&data)),
+        (_, msg) => msg,
+    }
+}
+
+/// Turns MessagePack frames into what a JSON connection would have sent, text frames are always
+/// accepted as JSON
+fn decode_incoming(format: WireFormat, msg: Message) -> Result<Message> {
+    match (format, msg) {
+        (WireFormat::MessagePack, Message::Binary(data)) => Ok(match msgpack::decode(&data)? {
+            msgpack::Frame::Value(value) => Message::Text(value.to_string()),
+            msgpack::Frame::Binary(data) => Message::Binary(data),
+        }),
+        (_, msg) => Ok(msg),
+    }
+}
+
+/// Bytes encoded by `data` in standard base64 with padding, `None` when it is not base64
+fn base64_decoded_len(data: &str) -> Option<usize> {
+    let data = data.as_bytes();
+    if !data.len().is_multiple_of(4) {
+        return None;
+    }
+    let padding = data.iter().rev().take_while(|byte| **byte == b'=').count();
+    let valid = padding <= 2 && data[..data.len() - padding].iter().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'/'));
+    valid.then(|| data.len() / 4 * 3 - padding)
+}
+
+/// Waits forever while the connection is not subscribed to any room
+async fn next_room_event(room_events: &mut Option<broadcast::Receiver<Message>>) -> Result<Message, broadcast::error::RecvError> {
+    match room_events {
+        Some(room_events) => room_events.recv().await,
+        None => std::future::pending().await,
     }
 }
 
@@ -339,28 +4216,113 @@ async fn validate_client_name(current_client: &Client) -> bool {
     }
 }
 
-fn response_with_text(current_client: &Client, payload: String) -> Result<(), SendError<Message>> {
-    current_client.tx.send(Message::Text(payload))
+pub fn response_with_text(current_client: &Client, payload: String) -> Result<(), TrySendError<Message>> {
+    current_client.send(Message::Text(payload))
+}
+
+/// Messages which fail to serialize are logged and dropped, a broken message must not take the
+/// connection or room task down with it
+fn encode_json(payload: &impl Serialize) -> Option<String> {
+    serde_json::to_string(payload)
+        .map_err(|e| tracing::error!("Failed to serialize an outgoing message: {:?}", e))
+        .ok()
+}
+
+/// Dropped when the client didn't announce the capability the message needs
+pub fn response_with_json(current_client: &Client, payload: OutgoingMessage) {
+    if !current_client.supports(&payload) {
+        return;
+    }
+    if let Some(payload) = encode_json(&payload) {
+        let _ = response_with_text(current_client, payload);
+    }
+}
+
+/// Sends the message to every one of `members` who can handle it, see `ClientCapability`
+fn send_to_members<'a>(members: impl Iterator<Item = &'a RoomClient>, message: &OutgoingMessage) -> Result<()> {
+    let payload = serde_json::to_string(message)?;
+    for room_client in members.filter(|room_client| room_client.client.supports(message)) {
+        let _ = response_with_text(&room_client.client, payload.clone());
+    }
+    Ok(())
 }
 
-fn response_with_json(current_client: &Client, payload: OutgoingMessage) {
-    let _ = response_with_text(current_client, serde_json::to_string(&payload).unwrap());
+/// Answer to the message being handled, tagged with its `id`
+fn reply_with_json(current_client: &Client, payload: OutgoingMessage) {
+    let envelope = OutgoingEnvelope { id: current_client.request_id(), message: &payload };
+    if let Some(payload) = encode_json(&envelope) {
+        let _ = response_with_text(current_client, payload);
+    }
 }
 
 fn response_with_success(current_client: &Client) {
-    response_with_json(current_client, OutgoingMessage::Success)
+    reply_with_json(current_client, OutgoingMessage::Success)
 }
 
 fn response_with_error(current_client: &Client, error_kind: ErrorKind) {
-    response_with_json(current_client, OutgoingMessage::Error {
+    tracing::debug!(client_uid = %current_client.uid, kind = ?error_kind, "Answering with an error");
+    let msg = error_message(current_client.locale(), &error_kind).to_string();
+    reply_with_json(current_client, OutgoingMessage::Error {
+        kind: error_kind,
+        msg: Some(msg),
+        retry_after: None,
+        field: None,
+        params: None,
+    })
+}
+
+fn response_with_error_params(current_client: &Client, error_kind: ErrorKind, params: ErrorParams) {
+    tracing::debug!(client_uid = %current_client.uid, kind = ?error_kind, ?params, "Answering with an error");
+    let msg = error_message(current_client.locale(), &error_kind).to_string();
+    reply_with_json(current_client, OutgoingMessage::Error {
         kind: error_kind,
-        msg: None
+        msg: Some(msg),
+        retry_after: None,
+        field: None,
+        params: Some(params),
     })
 }
 
-fn response_with_error_msg(current_client: &Client, error_kind: ErrorKind, msg: String) {
-    response_with_json(current_client, OutgoingMessage::Error {
+/// Answers with an error of `validate_name`, `validate_room_id` or `chat_message_text`, along with
+/// the configured limits for the ones about lengths
+fn response_with_validation_error(current_client: &Client, config: &ServerConfig, error_kind: ErrorKind) {
+    let params = match error_kind {
+        ErrorKind::ClientNameTooShort | ErrorKind::ClientNameTooLong => ErrorParams::range(config.min_name_length, config.max_name_length),
+        ErrorKind::RoomIdTooShort | ErrorKind::RoomIdTooLong => ErrorParams::range(config.min_room_id_length, config.max_room_id_length),
+        ErrorKind::MessageTooLong => ErrorParams::max(config.max_chat_message_length),
+        _ => return response_with_error(current_client, error_kind),
+    };
+    response_with_error_params(current_client, error_kind, params);
+}
+
+fn response_with_json_error(current_client: &Client, msg: String, field: Option<String>) {
+    tracing::debug!(client_uid = %current_client.uid, msg = %msg, ?field, "Answering with a JSON error");
+    reply_with_json(current_client, OutgoingMessage::Error {
+        kind: ErrorKind::JsonError,
+        msg: Some(msg),
+        retry_after: None,
+        field,
+        params: None,
+    })
+}
+
+fn response_with_error_retry_after(current_client: &Client, error_kind: ErrorKind, retry_after: Duration) {
+    tracing::debug!(client_uid = %current_client.uid, kind = ?error_kind, ?retry_after, "Answering with an error");
+    let msg = error_message(current_client.locale(), &error_kind).to_string();
+    reply_with_json(current_client, OutgoingMessage::Error {
         kind: error_kind,
-        msg: Some(msg)
+        msg: Some(msg),
+        retry_after: Some(retry_after.as_millis() as u64),
+        field: None,
+        params: None,
     })
+}
+
+/// Tells every client when to reconnect and closes the connections, used on shutdown
+pub async fn disconnect_all_clients(state: &WsAppState) {
+    for client in state.clients.snapshot() {
+        let retry_after = state.reconnect_retry_after();
+        response_with_json(&client, OutgoingMessage::ServerShuttingDown { retry_after: retry_after.as_millis() as u64 });
+        client.disconnect(DisconnectReason::ServerShuttingDown, "Server shutting down", Some(retry_after));
+    }
 }
\ No newline at end of file
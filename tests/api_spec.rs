@@ -0,0 +1,99 @@
+mod common;
+
+use common::{http_get, TestServer};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+async fn get_json(server: &TestServer, path: &str) -> Value {
+    let (status, body) = http_get(server, path).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_str(&body).expect("Invalid JSON")
+}
+
+/// `$ref`s of the document which aren't in its `components.schemas`
+fn unresolved_refs(document: &Value) -> Vec<String> {
+    fn collect(value: &Value, refs: &mut Vec<String>) {
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::String(reference)) = object.get("$ref") {
+                    refs.push(reference.clone());
+                }
+                object.values().for_each(|value| collect(value, refs));
+            }
+            Value::Array(values) => values.iter().for_each(|value| collect(value, refs)),
+            _ => {}
+        }
+    }
+
+    let mut refs = Vec::new();
+    collect(document, &mut refs);
+    refs.into_iter()
+        .filter(|reference| {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap_or(reference);
+            document["components"]["schemas"].get(name).is_none()
+        })
+        .collect()
+}
+
+/// Member of a `oneOf` union of tagged messages
+fn message_schema<'a>(union: &'a Value, message_type: &str) -> &'a Value {
+    union["oneOf"].as_array().into_iter().flatten()
+        .find(|member| member["properties"]["type"]["const"] == message_type)
+        .expect("No such message type")
+}
+
+#[tokio::test]
+async fn spec_index_links_the_documents() {
+    let server = TestServer::start().await;
+    let index = get_json(&server, "/api/spec").await;
+    assert_eq!(index, json!({ "asyncapi": "/api/spec/asyncapi.json", "openapi": "/api/spec/openapi.json" }));
+}
+
+#[tokio::test]
+async fn asyncapi_describes_the_messages() {
+    let server = TestServer::start().await;
+    let asyncapi = get_json(&server, "/api/spec/asyncapi.json").await;
+    assert_eq!(asyncapi["asyncapi"], "2.6.0");
+    assert_eq!(unresolved_refs(&asyncapi), Vec::<String>::new());
+    let channel = &asyncapi["channels"]["/ws"];
+    assert_eq!(channel["publish"]["message"]["payload"]["allOf"][0]["$ref"], "#/components/schemas/IncomingMessage");
+    assert_eq!(channel["subscribe"]["message"]["payload"]["allOf"][0]["$ref"], "#/components/schemas/OutgoingMessage");
+
+    let schemas = &asyncapi["components"]["schemas"];
+    let join_room = message_schema(&schemas["IncomingMessage"], "joinRoom");
+    assert_eq!(join_room["properties"]["roomId"], json!({ "type": "string" }));
+    assert_eq!(join_room["required"], json!(["type", "roomId", "spectator", "hidden"]));
+    let error = message_schema(&schemas["OutgoingMessage"], "error");
+    assert_eq!(error["properties"]["kind"]["$ref"], "#/components/schemas/ErrorKind");
+
+    let error_kinds = schemas["ErrorKind"]["oneOf"].as_array().expect("ErrorKind is not a union");
+    assert!(error_kinds[0]["enum"].as_array().is_some_and(|kinds| kinds.contains(&json!("roomLocked"))));
+    assert!(error_kinds.iter().any(|kind| kind["properties"]["roomNotStarted"]["properties"]["startsAt"] == json!({ "type": "integer" })));
+    assert!(schemas["ControlMode"]["description"].as_str().is_some_and(|description| description.starts_with("Who may play")));
+}
+
+#[tokio::test]
+async fn openapi_describes_the_api_routes() {
+    let server = TestServer::start().await;
+    let openapi = get_json(&server, "/api/spec/openapi.json").await;
+    assert_eq!(openapi["openapi"], "3.1.0");
+    assert_eq!(unresolved_refs(&openapi), Vec::<String>::new());
+
+    let paths = openapi["paths"].as_object().expect("No paths");
+    assert!(paths.keys().all(|path| path.starts_with("/api/")));
+    let get_room = &paths["/api/rooms/{room_id}"]["get"];
+    assert_eq!(get_room["security"], json!([{ "adminToken": [] }]));
+    assert_eq!(get_room["parameters"], json!([
+        { "name": "room_id", "in": "path", "required": true, "schema": { "type": "string" } },
+        { "name": "namespace", "in": "query", "required": false, "schema": { "type": "string" } },
+    ]));
+    assert_eq!(get_room["responses"]["200"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/AdminRoomDetailsDto");
+    assert!(get_room["responses"]["404"].is_object());
+    assert!(paths["/api/rooms/{room_id}"]["delete"]["responses"]["204"].is_object());
+
+    let send_notice = &paths["/api/notices"]["post"];
+    assert_eq!(send_notice["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ServerNoticeRequest");
+    let list_rooms = &paths["/api/public-rooms"]["get"];
+    assert!(list_rooms.get("security").is_none());
+    assert_eq!(list_rooms["responses"]["200"]["content"]["application/json"]["schema"]["items"]["$ref"], "#/components/schemas/PublicRoomDto");
+}
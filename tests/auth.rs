@@ -0,0 +1,94 @@
+mod common;
+
+use common::{handshake, handshake_with_headers, TestClient, TestServer};
+use jwt_simple::prelude::{Claims, Duration, HS256Key, MACLike};
+use sent_sync_server::ServerConfig;
+use sent_sync_server::protocol::{ErrorKind, IncomingMessage, OutgoingMessage};
+use tokio_tungstenite::tungstenite::Error;
+
+fn private_config() -> ServerConfig {
+    ServerConfig {
+        auth_api_keys: vec!["party-key".to_string()],
+        auth_jwt_secret: Some("a-long-enough-jwt-secret".to_string()),
+        ..ServerConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn connections_without_a_valid_token_are_refused() {
+    let server = TestServer::start_with(private_config()).await;
+
+    for query in ["", "token=wrong"] {
+        match handshake(&server, query).await {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+            Err(e) => panic!("Expected 401, got {}", e),
+            Ok(_) => panic!("Connection with {:?} was accepted", query),
+        }
+    }
+}
+
+#[tokio::test]
+async fn api_keys_and_jwts_are_accepted() {
+    let server = TestServer::start_with(private_config()).await;
+    TestClient::connect_with_query(&server, "token=party-key").await;
+
+    let claims = Claims::create(Duration::from_mins(5)).with_subject("user-1");
+    let jwt = HS256Key::from_bytes(b"a-long-enough-jwt-secret").authenticate(claims).expect("Failed to sign the JWT");
+    TestClient::connect_with_query(&server, &format!("token={}", jwt)).await;
+}
+
+#[tokio::test]
+async fn admin_token_makes_a_hidden_moderator() {
+    let server = TestServer::start_with(ServerConfig { admin_token: Some("admin-secret".to_string()), ..ServerConfig::default() }).await;
+    let mut owner = TestClient::join(&server, "owner", "watched").await;
+    let mut member = TestClient::join(&server, "member", "watched").await;
+    owner.expect(|msg| matches!(msg, OutgoingMessage::ClientJoined { .. }).then_some(())).await;
+
+    let mut moderator = TestClient::connect(&server).await;
+    moderator.send(IncomingMessage::ChangeName { new_name: "moderator".to_string() }).await;
+    moderator.expect_success().await;
+    moderator.send(IncomingMessage::Authenticate { token: "wrong".to_string() }).await;
+    let error = moderator.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::AuthenticationFailed));
+    moderator.send(IncomingMessage::Authenticate { token: "admin-secret".to_string() }).await;
+    moderator.expect_success().await;
+
+    moderator.send(IncomingMessage::JoinRoom { room_id: "watched".to_string(), invite: None, spectator: false, hidden: true }).await;
+    moderator.expect_success().await;
+    moderator.send(IncomingMessage::KickClient { client_uid: member.uid }).await;
+    moderator.expect_success().await;
+    member.expect(|msg| matches!(msg, OutgoingMessage::Kicked { .. }).then_some(())).await;
+    owner.expect(|msg| match msg {
+        OutgoingMessage::ClientLeft { .. } => Some(()),
+        OutgoingMessage::ClientJoined { .. } | OutgoingMessage::SpectatorCountChanged { .. } => panic!("Hidden moderator announced"),
+        _ => None,
+    }).await;
+
+    moderator.send(IncomingMessage::CloseRoom { room_id: "watched".to_string() }).await;
+    owner.expect(|msg| matches!(msg, OutgoingMessage::RoomClosed { .. }).then_some(())).await;
+    moderator.expect_success().await;
+}
+
+#[tokio::test]
+async fn only_allowed_origins_may_connect() {
+    let server = TestServer::start_with(ServerConfig {
+        allowed_origins: vec!["chrome-extension://abcdef".to_string(), "https://*.example.com".to_string()],
+        ..ServerConfig::default()
+    }).await;
+
+    for origin in ["chrome-extension://abcdef", "https://watch.example.com"] {
+        handshake_with_headers(&server, "", &[("Origin", origin)]).await.unwrap_or_else(|e| panic!("{} was refused: {}", origin, e));
+    }
+    for origin in ["https://evil.com", "https://example.com.evil.com"] {
+        match handshake_with_headers(&server, "", &[("Origin", origin)]).await {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 403),
+            Err(e) => panic!("Expected 403, got {}", e),
+            Ok(_) => panic!("Connection from {} was accepted", origin),
+        }
+    }
+    // Native clients send no origin
+    handshake(&server, "").await.expect("Connection without an origin was refused");
+}
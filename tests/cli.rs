@@ -0,0 +1,45 @@
+use rocket::config::LogLevel;
+use rocket::figment::providers::{Format, Toml};
+use rocket::figment::Figment;
+use sent_sync_server::cli::{default_config_toml, CliArgs};
+use sent_sync_server::ServerConfig;
+
+fn parse(args: &[&str]) -> anyhow::Result<CliArgs> {
+    CliArgs::parse(args.iter().map(|arg| arg.to_string()))
+}
+
+#[test]
+fn options_override_the_config_file() {
+    let config_path = std::env::temp_dir().join(format!("sent-sync-cli-{}.toml", std::process::id()));
+    std::fs::write(&config_path, "[default]\nport = 9000\nmax_rooms = 7\n").expect("Failed to write config");
+
+    let args = parse(&["--config", config_path.to_str().expect("Non UTF-8 temp dir"), "--address=0.0.0.0", "--log-level", "off"])
+        .expect("Failed to parse");
+    let figment = args.figment().expect("Failed to read config");
+    let rocket_config = rocket::Config::from(&figment);
+    assert_eq!(rocket_config.port, 9000);
+    assert_eq!(rocket_config.address.to_string(), "0.0.0.0");
+    assert_eq!(rocket_config.log_level, LogLevel::Off);
+    assert_eq!(ServerConfig::load(&figment).expect("Invalid config").max_rooms, 7);
+
+    let figment = parse(&["--config", config_path.to_str().expect("Non UTF-8 temp dir"), "--port", "9100"])
+        .expect("Failed to parse").figment().expect("Failed to read config");
+    assert_eq!(rocket::Config::from(&figment).port, 9100);
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[test]
+fn invalid_options_are_rejected() {
+    assert!(parse(&["--port", "not-a-port"]).is_err());
+    assert!(parse(&["--port"]).is_err());
+    assert!(parse(&["--log-level", "loud"]).is_err());
+    assert!(parse(&["--unknown"]).is_err());
+    assert!(parse(&["--config", "/nonexistent/Rocket.toml"]).expect("Failed to parse").figment().is_err());
+}
+
+#[test]
+fn default_config_reads_back() {
+    let toml = default_config_toml().expect("Failed to print the default config");
+    let config = ServerConfig::load(&Figment::from(Toml::string(&toml).nested())).expect("Invalid default config");
+    assert_eq!(config.max_rooms, ServerConfig::default().max_rooms);
+}
@@ -0,0 +1,23 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use common::{TestClient, TestServer};
+use sent_sync_server::client::SyncClient;
+use sent_sync_server::protocol::{IncomingMessage, OutgoingMessage};
+
+#[tokio::test]
+async fn sync_client_joins_rooms() {
+    let server = TestServer::start().await;
+    let owner = TestClient::join(&server, "owner", "sdk-room").await;
+
+    let mut client = SyncClient::connect(&format!("ws://127.0.0.1:{}/ws", server.port), "sdk-tests", None).await
+        .expect("Failed to connect");
+    assert_ne!(client.uid, owner.uid);
+    client.send(&IncomingMessage::ChangeName { new_name: "bot".to_string() }).await.expect("Failed to send");
+    assert!(matches!(client.recv().await.expect("No answer"), OutgoingMessage::Success));
+    client.send(&IncomingMessage::JoinRoom { room_id: "sdk-room".to_string(), invite: None, spectator: false, hidden: false }).await
+        .expect("Failed to send");
+    assert!(matches!(client.recv().await.expect("No answer"), OutgoingMessage::Success));
+    client.close().await.expect("Failed to close");
+}
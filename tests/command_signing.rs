@@ -0,0 +1,78 @@
+mod common;
+
+use common::{TestClient, TestServer};
+use sent_sync_server::command_signing::{from_hex, hmac_sha256, page_url_change_message, to_hex, verify_page_url_change_signature, verify_signature};
+use sent_sync_server::protocol::{ErrorKind, IncomingMessage, OutgoingMessage};
+
+const LARGE_KEY: [u8; 131] = [0xaa; 131];
+
+#[test]
+fn hmac_sha256_matches_rfc_4231() {
+    let cases: [(&[u8], &[u8], &str); 6] = [
+        (&[0x0b; 20], b"Hi There", "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+        (b"Jefe", b"what do ya want for nothing?", "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+        (&[0xaa; 20], &[0xdd; 50], "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+        (
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25],
+            &[0xcd; 50],
+            "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+        ),
+        (&LARGE_KEY, b"Test Using Larger Than Block-Size Key - Hash Key First", "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+        (
+            &LARGE_KEY,
+            b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.",
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+        ),
+    ];
+
+    for (key, data, expected) in cases {
+        assert_eq!(to_hex(&hmac_sha256(key, data)), expected);
+    }
+}
+
+#[test]
+fn signatures_are_checked_in_any_hex_case() {
+    let signature = to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?"));
+    assert!(verify_signature(b"Jefe", "what do ya want for nothing?", &signature));
+    assert!(verify_signature(b"Jefe", "what do ya want for nothing?", &signature.to_ascii_uppercase()));
+    assert!(!verify_signature(b"Jefe", "what do ya want for something?", &signature));
+    assert!(!verify_signature(b"Jefe", "what do ya want for nothing?", &signature[..62]));
+    assert!(!verify_signature(b"Jefe", "what do ya want for nothing?", "not hex"));
+}
+
+#[test]
+fn reused_and_lower_nonces_are_rejected() {
+    let secret = [7u8; 32];
+    let sign = |nonce: u64| to_hex(&hmac_sha256(&secret, page_url_change_message("room", nonce, "https://example.com/").as_bytes()));
+
+    assert!(verify_page_url_change_signature(&secret, 5, "room", 6, "https://example.com/", &sign(6)));
+    assert!(!verify_page_url_change_signature(&secret, 5, "room", 5, "https://example.com/", &sign(5)));
+    assert!(!verify_page_url_change_signature(&secret, 5, "room", 4, "https://example.com/", &sign(4)));
+    assert!(!verify_page_url_change_signature(&secret, 5, "other", 6, "https://example.com/", &sign(6)));
+    assert!(!verify_page_url_change_signature(&secret, 5, "room", 6, "https://example.com/other", &sign(6)));
+}
+
+#[tokio::test]
+async fn replayed_page_url_change_is_refused() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "replayed").await;
+    owner.send(IncomingMessage::SetCommandSigning { required: true }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::RequestSigningSecret).await;
+    let secret = owner.expect(|msg| match msg {
+        OutgoingMessage::SigningSecret { secret } => from_hex(&secret),
+        _ => None,
+    }).await;
+
+    let url = "https://example.com/video";
+    let signature = to_hex(&hmac_sha256(&secret, page_url_change_message("replayed", 1, url).as_bytes()));
+    let change = || IncomingMessage::SetPageUrl { url: url.to_string(), nonce: Some(1), signature: Some(signature.clone()) };
+    owner.send(change()).await;
+    owner.expect_success().await;
+    owner.send(change()).await;
+    let error = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::InvalidSignature));
+}
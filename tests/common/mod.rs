@@ -0,0 +1,271 @@
+//! Server started in-process on a free port and a client speaking the JSON protocol to it
+
+// Every test file compiles its own copy and uses only part of it
+#![allow(dead_code)]
+
+use std::sync::Mutex;
+use std::time::Duration;
+use rocket::config::{LogLevel, Shutdown as ShutdownConfig};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::{Build, Config, Rocket, Shutdown};
+use sent_sync_server::{build_reloadable_rocket, build_rocket, ConfigLoader};
+use sent_sync_server::protocol::{ClientCapability, IncomingMessage, OutgoingMessage};
+use sent_sync_server::ServerConfig;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+/// Messages not arriving within this long fail the test
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+    pub port: u16,
+    shutdown: Shutdown,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        TestServer::start_with(ServerConfig::default()).await
+    }
+
+    pub async fn start_with(config: ServerConfig) -> Self {
+        TestServer::launch(|figment| build_rocket(figment, config)).await
+    }
+
+    /// Started with `build_reloadable_rocket`, settings are read again from `config_loader`
+    pub async fn start_reloadable(config: ServerConfig, config_loader: ConfigLoader) -> Self {
+        TestServer::launch(|figment| build_reloadable_rocket(figment, config, config_loader)).await
+    }
+
+    async fn launch(build: impl FnOnce(Figment) -> Rocket<Build>) -> Self {
+        let figment = Figment::from(Config {
+            port: 0,
+            log_level: LogLevel::Off,
+            shutdown: ShutdownConfig { ctrlc: false, ..ShutdownConfig::default() },
+            ..Config::debug_default()
+        });
+
+        let (port_tx, port_rx) = oneshot::channel();
+        let port_tx = Mutex::new(Some(port_tx));
+        let rocket = build(figment)
+            .attach(AdHoc::on_liftoff("Report port", move |rocket| Box::pin(async move {
+                if let Some(port_tx) = port_tx.lock().unwrap().take() {
+                    let _ = port_tx.send(rocket.config().port);
+                }
+            })))
+            .ignite().await
+            .expect("Server failed to ignite");
+        let shutdown = rocket.shutdown();
+        tokio::spawn(rocket.launch());
+
+        let port = port_rx.await.expect("Server failed to launch");
+        TestServer { port, shutdown }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.clone().notify();
+    }
+}
+
+/// Opens the WebSocket without saying hello, for checking whether the server accepts it at all
+pub async fn handshake(server: &TestServer, query: &str) -> Result<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Error> {
+    handshake_with_headers(server, query, &[]).await
+}
+
+/// Like `handshake`, with extra headers like `Origin` in the request
+pub async fn handshake_with_headers(server: &TestServer, query: &str, headers: &[(&'static str, &str)]) -> Result<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Error> {
+    let tcp_stream = TcpStream::connect(("127.0.0.1", server.port)).await.expect("Failed to connect");
+    let mut request = format!("ws://127.0.0.1:{}/ws?{}", server.port, query).into_client_request()?;
+    for (name, value) in headers {
+        request.headers_mut().insert(*name, value.parse().expect("Invalid header value"));
+    }
+    let (stream, _) = tokio_tungstenite::client_async(request, tcp_stream).await?;
+    Ok(stream)
+}
+
+/// HTTP request to the admin API with `admin_token`, answered with the status and the JSON body,
+/// `null` when there is none
+pub async fn admin_request(server: &TestServer, method: Method, path: &str, admin_token: &str) -> (StatusCode, serde_json::Value) {
+    admin_request_with_body(server, method, path, admin_token, Body::empty()).await
+}
+
+/// `admin_request` sending `body` as JSON
+pub async fn admin_request_with_json(server: &TestServer, method: Method, path: &str, admin_token: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+    admin_request_with_body(server, method, path, admin_token, Body::from(body.to_string())).await
+}
+
+async fn admin_request_with_body(server: &TestServer, method: Method, path: &str, admin_token: &str, body: Body) -> (StatusCode, serde_json::Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://127.0.0.1:{}{}", server.port, path))
+        .header("Authorization", format!("Bearer {}", admin_token))
+        .header("Content-Type", "application/json")
+        .body(body)
+        .expect("Invalid request");
+    let response = Client::new().request(request).await.expect("Request failed");
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read the body");
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+/// Plain HTTP `GET`, answered with the status and the body as text
+pub async fn http_get(server: &TestServer, path: &str) -> (StatusCode, String) {
+    let uri = format!("http://127.0.0.1:{}{}", server.port, path).parse().expect("Invalid URI");
+    let response = Client::new().get(uri).await.expect("Request failed");
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read the body");
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Plain HTTP `GET` that doesn't follow redirects, answered with the status and the `Location` header
+pub async fn http_get_location(server: &TestServer, path: &str) -> (StatusCode, Option<String>) {
+    let uri = format!("http://127.0.0.1:{}{}", server.port, path).parse().expect("Invalid URI");
+    let response = Client::new().get(uri).await.expect("Request failed");
+    let location = response.headers().get("Location").and_then(|location| location.to_str().ok()).map(str::to_string);
+    (response.status(), location)
+}
+
+pub struct TestClient {
+    pub uid: Uuid,
+    pub resume_token: String,
+    stream: WebSocketStream<TcpStream>,
+}
+
+impl TestClient {
+    /// Connects and says hello, the client is ready to join rooms once this returns
+    pub async fn connect(server: &TestServer) -> Self {
+        TestClient::connect_with_query(server, "").await
+    }
+
+    /// Like `connect`, with `query` like `token=...` appended to the URL
+    pub async fn connect_with_query(server: &TestServer, query: &str) -> Self {
+        TestClient::connect_with(server, query, None, None, 1, None).await
+    }
+
+    /// Like `connect`, announcing only `capabilities` in `Hello`
+    pub async fn connect_with_capabilities(server: &TestServer, capabilities: Vec<ClientCapability>) -> Self {
+        TestClient::connect_with(server, "", None, Some(capabilities), 1, None).await
+    }
+
+    /// Like `connect`, naming `locale` in `Hello`
+    pub async fn connect_with_locale(server: &TestServer, locale: &str) -> Self {
+        TestClient::connect_with(server, "", None, None, 1, Some(locale)).await
+    }
+
+    /// Like `connect`, speaking `protocol_version`
+    pub async fn connect_with_protocol_version(server: &TestServer, protocol_version: u32) -> Self {
+        TestClient::connect_with(server, "", None, None, protocol_version, None).await
+    }
+
+    async fn connect_with(server: &TestServer, query: &str, namespace: Option<&str>, capabilities: Option<Vec<ClientCapability>>, protocol_version: u32, locale: Option<&str>) -> Self {
+        let stream = handshake(server, query).await.expect("WebSocket handshake failed");
+
+        let mut client = TestClient { uid: Uuid::nil(), resume_token: String::new(), stream };
+        let (uid, resume_token) = client.expect(|msg| match msg {
+            OutgoingMessage::ClientUid { client_uid, resume_token } => Some((client_uid, resume_token)),
+            _ => None,
+        }).await;
+        client.uid = uid;
+        client.resume_token = resume_token;
+
+        client.send(IncomingMessage::Hello {
+            protocol_version,
+            client_name: "integration-tests".to_string(),
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            namespace: namespace.map(str::to_string),
+            capabilities,
+            locale: locale.map(str::to_string),
+        }).await;
+        client.expect(|msg| matches!(msg, OutgoingMessage::Welcome { .. }).then_some(())).await;
+        client
+    }
+
+    /// Connects, picks a name and joins the room, opening it if it doesn't exist yet
+    pub async fn join(server: &TestServer, name: &str, room_id: &str) -> Self {
+        TestClient::join_namespace(server, None, name, room_id).await
+    }
+
+    /// `join` saying hello with the namespace
+    pub async fn join_namespace(server: &TestServer, namespace: Option<&str>, name: &str, room_id: &str) -> Self {
+        let mut client = TestClient::connect_with(server, "", namespace, None, 1, None).await;
+        client.send(IncomingMessage::ChangeName { new_name: name.to_string() }).await;
+        client.expect_success().await;
+        client.send(IncomingMessage::JoinRoom { room_id: room_id.to_string(), invite: None, spectator: false, hidden: false }).await;
+        client.expect_success().await;
+        client
+    }
+
+    pub async fn send(&mut self, msg: IncomingMessage) {
+        self.send_text(serde_json::to_string(&msg).expect("Failed to serialize message")).await;
+    }
+
+    /// For payloads the message types can't express
+    pub async fn send_text(&mut self, payload: String) {
+        self.stream.send(Message::Text(payload)).await.expect("Failed to send message");
+    }
+
+    /// Text frame with `payload` as is, which doesn't have to be valid UTF-8
+    pub async fn send_text_bytes(&mut self, payload: Vec<u8>) {
+        let frame = Frame::message(payload, OpCode::Data(Data::Text), true);
+        self.stream.send(Message::Frame(frame)).await.expect("Failed to send message");
+    }
+
+    /// Close frame the server ends the connection with, the messages before it are skipped
+    pub async fn expect_close(&mut self) -> Option<CloseFrame<'static>> {
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.stream.next()).await
+                .expect("Timed out waiting for the connection to close")
+                .expect("Connection closed without a close frame")
+                .expect("Failed to read message");
+            if let Message::Close(close_frame) = frame {
+                return close_frame;
+            }
+        }
+    }
+
+    /// Next message of the server, pings and other control frames are skipped
+    pub async fn recv(&mut self) -> OutgoingMessage {
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.stream.next()).await
+                .expect("Timed out waiting for a message")
+                .expect("Connection closed")
+                .expect("Failed to read message");
+            if let Message::Text(payload) = frame {
+                return serde_json::from_str(&payload).unwrap_or_else(|e| panic!("Unexpected message {}: {}", payload, e));
+            }
+        }
+    }
+
+    /// Skips messages until `select` picks one
+    pub async fn expect<T>(&mut self, mut select: impl FnMut(OutgoingMessage) -> Option<T>) -> T {
+        loop {
+            if let Some(value) = select(self.recv().await) {
+                return value;
+            }
+        }
+    }
+
+    /// Skips messages until `Success`, fails on an error
+    pub async fn expect_success(&mut self) {
+        self.expect(|msg| match msg {
+            OutgoingMessage::Success => Some(()),
+            OutgoingMessage::Error { kind, .. } => panic!("Expected success, got {:?}", kind),
+            _ => None,
+        }).await
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.stream.close(None).await;
+    }
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use common::{admin_request, TestClient, TestServer};
+use hyper::{Method, StatusCode};
+use sent_sync_server::protocol::{ErrorKind, IncomingMessage, OutgoingMessage};
+use sent_sync_server::{ConfigLoader, ServerConfig};
+
+#[tokio::test]
+async fn reloaded_limits_apply_to_open_connections() {
+    let config = ServerConfig { admin_token: Some("admin-token".to_string()), max_clients_per_room: 1, ..ServerConfig::default() };
+    let config_file = Arc::new(Mutex::new(config.clone()));
+    let loader_file = config_file.clone();
+    let server = TestServer::start_reloadable(config, ConfigLoader::new(move || Ok(loader_file.lock().unwrap().clone()))).await;
+
+    let _owner = TestClient::join(&server, "owner", "reloaded").await;
+    let mut guest = TestClient::connect(&server).await;
+    guest.send(IncomingMessage::ChangeName { new_name: "guest".to_string() }).await;
+    guest.expect_success().await;
+    let join = || IncomingMessage::JoinRoom { room_id: "reloaded".to_string(), invite: None, spectator: false, hidden: false };
+    guest.send(join()).await;
+    let kind = guest.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::RoomFull));
+
+    {
+        let mut config_file = config_file.lock().unwrap();
+        config_file.max_clients_per_room = 2;
+        config_file.lobby_enabled = true;
+    }
+    let (status, changes) = admin_request(&server, Method::POST, "/api/config/reload", "admin-token").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(changes["applied"], serde_json::json!(["max_clients_per_room: 1 -> 2"]));
+    assert_eq!(changes["needsRestart"], serde_json::json!(["lobby_enabled: false -> true"]));
+
+    guest.send(join()).await;
+    guest.expect_success().await;
+}
@@ -0,0 +1,422 @@
+mod common;
+
+use common::{handshake, http_get, TestClient, TestServer};
+use sent_sync_server::ws_app_state::DisconnectReason;
+use sent_sync_server::protocol::{ClientCapability, ErrorKind, ErrorParams, IncomingMessage, OutgoingMessage};
+use sent_sync_server::localization::{error_message, Locale};
+use sent_sync_server::ws_dto_models::PollKind;
+use sent_sync_server::content_filter::ContentFilterAction;
+use sent_sync_server::ServerConfig;
+use std::time::Duration;
+use rocket::futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_tungstenite::tungstenite::Message;
+
+async fn expect_json_error(client: &mut TestClient) -> Option<String> {
+    client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind: ErrorKind::JsonError, field, .. } => Some(field),
+        OutgoingMessage::Error { kind, .. } => panic!("Expected a JSON error, got {:?}", kind),
+        _ => None,
+    }).await
+}
+
+#[tokio::test]
+async fn missing_field_is_named() {
+    let server = TestServer::start().await;
+    let mut client = TestClient::connect(&server).await;
+
+    client.send_text(r#"{"type":"changeName","name":"typo"}"#.to_string()).await;
+    assert_eq!(expect_json_error(&mut client).await.as_deref(), Some("newName"));
+}
+
+#[tokio::test]
+async fn unknown_fields_are_ignored_by_default() {
+    let server = TestServer::start().await;
+    let mut client = TestClient::connect(&server).await;
+
+    client.send_text(r#"{"type":"changeName","newName":"member","color":"red"}"#.to_string()).await;
+    client.expect_success().await;
+}
+
+#[tokio::test]
+async fn unknown_fields_are_rejected_in_strict_mode() {
+    let server = TestServer::start_with(ServerConfig { strict_messages: true, ..ServerConfig::default() }).await;
+    let mut client = TestClient::connect(&server).await;
+
+    client.send_text(r#"{"id":1,"type":"changeName","newName":"member","color":"red"}"#.to_string()).await;
+    assert_eq!(expect_json_error(&mut client).await.as_deref(), Some("color"));
+
+    client.send_text(r#"{"type":"updateRoomSettings","settings":{"alowStopDueToVideoLoading":true}}"#.to_string()).await;
+    assert_eq!(expect_json_error(&mut client).await.as_deref(), Some("settings.alowStopDueToVideoLoading"));
+
+    client.send_text(r#"{"id":2,"type":"changeName","newName":"member"}"#.to_string()).await;
+    client.expect_success().await;
+}
+
+#[tokio::test]
+async fn flooding_clients_are_told_why_they_are_disconnected() {
+    let server = TestServer::start_with(ServerConfig {
+        client_messages_per_second: 0.1,
+        client_messages_burst: 5.0,
+        client_rate_limit_max_violations: 1,
+        ..ServerConfig::default()
+    }).await;
+    let mut client = TestClient::connect(&server).await;
+
+    for _ in 0..10 {
+        client.send(IncomingMessage::Ping { client_time: None, rtt_ms: None }).await;
+    }
+    let (reason, retry_after) = client.expect(|msg| match msg {
+        OutgoingMessage::Disconnecting { reason, retry_after } => Some((reason, retry_after)),
+        _ => None,
+    }).await;
+    assert_eq!(reason, DisconnectReason::RateLimited);
+    let retry_after = retry_after.expect("Disconnecting without a reconnect hint");
+    assert!((5_000..=30_000).contains(&retry_after));
+    let close_frame = client.expect_close().await.expect("Close frame without a code");
+    assert_eq!(close_frame.reason, format!("Rate limit exceeded, retry_after={}", retry_after));
+}
+
+#[test]
+fn typescript_definitions_cover_the_protocol() {
+    let out_dir = std::env::temp_dir().join(format!("sent-sync-types-{}", std::process::id()));
+    sent_sync_server::protocol::export_typescript(&out_dir).expect("Failed to export types");
+
+    let incoming = std::fs::read_to_string(out_dir.join("IncomingMessage.ts")).expect("IncomingMessage.ts missing");
+    assert!(incoming.contains("\"type\": \"joinRoom\""));
+    assert!(incoming.contains("import type { PlayerEvent }"));
+    for file in ["OutgoingMessage.ts", "ErrorKind.ts", "RoomDataDto.ts", "PublicRoomDto.ts", "AdminRoomDto.ts", "PushSubscribeRequest.ts"] {
+        assert!(out_dir.join(file).exists(), "{} missing", file);
+    }
+    let _ = std::fs::remove_dir_all(&out_dir);
+}
+
+#[tokio::test]
+async fn clients_only_get_messages_they_announced() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "capable").await;
+    let mut limited = TestClient::connect_with_capabilities(&server, vec![ClientCapability::Polls]).await;
+    limited.send(IncomingMessage::ChangeName { new_name: "limited".to_string() }).await;
+    limited.expect_success().await;
+    limited.send(IncomingMessage::JoinRoom { room_id: "capable".to_string(), invite: None, spectator: false, hidden: false }).await;
+    limited.expect_success().await;
+
+    let capabilities = owner.expect(|msg| match msg {
+        OutgoingMessage::ClientJoined { client, .. } => Some(client.capabilities),
+        _ => None,
+    }).await;
+    assert!(matches!(capabilities.as_deref(), Some([ClientCapability::Polls])));
+
+    owner.send(IncomingMessage::SendReaction { emoji: "🎉".to_string() }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::StartPoll {
+        kind: PollKind::Custom,
+        question: "Next?".to_string(),
+        options: vec!["Yes".to_string(), "No".to_string()],
+        duration_secs: None,
+    }).await;
+    owner.expect_success().await;
+    limited.expect(|msg| match msg {
+        OutgoingMessage::ReactionReceived { .. } => panic!("Reaction sent to a client without the capability"),
+        OutgoingMessage::PollUpdated { .. } => Some(()),
+        _ => None,
+    }).await;
+}
+
+#[tokio::test]
+async fn typing_ends_with_the_chat_message() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "typing").await;
+    let mut member = TestClient::join(&server, "member", "typing").await;
+
+    member.send(IncomingMessage::TypingStart).await;
+    member.expect_success().await;
+    let client_uid = owner.expect(|msg| match msg {
+        OutgoingMessage::TypingChanged { client_uid, typing: true } => Some(client_uid),
+        _ => None,
+    }).await;
+    assert_eq!(client_uid, member.uid);
+
+    member.send(IncomingMessage::ChatMessage { text: "hi".to_string() }).await;
+    member.expect_success().await;
+    owner.expect(|msg| match msg {
+        OutgoingMessage::TypingChanged { typing: false, .. } => Some(()),
+        OutgoingMessage::ChatMessage { .. } => panic!("Chat message before the end of typing"),
+        _ => None,
+    }).await;
+}
+
+#[tokio::test]
+async fn late_joiner_sees_edited_chat_history() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "chat-edit").await;
+    let mut member = TestClient::join(&server, "member", "chat-edit").await;
+
+    owner.send(IncomingMessage::ChatMessage { text: "helo".to_string() }).await;
+    owner.expect_success().await;
+    let message_id = member.expect(|msg| match msg {
+        OutgoingMessage::ChatMessage { message_id, .. } => Some(message_id),
+        _ => None,
+    }).await;
+
+    member.send(IncomingMessage::DeleteChatMessage { message_id }).await;
+    let kind = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::Forbidden));
+
+    owner.send(IncomingMessage::EditChatMessage { message_id, text: "hello".to_string() }).await;
+    owner.expect_success().await;
+    let text = member.expect(|msg| match msg {
+        OutgoingMessage::ChatMessageEdited { text, .. } => Some(text),
+        _ => None,
+    }).await;
+    assert_eq!(text, "hello");
+
+    let mut late_joiner = TestClient::join(&server, "late", "chat-edit").await;
+    let messages = late_joiner.expect(|msg| match msg {
+        OutgoingMessage::ChatHistory { messages } => Some(messages),
+        _ => None,
+    }).await;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].text, "hello");
+    assert!(messages[0].edited);
+
+    owner.send(IncomingMessage::DeleteChatMessage { message_id }).await;
+    owner.expect_success().await;
+    late_joiner.expect(|msg| matches!(msg, OutgoingMessage::ChatMessageDeleted { .. }).then_some(())).await;
+}
+
+#[tokio::test]
+async fn oversized_message_is_refused_before_parsing() {
+    let server = TestServer::start().await;
+    let mut client = TestClient::join(&server, "client", "oversized").await;
+
+    client.send(IncomingMessage::ChatMessage { text: "a".repeat(100 * 1024) }).await;
+    let kind = client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::PayloadTooLarge));
+
+    client.send(IncomingMessage::ChatMessage { text: "short".to_string() }).await;
+    client.expect_success().await;
+}
+
+#[tokio::test]
+async fn filtered_words_are_rejected_or_masked() {
+    let words_path = std::env::temp_dir().join(format!("sent-sync-words-{}.txt", std::process::id()));
+    std::fs::write(&words_path, "# Blocked words\nheck\n\nDarn\n").unwrap();
+    let config = ServerConfig { content_filter_words_path: Some(words_path.display().to_string()), ..ServerConfig::default() };
+
+    let server = TestServer::start_with(config.clone()).await;
+    let mut client = TestClient::connect(&server).await;
+    client.send(IncomingMessage::ChangeName { new_name: "DARN it".to_string() }).await;
+    let kind = client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::ContentRejected));
+    client.send(IncomingMessage::ChangeName { new_name: "Checkmate".to_string() }).await;
+    client.expect_success().await;
+
+    let server = TestServer::start_with(ServerConfig { content_filter_action: ContentFilterAction::Mask, ..config }).await;
+    let mut client = TestClient::join(&server, "member", "filtered-room").await;
+    client.send(IncomingMessage::ChatMessage { text: "What the Heck, heckler?".to_string() }).await;
+    let text = client.expect(|msg| match msg {
+        OutgoingMessage::ChatMessage { text, .. } => Some(text),
+        _ => None,
+    }).await;
+    assert_eq!(text, "What the ****, heckler?");
+    std::fs::remove_file(words_path).unwrap();
+}
+
+#[tokio::test]
+async fn handling_time_is_exported_by_message_type() {
+    let server = TestServer::start().await;
+    let mut client = TestClient::join(&server, "member", "measured-room").await;
+    client.send(IncomingMessage::ChatMessage { text: "Hi".to_string() }).await;
+    client.expect(|msg| match msg {
+        OutgoingMessage::ChatMessage { .. } => Some(()),
+        _ => None,
+    }).await;
+    // Durations are recorded after the answer is sent, messages of a connection are handled one
+    // after the other
+    client.send(IncomingMessage::Ping { client_time: None, rtt_ms: None }).await;
+    client.expect(|msg| match msg {
+        OutgoingMessage::Pong { .. } => Some(()),
+        _ => None,
+    }).await;
+
+    let (status, metrics) = http_get(&server, "/metrics").await;
+    assert!(status.is_success());
+    assert!(metrics.contains("# TYPE sent_sync_message_handling_seconds histogram"));
+    assert!(metrics.contains("sent_sync_message_handling_seconds_count{type=\"joinRoom\"} 1\n"));
+    assert!(metrics.contains("sent_sync_message_handling_seconds_bucket{type=\"chatMessage\",le=\"+Inf\"} 1\n"));
+    assert!(metrics.contains("sent_sync_message_handling_seconds_sum{type=\"joinRoom\"} "));
+}
+
+#[test]
+fn message_kind_is_the_type_on_the_wire() {
+    let messages = [
+        IncomingMessage::RequestInviteQrCode,
+        IncomingMessage::ChatMessage { text: "Hi".to_string() },
+        IncomingMessage::Ping { client_time: None, rtt_ms: None },
+        IncomingMessage::ChangeName { new_name: "member".to_string() },
+    ];
+    for message in messages {
+        let json = serde_json::to_value(&message).expect("Serialization failed");
+        assert_eq!(json["type"], message.kind());
+    }
+}
+
+#[tokio::test]
+async fn invalid_utf8_closes_the_connection() {
+    let server = TestServer::start().await;
+    let mut client = TestClient::join(&server, "client", "invalid-utf8").await;
+
+    client.send_text_bytes(vec![b'{', 0xff, 0xfe, b'}']).await;
+    let kind = client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::InvalidUtf8));
+    let reason = client.expect(|msg| match msg {
+        OutgoingMessage::Disconnecting { reason, .. } => Some(reason),
+        _ => None,
+    }).await;
+    assert_eq!(reason, DisconnectReason::InvalidFrame);
+    let close_frame = client.expect_close().await.expect("Close frame without a code");
+    assert_eq!(u16::from(close_frame.code), 1007);
+}
+
+#[tokio::test]
+async fn offered_file_is_relayed_to_the_member_who_requested_it() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "file-offer").await;
+    let mut member = TestClient::join(&server, "member", "file-offer").await;
+    let hash = "5d41402abc4b2a76b9719d911017c592".to_string();
+
+    owner.send(IncomingMessage::OfferFile { name: "movie.en.srt".to_string(), size: 5, hash: hash.clone() }).await;
+    owner.expect_success().await;
+    let (from_uid, name) = member.expect(|msg| match msg {
+        OutgoingMessage::FileOffered { from_uid, name, .. } => Some((from_uid, name)),
+        _ => None,
+    }).await;
+    assert_eq!((from_uid, name.as_str()), (owner.uid, "movie.en.srt"));
+
+    // Chunks are only sent on request
+    owner.send(IncomingMessage::FileChunk { to_uid: member.uid, hash: hash.clone(), offset: 0, data: "aGVsbG8=".to_string() }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::Forbidden));
+
+    member.send(IncomingMessage::RequestFile { from_uid: owner.uid, hash: hash.clone() }).await;
+    member.expect_success().await;
+    let requested_by = owner.expect(|msg| match msg {
+        OutgoingMessage::FileRequested { from_uid, .. } => Some(from_uid),
+        _ => None,
+    }).await;
+    assert_eq!(requested_by, member.uid);
+
+    // Past the end of the offered file
+    owner.send(IncomingMessage::FileChunk { to_uid: member.uid, hash: hash.clone(), offset: 1, data: "aGVsbG8=".to_string() }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::InvalidFileChunk));
+
+    owner.send(IncomingMessage::FileChunk { to_uid: member.uid, hash: hash.clone(), offset: 0, data: "aGVsbG8=".to_string() }).await;
+    owner.expect_success().await;
+    let data = member.expect(|msg| match msg {
+        OutgoingMessage::FileChunk { data, .. } => Some(data),
+        _ => None,
+    }).await;
+    assert_eq!(data, "aGVsbG8=");
+}
+
+#[tokio::test]
+async fn errors_are_worded_in_the_hello_locale_with_their_limits() {
+    let server = TestServer::start().await;
+    let mut client = TestClient::connect_with_locale(&server, "ru-RU").await;
+
+    client.send(IncomingMessage::ChangeName { new_name: "x".repeat(100) }).await;
+    let (kind, msg, params) = client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, msg, params, .. } => Some((kind, msg, params)),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::ClientNameTooLong));
+    assert_eq!(msg.as_deref(), Some(error_message(Locale::Ru, &ErrorKind::ClientNameTooLong)));
+    let config = ServerConfig::default();
+    assert_eq!(params, Some(ErrorParams::range(config.min_name_length, config.max_name_length)));
+}
+
+#[cfg(unix)]
+async fn first_message<S: AsyncRead + AsyncWrite + Unpin>(stream: S) -> OutgoingMessage {
+    let (mut stream, _) = tokio_tungstenite::client_async("ws://localhost/ws", stream).await.expect("WebSocket handshake failed");
+    match stream.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text).expect("Invalid message"),
+        msg => panic!("Expected a message, got {:?}", msg),
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn additional_listeners_accept_connections() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let socket_path = std::env::temp_dir().join(format!("sent-sync-test-{}.sock", port));
+    let server = TestServer::start_with(ServerConfig {
+        listeners: vec![format!("127.0.0.1:{}", port).parse().unwrap(), format!("unix:{}", socket_path.display()).parse().unwrap()],
+        ..ServerConfig::default()
+    }).await;
+
+    // Bound once the server is up, which may be a bit after it answers
+    let mut attempts = 0;
+    let (tcp_stream, unix_stream) = loop {
+        match (TcpStream::connect(("127.0.0.1", port)).await, UnixStream::connect(&socket_path).await) {
+            (Ok(tcp_stream), Ok(unix_stream)) => break (tcp_stream, unix_stream),
+            _ if attempts < 50 => attempts += 1,
+            (tcp_stream, unix_stream) => panic!("Listeners not bound: {:?} {:?}", tcp_stream.err(), unix_stream.err()),
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert!(matches!(first_message(tcp_stream).await, OutgoingMessage::ClientUid { .. }));
+    assert!(matches!(first_message(unix_stream).await, OutgoingMessage::ClientUid { .. }));
+
+    drop(server);
+    let _ = std::fs::remove_file(socket_path);
+}
+
+#[tokio::test]
+async fn silent_connection_is_closed_without_being_registered() {
+    let server = TestServer::start_with(ServerConfig { handshake_timeout_secs: 1, ..ServerConfig::default() }).await;
+    let mut stream = handshake(&server, "").await.expect("WebSocket handshake failed");
+
+    let mut messages = Vec::new();
+    let close_frame = loop {
+        match stream.next().await {
+            Some(Ok(Message::Text(text))) => {
+                let msg: OutgoingMessage = serde_json::from_str(&text).expect("Invalid message");
+                if matches!(msg, OutgoingMessage::ClientUid { .. }) {
+                    let (_, metrics) = http_get(&server, "/metrics").await;
+                    assert!(metrics.lines().any(|line| line == "sent_sync_connections 0"));
+                }
+                messages.push(msg);
+            }
+            Some(Ok(Message::Close(close_frame))) => break close_frame.expect("Close frame without a code"),
+            msg => panic!("Expected a message, got {:?}", msg),
+        }
+    };
+    assert!(matches!(messages.first(), Some(OutgoingMessage::ClientUid { .. })));
+    assert!(matches!(messages.last(), Some(OutgoingMessage::Disconnecting { reason: DisconnectReason::HandshakeTimeout, retry_after: Some(_) })));
+    assert_eq!(u16::from(close_frame.code), 4011);
+    let (_, metrics) = http_get(&server, "/metrics").await;
+    assert!(metrics.lines().any(|line| line == "sent_sync_handshake_timeouts_total 1"));
+}
@@ -0,0 +1,317 @@
+mod common;
+
+use common::{TestClient, TestServer};
+use sha1::{Digest, Sha1};
+use sent_sync_server::command_signing::{from_hex, hmac_sha256, page_url_change_message, to_hex};
+use sent_sync_server::config::ServerConfig;
+use sent_sync_server::ws_dto_models::{RoomSettingsUpdateDto, TrackKind};
+use sent_sync_server::protocol::{ErrorKind, IncomingMessage, OutgoingMessage};
+
+#[tokio::test]
+async fn drifting_member_is_corrected() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "drift").await;
+    let mut member = TestClient::join(&server, "member", "drift").await;
+
+    owner.send(IncomingMessage::Seek { position: 30.0 }).await;
+    owner.expect_success().await;
+    member.expect(|msg| matches!(msg, OutgoingMessage::Seek { .. }).then_some(())).await;
+
+    member.send(IncomingMessage::ReportPosition { position: 95.0, playing: false }).await;
+    let (position, playing) = member.expect(|msg| match msg {
+        OutgoingMessage::CorrectPosition { position, playing } => Some((position, playing)),
+        _ => None,
+    }).await;
+    assert_eq!(position, 30.0);
+    assert!(!playing);
+}
+
+#[tokio::test]
+async fn seek_is_ahead_by_the_members_latency() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "latency").await;
+    let mut member = TestClient::join(&server, "member", "latency").await;
+
+    member.send(IncomingMessage::Ping { client_time: Some(123), rtt_ms: Some(400) }).await;
+    let (client_time, server_time) = member.expect(|msg| match msg {
+        OutgoingMessage::Pong { client_time, server_time } => Some((client_time, server_time)),
+        _ => None,
+    }).await;
+    assert_eq!(client_time, Some(123));
+    assert!(server_time > 0);
+
+    owner.send(IncomingMessage::Play).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::Seek { position: 10.0 }).await;
+    owner.expect_success().await;
+    let position = member.expect(|msg| match msg {
+        OutgoingMessage::Seek { position, .. } => Some(position),
+        _ => None,
+    }).await;
+    assert!((position - 10.2).abs() < 0.001, "Seek to {} instead of 10.2", position);
+}
+
+#[tokio::test]
+async fn room_plays_once_everybody_is_ready() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "ready-check").await;
+    let mut member = TestClient::join(&server, "member", "ready-check").await;
+
+    owner.send(IncomingMessage::RequestReadyCheck { quorum_percent: None }).await;
+    owner.expect_success().await;
+    let required_count = member.expect(|msg| match msg {
+        OutgoingMessage::ReadyCheckUpdated { ready_check: Some(ready_check) } => Some(ready_check.required_count),
+        _ => None,
+    }).await;
+    assert_eq!(required_count, 2);
+
+    owner.send(IncomingMessage::SetReady { ready: true }).await;
+    owner.expect_success().await;
+    member.send(IncomingMessage::SetReady { ready: true }).await;
+    member.expect_success().await;
+    member.expect(|msg| matches!(msg, OutgoingMessage::ReadyCheckUpdated { ready_check: None }).then_some(())).await;
+    member.expect(|msg| matches!(msg, OutgoingMessage::Play { .. }).then_some(())).await;
+    owner.expect(|msg| matches!(msg, OutgoingMessage::Play { .. }).then_some(())).await;
+
+    member.send(IncomingMessage::SetReady { ready: true }).await;
+    let kind = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::NoReadyCheck));
+}
+
+#[tokio::test]
+async fn room_plays_when_the_countdown_ends() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "countdown").await;
+    let mut member = TestClient::join(&server, "member", "countdown").await;
+
+    owner.send(IncomingMessage::StartCountdown { seconds: 60 }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::InvalidCountdown));
+
+    owner.send(IncomingMessage::StartCountdown { seconds: 1 }).await;
+    owner.expect_success().await;
+    let (starts_at, seconds) = member.expect(|msg| match msg {
+        OutgoingMessage::CountdownStarted { starts_at, seconds, .. } => Some((starts_at, seconds)),
+        _ => None,
+    }).await;
+    assert_eq!(seconds, 1);
+    assert!(starts_at > 0);
+    member.expect(|msg| matches!(msg, OutgoingMessage::Play { .. }).then_some(())).await;
+    owner.expect(|msg| matches!(msg, OutgoingMessage::Play { .. }).then_some(())).await;
+}
+
+#[tokio::test]
+async fn room_pauses_when_a_member_leaves() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "pause-on-leave").await;
+    let mut member = TestClient::join(&server, "member", "pause-on-leave").await;
+
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { pause_on_leave: Some(true), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::Play).await;
+    owner.expect_success().await;
+    member.expect(|msg| matches!(msg, OutgoingMessage::Play { .. }).then_some(())).await;
+
+    member.send(IncomingMessage::QuitRoom).await;
+    member.expect_success().await;
+    let (client_uid, left_name) = owner.expect(|msg| match msg {
+        OutgoingMessage::Pause { client_uid, left_name, .. } => Some((client_uid, left_name)),
+        _ => None,
+    }).await;
+    assert_eq!(client_uid, member.uid);
+    assert_eq!(left_name.as_deref(), Some("member"));
+}
+
+#[tokio::test]
+async fn late_joiner_is_told_the_picked_track() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "tracks").await;
+    let mut member = TestClient::join(&server, "member", "tracks").await;
+
+    owner.send(IncomingMessage::SetTrack { kind: TrackKind::Subtitle, track_id: "en".to_string() }).await;
+    owner.expect_success().await;
+    let track_id = member.expect(|msg| match msg {
+        OutgoingMessage::TrackChanged { kind: TrackKind::Subtitle, track_id, .. } => Some(track_id),
+        _ => None,
+    }).await;
+    assert_eq!(track_id.as_deref(), Some("en"));
+
+    let mut late_joiner = TestClient::join(&server, "late", "tracks").await;
+    let playback = late_joiner.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { data, .. } => Some(data.settings.playback),
+        _ => None,
+    }).await;
+    assert_eq!(playback.subtitle_track.as_deref(), Some("en"));
+    assert_eq!(playback.audio_track, None);
+}
+
+#[tokio::test]
+async fn member_jumps_to_a_marker() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "markers").await;
+    let mut member = TestClient::join(&server, "member", "markers").await;
+
+    owner.send(IncomingMessage::AddMarker { position: 90.0, label: "Opening".to_string() }).await;
+    owner.expect_success().await;
+    let markers = member.expect(|msg| match msg {
+        OutgoingMessage::RoomSettingsUpdated { settings, .. } if !settings.markers.is_empty() => Some(settings.markers),
+        _ => None,
+    }).await;
+    assert_eq!(markers[0].label, "Opening");
+
+    member.send(IncomingMessage::AddMarker { position: 10.0, label: "Mine".to_string() }).await;
+    let kind = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::Forbidden));
+
+    owner.send(IncomingMessage::JumpToMarker { marker_id: markers[0].marker_id }).await;
+    owner.expect_success().await;
+    let position = member.expect(|msg| match msg {
+        OutgoingMessage::Seek { position, .. } => Some(position),
+        _ => None,
+    }).await;
+    assert_eq!(position, 90.0);
+}
+
+#[tokio::test]
+async fn burst_of_changes_is_broadcast_once() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "burst").await;
+    owner.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { .. } => Some(()),
+        _ => None,
+    }).await;
+    let mut member = TestClient::join(&server, "member", "burst").await;
+
+    for (position, label) in [(10.0, "Intro"), (20.0, "Opening"), (30.0, "Credits")] {
+        owner.send(IncomingMessage::AddMarker { position, label: label.to_string() }).await;
+    }
+    for _ in 0..3 {
+        owner.expect_success().await;
+    }
+
+    let markers = member.expect(|msg| match msg {
+        OutgoingMessage::RoomSettingsUpdated { settings, .. } if !settings.markers.is_empty() => Some(settings.markers),
+        _ => None,
+    }).await;
+    assert_eq!(markers.len(), 3);
+}
+
+#[tokio::test]
+async fn room_advances_once_half_of_the_members_reported_the_end() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "auto-advance").await;
+    let mut first = TestClient::join(&server, "first", "auto-advance").await;
+    let mut second = TestClient::join(&server, "second", "auto-advance").await;
+    let mut third = TestClient::join(&server, "third", "auto-advance").await;
+
+    for url in ["https://example.com/1", "https://example.com/2"] {
+        owner.send(IncomingMessage::QueueAdd { url: url.to_string(), nonce: None, signature: None }).await;
+        owner.expect_success().await;
+    }
+    owner.send(IncomingMessage::QueueNext).await;
+    owner.expect_success().await;
+
+    first.send(IncomingMessage::VideoEnded { url: "https://example.com/1".to_string() }).await;
+    first.expect_success().await;
+    second.send(IncomingMessage::VideoEnded { url: "https://example.com/1".to_string() }).await;
+    second.expect_success().await;
+
+    let url = third.expect(|msg| match msg {
+        OutgoingMessage::PageUrlChanged { url, .. } if url != "https://example.com/1" => Some(url),
+        _ => None,
+    }).await;
+    assert_eq!(url, "https://example.com/2");
+    let watched = third.expect(|msg| match msg {
+        OutgoingMessage::RoomSettingsUpdated { settings, .. } if !settings.watched.is_empty() => Some(settings.watched),
+        _ => None,
+    }).await;
+    assert_eq!(watched, vec!["https://example.com/1".to_string()]);
+}
+
+#[tokio::test]
+async fn only_signed_urls_are_queued_once_commands_must_be_signed() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "signed-queue").await;
+
+    owner.send(IncomingMessage::QueueAdd { url: "https://example.com/unsigned".to_string(), nonce: None, signature: None }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::SetCommandSigning { required: true }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::QueueNext).await;
+    let error = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::QueueEmpty));
+
+    owner.send(IncomingMessage::QueueAdd { url: "https://example.com/unsigned".to_string(), nonce: None, signature: None }).await;
+    let error = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::InvalidSignature));
+
+    owner.send(IncomingMessage::RequestSigningSecret).await;
+    let secret = owner.expect(|msg| match msg {
+        OutgoingMessage::SigningSecret { secret } => from_hex(&secret),
+        _ => None,
+    }).await;
+    let url = "https://example.com/signed";
+    let signature = to_hex(&hmac_sha256(&secret, page_url_change_message("signed-queue", 1, url).as_bytes()));
+    owner.send(IncomingMessage::QueueAdd { url: url.to_string(), nonce: Some(1), signature: Some(signature) }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::QueueNext).await;
+    owner.expect_success().await;
+    let page_url = owner.expect(|msg| match msg {
+        OutgoingMessage::PageUrlChanged { url, .. } => Some(url),
+        _ => None,
+    }).await;
+    assert_eq!(page_url, url);
+}
+
+#[tokio::test]
+async fn room_preferences_switch_the_video_like_set_page_url() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "preferences").await;
+    let mut member = TestClient::join(&server, "member", "preferences").await;
+
+    let url = "https://example.com/preferences";
+    owner.send(IncomingMessage::ChangeRoomPreferences { page_url: url.to_string(), allow_stop_due_to_video_loading: false, nonce: None, signature: None }).await;
+    owner.expect_success().await;
+    let page_url = member.expect(|msg| match msg {
+        OutgoingMessage::PageUrlChanged { url, .. } => Some(url),
+        _ => None,
+    }).await;
+    assert_eq!(page_url, url);
+}
+
+#[tokio::test]
+async fn members_are_sent_a_digest_of_the_room() {
+    let server = TestServer::start_with(ServerConfig { room_digest_interval_secs: 1, ..ServerConfig::default() }).await;
+    let mut owner = TestClient::join(&server, "owner", "digest").await;
+
+    owner.send(IncomingMessage::Seek { position: 42.0 }).await;
+    owner.expect_success().await;
+
+    let (position, playing, member_count, members_digest) = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomDigest { position, playing, member_count, members_digest, .. } => Some((position, playing, member_count, members_digest)),
+        _ => None,
+    }).await;
+    assert_eq!(position, 42.0);
+    assert!(!playing);
+    assert_eq!(member_count, 1);
+    let members = serde_json::json!([[owner.uid, "owner", "owner"]]);
+    let expected_digest: String = Sha1::digest(members.to_string()).iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(members_digest, expected_digest);
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use common::{http_get, TestClient, TestServer};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use jwt_simple::prelude::{ECDSAP256PublicKeyLike, ES256PublicKey, NoCustomClaims, VerificationOptions};
+use p256::SecretKey;
+use sent_sync_server::config::ServerConfig;
+use sent_sync_server::protocol::IncomingMessage;
+use sent_sync_server::push_notifications::{decode_base64url, encrypt_payload, VapidKey};
+use serde_json::json;
+use std::collections::HashSet;
+
+// RFC 8291, appendix A
+const SENDER_PRIVATE_KEY: &str = "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw";
+const SENDER_PUBLIC_KEY: &str = "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8";
+const SUBSCRIPTION_PUBLIC_KEY: &str = "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4";
+const AUTH_SECRET: &str = "BTBZMqHH6r4Tts7J_aSIgg";
+const SALT: &str = "DGv6ra1nlYgDCS1FRnbzlw";
+const ENCRYPTED_BODY: &str = "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN";
+
+fn decode(text: &str) -> Vec<u8> {
+    decode_base64url(text).expect("Invalid base64url")
+}
+
+fn push_config() -> ServerConfig {
+    ServerConfig {
+        vapid_private_key: Some(SENDER_PRIVATE_KEY.to_string()),
+        vapid_subject: Some("mailto:admin@example.com".to_string()),
+        ..ServerConfig::default()
+    }
+}
+
+async fn subscribe(server: &TestServer, namespace: Option<&str>, room_id: &str, endpoint: &str) -> StatusCode {
+    let body = json!({
+        "namespace": namespace,
+        "roomId": room_id,
+        "subscription": {
+            "endpoint": endpoint,
+            "keys": { "p256dh": SUBSCRIPTION_PUBLIC_KEY, "auth": AUTH_SECRET },
+        },
+    });
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://127.0.0.1:{}/push/subscribe", server.port))
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Invalid request");
+    Client::new().request(request).await.expect("Request failed").status()
+}
+
+#[test]
+fn payload_is_encrypted_as_in_rfc_8291() {
+    let sender_key = SecretKey::from_slice(&decode(SENDER_PRIVATE_KEY)).expect("Invalid sender key");
+    let salt: [u8; 16] = decode(SALT).try_into().expect("Invalid salt");
+
+    let body = encrypt_payload(
+        &decode(SUBSCRIPTION_PUBLIC_KEY),
+        &decode(AUTH_SECRET),
+        b"When I grow up, I want to be a watermelon",
+        &sender_key,
+        salt,
+    ).expect("Encryption failed");
+    assert_eq!(body, decode(ENCRYPTED_BODY));
+}
+
+#[test]
+fn vapid_token_is_bound_to_the_push_service_origin() {
+    let vapid_key = VapidKey::new(SENDER_PRIVATE_KEY, Some("mailto:admin@example.com".to_string())).expect("Invalid VAPID key");
+    assert_eq!(vapid_key.public_key(), SENDER_PUBLIC_KEY);
+
+    let endpoint = "https://push.example.net/wpush/v2/abc".parse().expect("Invalid endpoint");
+    let authorization = vapid_key.authorization(&endpoint).expect("Signing failed");
+    let (token, public_key) = authorization
+        .strip_prefix("vapid t=")
+        .and_then(|rest| rest.split_once(", k="))
+        .expect("Not a VAPID authorization");
+    assert_eq!(public_key, SENDER_PUBLIC_KEY);
+
+    let verification_key = ES256PublicKey::from_bytes(&decode(public_key)).expect("Invalid public key");
+    let options = VerificationOptions {
+        allowed_audiences: Some(HashSet::from(["https://push.example.net".to_string()])),
+        ..VerificationOptions::default()
+    };
+    let claims = verification_key.verify_token::<NoCustomClaims>(token, Some(options)).expect("Invalid token");
+    assert_eq!(claims.subject.as_deref(), Some("mailto:admin@example.com"));
+}
+
+#[tokio::test]
+async fn push_is_unavailable_without_a_vapid_key() {
+    let server = TestServer::start().await;
+    let _owner = TestClient::join(&server, "owner", "room").await;
+
+    assert_eq!(subscribe(&server, None, "room", "https://push.example.net/1").await, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(http_get(&server, "/push/public-key").await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn subscriptions_are_checked_and_limited_per_room() {
+    let server = TestServer::start_with(ServerConfig { max_push_subscriptions_per_room: 1, ..push_config() }).await;
+    let (status, public_key) = http_get(&server, "/push/public-key").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(public_key, SENDER_PUBLIC_KEY);
+
+    assert_eq!(subscribe(&server, None, "room", "https://push.example.net/1").await, StatusCode::NOT_FOUND);
+    let mut owner = TestClient::join(&server, "owner", "room").await;
+    assert_eq!(subscribe(&server, None, "room", "http://127.0.0.1:6379/").await, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(subscribe(&server, Some("movies"), "room", "https://push.example.net/1").await, StatusCode::NOT_FOUND);
+
+    assert_eq!(subscribe(&server, None, "room", "https://push.example.net/1").await, StatusCode::NO_CONTENT);
+    assert_eq!(subscribe(&server, None, "room", "https://push.example.net/1").await, StatusCode::NO_CONTENT);
+    assert_eq!(subscribe(&server, None, "room", "https://push.example.net/2").await, StatusCode::TOO_MANY_REQUESTS);
+
+    // The subscriptions are gone with the room
+    owner.send(IncomingMessage::QuitRoom).await;
+    owner.expect_success().await;
+    let _owner = TestClient::join(&server, "owner", "room").await;
+    assert_eq!(subscribe(&server, None, "room", "https://push.example.net/2").await, StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn subscriptions_are_limited_on_the_whole_server() {
+    let server = TestServer::start_with(ServerConfig { max_push_subscriptions: 1, ..push_config() }).await;
+    let _first = TestClient::join(&server, "first", "first").await;
+    let _second = TestClient::join(&server, "second", "second").await;
+
+    assert_eq!(subscribe(&server, None, "first", "https://push.example.net/1").await, StatusCode::NO_CONTENT);
+    assert_eq!(subscribe(&server, None, "second", "https://push.example.net/2").await, StatusCode::TOO_MANY_REQUESTS);
+}
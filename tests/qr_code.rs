@@ -0,0 +1,159 @@
+mod common;
+
+use common::{http_get, TestClient, TestServer};
+use hyper::StatusCode;
+use sent_sync_server::protocol::{IncomingMessage, OutgoingMessage};
+use sent_sync_server::qr_code::{format_bits, reed_solomon_divisor, reed_solomon_remainder, version_bits, QrCode};
+
+const LONG_JOIN_URL: &str = "https://sync.example.com/join/a-rather-long-room-name-used-for-a-version-seven-symbol?namespace=some-namespace&invite=0123456789abcdef0123456789";
+
+// Symbols of the reference encoder (Kazuhiko Arase's qrcode-generator), level M with the same mask
+const HELLO_WORLD_VERSION_1: [&str; 21] = [
+    "#######.##..#.#######",
+    "#.....#....#..#.....#",
+    "#.###.#..#.#..#.###.#",
+    "#.###.#.#..#..#.###.#",
+    "#.###.#.###.#.#.###.#",
+    "#.....#.#..#..#.....#",
+    "#######.#.#.#.#######",
+    "........#..##........",
+    "#...#.######.#####..#",
+    "...#....#.###....####",
+    "..######..##.##.#..#.",
+    "#####...##...#.......",
+    "#####.#.#.#.#.##..##.",
+    "........#.#.####.#.##",
+    "#######.###.#.#.##.#.",
+    "#.....#..#.###.##..##",
+    "#.###.#.##.#.##...##.",
+    "#.###.#..#..#...##.##",
+    "#.###.#..###...###...",
+    "#.....#....#.#.......",
+    "#######.#########.#.#",
+];
+
+const LONG_JOIN_URL_VERSION_8: [&str; 49] = [
+    "#######..#.###..#.#....##...#.##........#.#######",
+    "#.....#....#.#####....##.##..#...##.#####.#.....#",
+    "#.###.#.####.####.#.#####.###.#.##.#...##.#.###.#",
+    "#.###.#.##.###.....#....##...#.#...###.#..#.###.#",
+    "#.###.#.##....#.###..######..####..#.#....#.###.#",
+    "#.....#.#.###.##.###..#...##.....##.#.#...#.....#",
+    "#######.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#######",
+    "........#..##...##....#...#.###.#.#..####........",
+    "#.#####..####..###.########....#....##..#.#####..",
+    "#.##.#.#.###..##.###.#.###...###...##..#..##.....",
+    "#..##.#....##..##.#.#...#.#.#..##.###.#..#.....##",
+    "#...##...###...###.##....#.###..##...##.....#..##",
+    "#.#.####.######......####.#..#.#.#.####.#..#.###.",
+    "##..##..##....#.#.###.#.##..###.#...##.#..##...#.",
+    "..#..####..#..####..###.#.#.#..####...##....##.##",
+    "##........##.##...###.#..##.#.####...#..##..#...#",
+    "..###.#.##.##..#.##.##.###.#......###.#####..##..",
+    ".##....##.##..#...##.#.#.#.##.#....##....####..#.",
+    "#.....####.#..#..#.#.##.#.#..#...##.###.##.#.#.##",
+    "#.###.....#.#####...#.....####.##.##.##.##.##..##",
+    ".#..#.###.....##.#..###.#....#.#...##.#.###...#..",
+    "....#..#.........###..###..#####...###...####.#..",
+    "..#######.####.#.#.##.#####.#..##.###.#.######.##",
+    "#.###...##..#.##..#...#...####..#....##.#...#....",
+    "##.##.#.#....###....###.#.##.#.....###.##.#.#####",
+    "#####...##.##.#.##.#..#...#..##....###.##...#.#..",
+    "##..#######..#...#....######.#.#..##..#.#######.#",
+    "#...##..##.#.#.##.#.##..######..##.#.##......#...",
+    "##...###.#..##.#..##....###..##....##.##..#####.#",
+    "#..#.#..####.#.......###..######...###.##........",
+    "...#.##.###.##.#####..#....##..#.####.##..#....##",
+    ".##.#.....##.#..#....#.#..#.#.###....#..##...#..#",
+    ".##...###..#....#.#.##.#.#.#.#...#.#######.##.#.#",
+    "###......###.###.#.#.#####....#.#..##...##.#.....",
+    "....###..####.#...#.##.#.#...#.#.##..##...####.##",
+    "...##..###.#.....###.##.######.###......#........",
+    "#..##.#.#.##.##..#.###.#.#...##..#.######.#.#.###",
+    ".##.....####.#..####.#####....##...##...#..#..##.",
+    ".#...##.##.##..#.#.#..#..#..#..#.###..#.###.#.###",
+    ".###.........##....##.#.#####.#.##...##.#..##..##",
+    "###...###.#.##.#.#..#.#####....#..###.###########",
+    "........####...#.#.#..#...#...##...###..#...#.##.",
+    "#######..###.#.#..#..##.#.#.#..#.##.#.#.#.#.#..##",
+    "#.....#.#.##.##.#..#.##...#####.##......#...#..##",
+    "#.###.#.##.##.#.#.#..######..###..####..#####.###",
+    "#.###.#.##..##.#..#####...#.#.#......#..#.###.###",
+    "#.###.#.#.#.##...##.#..###..#..#####..##...#.....",
+    "#.....#..#..#...#####..#....###.#..#..#.###..#..#",
+    "#######.####..##.##.##.#...#..##....#..#......###",
+];
+
+fn assert_symbol(qr_code: &QrCode, expected: &[&str]) {
+    assert_eq!(qr_code.size(), expected.len());
+    for (y, row) in expected.iter().enumerate() {
+        let actual: String = (0..qr_code.size()).map(|x| if qr_code.is_dark(x, y) { '#' } else { '.' }).collect();
+        assert_eq!(&actual, row, "row {}", y);
+    }
+}
+
+#[test]
+fn format_bits_match_iso_18004() {
+    let expected = [
+        0b101010000010010, 0b101000100100101, 0b101111001111100, 0b101101101001011,
+        0b100010111111001, 0b100000011001110, 0b100111110010111, 0b100101010100000,
+    ];
+    for (mask, bits) in expected.into_iter().enumerate() {
+        assert_eq!(format_bits(mask as u32), bits, "mask {}", mask);
+    }
+}
+
+#[test]
+fn version_bits_match_iso_18004() {
+    assert_eq!(version_bits(7), 0b000111110010010100);
+    assert_eq!(version_bits(8), 0b001000010110111100);
+    assert_eq!(version_bits(9), 0b001001101010011001);
+    assert_eq!(version_bits(10), 0b001010010011010011);
+}
+
+#[test]
+fn error_correction_of_hello_world_version_1() {
+    let data = [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+    let ecc = reed_solomon_remainder(&data, &reed_solomon_divisor(10));
+    assert_eq!(ecc, vec![196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+}
+
+#[test]
+fn symbols_match_the_reference_encoder() {
+    let hello_world = QrCode::encode(b"HELLO WORLD").expect("Too long");
+    assert_eq!(hello_world.mask(), 4);
+    assert_symbol(&hello_world, &HELLO_WORLD_VERSION_1);
+
+    // Version information and blocks of two lengths
+    let long_join_url = QrCode::encode(LONG_JOIN_URL.as_bytes()).expect("Too long");
+    assert_eq!(long_join_url.mask(), 2);
+    assert_symbol(&long_join_url, &LONG_JOIN_URL_VERSION_8);
+}
+
+#[test]
+fn data_beyond_version_10_is_refused() {
+    assert_eq!(QrCode::encode(&[b'a'; 213]).map(|qr_code| qr_code.size()), Some(57));
+    assert!(QrCode::encode(&[b'a'; 214]).is_none());
+}
+
+#[tokio::test]
+async fn qr_code_image_is_served_with_its_token() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "party").await;
+    owner.send(IncomingMessage::RequestInviteQrCode).await;
+    let (svg, image_url) = owner.expect(|msg| match msg {
+        OutgoingMessage::InviteQrCode { svg, image_url, .. } => Some((svg, image_url)),
+        _ => None,
+    }).await;
+
+    let path = &image_url[image_url.find("/join/").expect("No image path")..];
+    let (status, body) = http_get(&server, path).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, svg);
+
+    let (path_without_token, _) = path.split_once('?').expect("No token");
+    assert_eq!(http_get(&server, path_without_token).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(http_get(&server, &format!("{}?token=00", path_without_token)).await.0, StatusCode::NOT_FOUND);
+    let other_room = path.replace("/join/party/", "/join/other/");
+    assert_eq!(http_get(&server, &other_room).await.0, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,396 @@
+//! Seeded random sessions against a model of the server built on the rules of `room_logic`. The
+//! model only covers what those rules decide, like who owns a room and whether it plays, not the
+//! permission checks of the message handlers, which tests/rooms.rs and tests/playback.rs cover.
+
+use std::collections::{BTreeMap, HashMap};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sent_sync_server::protocol::ErrorKind;
+use sent_sync_server::room_logic::{buffering_transition, closes_when_owner_left, hand_over_ownership, joining_member_role, pauses_on_leave, transfer_ownership, BufferingTransition, Member};
+use sent_sync_server::ws_dto_models::{OwnerSuccession, Role};
+use uuid::Uuid;
+
+const SEEDS: u64 = 200;
+const STEPS: usize = 300;
+const ROOM_IDS: [&str; 3] = ["first", "second", "third"];
+
+/// Member of a `RoomMachine`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ModelMember {
+    uid: Uuid,
+    role: Role,
+    spectator: bool,
+    buffering: bool,
+    joined_at: u64,
+}
+
+impl Member for ModelMember {
+    type JoinOrder = u64;
+
+    fn role(&self) -> Role {
+        self.role
+    }
+
+    fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+    fn joined_at(&self) -> u64 {
+        self.joined_at
+    }
+}
+
+/// Something a member does in their room
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RoomEvent {
+    Join { spectator: bool },
+    Leave,
+    TransferOwnership { to_uid: Uuid },
+    SetRole { uid: Uuid, role: Role },
+    Play { position: f64 },
+    Pause { position: f64 },
+    Seek { position: f64 },
+    Buffering { buffering: bool },
+}
+
+/// What the members would be told after a `RoomEvent`, the tests only look at some of it
+#[allow(dead_code)]
+#[derive(Debug)]
+enum RoomEffect {
+    Joined { uid: Uuid, role: Role },
+    Left { uid: Uuid },
+    OwnershipTransferred { from_uid: Uuid, to_uid: Uuid, automatic: bool },
+    RoleChanged { uid: Uuid, role: Role },
+    Playback { playing: bool, position: f64 },
+    PausedForBuffering,
+    ResumedAfterBuffering,
+    PausedOnLeave,
+    Closed,
+    Denied(ErrorKind),
+}
+
+/// One room as far as membership, ownership and play state go. Playback commands of every member
+/// are applied, permissions are left to the handlers.
+#[derive(Debug, Clone)]
+struct RoomMachine {
+    members: Vec<ModelMember>,
+    owner_succession: OwnerSuccession,
+    admin_by_default: bool,
+    pause_on_leave: bool,
+    allow_stop_due_to_video_loading: bool,
+    playing: bool,
+    position: f64,
+    paused_for_buffering: bool,
+    closed: bool,
+    joins: u64,
+}
+
+impl RoomMachine {
+    fn new(owner_succession: OwnerSuccession) -> Self {
+        RoomMachine {
+            members: Vec::new(),
+            owner_succession,
+            admin_by_default: false,
+            pause_on_leave: false,
+            allow_stop_due_to_video_loading: true,
+            playing: false,
+            position: 0.0,
+            paused_for_buffering: false,
+            closed: false,
+            joins: 0,
+        }
+    }
+
+    fn member(&self, uid: Uuid) -> Option<&ModelMember> {
+        self.members.iter().find(|member| member.uid == uid)
+    }
+
+    fn owners_count(&self) -> usize {
+        self.members.iter().filter(|member| member.role == Role::Owner).count()
+    }
+
+    fn apply(&mut self, actor: Uuid, event: RoomEvent) -> Vec<RoomEffect> {
+        if self.closed {
+            return vec![RoomEffect::Denied(ErrorKind::NoSuchRoom)];
+        }
+        if !matches!(event, RoomEvent::Join { .. }) && self.member(actor).is_none() {
+            return vec![RoomEffect::Denied(ErrorKind::ClientNotInAnyRoom)];
+        }
+        let mut effects = Vec::new();
+        match event {
+            RoomEvent::Join { spectator } => {
+                // Joining the room again changes nothing
+                if let Some(member) = self.member(actor) {
+                    return vec![RoomEffect::Joined { uid: actor, role: member.role }];
+                }
+                let role = if spectator { Role::Viewer } else { joining_member_role(&self.members, self.admin_by_default) };
+                self.joins += 1;
+                self.members.push(ModelMember { uid: actor, role, spectator, buffering: false, joined_at: self.joins });
+                effects.push(RoomEffect::Joined { uid: actor, role });
+            }
+            RoomEvent::Leave => self.leave(actor, &mut effects),
+            RoomEvent::TransferOwnership { to_uid } => {
+                if !self.has_role(actor, Role::Owner) {
+                    return vec![RoomEffect::Denied(ErrorKind::Forbidden)];
+                }
+                let Some(index) = self.members.iter().position(|member| member.uid == to_uid) else {
+                    return vec![RoomEffect::Denied(ErrorKind::NoSuchClient)];
+                };
+                transfer_ownership(&mut self.members, index);
+                effects.push(RoomEffect::OwnershipTransferred { from_uid: actor, to_uid, automatic: false });
+            }
+            RoomEvent::SetRole { uid, role } => {
+                if !self.has_role(actor, Role::Owner) || role == Role::Owner || uid == actor {
+                    return vec![RoomEffect::Denied(ErrorKind::Forbidden)];
+                }
+                let Some(member) = self.members.iter_mut().find(|member| member.uid == uid) else {
+                    return vec![RoomEffect::Denied(ErrorKind::NoSuchClient)];
+                };
+                member.role = role;
+                effects.push(RoomEffect::RoleChanged { uid, role });
+            }
+            RoomEvent::Play { position } => self.play_command(Some(true), position, &mut effects),
+            RoomEvent::Pause { position } => self.play_command(Some(false), position, &mut effects),
+            RoomEvent::Seek { position } => self.play_command(None, position, &mut effects),
+            RoomEvent::Buffering { buffering } => {
+                if let Some(member) = self.members.iter_mut().find(|member| member.uid == actor) {
+                    member.buffering = buffering;
+                }
+                self.update_buffering_pause(&mut effects);
+            }
+        }
+        effects
+    }
+
+    fn has_role(&self, uid: Uuid, role: Role) -> bool {
+        self.member(uid).is_some_and(|member| member.role >= role)
+    }
+
+    fn leave(&mut self, uid: Uuid, effects: &mut Vec<RoomEffect>) {
+        let Some(index) = self.members.iter().position(|member| member.uid == uid) else {
+            return;
+        };
+        let member = self.members.remove(index);
+        effects.push(RoomEffect::Left { uid });
+        if member.role == Role::Owner {
+            if closes_when_owner_left(self.owner_succession, self.members.len()) {
+                self.members.clear();
+            } else if let Some(index) = hand_over_ownership(&mut self.members, self.owner_succession) {
+                effects.push(RoomEffect::OwnershipTransferred { from_uid: uid, to_uid: self.members[index].uid, automatic: true });
+            }
+        }
+        if self.members.is_empty() {
+            self.closed = true;
+            effects.push(RoomEffect::Closed);
+            return;
+        }
+        if self.pause_on_leave && !member.spectator && pauses_on_leave(self.playing, self.paused_for_buffering) {
+            self.paused_for_buffering = false;
+            self.playing = false;
+            effects.push(RoomEffect::PausedOnLeave);
+        }
+        self.update_buffering_pause(effects);
+    }
+
+    /// A deliberate command overrides the automatic resume
+    fn play_command(&mut self, playing: Option<bool>, position: f64, effects: &mut Vec<RoomEffect>) {
+        self.paused_for_buffering = false;
+        if let Some(playing) = playing {
+            self.playing = playing;
+        }
+        self.position = position;
+        effects.push(RoomEffect::Playback { playing: self.playing, position });
+    }
+
+    fn update_buffering_pause(&mut self, effects: &mut Vec<RoomEffect>) {
+        if !self.allow_stop_due_to_video_loading {
+            return;
+        }
+        let anybody_buffering = self.members.iter().any(|member| member.buffering);
+        match buffering_transition(anybody_buffering, self.playing, self.paused_for_buffering) {
+            Some(BufferingTransition::PauseForBuffering) => {
+                self.paused_for_buffering = true;
+                self.playing = false;
+                effects.push(RoomEffect::PausedForBuffering);
+            }
+            Some(BufferingTransition::ResumeAfterBuffering) => {
+                self.paused_for_buffering = false;
+                self.playing = true;
+                effects.push(RoomEffect::ResumedAfterBuffering);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Something a client does, rooms are opened by joining them and closed when they empty. Named
+/// after the messages.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq)]
+enum ServerEvent {
+    JoinRoom { room_id: String, spectator: bool },
+    QuitRoom,
+    /// Sent to the room the client is in
+    InRoom(RoomEvent),
+}
+
+/// Rooms of the server and who is in which
+#[derive(Debug, Clone)]
+struct ServerMachine {
+    rooms: BTreeMap<String, RoomMachine>,
+    client_rooms: HashMap<Uuid, String>,
+    owner_succession: OwnerSuccession,
+}
+
+impl ServerMachine {
+    fn new(owner_succession: OwnerSuccession) -> Self {
+        ServerMachine { rooms: BTreeMap::new(), client_rooms: HashMap::new(), owner_succession }
+    }
+
+    fn apply(&mut self, actor: Uuid, event: ServerEvent) -> Vec<RoomEffect> {
+        let mut effects = Vec::new();
+        match event {
+            ServerEvent::JoinRoom { room_id, spectator } => {
+                if !self.rooms.contains_key(&room_id) {
+                    if spectator {
+                        return vec![RoomEffect::Denied(ErrorKind::NoSuchRoom)];
+                    }
+                    self.rooms.insert(room_id.clone(), RoomMachine::new(self.owner_succession));
+                }
+                effects.extend(self.apply_in_room(&room_id, actor, RoomEvent::Join { spectator }));
+                // A client is in one room at a time, once in the new one it leaves the previous one
+                if let Some(previous_room_id) = self.client_rooms.get(&actor).cloned().filter(|previous| *previous != room_id) {
+                    effects.extend(self.apply_in_room(&previous_room_id, actor, RoomEvent::Leave));
+                }
+                self.client_rooms.insert(actor, room_id);
+            }
+            ServerEvent::QuitRoom => effects.extend(self.quit_room(actor)),
+            ServerEvent::InRoom(event) => match self.client_rooms.get(&actor).cloned() {
+                Some(room_id) => effects.extend(self.apply_in_room(&room_id, actor, event)),
+                None => effects.push(RoomEffect::Denied(ErrorKind::ClientNotInAnyRoom)),
+            },
+        }
+        effects
+    }
+
+    fn quit_room(&mut self, actor: Uuid) -> Vec<RoomEffect> {
+        match self.client_rooms.get(&actor).cloned() {
+            Some(room_id) => self.apply_in_room(&room_id, actor, RoomEvent::Leave),
+            None => vec![RoomEffect::Denied(ErrorKind::ClientNotInAnyRoom)],
+        }
+    }
+
+    /// Clients of a room closed by the event are in no room afterwards
+    fn apply_in_room(&mut self, room_id: &str, actor: Uuid, event: RoomEvent) -> Vec<RoomEffect> {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return vec![RoomEffect::Denied(ErrorKind::NoSuchRoom)];
+        };
+        let effects = room.apply(actor, event);
+        if room.closed {
+            self.rooms.remove(room_id);
+        }
+        let rooms = &self.rooms;
+        self.client_rooms.retain(|uid, room_id| rooms.get(room_id).is_some_and(|room| room.member(*uid).is_some()));
+        effects
+    }
+}
+
+fn random_event(rng: &mut StdRng, uids: &[Uuid]) -> ServerEvent {
+    let uid = uids[rng.gen_range(0..uids.len())];
+    let position = rng.gen_range(0.0..3600.0);
+    match rng.gen_range(0..10) {
+        0 | 1 => ServerEvent::JoinRoom { room_id: ROOM_IDS[rng.gen_range(0..ROOM_IDS.len())].to_string(), spectator: rng.gen_bool(0.2) },
+        2 => ServerEvent::QuitRoom,
+        3 => ServerEvent::InRoom(RoomEvent::TransferOwnership { to_uid: uid }),
+        4 => ServerEvent::InRoom(RoomEvent::SetRole { uid, role: [Role::Viewer, Role::Member, Role::Admin, Role::Owner][rng.gen_range(0..4)] }),
+        5 => ServerEvent::InRoom(RoomEvent::Play { position }),
+        6 => ServerEvent::InRoom(RoomEvent::Pause { position }),
+        7 => ServerEvent::InRoom(RoomEvent::Seek { position }),
+        _ => ServerEvent::InRoom(RoomEvent::Buffering { buffering: rng.gen_bool(0.5) }),
+    }
+}
+
+fn check_invariants(server: &ServerMachine, uids: &[Uuid], step: &str) {
+    for (room_id, room) in &server.rooms {
+        assert!(!room.closed && !room.members.is_empty(), "{}: empty room {} left open", step, room_id);
+        assert!(room.owners_count() <= 1, "{}: room {} has {} owners", step, room_id, room.owners_count());
+        if room.members.iter().any(|member| !member.spectator) {
+            assert_eq!(room.owners_count(), 1, "{}: room {} with members has no owner", step, room_id);
+        }
+        assert!(!(room.paused_for_buffering && room.playing), "{}: room {} plays while paused for buffering", step, room_id);
+    }
+    for uid in uids {
+        let rooms: Vec<&String> = server.rooms.iter().filter(|(_, room)| room.member(*uid).is_some()).map(|(room_id, _)| room_id).collect();
+        assert!(rooms.len() <= 1, "{}: client is in the rooms {:?}", step, rooms);
+        assert_eq!(rooms.first().copied(), server.client_rooms.get(uid), "{}: client is listed in the wrong room", step);
+    }
+}
+
+#[test]
+fn random_sessions_keep_one_owner_per_room_and_one_room_per_client() {
+    for owner_succession in [OwnerSuccession::LongestPresentMember, OwnerSuccession::LongestPresentAdmin, OwnerSuccession::CloseRoom] {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let uids: Vec<Uuid> = (0..8).map(|_| Uuid::from_u128(rng.r#gen())).collect();
+            let mut server = ServerMachine::new(owner_succession);
+            for step in 0..STEPS {
+                let actor = uids[rng.gen_range(0..uids.len())];
+                let event = random_event(&mut rng, &uids);
+                let description = format!("{:?} seed {} step {}: {:?}", owner_succession, seed, step, event);
+                server.apply(actor, event);
+                check_invariants(&server, &uids, &description);
+            }
+        }
+    }
+}
+
+#[test]
+fn owner_leaving_hands_over_to_the_longest_present_admin() {
+    let [owner, member, admin, spectator] = [1, 2, 3, 4].map(Uuid::from_u128);
+    let mut room = RoomMachine::new(OwnerSuccession::LongestPresentAdmin);
+    room.apply(owner, RoomEvent::Join { spectator: false });
+    room.apply(spectator, RoomEvent::Join { spectator: true });
+    room.apply(member, RoomEvent::Join { spectator: false });
+    room.apply(admin, RoomEvent::Join { spectator: false });
+    room.apply(owner, RoomEvent::SetRole { uid: admin, role: Role::Admin });
+
+    let effects = room.apply(owner, RoomEvent::Leave);
+    assert!(effects.iter().any(|effect| matches!(effect, RoomEffect::OwnershipTransferred { to_uid, automatic: true, .. } if *to_uid == admin)));
+    assert_eq!(room.member(admin).map(|member| member.role), Some(Role::Owner));
+    assert!(matches!(room.apply(member, RoomEvent::TransferOwnership { to_uid: member })[..], [RoomEffect::Denied(ErrorKind::Forbidden)]));
+}
+
+#[test]
+fn owner_leaving_closes_the_room_for_everybody_when_asked_to() {
+    let [owner, member] = [1, 2].map(Uuid::from_u128);
+    let mut server = ServerMachine::new(OwnerSuccession::CloseRoom);
+    server.apply(owner, ServerEvent::JoinRoom { room_id: "room".to_string(), spectator: false });
+    server.apply(member, ServerEvent::JoinRoom { room_id: "room".to_string(), spectator: false });
+
+    let effects = server.apply(owner, ServerEvent::QuitRoom);
+    assert!(effects.iter().any(|effect| matches!(effect, RoomEffect::Closed)));
+    assert!(server.rooms.is_empty());
+    assert!(server.client_rooms.is_empty());
+}
+
+#[test]
+fn buffering_pause_is_not_resumed_after_a_deliberate_pause() {
+    let [owner, member] = [1, 2].map(Uuid::from_u128);
+    let mut room = RoomMachine::new(OwnerSuccession::LongestPresentMember);
+    room.apply(owner, RoomEvent::Join { spectator: false });
+    room.apply(member, RoomEvent::Join { spectator: false });
+    room.apply(owner, RoomEvent::Play { position: 10.0 });
+
+    assert!(matches!(room.apply(member, RoomEvent::Buffering { buffering: true })[..], [RoomEffect::PausedForBuffering]));
+    assert!(matches!(room.apply(member, RoomEvent::Buffering { buffering: false })[..], [RoomEffect::ResumedAfterBuffering]));
+    assert!(room.playing);
+
+    room.apply(member, RoomEvent::Buffering { buffering: true });
+    room.apply(owner, RoomEvent::Pause { position: 12.0 });
+    assert!(room.apply(member, RoomEvent::Buffering { buffering: false }).is_empty());
+    assert!(!room.playing);
+}
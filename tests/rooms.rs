@@ -0,0 +1,1262 @@
+mod common;
+
+use common::{admin_request, admin_request_with_json, handshake, http_get, http_get_location, TestClient, TestServer};
+use hyper::{Method, StatusCode};
+use rocket::futures::StreamExt;
+use serde_json::json;
+use sent_sync_server::protocol::{ErrorKind, IncomingMessage, NoticeLevel, OutgoingMessage, PlayerEvent};
+use tokio_tungstenite::tungstenite::Message;
+use sent_sync_server::ws_dto_models::{ControlMode, DuplicateNames, OwnerSuccession, Role, RoomSettingsUpdateDto};
+use sent_sync_server::ServerConfig;
+use sent_sync_server::config::{CustomChannelLimits, NamespaceLimits, RelayQuotaAction, RoomTemplate};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn joining_member_is_announced_to_the_room() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "announce").await;
+    let member = TestClient::join(&server, "member", "announce").await;
+
+    let joined = owner.expect(|msg| match msg {
+        OutgoingMessage::ClientJoined { client, .. } => Some(client),
+        _ => None,
+    }).await;
+    assert_eq!(joined.uid, member.uid);
+    assert_eq!(joined.name.as_deref(), Some("member"));
+    assert!(!joined.owner);
+}
+
+#[tokio::test]
+async fn joining_member_gets_a_snapshot_before_any_event() {
+    let server = TestServer::start().await;
+    let _owner = TestClient::join(&server, "owner", "backfill").await;
+    let mut member = TestClient::connect_with_protocol_version(&server, 2).await;
+    member.send(IncomingMessage::ChangeName { new_name: "member".to_string() }).await;
+    member.expect_success().await;
+    member.send(IncomingMessage::JoinRoom { room_id: "backfill".to_string(), invite: None, spectator: false, hidden: false }).await;
+    member.expect_success().await;
+
+    match member.recv().await {
+        OutgoingMessage::RoomSnapshot { data, .. } => {
+            let mut names: Vec<_> = data.clients.iter().filter_map(|client| client.name.clone()).collect();
+            names.sort();
+            assert_eq!(names, ["member", "owner"]);
+        }
+        msg => panic!("Expected RoomSnapshot, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn large_rooms_list_the_first_members_and_page_through_the_rest() {
+    let server = TestServer::start_with(ServerConfig { max_listed_members: 2, ..ServerConfig::default() }).await;
+    let _owner = TestClient::join(&server, "owner", "crowded").await;
+    let _second = TestClient::join(&server, "second", "crowded").await;
+    let mut third = TestClient::join(&server, "third", "crowded").await;
+
+    let data = third.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { data, .. } => Some(data),
+        _ => None,
+    }).await;
+    assert_eq!(data.clients.len(), 2);
+    assert_eq!(data.member_count, 3);
+
+    third.send(IncomingMessage::GetMembers { offset: 2, limit: 10 }).await;
+    let (offset, total, members) = third.expect(|msg| match msg {
+        OutgoingMessage::Members { offset, total, members } => Some((offset, total, members)),
+        _ => None,
+    }).await;
+    assert_eq!((offset, total), (2, 3));
+    assert_eq!(members.len(), 1);
+    assert_eq!(members[0].uid, third.uid);
+}
+
+#[tokio::test]
+async fn ownership_passes_on_when_the_owner_leaves() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "handover").await;
+    let mut member = TestClient::join(&server, "member", "handover").await;
+
+    owner.send(IncomingMessage::QuitRoom).await;
+    owner.expect_success().await;
+
+    let left_uid = member.expect(|msg| match msg {
+        OutgoingMessage::ClientLeft { client_uid, .. } => Some(client_uid),
+        _ => None,
+    }).await;
+    assert_eq!(left_uid, owner.uid);
+    let new_owner = member.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } => Some(client),
+        _ => None,
+    }).await;
+    assert_eq!(new_owner.uid, member.uid);
+    assert!(new_owner.owner);
+}
+
+#[tokio::test]
+async fn ownership_passes_on_when_the_owner_disconnects() {
+    let server = TestServer::start_with(ServerConfig { disconnect_grace_period_secs: 0, ..ServerConfig::default() }).await;
+    let owner = TestClient::join(&server, "owner", "disconnect").await;
+    let mut member = TestClient::join(&server, "member", "disconnect").await;
+    let owner_uid = owner.uid;
+
+    owner.close().await;
+
+    member.expect(|msg| matches!(msg, OutgoingMessage::ClientLeft { client_uid, .. } if client_uid == owner_uid).then_some(())).await;
+    let new_owner = member.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } => Some(client),
+        _ => None,
+    }).await;
+    assert_eq!(new_owner.uid, member.uid);
+    assert!(new_owner.owner);
+}
+
+#[tokio::test]
+async fn owner_transfers_ownership() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "transfer").await;
+    let mut member = TestClient::join(&server, "member", "transfer").await;
+
+    member.send(IncomingMessage::TransferOwnership { client_uid: member.uid }).await;
+    let error = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+
+    let member_uid = member.uid;
+    owner.send(IncomingMessage::TransferOwnership { client_uid: member_uid }).await;
+    owner.expect_success().await;
+    let new_owner = member.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } if client.uid == member_uid => Some(client),
+        _ => None,
+    }).await;
+    assert!(new_owner.owner);
+}
+
+#[tokio::test]
+async fn kicked_member_is_told_who_kicked_them() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "kick").await;
+    let mut member = TestClient::join(&server, "member", "kick").await;
+
+    owner.send(IncomingMessage::KickClient { client_uid: member.uid }).await;
+    owner.expect_success().await;
+
+    let (room_id, by_uid) = member.expect(|msg| match msg {
+        OutgoingMessage::Kicked { room_id, by_uid } => Some((room_id, by_uid)),
+        _ => None,
+    }).await;
+    assert_eq!(room_id, "kick");
+    assert_eq!(by_uid, owner.uid);
+}
+
+#[tokio::test]
+async fn created_room_gets_a_code_others_can_join() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::connect(&server).await;
+    owner.send(IncomingMessage::ChangeName { new_name: "owner".to_string() }).await;
+    owner.expect_success().await;
+
+    owner.send(IncomingMessage::CreateRoom { template: None }).await;
+    let room_id = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomCreated { room_id } => Some(room_id),
+        _ => None,
+    }).await;
+    assert_eq!(room_id.chars().count(), 6);
+
+    let mut member = TestClient::connect(&server).await;
+    member.send(IncomingMessage::ChangeName { new_name: "member".to_string() }).await;
+    member.expect_success().await;
+    member.send(IncomingMessage::JoinRoom { room_id, invite: None, spectator: false, hidden: false }).await;
+    member.expect_success().await;
+}
+
+#[tokio::test]
+async fn joining_a_missing_room_fails_when_join_does_not_create_rooms() {
+    let server = TestServer::start_with(ServerConfig { join_creates_rooms: false, ..ServerConfig::default() }).await;
+    let mut client = TestClient::connect(&server).await;
+    client.send(IncomingMessage::ChangeName { new_name: "member".to_string() }).await;
+    client.expect_success().await;
+
+    client.send(IncomingMessage::JoinRoom { room_id: "mistyped".to_string(), invite: None, spectator: false, hidden: false }).await;
+    let error = client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::NoSuchRoom));
+
+    client.send(IncomingMessage::CreateRoom { template: None }).await;
+    client.expect(|msg| matches!(msg, OutgoingMessage::RoomCreated { .. }).then_some(())).await;
+}
+
+#[tokio::test]
+async fn owner_sets_room_metadata() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "metadata").await;
+    let mut member = TestClient::join(&server, "member", "metadata").await;
+
+    member.send(IncomingMessage::UpdateRoomMetadata { title: Some("Hijacked".to_string()), description: None }).await;
+    let error = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+
+    owner.send(IncomingMessage::UpdateRoomMetadata { title: Some(" Movie night ".to_string()), description: Some(String::new()) }).await;
+    owner.expect_success().await;
+    let settings = member.expect(|msg| match msg {
+        OutgoingMessage::RoomSettingsUpdated { settings, .. } => Some(settings),
+        _ => None,
+    }).await;
+    assert_eq!(settings.title.as_deref(), Some("Movie night"));
+    assert_eq!(settings.description, None);
+    assert!(settings.created_at <= settings.last_activity_at);
+}
+
+#[tokio::test]
+async fn namespaces_keep_their_rooms_apart() {
+    let mut config = ServerConfig::default();
+    config.namespaces.insert("small".to_string(), NamespaceLimits { max_rooms: 1, ..NamespaceLimits::default() });
+    let server = TestServer::start_with(config).await;
+    let mut first = TestClient::join_namespace(&server, Some("movies"), "first", "shared").await;
+    let mut second = TestClient::join_namespace(&server, Some("series"), "second", "shared").await;
+    let _third = TestClient::join_namespace(&server, Some("movies"), "third", "shared").await;
+
+    let name = first.expect(|msg| match msg {
+        OutgoingMessage::ClientJoined { client, .. } => client.name,
+        _ => None,
+    }).await;
+    assert_eq!(name, "third");
+    second.send(IncomingMessage::GetRoomInfo { room_id: "shared".to_string() }).await;
+    let info = second.expect(|msg| match msg {
+        OutgoingMessage::RoomInfo { info } => Some(info),
+        _ => None,
+    }).await;
+    assert_eq!(info.members_count, 1);
+
+    let _owner = TestClient::join_namespace(&server, Some("small"), "owner", "one").await;
+    let mut member = TestClient::join_namespace(&server, Some("small"), "member", "one").await;
+    member.send(IncomingMessage::JoinRoom { room_id: "two".to_string(), invite: None, spectator: false, hidden: false }).await;
+    let kind = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::ServerRoomLimitReached));
+}
+
+#[tokio::test]
+async fn public_rooms_are_listed() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "public").await;
+    let _private_owner = TestClient::join(&server, "other", "private").await;
+
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { public: Some(true), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+
+    let mut visitor = TestClient::connect(&server).await;
+    visitor.send(IncomingMessage::ListPublicRooms).await;
+    let rooms = visitor.expect(|msg| match msg {
+        OutgoingMessage::PublicRooms { rooms } => Some(rooms),
+        _ => None,
+    }).await;
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0].room_id, "public");
+    assert_eq!(rooms[0].members_count, 1);
+}
+
+#[tokio::test]
+async fn subscribers_follow_public_rooms() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "followed").await;
+    let mut observer = TestClient::join(&server, "observer", "elsewhere").await;
+
+    observer.send(IncomingMessage::Subscribe { room_id: "followed".to_string() }).await;
+    let kind = observer.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::NoSuchRoom));
+
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { public: Some(true), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+
+    observer.send(IncomingMessage::Subscribe { room_id: "followed".to_string() }).await;
+    let (room_id, data) = observer.expect(|msg| match msg {
+        OutgoingMessage::SubscribedRoomChanged { room_id, data } => Some((room_id, data)),
+        _ => None,
+    }).await;
+    assert_eq!(room_id, "followed");
+    assert_eq!(data.clients.len(), 1);
+
+    owner.send(IncomingMessage::UpdateRoomMetadata { title: Some("Movie night".to_string()), description: None }).await;
+    owner.expect_success().await;
+    let title = observer.expect(|msg| match msg {
+        OutgoingMessage::SubscribedRoomChanged { data, .. } => Some(data.settings.title),
+        _ => None,
+    }).await;
+    assert_eq!(title.as_deref(), Some("Movie night"));
+
+    observer.send(IncomingMessage::Unsubscribe { room_id: "followed".to_string() }).await;
+    observer.expect_success().await;
+}
+
+#[tokio::test]
+async fn room_info_is_shown_without_joining() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "preview").await;
+    owner.send(IncomingMessage::UpdateRoomMetadata { title: Some("Movie night".to_string()), description: None }).await;
+    owner.expect_success().await;
+
+    let mut visitor = TestClient::connect(&server).await;
+    visitor.send(IncomingMessage::GetRoomInfo { room_id: "preview".to_string() }).await;
+    let info = visitor.expect(|msg| match msg {
+        OutgoingMessage::RoomInfo { info } => Some(info),
+        _ => None,
+    }).await;
+    assert_eq!(info.title.as_deref(), Some("Movie night"));
+    assert_eq!(info.members_count, 1);
+
+    visitor.send(IncomingMessage::GetRoomInfo { room_id: "missing".to_string() }).await;
+    let error = visitor.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::NoSuchRoom));
+}
+
+#[tokio::test]
+async fn invite_is_used_up() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "invite").await;
+    owner.send(IncomingMessage::CreateInvite { max_uses: Some(1), expires_in_secs: None }).await;
+    let token = owner.expect(|msg| match msg {
+        OutgoingMessage::InviteCreated { token, .. } => Some(token),
+        _ => None,
+    }).await;
+
+    let mut guests = Vec::new();
+    for name in ["first", "second"] {
+        let mut guest = TestClient::connect(&server).await;
+        guest.send(IncomingMessage::ChangeName { new_name: name.to_string() }).await;
+        guest.expect_success().await;
+        guest.send(IncomingMessage::JoinRoom { room_id: "invite".to_string(), invite: Some(token.clone()), spectator: false, hidden: false }).await;
+        guests.push(guest);
+    }
+    guests[0].expect_success().await;
+    let error = guests[1].expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::InvalidInvite));
+}
+
+#[tokio::test]
+async fn muted_member_cannot_chat() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "mute").await;
+    let mut member = TestClient::join(&server, "member", "mute").await;
+    let member_uid = member.uid;
+
+    owner.send(IncomingMessage::MuteClient { client_uid: member_uid, duration_secs: 60 }).await;
+    owner.expect_success().await;
+    let muted = member.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } if client.uid == member_uid => Some(client),
+        _ => None,
+    }).await;
+    assert!(muted.muted_until.is_some());
+
+    member.send(IncomingMessage::ChatMessage { text: "hello".to_string() }).await;
+    let error = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Muted));
+
+    owner.send(IncomingMessage::MuteClient { client_uid: member_uid, duration_secs: 0 }).await;
+    owner.expect_success().await;
+    member.send(IncomingMessage::ChatMessage { text: "hello".to_string() }).await;
+    member.expect_success().await;
+}
+
+#[tokio::test]
+async fn owner_sets_member_roles() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "roles").await;
+    let mut member = TestClient::join(&server, "member", "roles").await;
+    let member_uid = member.uid;
+
+    owner.send(IncomingMessage::SetRole { client_uid: member_uid, role: Role::Owner }).await;
+    let error = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+
+    owner.send(IncomingMessage::SetRole { client_uid: member_uid, role: Role::Admin }).await;
+    owner.expect_success().await;
+    let admin = member.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } if client.uid == member_uid => Some(client),
+        _ => None,
+    }).await;
+    assert_eq!(admin.role, Role::Admin);
+    assert!(admin.admin && !admin.owner);
+
+    owner.send(IncomingMessage::SetRole { client_uid: member_uid, role: Role::Viewer }).await;
+    owner.expect_success().await;
+    let viewer = member.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } if client.uid == member_uid => Some(client),
+        _ => None,
+    }).await;
+    assert_eq!(viewer.role, Role::Viewer);
+    assert!(!viewer.admin);
+}
+
+#[tokio::test]
+async fn spectators_are_only_counted() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "lurkers").await;
+
+    let mut spectator = TestClient::connect(&server).await;
+    spectator.send(IncomingMessage::ChangeName { new_name: "lurker".to_string() }).await;
+    spectator.expect_success().await;
+    spectator.send(IncomingMessage::JoinRoom { room_id: "lurkers".to_string(), invite: None, spectator: true, hidden: false }).await;
+    spectator.expect_success().await;
+    let data = spectator.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { data, .. } => Some(data),
+        _ => None,
+    }).await;
+    assert_eq!(data.clients.len(), 1);
+    assert_eq!(data.spectator_count, 1);
+
+    let spectator_count = owner.expect(|msg| match msg {
+        OutgoingMessage::SpectatorCountChanged { spectator_count, .. } => Some(spectator_count),
+        OutgoingMessage::ClientJoined { .. } => panic!("Spectator announced as a member"),
+        _ => None,
+    }).await;
+    assert_eq!(spectator_count, 1);
+
+    spectator.send(IncomingMessage::ChatMessage { text: "hello".to_string() }).await;
+    let error = spectator.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+}
+
+#[tokio::test]
+async fn spectators_neither_start_playback_nor_vote() {
+    let server = TestServer::start().await;
+    let _owner = TestClient::join(&server, "owner", "spectator-controls").await;
+
+    let mut spectator = TestClient::connect(&server).await;
+    spectator.send(IncomingMessage::ChangeName { new_name: "lurker".to_string() }).await;
+    spectator.expect_success().await;
+    spectator.send(IncomingMessage::JoinRoom { room_id: "spectator-controls".to_string(), invite: None, spectator: true, hidden: false }).await;
+    spectator.expect_success().await;
+
+    spectator.send(IncomingMessage::PlayerEvent { event: PlayerEvent::StartPlaying { at_second: 0.0 } }).await;
+    let error = spectator.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+
+    spectator.send(IncomingMessage::Vote { poll_id: Uuid::new_v4(), option: 0 }).await;
+    let error = spectator.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+}
+
+#[tokio::test]
+async fn breakout_rooms_need_a_member_each() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "breakout").await;
+    let _member = TestClient::join(&server, "member", "breakout").await;
+
+    let mut spectator = TestClient::connect(&server).await;
+    spectator.send(IncomingMessage::ChangeName { new_name: "lurker".to_string() }).await;
+    spectator.expect_success().await;
+    spectator.send(IncomingMessage::JoinRoom { room_id: "breakout".to_string(), invite: None, spectator: true, hidden: false }).await;
+    spectator.expect_success().await;
+
+    owner.send(IncomingMessage::CreateBreakoutRooms { count: 2 }).await;
+    let error = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::InvalidBreakoutRoomCount));
+}
+
+#[tokio::test]
+async fn banning_a_client_of_another_room_records_only_its_uid() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "bans").await;
+    let mut outsider = TestClient::join(&server, "outsider", "elsewhere").await;
+
+    owner.send(IncomingMessage::BanClient { client_uid: outsider.uid, ban_ip: true }).await;
+    owner.expect_success().await;
+    let bans = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomSettingsUpdated { settings, .. } => Some(settings.bans),
+        _ => None,
+    }).await;
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].client_uid, outsider.uid);
+    assert_eq!(bans[0].name, None);
+
+    outsider.send(IncomingMessage::ChatMessage { text: "still here".to_string() }).await;
+    outsider.expect_success().await;
+}
+
+#[tokio::test]
+async fn control_mode_decides_who_controls_playback() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "control").await;
+    let mut member = TestClient::join(&server, "member", "control").await;
+
+    member.send(IncomingMessage::Seek { position: 10.0 }).await;
+    let error = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { control_mode: Some(ControlMode::Everyone), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+    let settings = member.expect(|msg| match msg {
+        OutgoingMessage::RoomSettingsUpdated { settings, .. } => Some(settings),
+        _ => None,
+    }).await;
+    assert_eq!(settings.control_mode, ControlMode::Everyone);
+
+    member.send(IncomingMessage::Seek { position: 10.0 }).await;
+    member.expect_success().await;
+    let position = owner.expect(|msg| match msg {
+        OutgoingMessage::Seek { position, .. } => Some(position),
+        _ => None,
+    }).await;
+    assert_eq!(position, 10.0);
+}
+
+#[tokio::test]
+async fn custom_messages_are_relayed_to_the_others() {
+    let server = TestServer::start().await;
+    let mut sender = TestClient::join(&server, "sender", "extensions").await;
+    let mut receiver = TestClient::join(&server, "receiver", "extensions").await;
+
+    let payload = serde_json::json!({ "x": 1, "items": ["a", "b"] });
+    sender.send(IncomingMessage::Custom { channel: "drawing".to_string(), payload: payload.clone() }).await;
+    sender.expect_success().await;
+    let (channel, received, from_uid) = receiver.expect(|msg| match msg {
+        OutgoingMessage::Custom { channel, payload, from_uid } => Some((channel, payload, from_uid)),
+        _ => None,
+    }).await;
+    assert_eq!(channel, "drawing");
+    assert_eq!(received, payload);
+    assert_eq!(from_uid, sender.uid);
+
+    let oversized = serde_json::json!("x".repeat(8 * 1024));
+    sender.send(IncomingMessage::Custom { channel: "drawing".to_string(), payload: oversized }).await;
+    let error = sender.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::PayloadTooLarge));
+}
+
+#[tokio::test]
+async fn relays_over_the_room_quota_are_throttled_and_traffic_is_listed() {
+    let server = TestServer::start_with(ServerConfig {
+        admin_token: Some("admin-secret".to_string()),
+        room_relay_quota_bytes_per_minute: 100,
+        room_relay_quota_action: RelayQuotaAction::Throttle,
+        ..ServerConfig::default()
+    }).await;
+    let mut sender = TestClient::join(&server, "sender", "relay-quota").await;
+    let mut receiver = TestClient::join(&server, "receiver", "relay-quota").await;
+
+    let payload = serde_json::json!("x".repeat(60));
+    sender.send(IncomingMessage::Custom { channel: "drawing".to_string(), payload: payload.clone() }).await;
+    sender.expect_success().await;
+    receiver.expect(|msg| matches!(msg, OutgoingMessage::Custom { .. }).then_some(())).await;
+    sender.send(IncomingMessage::Custom { channel: "drawing".to_string(), payload }).await;
+    let (kind, retry_after) = sender.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, retry_after, .. } => Some((kind, retry_after)),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::RelayQuotaExceeded));
+    assert!(retry_after.is_some_and(|retry_after| retry_after <= 60_000));
+
+    let (status, rooms) = admin_request(&server, Method::GET, "/api/rooms", "admin-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    let room = rooms.as_array().unwrap().iter().find(|room| room["roomId"] == "relay-quota").unwrap();
+    assert!(room["bytesReceived"].as_u64().unwrap() > 120);
+    assert!(room["bytesSent"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn client_meta_is_listed_to_the_others() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "meta").await;
+    let mut member = TestClient::join(&server, "member", "meta").await;
+
+    member.send(IncomingMessage::SetClientMeta { key: "platform".to_string(), value: Some("Firefox".to_string()) }).await;
+    member.expect_success().await;
+    let meta = owner.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } if client.uid == member.uid => Some(client.meta),
+        _ => None,
+    }).await;
+    assert_eq!(meta.get("platform").map(String::as_str), Some("Firefox"));
+
+    member.send(IncomingMessage::SetClientMeta { key: "bad key".to_string(), value: None }).await;
+    let error = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::InvalidClientMeta));
+}
+
+#[tokio::test]
+async fn rtc_signals_reach_only_the_addressed_member() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "voice").await;
+    let mut caller = TestClient::join(&server, "caller", "voice").await;
+    let mut bystander = TestClient::join(&server, "bystander", "voice").await;
+
+    caller.send(IncomingMessage::RtcOffer { to_uid: owner.uid, sdp: "v=0".to_string() }).await;
+    let error = caller.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::VoiceDisabled));
+
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { voice_enabled: Some(true), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+
+    caller.send(IncomingMessage::RtcOffer { to_uid: owner.uid, sdp: "v=0".to_string() }).await;
+    caller.expect_success().await;
+    let from_uid = owner.expect(|msg| match msg {
+        OutgoingMessage::RtcOffer { from_uid, sdp } if sdp == "v=0" => Some(from_uid),
+        _ => None,
+    }).await;
+    assert_eq!(from_uid, caller.uid);
+
+    // The bystander's next message after the offer is the answer to its own request
+    bystander.send(IncomingMessage::Ping { client_time: None, rtt_ms: None }).await;
+    bystander.expect(|msg| match msg {
+        OutgoingMessage::RtcOffer { .. } => panic!("Offer relayed to a bystander"),
+        OutgoingMessage::Pong { .. } => Some(()),
+        _ => None,
+    }).await;
+}
+
+#[tokio::test]
+async fn missed_events_are_replayed() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "resync").await;
+    let joined_seq = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { seq, .. } => Some(seq),
+        _ => None,
+    }).await;
+    let member = TestClient::join(&server, "member", "resync").await;
+    owner.expect(|msg| matches!(msg, OutgoingMessage::ClientJoined { .. }).then_some(())).await;
+
+    owner.send(IncomingMessage::ResyncFrom { seq: joined_seq }).await;
+    let (seq, client) = owner.expect(|msg| match msg {
+        OutgoingMessage::ClientJoined { seq, client } => Some((seq, client)),
+        _ => None,
+    }).await;
+    assert_eq!(seq, joined_seq + 1);
+    assert_eq!(client.uid, member.uid);
+    owner.expect_success().await;
+
+    owner.send(IncomingMessage::ResyncFrom { seq: u64::MAX }).await;
+    let seq = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { seq, .. } => Some(seq),
+        _ => None,
+    }).await;
+    assert_eq!(seq, joined_seq + 1);
+}
+
+#[tokio::test]
+async fn stats_count_everybody_and_list_rooms_for_moderators() {
+    let server = TestServer::start_with(ServerConfig { admin_token: Some("admin-secret".to_string()), ..ServerConfig::default() }).await;
+    let _owner = TestClient::join(&server, "owner", "counted").await;
+    let _member = TestClient::join(&server, "member", "counted").await;
+
+    let mut visitor = TestClient::connect(&server).await;
+    visitor.send(IncomingMessage::GetStats).await;
+    let stats = visitor.expect(|msg| match msg {
+        OutgoingMessage::Stats { stats } => Some(stats),
+        _ => None,
+    }).await;
+    assert_eq!((stats.rooms_count, stats.watching_count, stats.clients_count), (1, 2, 3));
+    assert!(stats.rooms.is_none());
+
+    visitor.send(IncomingMessage::Authenticate { token: "admin-secret".to_string() }).await;
+    visitor.expect_success().await;
+    visitor.send(IncomingMessage::GetStats).await;
+    let rooms = visitor.expect(|msg| match msg {
+        OutgoingMessage::Stats { stats } => Some(stats.rooms),
+        _ => None,
+    }).await.expect("Rooms not listed for a moderator");
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0].members_count, 2);
+}
+
+#[tokio::test]
+async fn duplicate_names_are_suffixed_or_rejected() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "Alex", "namesakes").await;
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { duplicate_names: Some(DuplicateNames::Suffix), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+
+    let _namesake = TestClient::join(&server, "alex", "namesakes").await;
+    let name = owner.expect(|msg| match msg {
+        OutgoingMessage::ClientJoined { client, .. } => Some(client.name),
+        _ => None,
+    }).await;
+    assert_eq!(name.as_deref(), Some("alex (2)"));
+
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { duplicate_names: Some(DuplicateNames::Reject), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+    let mut renamer = TestClient::join(&server, "Sam", "namesakes").await;
+    renamer.send(IncomingMessage::ChangeName { new_name: "ALEX".to_string() }).await;
+    let error = renamer.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::NameTaken));
+}
+
+#[tokio::test]
+async fn room_changed_describes_the_whole_room() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "described").await;
+    owner.send(IncomingMessage::SetPageUrl { url: "https://example.com/video".to_string(), nonce: None, signature: None }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { control_mode: Some(ControlMode::Everyone), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+
+    let mut late_joiner = TestClient::join(&server, "late", "described").await;
+    let data = late_joiner.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { data, .. } => Some(data),
+        _ => None,
+    }).await;
+    assert_eq!(data.clients.len(), 2);
+    assert_eq!(data.settings.page_url.as_deref(), Some("https://example.com/video"));
+    assert_eq!(data.settings.control_mode, ControlMode::Everyone);
+    assert!(!data.settings.playback.playing);
+}
+
+#[tokio::test]
+async fn members_cannot_make_admins() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "admins").await;
+    let mut member = TestClient::join(&server, "member", "admins").await;
+    let mut other = TestClient::join(&server, "other", "admins").await;
+
+    member.send(IncomingMessage::ChangeClientAdminStatus { client_uid: other.uid, admin: true }).await;
+    let error = member.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(error, ErrorKind::Forbidden));
+
+    // The refused change is not broadcast, the next update of the member is the owner's
+    owner.send(IncomingMessage::ChangeClientAdminStatus { client_uid: other.uid, admin: false }).await;
+    owner.expect_success().await;
+    let other_uid = other.uid;
+    let updated = other.expect(|msg| match msg {
+        OutgoingMessage::ClientUpdated { client, .. } if client.uid == other_uid => Some(client),
+        _ => None,
+    }).await;
+    assert!(!updated.admin);
+}
+
+#[tokio::test]
+async fn owner_succession_follows_the_room_setting() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "succession").await;
+    let mut member = TestClient::join(&server, "member", "succession").await;
+    let mut admin = TestClient::join(&server, "admin", "succession").await;
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { owner_succession: Some(OwnerSuccession::LongestPresentAdmin), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::ChangeClientAdminStatus { client_uid: admin.uid, admin: true }).await;
+    owner.expect_success().await;
+
+    let owner_uid = owner.uid;
+    owner.send(IncomingMessage::QuitRoom).await;
+    let (from_uid, to_uid, automatic) = member.expect(|msg| match msg {
+        OutgoingMessage::OwnershipTransferred { from_uid, to_uid, automatic, .. } => Some((from_uid, to_uid, automatic)),
+        _ => None,
+    }).await;
+    assert_eq!((from_uid, to_uid, automatic), (owner_uid, admin.uid, true));
+
+    admin.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { owner_succession: Some(OwnerSuccession::CloseRoom), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    admin.expect_success().await;
+    admin.send(IncomingMessage::QuitRoom).await;
+    member.expect(|msg| match msg {
+        OutgoingMessage::RoomClosed { .. } => Some(()),
+        OutgoingMessage::OwnershipTransferred { .. } => panic!("Ownership handed over instead of closing"),
+        _ => None,
+    }).await;
+}
+
+#[tokio::test]
+async fn closing_room_sends_a_session_summary() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "summary").await;
+    let mut guest = TestClient::join(&server, "guest", "summary").await;
+    for url in ["https://example.com/first", "https://example.com/second"] {
+        owner.send(IncomingMessage::SetPageUrl { url: url.to_string(), nonce: None, signature: None }).await;
+        owner.expect_success().await;
+    }
+    owner.send(IncomingMessage::UpdateRoomSettings {
+        settings: RoomSettingsUpdateDto { owner_succession: Some(OwnerSuccession::CloseRoom), ..RoomSettingsUpdateDto::default() },
+    }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::QuitRoom).await;
+
+    let summary = guest.expect(|msg| match msg {
+        OutgoingMessage::SessionSummary { summary } => Some(summary),
+        OutgoingMessage::RoomClosed { .. } => panic!("Room closed without a summary"),
+        _ => None,
+    }).await;
+    assert_eq!(summary.room_id, "summary");
+    assert_eq!(summary.peak_viewers, 2);
+    assert_eq!(summary.videos_played, 2);
+    guest.expect(|msg| matches!(msg, OutgoingMessage::RoomClosed { .. }).then_some(())).await;
+}
+
+#[tokio::test]
+async fn reports_reach_the_admin_api_with_the_chat() {
+    let server = TestServer::start_with(ServerConfig { admin_token: Some("admin-secret".to_string()), ..ServerConfig::default() }).await;
+    let mut owner = TestClient::join(&server, "owner", "reported").await;
+    let mut troll = TestClient::join(&server, "troll", "reported").await;
+    troll.send(IncomingMessage::ChatMessage { text: "something rude".to_string() }).await;
+    troll.expect_success().await;
+
+    owner.send(IncomingMessage::ReportClient { client_uid: troll.uid, reason: " ".to_string() }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::InvalidReport));
+    owner.send(IncomingMessage::ReportClient { client_uid: troll.uid, reason: "Insults".to_string() }).await;
+    owner.expect_success().await;
+
+    let (status, reports) = admin_request(&server, Method::GET, "/api/reports", "admin-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    let report = &reports[0];
+    assert_eq!(report["reporterUid"], owner.uid.to_string());
+    assert_eq!(report["targetUid"], troll.uid.to_string());
+    assert_eq!(report["reason"], "Insults");
+    assert_eq!(report["chat"][0]["text"], "something rude");
+}
+
+#[tokio::test]
+async fn closed_rooms_are_archived_and_can_be_restored() {
+    let archive_path = std::env::temp_dir().join(format!("sent-sync-archive-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&archive_path);
+    let server = TestServer::start_with(ServerConfig {
+        admin_token: Some("admin-secret".to_string()),
+        archive_path: Some(archive_path.display().to_string()),
+        ..ServerConfig::default()
+    }).await;
+    let mut owner = TestClient::join(&server, "owner", "last-night").await;
+    owner.send(IncomingMessage::UpdateRoomMetadata { title: Some("Movie night".to_string()), description: None }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::QueueAdd { url: "https://example.com/next".to_string(), nonce: None, signature: None }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::QuitRoom).await;
+    owner.expect_success().await;
+
+    let (status, rooms) = admin_request(&server, Method::GET, "/api/archive?room_id=last-night", "admin-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rooms[0]["title"], "Movie night");
+    assert_eq!(rooms[0]["queue"][0], "https://example.com/next");
+
+    owner.send(IncomingMessage::RestoreRoom { room_id: "last-night".to_string() }).await;
+    owner.expect_success().await;
+    let data = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { data, .. } => Some(data),
+        _ => None,
+    }).await;
+    assert_eq!(data.settings.title.as_deref(), Some("Movie night"));
+    assert_eq!(data.settings.queue, ["https://example.com/next"]);
+
+    let mut other = TestClient::connect(&server).await;
+    other.send(IncomingMessage::ChangeName { new_name: "other".to_string() }).await;
+    other.expect_success().await;
+    other.send(IncomingMessage::RestoreRoom { room_id: "last-night".to_string() }).await;
+    let kind = other.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::RoomAlreadyOpen));
+    std::fs::remove_file(archive_path).unwrap();
+}
+
+#[tokio::test]
+async fn scheduled_rooms_refuse_members_before_the_start() {
+    let server = TestServer::start().await;
+    let starts_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64 + 60 * 60 * 1000;
+    let mut host = TestClient::connect(&server).await;
+    host.send(IncomingMessage::ChangeName { new_name: "host".to_string() }).await;
+    host.expect_success().await;
+    host.send(IncomingMessage::ScheduleRoom { room_id: "premiere".to_string(), starts_at, page_url: None }).await;
+    host.expect_success().await;
+
+    let mut guest = TestClient::connect(&server).await;
+    guest.send(IncomingMessage::ChangeName { new_name: "guest".to_string() }).await;
+    guest.expect_success().await;
+    guest.send(IncomingMessage::JoinRoom { room_id: "premiere".to_string(), invite: None, spectator: false, hidden: false }).await;
+    let (kind, retry_after) = guest.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, retry_after, .. } => Some((kind, retry_after)),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::RoomNotStarted { starts_at: scheduled } if scheduled == starts_at));
+    assert!(retry_after.is_some_and(|retry_after| retry_after > 59 * 60 * 1000));
+
+    host.send(IncomingMessage::JoinRoom { room_id: "premiere".to_string(), invite: None, spectator: false, hidden: false }).await;
+    host.expect_success().await;
+    let data = host.expect(|msg| match msg {
+        OutgoingMessage::RoomChanged { data, .. } => Some(data),
+        _ => None,
+    }).await;
+    assert_eq!(data.settings.starts_at, Some(starts_at));
+}
+
+#[tokio::test]
+async fn locked_room_refuses_new_members() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "locked").await;
+    owner.send(IncomingMessage::LockRoom { locked: true }).await;
+    owner.expect_success().await;
+
+    let mut guest = TestClient::connect(&server).await;
+    guest.send(IncomingMessage::ChangeName { new_name: "guest".to_string() }).await;
+    guest.expect_success().await;
+    guest.send(IncomingMessage::JoinRoom { room_id: "locked".to_string(), invite: None, spectator: false, hidden: false }).await;
+    let kind = guest.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::RoomLocked));
+
+    owner.send(IncomingMessage::LockRoom { locked: false }).await;
+    owner.expect_success().await;
+    guest.send(IncomingMessage::JoinRoom { room_id: "locked".to_string(), invite: None, spectator: false, hidden: false }).await;
+    guest.expect_success().await;
+}
+
+#[tokio::test]
+async fn renamed_room_keeps_its_members_and_old_id() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "before").await;
+    let mut member = TestClient::join(&server, "member", "before").await;
+    let _other_owner = TestClient::join(&server, "other", "taken").await;
+
+    owner.send(IncomingMessage::RenameRoom { new_room_id: "taken".to_string() }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::AliasTaken));
+
+    owner.send(IncomingMessage::RenameRoom { new_room_id: "after".to_string() }).await;
+    owner.expect_success().await;
+    let (old_room_id, new_room_id) = member.expect(|msg| match msg {
+        OutgoingMessage::RoomRenamed { old_room_id, new_room_id } => Some((old_room_id, new_room_id)),
+        _ => None,
+    }).await;
+    assert_eq!((old_room_id.as_str(), new_room_id.as_str()), ("before", "after"));
+
+    let late_joiner = TestClient::join(&server, "late", "before").await;
+    let client_uid = member.expect(|msg| match msg {
+        OutgoingMessage::ClientJoined { client, .. } => Some(client.uid),
+        _ => None,
+    }).await;
+    assert_eq!(client_uid, late_joiner.uid);
+
+    member.send(IncomingMessage::ChatMessage { text: "still here".to_string() }).await;
+    member.expect_success().await;
+    owner.expect(|msg| matches!(msg, OutgoingMessage::ChatMessage { .. }).then_some(())).await;
+}
+
+#[tokio::test]
+async fn created_room_starts_with_the_settings_of_its_template() {
+    let template = RoomTemplate { control_mode: Some(ControlMode::Vote), public: Some(true), max_clients: Some(2), ..RoomTemplate::default() };
+    let config = ServerConfig { room_templates: [("movie-night".to_string(), template)].into(), ..ServerConfig::default() };
+    let server = TestServer::start_with(config).await;
+    let mut owner = TestClient::connect(&server).await;
+    owner.send(IncomingMessage::ChangeName { new_name: "owner".to_string() }).await;
+    owner.expect_success().await;
+
+    owner.send(IncomingMessage::CreateRoom { template: Some("matinee".to_string()) }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::NoSuchTemplate));
+
+    owner.send(IncomingMessage::CreateRoom { template: Some("movie-night".to_string()) }).await;
+    let room_id = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomCreated { room_id } => Some(room_id),
+        _ => None,
+    }).await;
+    let settings = owner.expect(|msg| match msg {
+        OutgoingMessage::RoomSnapshot { data, .. } | OutgoingMessage::RoomChanged { data, .. } => Some(data.settings),
+        _ => None,
+    }).await;
+    assert_eq!(settings.control_mode, ControlMode::Vote);
+    assert!(settings.public);
+    assert_eq!(settings.max_clients, Some(2));
+
+    let _member = TestClient::join(&server, "member", &room_id).await;
+    let mut latecomer = TestClient::connect(&server).await;
+    latecomer.send(IncomingMessage::ChangeName { new_name: "latecomer".to_string() }).await;
+    latecomer.expect_success().await;
+    latecomer.send(IncomingMessage::JoinRoom { room_id, invite: None, spectator: false, hidden: false }).await;
+    let kind = latecomer.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::RoomFull));
+}
+
+#[tokio::test]
+async fn operators_send_notices_to_every_client_or_one_room() {
+    let server = TestServer::start_with(ServerConfig {
+        admin_token: Some("admin-secret".to_string()),
+        motd: Some("Welcome to the watch party server".to_string()),
+        ..ServerConfig::default()
+    }).await;
+    let mut stream = handshake(&server, "").await.expect("WebSocket handshake failed");
+    let mut first_messages = Vec::new();
+    while first_messages.len() < 2 {
+        if let Some(Ok(Message::Text(text))) = stream.next().await {
+            first_messages.push(serde_json::from_str::<OutgoingMessage>(&text).expect("Invalid message"));
+        }
+    }
+    assert!(matches!(first_messages[0], OutgoingMessage::ClientUid { .. }));
+    assert!(matches!(&first_messages[1], OutgoingMessage::ServerNotice { text, level: NoticeLevel::Info } if text == "Welcome to the watch party server"));
+
+    let mut member = TestClient::join(&server, "member", "maintenance").await;
+    let mut outsider = TestClient::connect(&server).await;
+    let (status, _) = admin_request_with_json(&server, Method::POST, "/api/notices", "admin-secret", json!({
+        "text": "Restarting in 5 minutes",
+        "level": "warning",
+        "roomId": "maintenance",
+    })).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (text, level) = member.expect(|msg| match msg {
+        OutgoingMessage::ServerNotice { text, level } => Some((text, level)),
+        _ => None,
+    }).await;
+    assert_eq!(text, "Restarting in 5 minutes");
+    assert_eq!(level, NoticeLevel::Warning);
+
+    let (status, _) = admin_request_with_json(&server, Method::POST, "/api/notices", "admin-secret", json!({ "text": "Back soon" })).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let text = outsider.expect(|msg| match msg {
+        OutgoingMessage::ServerNotice { text, .. } => Some(text),
+        _ => None,
+    }).await;
+    assert_eq!(text, "Back soon");
+
+    let (status, _) = admin_request_with_json(&server, Method::POST, "/api/notices", "admin-secret", json!({ "text": "Hi", "roomId": "missing" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn draining_server_keeps_its_rooms_but_opens_no_new_ones() {
+    let server = TestServer::start_with(ServerConfig { admin_token: Some("admin-secret".to_string()), ..ServerConfig::default() }).await;
+    let mut owner = TestClient::join(&server, "owner", "before-deploy").await;
+    assert_eq!(http_get(&server, "/ready").await.0, StatusCode::OK);
+
+    let (status, drain) = admin_request(&server, Method::POST, "/api/drain", "admin-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(drain["draining"], true);
+    assert_eq!(drain["roomsCount"], 1);
+    assert_eq!(http_get(&server, "/ready").await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+    owner.send(IncomingMessage::Seek { position: 12.0 }).await;
+    owner.expect_success().await;
+    let mut latecomer = TestClient::connect(&server).await;
+    latecomer.send(IncomingMessage::ChangeName { new_name: "latecomer".to_string() }).await;
+    latecomer.expect_success().await;
+    for msg in [IncomingMessage::JoinRoom { room_id: "before-deploy".to_string(), invite: None, spectator: false, hidden: false }, IncomingMessage::CreateRoom { template: None }] {
+        latecomer.send(msg).await;
+        let kind = latecomer.expect(|msg| match msg {
+            OutgoingMessage::Error { kind, .. } => Some(kind),
+            _ => None,
+        }).await;
+        assert!(matches!(kind, ErrorKind::ServerDraining));
+    }
+
+    let (status, drain) = admin_request(&server, Method::DELETE, "/api/drain", "admin-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(drain["draining"], false);
+    assert_eq!(http_get(&server, "/ready").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn joining_another_room_leaves_the_previous_one() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "first-room").await;
+    let mut member = TestClient::join(&server, "member", "first-room").await;
+
+    member.send(IncomingMessage::JoinRoom { room_id: "second-room".to_string(), invite: None, spectator: false, hidden: false }).await;
+    member.expect_success().await;
+    let left_uid = owner.expect(|msg| match msg {
+        OutgoingMessage::ClientLeft { client_uid, .. } => Some(client_uid),
+        _ => None,
+    }).await;
+    assert_eq!(left_uid, member.uid);
+
+    // Joining the room again doesn't list the member twice
+    member.send(IncomingMessage::JoinRoom { room_id: "second-room".to_string(), invite: None, spectator: false, hidden: false }).await;
+    member.expect_success().await;
+    let mut newcomer = TestClient::join(&server, "newcomer", "second-room").await;
+    newcomer.send(IncomingMessage::GetRoomInfo { room_id: "second-room".to_string() }).await;
+    let info = newcomer.expect(|msg| match msg {
+        OutgoingMessage::RoomInfo { info } => Some(info),
+        _ => None,
+    }).await;
+    assert_eq!(info.members_count, 2);
+}
+
+#[tokio::test]
+async fn encrypted_rooms_relay_chat_and_custom_messages_only_as_ciphertext() {
+    let server = TestServer::start_with(ServerConfig {
+        custom_channels: [("drawing".to_string(), CustomChannelLimits { max_payload_size: 16, ..CustomChannelLimits::default() })].into(),
+        ..ServerConfig::default()
+    }).await;
+    let mut owner = TestClient::join(&server, "owner", "encrypted").await;
+    let mut member = TestClient::join(&server, "member", "encrypted").await;
+    owner.send(IncomingMessage::SetEndToEndEncryption { enabled: true }).await;
+    owner.expect_success().await;
+
+    owner.send(IncomingMessage::ChatMessage { text: "plaintext".to_string() }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::DisabledInEncryptedRoom));
+    owner.send(IncomingMessage::Custom { channel: "drawing".to_string(), payload: json!("plaintext") }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::DisabledInEncryptedRoom));
+
+    owner.send(IncomingMessage::KeyExchange { to_uid: Some(member.uid), public_key: "owner-key".to_string() }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::EncryptedPayload { to_uid: None, ciphertext: "c2VjcmV0".to_string(), channel: None }).await;
+    owner.expect_success().await;
+    owner.send(IncomingMessage::EncryptedPayload { to_uid: None, ciphertext: "ZHJhd2luZw==".to_string(), channel: Some("drawing".to_string()) }).await;
+    owner.expect_success().await;
+    let public_key = member.expect(|msg| match msg {
+        OutgoingMessage::KeyExchange { public_key, .. } => Some(public_key),
+        _ => None,
+    }).await;
+    assert_eq!(public_key, "owner-key");
+    for expected_channel in [None, Some("drawing".to_string())] {
+        let channel = member.expect(|msg| match msg {
+            OutgoingMessage::EncryptedPayload { from_uid, channel, .. } if from_uid == owner.uid => Some(channel),
+            _ => None,
+        }).await;
+        assert_eq!(channel, expected_channel);
+    }
+
+    // The limits of the channel apply to the ciphertext
+    owner.send(IncomingMessage::EncryptedPayload { to_uid: None, ciphertext: "x".repeat(17), channel: Some("drawing".to_string()) }).await;
+    let kind = owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::PayloadTooLarge));
+}
+
+#[tokio::test]
+async fn invite_links_are_revoked_only_from_their_own_namespace() {
+    let server = TestServer::start().await;
+    let mut movies_owner = TestClient::join_namespace(&server, Some("movies"), "movies-owner", "shared").await;
+    let mut series_owner = TestClient::join_namespace(&server, Some("series"), "series-owner", "shared").await;
+
+    movies_owner.send(IncomingMessage::CreateInviteLink { expires_in_secs: None }).await;
+    let slug = movies_owner.expect(|msg| match msg {
+        OutgoingMessage::InviteLinkCreated { slug, .. } => Some(slug),
+        _ => None,
+    }).await;
+
+    series_owner.send(IncomingMessage::RevokeInviteLink { slug: slug.clone() }).await;
+    let kind = series_owner.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::NoSuchInviteLink));
+    movies_owner.send(IncomingMessage::RevokeInviteLink { slug }).await;
+    movies_owner.expect_success().await;
+}
+
+#[tokio::test]
+async fn invite_links_lead_through_the_join_page_into_the_room() {
+    let server = TestServer::start().await;
+    let mut owner = TestClient::join(&server, "owner", "linked").await;
+    owner.send(IncomingMessage::CreateInviteLink { expires_in_secs: None }).await;
+    let slug = owner.expect(|msg| match msg {
+        OutgoingMessage::InviteLinkCreated { slug, .. } => Some(slug),
+        _ => None,
+    }).await;
+
+    let (status, location) = http_get_location(&server, &format!("/i/{}", slug)).await;
+    assert!(status.is_redirection());
+    let location = location.expect("No redirect location");
+    let (status, page) = http_get(&server, &location).await;
+    assert_eq!(status, StatusCode::OK);
+    let invite = page
+        .split(r#"<meta name="sent-sync-invite" content=""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .expect("No invite on the join page")
+        .to_string();
+
+    let mut guest = TestClient::connect(&server).await;
+    guest.send(IncomingMessage::ChangeName { new_name: "guest".to_string() }).await;
+    guest.expect_success().await;
+    guest.send(IncomingMessage::JoinRoom { room_id: "linked".to_string(), invite: Some(invite.clone()), spectator: false, hidden: false }).await;
+    guest.expect_success().await;
+    guest.send(IncomingMessage::QuitRoom).await;
+    guest.expect_success().await;
+
+    owner.send(IncomingMessage::RevokeInviteLink { slug }).await;
+    owner.expect_success().await;
+    guest.send(IncomingMessage::JoinRoom { room_id: "linked".to_string(), invite: Some(invite), spectator: false, hidden: false }).await;
+    let kind = guest.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await;
+    assert!(matches!(kind, ErrorKind::InvalidInvite));
+}
@@ -0,0 +1,159 @@
+mod common;
+
+use common::{http_get, TestClient, TestServer};
+use hyper::StatusCode;
+use sent_sync_server::config::ServerConfig;
+use sent_sync_server::protocol::{ErrorKind, IncomingMessage, OutgoingMessage};
+use sent_sync_server::ws_dto_models::ScheduledSessionDto;
+use uuid::Uuid;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn unix_millis_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_millis() as u64).unwrap_or_default()
+}
+
+fn schedule(room_id: &str, title: &str, page_url: Option<&str>) -> IncomingMessage {
+    IncomingMessage::ScheduleSession {
+        room_id: room_id.to_string(),
+        title: title.to_string(),
+        page_url: page_url.map(str::to_string),
+        starts_at: unix_millis_now() + 60 * 60 * 1000,
+        invited_uids: Vec::new(),
+        public: false,
+    }
+}
+
+fn schedule_with(room_id: &str, invited_uids: Vec<Uuid>, public: bool) -> IncomingMessage {
+    IncomingMessage::ScheduleSession {
+        room_id: room_id.to_string(),
+        title: "Movie night".to_string(),
+        page_url: None,
+        starts_at: unix_millis_now() + 60 * 60 * 1000,
+        invited_uids,
+        public,
+    }
+}
+
+async fn expect_scheduled(client: &mut TestClient) -> ScheduledSessionDto {
+    client.expect(|msg| match msg {
+        OutgoingMessage::SessionScheduled { session } => Some(session),
+        _ => None,
+    }).await
+}
+
+async fn expect_error(client: &mut TestClient) -> ErrorKind {
+    client.expect(|msg| match msg {
+        OutgoingMessage::Error { kind, .. } => Some(kind),
+        _ => None,
+    }).await
+}
+
+async fn list_sessions(client: &mut TestClient) -> Vec<ScheduledSessionDto> {
+    client.send(IncomingMessage::ListUpcomingSessions).await;
+    client.expect(|msg| match msg {
+        OutgoingMessage::UpcomingSessions { sessions } => Some(sessions),
+        _ => None,
+    }).await
+}
+
+async fn named_client(server: &TestServer, name: &str) -> TestClient {
+    let mut client = TestClient::connect(server).await;
+    client.send(IncomingMessage::ChangeName { new_name: name.to_string() }).await;
+    client.expect_success().await;
+    client
+}
+
+#[tokio::test]
+async fn page_urls_with_line_breaks_are_refused() {
+    let server = TestServer::start().await;
+    let mut host = named_client(&server, "host").await;
+
+    host.send(schedule("party", "Movie night", Some("https://example.com/video\r\nBEGIN:VEVENT"))).await;
+    assert!(matches!(expect_error(&mut host).await, ErrorKind::InvalidUrl));
+}
+
+#[tokio::test]
+async fn user_calendar_is_only_served_with_its_token() {
+    let server = TestServer::start().await;
+    let mut host = named_client(&server, "host").await;
+    host.send(schedule("party", "Movie night", Some("https://example.com/video"))).await;
+    expect_scheduled(&mut host).await;
+
+    host.send(IncomingMessage::RequestCalendarUrl).await;
+    let url = host.expect(|msg| match msg {
+        OutgoingMessage::CalendarUrl { url } => Some(url),
+        _ => None,
+    }).await;
+    let path = &url[url.find("/users/").expect("No calendar path")..];
+    let (status, calendar) = http_get(&server, path).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(calendar.contains("SUMMARY:Movie night\r\n"));
+    assert!(calendar.contains("URL:https://example.com/video\r\n"));
+
+    let (path_without_token, _) = path.split_once('?').expect("No token");
+    let (status, _) = http_get(&server, path_without_token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = http_get(&server, &format!("{}?token=00", path_without_token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = http_get(&server, &format!("/users/{}/calendar.ics", host.uid)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn private_sessions_are_listed_only_to_their_creator_and_invitees() {
+    let server = TestServer::start().await;
+    let mut host = named_client(&server, "host").await;
+    let mut guest = named_client(&server, "guest").await;
+    let mut stranger = named_client(&server, "stranger").await;
+
+    host.send(schedule_with("private", vec![guest.uid], false)).await;
+    let private = expect_scheduled(&mut host).await;
+    assert_eq!(private.invited_uids, vec![guest.uid]);
+    let invitation = guest.expect(|msg| match msg {
+        OutgoingMessage::SessionInvitation { session } => Some(session),
+        _ => None,
+    }).await;
+    assert!(invitation.invited_uids.is_empty());
+    host.send(schedule_with("public", vec![guest.uid], true)).await;
+    let public = expect_scheduled(&mut host).await;
+
+    let listed: Vec<Uuid> = list_sessions(&mut stranger).await.iter().map(|session| session.session_id).collect();
+    assert_eq!(listed, vec![public.session_id]);
+    let sessions = list_sessions(&mut guest).await;
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions.iter().all(|session| session.invited_uids.is_empty()));
+    assert!(list_sessions(&mut host).await.iter().all(|session| session.invited_uids == vec![guest.uid]));
+
+    let (status, body) = http_get(&server, "/sessions").await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions: Vec<ScheduledSessionDto> = serde_json::from_str(&body).expect("Invalid sessions");
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].session_id, public.session_id);
+    assert!(sessions[0].invited_uids.is_empty());
+}
+
+#[tokio::test]
+async fn sessions_are_not_scheduled_for_open_rooms() {
+    let server = TestServer::start().await;
+    let _owner = TestClient::join(&server, "owner", "taken").await;
+    let mut host = named_client(&server, "host").await;
+
+    host.send(schedule_with("taken", Vec::new(), true)).await;
+    assert!(matches!(expect_error(&mut host).await, ErrorKind::RoomAlreadyOpen));
+}
+
+#[tokio::test]
+async fn sessions_and_invitees_are_limited() {
+    let server = TestServer::start_with(ServerConfig { max_sessions_per_creator: 2, max_session_invitees: 1, ..ServerConfig::default() }).await;
+    let mut host = named_client(&server, "host").await;
+
+    host.send(schedule_with("crowded", vec![Uuid::new_v4(), Uuid::new_v4()], false)).await;
+    assert!(matches!(expect_error(&mut host).await, ErrorKind::TooManyInvitees));
+
+    for room_id in ["first", "second"] {
+        host.send(schedule_with(room_id, vec![Uuid::new_v4()], false)).await;
+        expect_scheduled(&mut host).await;
+    }
+    host.send(schedule_with("third", Vec::new(), false)).await;
+    assert!(matches!(expect_error(&mut host).await, ErrorKind::TooManySessions));
+}
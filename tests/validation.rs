@@ -0,0 +1,48 @@
+use sent_sync_server::validation::{grapheme_count, validate_name, validate_page_url, validate_room_id, CharacterPolicy};
+use sent_sync_server::protocol::ErrorKind;
+use sent_sync_server::ServerConfig;
+
+#[test]
+fn graphemes_are_counted_once() {
+    assert_eq!(grapheme_count("abc"), 3);
+    assert_eq!(grapheme_count("Юля"), 3);
+    assert_eq!(grapheme_count("e\u{0301}"), 1);
+    assert_eq!(grapheme_count("👍🏽"), 1);
+    assert_eq!(grapheme_count("👩\u{200D}💻"), 1);
+    assert_eq!(grapheme_count("🇺🇦🇩🇪"), 2);
+}
+
+#[test]
+fn short_names_are_measured_in_characters() {
+    let config = ServerConfig { min_name_length: 2, ..ServerConfig::default() };
+    assert_eq!(validate_name(&config, "Ян").ok().as_deref(), Some("Ян"));
+    assert!(matches!(validate_name(&config, "👍🏽"), Err(ErrorKind::ClientNameTooShort)));
+    assert_eq!(validate_name(&config, "  Ян  ").ok().as_deref(), Some("Ян"));
+}
+
+#[test]
+fn room_ids_follow_the_character_policy() {
+    let config = ServerConfig { room_id_characters: CharacterPolicy::UrlSafe, ..ServerConfig::default() };
+    assert_eq!(validate_room_id(&config, " movie-night ").ok().as_deref(), Some("movie-night"));
+    assert!(matches!(validate_room_id(&config, "movie night"), Err(ErrorKind::RoomIdInvalidCharacters)));
+    assert!(matches!(validate_room_id(&config, "кино"), Err(ErrorKind::RoomIdInvalidCharacters)));
+}
+
+#[test]
+fn reserved_room_ids_are_refused() {
+    let config = ServerConfig { reserved_room_ids: vec!["admin".to_string()], ..ServerConfig::default() };
+    assert!(matches!(validate_room_id(&config, "Admin"), Err(ErrorKind::RoomIdReserved)));
+    assert!(validate_room_id(&config, "admins").is_ok());
+}
+
+#[test]
+fn page_urls_must_be_http_links_to_allowed_domains() {
+    let config = ServerConfig { allowed_page_domains: vec!["youtube.com".to_string()], ..ServerConfig::default() };
+    assert_eq!(validate_page_url(&config, " https://www.youtube.com/watch?v=1 ").ok().as_deref(), Some("https://www.youtube.com/watch?v=1"));
+    assert!(validate_page_url(&config, "http://youtube.com/").is_ok());
+    assert!(matches!(validate_page_url(&config, "https://notyoutube.com/"), Err(ErrorKind::InvalidUrl)));
+    assert!(matches!(validate_page_url(&config, "javascript:alert(1)"), Err(ErrorKind::InvalidUrl)));
+    assert!(matches!(validate_page_url(&config, "youtube.com/watch"), Err(ErrorKind::InvalidUrl)));
+    assert!(matches!(validate_page_url(&config, ""), Err(ErrorKind::InvalidPageUrl)));
+    assert!(validate_page_url(&ServerConfig::default(), "https://example.com/").is_ok());
+}